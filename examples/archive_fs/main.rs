@@ -0,0 +1,25 @@
+use nfsserve::backends::ArchiveFS;
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+
+/// Port number on which the NFS server will listen
+const HOSTPORT: u32 = 11111;
+
+/// NFS server exporting the contents of a tar or zip archive read-only.
+///
+/// Usage: archivefs <archive>
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let path = std::env::args()
+        .nth(1)
+        .expect("must supply archive to export");
+    let fs = ArchiveFS::open(path).expect("cannot open archive");
+    let listener = NFSTcpListener::bind(&format!("127.0.0.1:{HOSTPORT}"), fs)
+        .await
+        .unwrap();
+    listener.handle_forever().await.unwrap();
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use nfsserve::backends::{DedupFS, DedupOptions};
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+
+/// Port number on which the NFS server will listen
+const HOSTPORT: u32 = 11111;
+
+/// NFS server storing files deduplicated in a local chunk store.
+///
+/// Usage: dedupfs <dir> [--avg-chunk <bytes>] [--gc-interval <seconds>]
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .expect("must supply directory to store files in");
+    let mut options = DedupOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--avg-chunk" => {
+                let avg: u32 = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .expect("missing chunk size");
+                options.min_chunk = avg / 4;
+                options.avg_chunk = avg;
+                options.max_chunk = avg * 4;
+            }
+            "--gc-interval" => {
+                let secs = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .expect("missing interval");
+                options.gc_interval = Some(Duration::from_secs(secs));
+            }
+            _ => panic!("unknown option {arg}"),
+        }
+    }
+
+    let fs = DedupFS::with_options(path, options).expect("cannot open chunk store");
+    let listener = NFSTcpListener::bind(&format!("127.0.0.1:{HOSTPORT}"), fs)
+        .await
+        .unwrap();
+    listener.handle_forever().await.unwrap();
+}
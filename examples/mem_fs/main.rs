@@ -0,0 +1,22 @@
+use nfsserve::backends::MemFS;
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+
+/// Port number on which the NFS server will listen
+const HOSTPORT: u32 = 11111;
+
+/// NFS server exporting an empty in-memory file system.
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(std::io::stderr)
+        .init();
+
+    println!("Starting NFS server on 127.0.0.1:{HOSTPORT}");
+    println!("You can mount it with: sudo mount -o proto=tcp,port={HOSTPORT},mountport={HOSTPORT},nolock,addr=127.0.0.1 127.0.0.1:/ /mnt/nfs");
+
+    let listener = NFSTcpListener::bind(&format!("127.0.0.1:{HOSTPORT}"), MemFS::new())
+        .await
+        .unwrap();
+    listener.handle_forever().await.unwrap();
+}
@@ -193,6 +193,36 @@ impl vfs::NFSFileSystem for MirrorFS {
         Ok((buf, eof))
     }
 
+    /// Locates read data in the mirrored file so it can be sent without copying
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> NFSResult<Option<vfs::FileRange>> {
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+        let path = fsmap.sym_to_path(&ent.name).await;
+        drop(fsmap);
+
+        let f = File::open(&path)
+            .await
+            .or(Err(nfs3::nfsstat3::NFS3ERR_NOENT))?;
+        let len = f
+            .metadata()
+            .await
+            .or(Err(nfs3::nfsstat3::NFS3ERR_NOENT))?
+            .len();
+        let start = offset.min(len);
+        let end = (offset + count as u64).min(len);
+        Ok(Some(vfs::FileRange {
+            file: std::sync::Arc::new(f.into_std().await),
+            offset: start,
+            len: (end - start) as u32,
+            eof: offset + count as u64 >= len,
+        }))
+    }
+
     /// Reads directory entries
     async fn readdir(
         &self,
@@ -209,7 +209,7 @@ impl FSMap {
             self.id_to_path
                 .get_mut(&id)
                 .ok_or(nfs3::nfsstat3::NFS3ERR_NOENT)?
-                .children = Some(BTreeSet::from_iter(new_children.into_iter()));
+                .children = Some(BTreeSet::from_iter(new_children));
         }
 
         Ok(())
@@ -0,0 +1,34 @@
+use nfsserve::backends::{PassthroughFS, PassthroughOptions};
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+
+/// Port number on which the NFS server will listen
+const HOSTPORT: u32 = 11111;
+
+/// NFS server exporting a local directory.
+///
+/// Usage: passthroughfs <directory> [--follow-symlinks] [--one-filesystem] [--id-map <file>]
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let path = args.next().expect("must supply directory to export");
+    let mut options = PassthroughOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--follow-symlinks" => options.follow_symlinks = true,
+            "--one-filesystem" => options.one_filesystem = true,
+            "--id-map" => options.id_map_path = Some(args.next().expect("missing file").into()),
+            _ => panic!("unknown option {arg}"),
+        }
+    }
+
+    let fs = PassthroughFS::with_options(path, options).expect("cannot export directory");
+    let listener = NFSTcpListener::bind(&format!("127.0.0.1:{HOSTPORT}"), fs)
+        .await
+        .unwrap();
+    listener.handle_forever().await.unwrap();
+}
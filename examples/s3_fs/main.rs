@@ -0,0 +1,54 @@
+use nfsserve::backends::{DirMarker, S3Options, S3FS};
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+
+/// Port number on which the NFS server will listen
+const HOSTPORT: u32 = 11111;
+
+/// NFS server exporting a bucket of an S3-compatible object store.
+///
+/// Credentials are taken from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// `AWS_SESSION_TOKEN`.
+///
+/// Usage: s3fs <endpoint> <bucket> [--prefix <prefix>] [--region <region>] [--virtual-hosted]
+///             [--marker-suffix <suffix> | --no-markers] [--part-size <bytes>]
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let mut options = S3Options {
+        endpoint: args.next().expect("must supply endpoint URL"),
+        bucket: args.next().expect("must supply bucket"),
+        access_key: std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+        secret_key: std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+        session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        ..Default::default()
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => options.prefix = args.next().expect("missing prefix"),
+            "--region" => options.region = args.next().expect("missing region"),
+            "--virtual-hosted" => options.virtual_hosted = true,
+            "--marker-suffix" => {
+                options.dir_marker = DirMarker::Suffix(args.next().expect("missing suffix"))
+            }
+            "--no-markers" => options.dir_marker = DirMarker::None,
+            "--part-size" => {
+                options.part_size = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .expect("missing part size")
+            }
+            _ => panic!("unknown option {arg}"),
+        }
+    }
+
+    let fs = S3FS::new(options).expect("invalid options");
+    let listener = NFSTcpListener::bind(&format!("127.0.0.1:{HOSTPORT}"), fs)
+        .await
+        .unwrap();
+    listener.handle_forever().await.unwrap();
+}
@@ -0,0 +1,980 @@
+//! Adapter for synchronous file system implementations.
+//!
+//! Calling blocking code from the async methods of [`NFSFileSystem`] stalls the
+//! runtime threads that serve all other clients. [`BlockingNFSFileSystem`] has the
+//! same operations as plain functions, and [`BlockingAdapter`] implements
+//! [`NFSFileSystem`] on top of it by running every call on the blocking thread pool
+//! of the runtime. The number of calls running at once is limited, so a slow backend
+//! cannot exhaust the threads of the pool; calls beyond the limit wait for a free
+//! slot without holding a thread.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::sync::Semaphore;
+use tracing::error;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// Number of concurrent calls allowed by [`BlockingAdapter::new`]
+pub const DEFAULT_POOL_SIZE: usize = 16;
+
+/// Number of entries fetched per call when paging through a directory
+const READDIR_BATCH: usize = 128;
+
+/// Directory entries returned by [`BlockingNFSFileSystem::readdir_stream`]
+pub type BlockingDirEntryIter =
+    Box<dyn Iterator<Item = Result<vfs::DirEntrySimple, nfs3::nfsstat3>> + Send>;
+
+/// A synchronous file system
+///
+/// The operations are the same as the ones of [`NFSFileSystem`]; see there for
+/// their semantics. All methods except `capabilities`, `root_dir`, `id_to_fh`,
+/// `fh_to_id` and `serverid` may block. The provided methods behave like the
+/// default implementations of [`NFSFileSystem`].
+pub trait BlockingNFSFileSystem: Send + Sync + 'static {
+    /// Returns the set of capabilities supported by this file system implementation
+    fn capabilities(&self) -> vfs::Capabilities;
+
+    /// Returns the ID of the root directory
+    fn root_dir(&self) -> nfs3::fileid3;
+
+    /// Looks up a file or directory by name in a directory
+    fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3>;
+
+    /// Gets the attributes of a file or directory
+    fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3>;
+
+    /// Sets the attributes of a file or directory
+    fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3>;
+
+    /// Reads data from a file
+    fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3>;
+
+    /// Writes data to a file
+    fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3>;
+
+    /// Creates a file
+    fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3>;
+
+    /// Creates a file if it does not exist
+    fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3>;
+
+    /// Creates a directory
+    fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3>;
+
+    /// Removes a file or directory
+    fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3>;
+
+    /// Renames a file or directory
+    fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3>;
+
+    /// Reads directory entries after the given file ID
+    fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3>;
+
+    /// Creates a symbolic link
+    fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3>;
+
+    /// Reads the target of a symbolic link
+    fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3>;
+
+    /// Creates a hard link
+    fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3>;
+
+    /// Creates a special file (device, FIFO or socket)
+    fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3>;
+
+    /// Commits written data of a file to stable storage
+    fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3>;
+
+    /// Reads directory entries without attributes after the given file ID
+    fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        eval(Defaults(self).readdir_simple_after(dirid, start_after, count))
+    }
+
+    /// Lists a directory as an iterator of entries
+    ///
+    /// The iterator is advanced on the blocking thread pool. The default
+    /// implementation pages through [`BlockingNFSFileSystem::readdir_simple_after`].
+    fn readdir_stream(
+        self: Arc<Self>,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<BlockingDirEntryIter, nfs3::nfsstat3> {
+        let first = self.readdir_simple_after(dirid, start_after, READDIR_BATCH)?;
+        let mut entries = vfs::paged_entries(dirid, start_after, first, move |last| {
+            std::future::ready(self.readdir_simple_after(dirid, last, READDIR_BATCH))
+        });
+        Ok(Box::new(std::iter::from_fn(move || eval(entries.next()))))
+    }
+
+    /// Gets the attributes of several files at once
+    fn getattr_batch(&self, ids: &[nfs3::fileid3]) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        ids.iter().map(|id| self.getattr(*id)).collect()
+    }
+
+    /// Sets the attributes of a file or directory and returns its wcc data
+    fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        eval(Defaults(self).setattr_wcc(id, setattr, guard))
+    }
+
+    /// Writes data to a file and returns its wcc data and how far the data was committed
+    fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        eval(Defaults(self).write_wcc(id, offset, data, stable))
+    }
+
+    /// Creates a file and returns the wcc data of the directory
+    fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        eval(Defaults(self).create_wcc(dirid, filename, attr))
+    }
+
+    /// Creates a file if it does not exist and returns the wcc data of the directory
+    fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        eval(Defaults(self).create_exclusive_wcc(dirid, filename))
+    }
+
+    /// Creates a directory and returns the wcc data of its parent
+    fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        eval(Defaults(self).mkdir_wcc(dirid, dirname))
+    }
+
+    /// Removes a file or directory of the given kind and returns the wcc data of the
+    /// directory
+    fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        eval(Defaults(self).remove_wcc(dirid, filename, kind))
+    }
+
+    /// Renames a file or directory and returns the wcc data of both directories
+    fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        eval(Defaults(self).rename_wcc(from_dirid, from_filename, to_dirid, to_filename))
+    }
+
+    /// Creates a symbolic link and returns the wcc data of the directory
+    fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        eval(Defaults(self).symlink_wcc(dirid, linkname, symlink, attr))
+    }
+
+    /// Creates a hard link and returns the wcc data of the link directory
+    fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        eval(Defaults(self).link_wcc(file_id, link_dir_id, link_name))
+    }
+
+    /// Creates a special file and returns the wcc data of the directory
+    fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        eval(Defaults(self).mknod_wcc(dir_id, name, ftype, specdata, attrs))
+    }
+
+    /// Commits written data of a file to stable storage and returns its wcc data
+    fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        eval(Defaults(self).commit_wcc(file_id, offset, count))
+    }
+
+    /// Retrieves static file system information
+    fn fsinfo(&self, root_fileid: nfs3::fileid3) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        eval(Defaults(self).fsinfo(root_fileid))
+    }
+
+    /// Retrieves volatile file system state, such as the free space
+    fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        eval(Defaults(self).fsstat(id))
+    }
+
+    /// Converts a file ID to an opaque NFS file handle
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        Defaults(self).id_to_fh(id)
+    }
+
+    /// Converts an opaque NFS file handle to a file ID
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        Defaults(self).fh_to_id(id)
+    }
+
+    /// Returns a unique server ID used for cookie and write verification
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        Defaults(self).serverid()
+    }
+}
+
+/// Runs a future to completion on the current thread
+///
+/// The thread is parked while the future waits. This does not enter an executor,
+/// since the blocking file system may itself run one on this thread, for example
+/// with `futures::executor::block_on`, which refuses to be nested.
+fn eval<T>(f: impl Future<Output = T>) -> T {
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut f = std::pin::pin!(f);
+    loop {
+        match f.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => return value,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+/// Waker that unparks the thread running [`eval`]
+struct ThreadWaker(std::thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// View of a blocking file system through [`NFSFileSystem`], used to evaluate the
+/// default implementations of its methods
+///
+/// The methods of this view call the blocking file system directly on the thread
+/// that runs [`eval`].
+struct Defaults<'a, F: ?Sized>(&'a F);
+
+#[async_trait]
+impl<F: BlockingNFSFileSystem + ?Sized> NFSFileSystem for Defaults<'_, F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.0.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.0.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.0.lookup(dirid, filename)
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.0.getattr(id)
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.0.setattr(id, setattr)
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        self.0.read(id, offset, count)
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.0.write(id, offset, data)
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.0.create(dirid, filename, attr)
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.0.create_exclusive(dirid, filename)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.0.mkdir(dirid, dirname)
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.0.remove(dirid, filename)
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.0
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        self.0.readdir(dirid, start_after, max_entries)
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.0.symlink(dirid, linkname, symlink, attr)
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.0.readlink(id)
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.0.link(file_id, link_dir_id, link_name)
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.0.mknod(dir_id, name, ftype, specdata, attrs)
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.0.commit(file_id, offset, count)
+    }
+}
+
+/// Implements [`NFSFileSystem`] on top of a [`BlockingNFSFileSystem`]
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct BlockingAdapter<F> {
+    /// The synchronous file system
+    fs: Arc<F>,
+    /// Slots for calls into the file system, one per call running at a time
+    slots: Arc<Semaphore>,
+}
+
+impl<F: BlockingNFSFileSystem> BlockingAdapter<F> {
+    /// Creates an adapter allowing [`DEFAULT_POOL_SIZE`] concurrent calls
+    pub fn new(fs: F) -> Self {
+        Self::with_pool_size(fs, DEFAULT_POOL_SIZE)
+    }
+
+    /// Creates an adapter allowing the given number of concurrent calls
+    ///
+    /// # Arguments
+    ///
+    /// * `fs` - The synchronous file system
+    /// * `threads` - Maximum number of concurrent calls into the file system (at least 1)
+    pub fn with_pool_size(fs: F, threads: usize) -> Self {
+        BlockingAdapter {
+            fs: Arc::new(fs),
+            slots: Arc::new(Semaphore::new(threads.max(1))),
+        }
+    }
+
+    /// Returns a reference to the synchronous file system
+    pub fn inner(&self) -> &F {
+        &self.fs
+    }
+
+    /// Runs a call on the blocking thread pool once a slot is free and waits for its result
+    ///
+    /// A call that panics fails with NFS3ERR_SERVERFAULT.
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Arc<F>) -> T + Send + 'static,
+    ) -> Result<T, nfs3::nfsstat3> {
+        let slot = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| nfs3::nfsstat3::NFS3ERR_SERVERFAULT)?;
+        let fs = self.fs.clone();
+        let call = tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            f(&fs)
+        });
+        call.await.map_err(|e| {
+            error!("Blocking file system call failed: {}", e);
+            nfs3::nfsstat3::NFS3ERR_SERVERFAULT
+        })
+    }
+
+    /// Runs a call returning a result on the blocking thread pool
+    async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&F) -> Result<T, nfs3::nfsstat3> + Send + 'static,
+    ) -> Result<T, nfs3::nfsstat3> {
+        self.run(move |fs| f(fs)).await?
+    }
+
+    /// Runs a call returning wcc data on the blocking thread pool
+    async fn call_wcc<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&F) -> WccResult<T> + Send + 'static,
+    ) -> WccResult<T> {
+        self.run(move |fs| f(fs))
+            .await
+            .unwrap_or_else(|stat| WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            })
+    }
+}
+
+#[async_trait]
+impl<F: BlockingNFSFileSystem> NFSFileSystem for BlockingAdapter<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.fs.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.fs.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let filename = filename.clone();
+        self.call(move |fs| fs.lookup(dirid, &filename)).await
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.call(move |fs| fs.getattr(id)).await
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.call(move |fs| fs.setattr(id, setattr)).await
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        self.call(move |fs| fs.read(id, offset, count)).await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let data = data.to_vec();
+        self.call(move |fs| fs.write(id, offset, &data)).await
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let filename = filename.clone();
+        self.call(move |fs| fs.create(dirid, &filename, attr)).await
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let filename = filename.clone();
+        self.call(move |fs| fs.create_exclusive(dirid, &filename))
+            .await
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let dirname = dirname.clone();
+        self.call(move |fs| fs.mkdir(dirid, &dirname)).await
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let filename = filename.clone();
+        self.call(move |fs| fs.remove(dirid, &filename)).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let from_filename = from_filename.clone();
+        let to_filename = to_filename.clone();
+        self.call(move |fs| fs.rename(from_dirid, &from_filename, to_dirid, &to_filename))
+            .await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        self.call(move |fs| fs.readdir(dirid, start_after, max_entries))
+            .await
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let linkname = linkname.clone();
+        let symlink = symlink.clone();
+        let attr = *attr;
+        self.call(move |fs| fs.symlink(dirid, &linkname, &symlink, &attr))
+            .await
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.call(move |fs| fs.readlink(id)).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let link_name = link_name.clone();
+        self.call(move |fs| fs.link(file_id, link_dir_id, &link_name))
+            .await
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = name.clone();
+        let attrs = *attrs;
+        self.call(move |fs| fs.mknod(dir_id, &name, ftype, specdata, &attrs))
+            .await
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.call(move |fs| fs.commit(file_id, offset, count)).await
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        self.call(move |fs| fs.readdir_simple_after(dirid, start_after, count))
+            .await
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        let iter = self
+            .run(move |fs| fs.clone().readdir_stream(dirid, start_after))
+            .await??;
+        // entries are pulled in batches so that every thread hop returns many of them
+        let batches = futures::stream::unfold(Some(iter), move |iter| async move {
+            let mut iter = iter?;
+            let pulled = self
+                .run(move |_| {
+                    let batch: Vec<_> = iter.by_ref().take(READDIR_BATCH).collect();
+                    (batch, iter)
+                })
+                .await;
+            match pulled {
+                Ok((batch, _)) if batch.is_empty() => None,
+                Ok((batch, iter)) => Some((batch, Some(iter))),
+                Err(stat) => Some((vec![Err(stat)], None)),
+            }
+        });
+        Ok(batches.flat_map(futures::stream::iter).boxed())
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let ids = ids.to_vec();
+        let count = ids.len();
+        self.run(move |fs| fs.getattr_batch(&ids))
+            .await
+            .unwrap_or_else(|stat| vec![Err(stat); count])
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        self.call_wcc(move |fs| fs.setattr_wcc(id, setattr, guard))
+            .await
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let data = data.to_vec();
+        self.call_wcc(move |fs| fs.write_wcc(id, offset, &data, stable))
+            .await
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let filename = filename.clone();
+        self.call_wcc(move |fs| fs.create_wcc(dirid, &filename, attr))
+            .await
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        let filename = filename.clone();
+        self.call_wcc(move |fs| fs.create_exclusive_wcc(dirid, &filename))
+            .await
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let dirname = dirname.clone();
+        self.call_wcc(move |fs| fs.mkdir_wcc(dirid, &dirname)).await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        let filename = filename.clone();
+        self.call_wcc(move |fs| fs.remove_wcc(dirid, &filename, kind))
+            .await
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        let from_filename = from_filename.clone();
+        let to_filename = to_filename.clone();
+        self.run(move |fs| fs.rename_wcc(from_dirid, &from_filename, to_dirid, &to_filename))
+            .await
+            .unwrap_or_else(|stat| RenameWccResult {
+                result: Err(stat),
+                from_dir_wcc: nfs3::wcc_data::default(),
+                to_dir_wcc: nfs3::wcc_data::default(),
+            })
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let linkname = linkname.clone();
+        let symlink = symlink.clone();
+        let attr = *attr;
+        self.call_wcc(move |fs| fs.symlink_wcc(dirid, &linkname, &symlink, &attr))
+            .await
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        let link_name = link_name.clone();
+        self.call_wcc(move |fs| fs.link_wcc(file_id, link_dir_id, &link_name))
+            .await
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let name = name.clone();
+        let attrs = *attrs;
+        self.call_wcc(move |fs| fs.mknod_wcc(dir_id, &name, ftype, specdata, &attrs))
+            .await
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        self.call_wcc(move |fs| fs.commit_wcc(file_id, offset, count))
+            .await
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        self.call(move |fs| fs.fsinfo(root_fileid)).await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        self.call(move |fs| fs.fsstat(id)).await
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.fs.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.fs.fh_to_id(id)
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.fs.serverid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval_waits_for_the_future_to_be_woken() {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            tx.send(7).unwrap();
+        });
+        assert_eq!(eval(async { rx.await.unwrap() }), 7);
+    }
+
+    #[test]
+    fn eval_allows_an_executor_inside() {
+        let value = eval(async { futures::executor::block_on(async { 7 }) });
+        assert_eq!(value, 7);
+    }
+}
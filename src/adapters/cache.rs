@@ -0,0 +1,1024 @@
+//! Attribute and lookup caching for slow file systems.
+//!
+//! The NFS handlers fetch attributes for almost every reply, often several times
+//! per request, and clients issue a steady stream of GETATTR, LOOKUP and ACCESS
+//! calls. [`Cached`] wraps an [`NFSFileSystem`] and answers these from memory for
+//! a configurable time:
+//! - Attributes by file ID
+//! - Lookup results by directory and name, both successful ones and NFS3ERR_NOENT
+//!
+//! Directory listings and the results of mutating operations fill the caches.
+//! Mutations made through the wrapper invalidate the entries they affect, and
+//! results fetched from the wrapped file system while such a mutation was under
+//! way are returned but not cached, so the server never contradicts itself. Changes made to the backend by other means are
+//! only noticed once the entries expire, unless they are reported with
+//! [`Cached::invalidate`], [`Cached::invalidate_lookup`] or [`Cached::clear`].
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// Options of a [`Cached`] file system
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    /// How long attributes are cached; zero disables the attribute cache
+    pub attr_ttl: Duration,
+    /// How long successful lookups are cached; zero disables them
+    pub lookup_ttl: Duration,
+    /// How long failed lookups (NFS3ERR_NOENT) are cached; zero disables them
+    pub negative_ttl: Duration,
+    /// Maximum number of cached attributes and lookups, each
+    ///
+    /// Expired entries are dropped when the limit is reached, and everything
+    /// is dropped if that is not enough.
+    pub max_entries: usize,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            attr_ttl: Duration::from_secs(1),
+            lookup_ttl: Duration::from_secs(1),
+            negative_ttl: Duration::from_secs(1),
+            max_entries: 100_000,
+        }
+    }
+}
+
+/// A cached value with its expiry time
+#[derive(Debug)]
+struct Entry<T> {
+    /// The cached value
+    value: T,
+    /// Time after which the value must not be used
+    expires: Instant,
+}
+
+/// The cached attributes and lookups
+#[derive(Debug, Default)]
+struct Caches {
+    /// Attributes by file ID
+    attrs: HashMap<nfs3::fileid3, Entry<nfs3::fattr3>>,
+    /// Lookup results by directory and name; `None` records a missing name
+    lookups: HashMap<nfs3::fileid3, HashMap<Vec<u8>, Entry<Option<nfs3::fileid3>>>>,
+    /// Number of cached lookups
+    lookup_count: usize,
+    /// Counter incremented whenever a mutation changes cached entries
+    epoch: u64,
+    /// Epoch of the last mutation changing the attributes of an object
+    attrs_changed: HashMap<nfs3::fileid3, u64>,
+    /// Epoch of the last mutation changing the entries of a directory
+    dirs_changed: HashMap<nfs3::fileid3, u64>,
+    /// Epoch before which all fetched results are discarded, set when the
+    /// change epochs are forgotten
+    floor: u64,
+}
+
+impl Caches {
+    /// Records that a mutation changed the attributes of an object
+    fn changed_attr(&mut self, id: nfs3::fileid3, options: &CacheOptions) {
+        self.epoch += 1;
+        self.attrs_changed.insert(id, self.epoch);
+        self.limit_changes(options);
+    }
+
+    /// Records that a mutation changed the entries of a directory
+    fn changed_dir(&mut self, dirid: nfs3::fileid3, options: &CacheOptions) {
+        self.epoch += 1;
+        self.dirs_changed.insert(dirid, self.epoch);
+        self.limit_changes(options);
+    }
+
+    /// Forgets the change epochs if there are too many, discarding results
+    /// fetched before
+    fn limit_changes(&mut self, options: &CacheOptions) {
+        if self.attrs_changed.len() + self.dirs_changed.len() > options.max_entries {
+            self.attrs_changed.clear();
+            self.dirs_changed.clear();
+            self.floor = self.epoch;
+        }
+    }
+
+    /// Checks that the attributes of an object fetched since an epoch may be cached
+    fn attr_current(&self, id: nfs3::fileid3, since: u64) -> bool {
+        since >= self.floor && self.attrs_changed.get(&id).is_none_or(|e| *e <= since)
+    }
+
+    /// Checks that lookups in a directory fetched since an epoch may be cached
+    fn dir_current(&self, dirid: nfs3::fileid3, since: u64) -> bool {
+        since >= self.floor && self.dirs_changed.get(&dirid).is_none_or(|e| *e <= since)
+    }
+
+    /// Caches fetched attributes of an object unless a mutation changed them since
+    /// the fetch started
+    fn fill_attr(
+        &mut self,
+        id: nfs3::fileid3,
+        result: &Result<nfs3::fattr3, nfs3::nfsstat3>,
+        since: u64,
+        options: &CacheOptions,
+    ) {
+        match result {
+            Ok(attr) if self.attr_current(id, since) => self.put_attr(*attr, options),
+            Ok(_) => {}
+            Err(_) => {
+                self.attrs.remove(&id);
+            }
+        }
+    }
+
+    /// Returns the cached attributes of an object
+    fn attr(&self, id: nfs3::fileid3, now: Instant) -> Option<nfs3::fattr3> {
+        self.attrs
+            .get(&id)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.value)
+    }
+
+    /// Caches the attributes of an object
+    fn put_attr(&mut self, attr: nfs3::fattr3, options: &CacheOptions) {
+        if options.attr_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if self.attrs.len() >= options.max_entries {
+            self.attrs.retain(|_, entry| entry.expires > now);
+            if self.attrs.len() >= options.max_entries {
+                self.attrs.clear();
+            }
+        }
+        self.attrs.insert(
+            attr.fileid,
+            Entry {
+                value: attr,
+                expires: now + options.attr_ttl,
+            },
+        );
+    }
+
+    /// Returns the cached result of a lookup
+    fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        now: Instant,
+    ) -> Option<Option<nfs3::fileid3>> {
+        self.lookups
+            .get(&dirid)?
+            .get(name)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.value)
+    }
+
+    /// Caches the result of a lookup
+    fn put_lookup(
+        &mut self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        id: Option<nfs3::fileid3>,
+        options: &CacheOptions,
+    ) {
+        let ttl = match id {
+            Some(_) => options.lookup_ttl,
+            None => options.negative_ttl,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if self.lookup_count >= options.max_entries {
+            self.lookups.retain(|_, dir| {
+                dir.retain(|_, entry| entry.expires > now);
+                !dir.is_empty()
+            });
+            self.lookup_count = self.lookups.values().map(HashMap::len).sum();
+            if self.lookup_count >= options.max_entries {
+                self.lookups.clear();
+                self.lookup_count = 0;
+            }
+        }
+        let entry = Entry {
+            value: id,
+            expires: now + ttl,
+        };
+        let dir = self.lookups.entry(dirid).or_default();
+        if dir.insert(name.to_vec(), entry).is_none() {
+            self.lookup_count += 1;
+        }
+    }
+
+    /// Drops a cached lookup, returning the file ID it resolved to
+    fn drop_lookup(&mut self, dirid: nfs3::fileid3, name: &[u8]) -> Option<nfs3::fileid3> {
+        let dir = self.lookups.get_mut(&dirid)?;
+        let entry = dir.remove(name)?;
+        self.lookup_count -= 1;
+        if dir.is_empty() {
+            self.lookups.remove(&dirid);
+        }
+        entry.value
+    }
+
+    /// Drops the cached lookups of a directory
+    fn drop_dir(&mut self, dirid: nfs3::fileid3) {
+        if let Some(dir) = self.lookups.remove(&dirid) {
+            self.lookup_count -= dir.len();
+        }
+    }
+
+    /// Drops a cached name of a directory together with the attributes of the
+    /// directory and of the object the name resolved to
+    fn drop_name(&mut self, dirid: nfs3::fileid3, name: &[u8], options: &CacheOptions) {
+        if let Some(id) = self.drop_lookup(dirid, name) {
+            self.attrs.remove(&id);
+            self.changed_attr(id, options);
+            // a moved directory has a new parent
+            self.drop_lookup(id, b"..");
+            self.changed_dir(id, options);
+        }
+        self.attrs.remove(&dirid);
+        self.changed_attr(dirid, options);
+        self.changed_dir(dirid, options);
+    }
+}
+
+/// Caching wrapper around a file system
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Cached<F> {
+    /// The wrapped file system
+    inner: F,
+    /// Cache settings
+    options: CacheOptions,
+    /// The cached attributes and lookups
+    caches: Mutex<Caches>,
+}
+
+impl<F: NFSFileSystem> Cached<F> {
+    /// Creates a caching wrapper with default options
+    pub fn new(inner: F) -> Self {
+        Self::with_options(inner, CacheOptions::default())
+    }
+
+    /// Creates a caching wrapper
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The file system to cache
+    /// * `options` - Cache settings
+    pub fn with_options(inner: F, options: CacheOptions) -> Self {
+        Cached {
+            inner,
+            options,
+            caches: Mutex::new(Caches::default()),
+        }
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Returns the wrapped file system
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Drops the cached attributes of an object, and its cached lookups if it is a directory
+    pub fn invalidate(&self, id: nfs3::fileid3) {
+        let mut caches = self.caches.lock().unwrap();
+        caches.attrs.remove(&id);
+        caches.drop_dir(id);
+        caches.changed_attr(id, &self.options);
+        caches.changed_dir(id, &self.options);
+    }
+
+    /// Drops the cached lookup of a name in a directory
+    pub fn invalidate_lookup(&self, dirid: nfs3::fileid3, name: &[u8]) {
+        let mut caches = self.caches.lock().unwrap();
+        caches.drop_lookup(dirid, name);
+        caches.changed_dir(dirid, &self.options);
+    }
+
+    /// Drops everything that is cached
+    pub fn clear(&self) {
+        let mut caches = self.caches.lock().unwrap();
+        let epoch = caches.epoch + 1;
+        *caches = Caches {
+            epoch,
+            floor: epoch,
+            ..Caches::default()
+        };
+    }
+
+    /// Returns the current epoch, to be passed to the fills of results fetched
+    /// from now on
+    fn epoch(&self) -> u64 {
+        self.caches.lock().unwrap().epoch
+    }
+
+    /// Caches the outcome of an operation returning the new attributes of an object
+    fn note_attr(&self, id: nfs3::fileid3, result: &Result<nfs3::fattr3, nfs3::nfsstat3>) {
+        let mut caches = self.caches.lock().unwrap();
+        caches.changed_attr(id, &self.options);
+        match result {
+            Ok(attr) => caches.put_attr(*attr, &self.options),
+            Err(_) => {
+                caches.attrs.remove(&id);
+            }
+        }
+    }
+
+    /// Caches the post-operation attributes of weak cache consistency data
+    fn note_wcc(&self, id: nfs3::fileid3, wcc: &nfs3::wcc_data) {
+        let mut caches = self.caches.lock().unwrap();
+        caches.changed_attr(id, &self.options);
+        match wcc.after {
+            nfs3::post_op_attr::attributes(attr) if attr.fileid == id => {
+                caches.put_attr(attr, &self.options)
+            }
+            _ => {
+                caches.attrs.remove(&id);
+            }
+        }
+    }
+
+    /// Caches the outcome of an operation creating a new directory entry
+    ///
+    /// The cached attributes of the directory are dropped; operations with weak
+    /// cache consistency data replace them with [`Cached::note_wcc`] afterwards.
+    fn note_new(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        new: Option<(nfs3::fileid3, Option<nfs3::fattr3>)>,
+    ) {
+        let mut caches = self.caches.lock().unwrap();
+        caches.attrs.remove(&dirid);
+        caches.changed_attr(dirid, &self.options);
+        caches.changed_dir(dirid, &self.options);
+        if let Some((id, _)) = new {
+            caches.changed_attr(id, &self.options);
+        }
+        match new {
+            Some((id, Some(attr))) => {
+                caches.put_lookup(dirid, name, Some(id), &self.options);
+                caches.put_attr(attr, &self.options);
+            }
+            Some((id, None)) => {
+                caches.put_lookup(dirid, name, Some(id), &self.options);
+                caches.attrs.remove(&id);
+            }
+            None => {
+                caches.drop_lookup(dirid, name);
+            }
+        }
+    }
+
+    /// Drops everything a removal of a directory entry may have changed
+    ///
+    /// The removed object's attributes are only dropped if its name is cached,
+    /// which [`Cached::lookup`] before the removal makes sure of.
+    fn note_removed(&self, dirid: nfs3::fileid3, name: &[u8]) {
+        self.caches
+            .lock()
+            .unwrap()
+            .drop_name(dirid, name, &self.options);
+    }
+
+    /// Drops everything a rename may have changed
+    fn note_renamed(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_name: &[u8],
+        to_dirid: nfs3::fileid3,
+        to_name: &[u8],
+    ) {
+        let mut caches = self.caches.lock().unwrap();
+        caches.drop_name(from_dirid, from_name, &self.options);
+        caches.drop_name(to_dirid, to_name, &self.options);
+    }
+
+    /// Caches the entries of a directory listing read since an epoch, leaving out
+    /// those a mutation changed in the meantime
+    fn note_listing<'a>(
+        &self,
+        dirid: nfs3::fileid3,
+        since: u64,
+        entries: impl Iterator<Item = (nfs3::fileid3, &'a [u8], Option<&'a nfs3::fattr3>)>,
+    ) {
+        let mut caches = self.caches.lock().unwrap();
+        let dir_current = caches.dir_current(dirid, since);
+        for (id, name, attr) in entries {
+            if dir_current {
+                caches.put_lookup(dirid, name, Some(id), &self.options);
+            }
+            if let Some(attr) = attr.filter(|_| caches.attr_current(id, since)) {
+                caches.put_attr(*attr, &self.options);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<F: NFSFileSystem> NFSFileSystem for Cached<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.inner.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let (cached, since) = {
+            let caches = self.caches.lock().unwrap();
+            (caches.lookup(dirid, filename, Instant::now()), caches.epoch)
+        };
+        match cached {
+            Some(Some(id)) => return Ok(id),
+            Some(None) => return Err(nfs3::nfsstat3::NFS3ERR_NOENT),
+            None => {}
+        }
+        let result = self.inner.lookup(dirid, filename).await;
+        let mut caches = self.caches.lock().unwrap();
+        match result {
+            _ if !caches.dir_current(dirid, since) => {}
+            Ok(id) => caches.put_lookup(dirid, filename, Some(id), &self.options),
+            Err(nfs3::nfsstat3::NFS3ERR_NOENT) => {
+                caches.put_lookup(dirid, filename, None, &self.options)
+            }
+            Err(_) => {
+                caches.drop_lookup(dirid, filename);
+            }
+        }
+        result
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let since = {
+            let caches = self.caches.lock().unwrap();
+            if let Some(attr) = caches.attr(id, Instant::now()) {
+                return Ok(attr);
+            }
+            caches.epoch
+        };
+        let result = self.inner.getattr(id).await;
+        self.caches
+            .lock()
+            .unwrap()
+            .fill_attr(id, &result, since, &self.options);
+        result
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let result = self.inner.setattr(id, setattr).await;
+        self.note_attr(id, &result);
+        result
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        self.inner.read(id, offset, count).await
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        self.inner.read_bytes(id, offset, count).await
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        self.inner.read_file_range(id, offset, count).await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let result = self.inner.write(id, offset, data).await;
+        self.note_attr(id, &result);
+        result
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let result = self.inner.create(dirid, filename, attr).await;
+        let new = result.as_ref().ok().map(|(id, attr)| (*id, Some(*attr)));
+        self.note_new(dirid, filename, new);
+        result
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let result = self.inner.create_exclusive(dirid, filename).await;
+        self.note_new(dirid, filename, result.as_ref().ok().map(|id| (*id, None)));
+        result
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let result = self.inner.mkdir(dirid, dirname).await;
+        let new = result.as_ref().ok().map(|(id, attr)| (*id, Some(*attr)));
+        self.note_new(dirid, dirname, new);
+        result
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        // the removed object is only known to the cache under its name
+        self.lookup(dirid, filename).await.ok();
+        let result = self.inner.remove(dirid, filename).await;
+        self.note_removed(dirid, filename);
+        result
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        // the moved object is only known to the cache under its old name
+        self.lookup(from_dirid, from_filename).await.ok();
+        let result = self
+            .inner
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await;
+        self.note_renamed(from_dirid, from_filename, to_dirid, to_filename);
+        result
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let since = self.epoch();
+        let result = self.inner.readdir(dirid, start_after, max_entries).await?;
+        self.note_listing(
+            dirid,
+            since,
+            result
+                .entries
+                .iter()
+                .map(|e| (e.fileid, e.name.as_slice(), Some(&e.attr))),
+        );
+        Ok(result)
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        let since = self.epoch();
+        let result = self
+            .inner
+            .readdir_simple_after(dirid, start_after, count)
+            .await?;
+        self.note_listing(
+            dirid,
+            since,
+            result
+                .entries
+                .iter()
+                .map(|e| (e.fileid, e.name.as_slice(), e.attr.as_ref())),
+        );
+        Ok(result)
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        let since = self.epoch();
+        let stream = self.inner.readdir_stream(dirid, start_after).await?;
+        Ok(stream
+            .inspect(move |entry| {
+                if let Ok(e) = entry {
+                    self.note_listing(
+                        dirid,
+                        since,
+                        std::iter::once((e.fileid, e.name.as_slice(), e.attr.as_ref())),
+                    );
+                }
+            })
+            .boxed())
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let now = Instant::now();
+        let (cached, since): (Vec<_>, _) = {
+            let caches = self.caches.lock().unwrap();
+            let cached = ids.iter().map(|id| caches.attr(*id, now)).collect();
+            (cached, caches.epoch)
+        };
+        let missing: Vec<_> = ids
+            .iter()
+            .zip(cached.iter())
+            .filter(|(_, attr)| attr.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        if missing.is_empty() {
+            return cached.into_iter().map(|attr| Ok(attr.unwrap())).collect();
+        }
+        let mut fetched = self.inner.getattr_batch(&missing).await.into_iter();
+        let mut caches = self.caches.lock().unwrap();
+        let mut results = Vec::with_capacity(ids.len());
+        for (id, attr) in ids.iter().zip(cached) {
+            let result = match attr {
+                Some(attr) => Ok(attr),
+                None => {
+                    let result = fetched
+                        .next()
+                        .unwrap_or(Err(nfs3::nfsstat3::NFS3ERR_SERVERFAULT));
+                    caches.fill_attr(*id, &result, since, &self.options);
+                    result
+                }
+            };
+            results.push(result);
+        }
+        results
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let result = self.inner.symlink(dirid, linkname, symlink, attr).await;
+        let new = result.as_ref().ok().map(|(id, attr)| (*id, Some(*attr)));
+        self.note_new(dirid, linkname, new);
+        result
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.inner.readlink(id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let result = self.inner.link(file_id, link_dir_id, link_name).await;
+        let new = result.as_ref().ok().map(|attr| (file_id, Some(*attr)));
+        self.note_new(link_dir_id, link_name, new);
+        self.note_attr(file_id, &result);
+        result
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let result = self.inner.mknod(dir_id, name, ftype, specdata, attrs).await;
+        let new = result.as_ref().ok().map(|(id, attr)| (*id, Some(*attr)));
+        self.note_new(dir_id, name, new);
+        result
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let result = self.inner.commit(file_id, offset, count).await;
+        self.note_attr(file_id, &result);
+        result
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        let res = self.inner.setattr_wcc(id, setattr, guard).await;
+        self.note_wcc(id, &res.wcc);
+        res
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let res = self.inner.write_wcc(id, offset, data, stable).await;
+        self.note_wcc(id, &res.wcc);
+        res
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let res = self.inner.create_wcc(dirid, filename, attr).await;
+        let new = res
+            .result
+            .as_ref()
+            .ok()
+            .map(|(id, attr)| (*id, Some(*attr)));
+        self.note_new(dirid, filename, new);
+        self.note_wcc(dirid, &res.wcc);
+        res
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        let res = self.inner.create_exclusive_wcc(dirid, filename).await;
+        self.note_new(
+            dirid,
+            filename,
+            res.result.as_ref().ok().map(|id| (*id, None)),
+        );
+        self.note_wcc(dirid, &res.wcc);
+        res
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let res = self.inner.mkdir_wcc(dirid, dirname).await;
+        let new = res
+            .result
+            .as_ref()
+            .ok()
+            .map(|(id, attr)| (*id, Some(*attr)));
+        self.note_new(dirid, dirname, new);
+        self.note_wcc(dirid, &res.wcc);
+        res
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        // the removed object is only known to the cache under its name
+        self.lookup(dirid, filename).await.ok();
+        let res = self.inner.remove_wcc(dirid, filename, kind).await;
+        self.note_removed(dirid, filename);
+        self.note_wcc(dirid, &res.wcc);
+        res
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        // the moved object is only known to the cache under its old name
+        self.lookup(from_dirid, from_filename).await.ok();
+        let res = self
+            .inner
+            .rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await;
+        self.note_renamed(from_dirid, from_filename, to_dirid, to_filename);
+        self.note_wcc(from_dirid, &res.from_dir_wcc);
+        self.note_wcc(to_dirid, &res.to_dir_wcc);
+        res
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let res = self.inner.symlink_wcc(dirid, linkname, symlink, attr).await;
+        let new = res
+            .result
+            .as_ref()
+            .ok()
+            .map(|(id, attr)| (*id, Some(*attr)));
+        self.note_new(dirid, linkname, new);
+        self.note_wcc(dirid, &res.wcc);
+        res
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        let res = self.inner.link_wcc(file_id, link_dir_id, link_name).await;
+        let new = res.result.as_ref().ok().map(|attr| (file_id, Some(*attr)));
+        self.note_new(link_dir_id, link_name, new);
+        self.note_attr(file_id, &res.result);
+        self.note_wcc(link_dir_id, &res.wcc);
+        res
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let res = self
+            .inner
+            .mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await;
+        let new = res
+            .result
+            .as_ref()
+            .ok()
+            .map(|(id, attr)| (*id, Some(*attr)));
+        self.note_new(dir_id, name, new);
+        self.note_wcc(dir_id, &res.wcc);
+        res
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        let res = self.inner.commit_wcc(file_id, offset, count).await;
+        self.note_wcc(file_id, &res.wcc);
+        res
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        self.inner.fsinfo(root_fileid).await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        self.inner.fsstat(id).await
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.path_to_id(path).await
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.inner.serverid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    #[tokio::test]
+    async fn fetches_overtaken_by_a_mutation_are_not_cached() {
+        let fs = Cached::new(MemFS::new());
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+
+        // a GETATTR fetching from the backend while a WRITE completes
+        let since = fs.epoch();
+        let fetched = fs.inner().getattr(file).await;
+        fs.write(file, 0, b"data").await.unwrap();
+        fs.caches
+            .lock()
+            .unwrap()
+            .fill_attr(file, &fetched, since, &fs.options);
+        assert_eq!(fs.getattr(file).await.unwrap().size, 4);
+
+        // a READDIR listing the directory while a REMOVE completes
+        let since = fs.epoch();
+        let listing = fs.inner().readdir(root, 0, 10).await.unwrap();
+        fs.remove(root, &name("file")).await.unwrap();
+        fs.note_listing(
+            root,
+            since,
+            listing
+                .entries
+                .iter()
+                .map(|e| (e.fileid, e.name.as_slice(), Some(&e.attr))),
+        );
+        assert!(matches!(
+            fs.lookup(root, &name("file")).await,
+            Err(nfs3::nfsstat3::NFS3ERR_NOENT)
+        ));
+    }
+
+    #[tokio::test]
+    async fn removing_a_hard_link_refreshes_the_other_name() {
+        let fs = Cached::new(MemFS::new());
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .inner()
+            .create(root, &name("a"), Default::default())
+            .await
+            .unwrap();
+        fs.inner().link(file, root, &name("b")).await.unwrap();
+
+        assert_eq!(fs.lookup(root, &name("a")).await.unwrap(), file);
+        assert_eq!(fs.getattr(file).await.unwrap().nlink, 2);
+        // the removed name was never looked up through the cache
+        fs.remove_wcc(root, &name("b"), vfs::RemoveKind::NonDir)
+            .await
+            .result
+            .unwrap();
+        assert_eq!(fs.getattr(file).await.unwrap().nlink, 1);
+    }
+
+    #[tokio::test]
+    async fn clearing_discards_fetches_in_flight() {
+        let fs = Cached::new(MemFS::new());
+        let root = fs.root_dir();
+        let since = fs.epoch();
+        let fetched = fs.inner().getattr(root).await;
+        fs.clear();
+        let mut caches = fs.caches.lock().unwrap();
+        caches.fill_attr(root, &fetched, since, &fs.options);
+        assert!(caches.attr(root, Instant::now()).is_none());
+    }
+}
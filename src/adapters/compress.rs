@@ -0,0 +1,1210 @@
+//! Transparent compression for any file system.
+//!
+//! [`Compressed`] wraps an [`NFSFileSystem`] and stores file contents compressed,
+//! while NFS clients read and write the original data. Files are split into
+//! fixed-size chunks that are compressed independently with Deflate, so that any
+//! range can be read by decompressing only the chunks it overlaps. Each file is
+//! stored as a header followed by a log of chunk records:
+//! - The header holds a format tag, the chunk size, the logical size of the file and
+//!   the offset of the first record
+//! - A write appends new records for the chunks it touches; a write that covers
+//!   part of a chunk decompresses the chunk, patches it and compresses it again
+//! - Chunks that do not compress are stored as they are, and chunks that were
+//!   never written are holes that read as zeros
+//!
+//! Records replaced by later writes are dead space. Once a file holds more dead
+//! than live data, it is compacted: its live records are copied to the end of the
+//! file and the header is pointed at the copy, then the copy is moved to the front,
+//! the header is pointed back at it and the file is shortened. Every step is written
+//! stably before the next one starts, so a server crash during compaction leaves a
+//! file that reads the same, at worst with dead space left for the next compaction.
+//! This relies on the header, which fits in one disk sector, being written
+//! atomically.
+//!
+//! The wrapper keeps an index of the latest record of every chunk for recently used
+//! files, rebuilt by scanning the record headers whenever the size or modification
+//! time of the stored file changes behind its back.
+//!
+//! Attributes report the logical size in `size` and the space taken by the stored
+//! file in `used`. Files that were not written through the wrapper are passed
+//! through unchanged until they are truncated to zero.
+//!
+//! Requires the `compression` feature.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use tracing::warn;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, WccResult};
+
+/// Format tag at the start of every compressed file
+const MAGIC: &[u8; 7] = b"NFSCMP2";
+
+/// Length of the file header: format tag, chunk size exponent, logical size and
+/// offset of the first record
+const HEADER_LEN: u64 = MAGIC.len() as u64 + 1 + 8 + 8;
+
+/// Length of a record header: chunk number, stored length and logical length
+const RECORD_LEN: u64 = 16;
+
+/// Record header ending the records of a file, written after the records moved by
+/// a compaction until the file is shortened
+const END_RECORD: [u8; RECORD_LEN as usize] = [0xff; RECORD_LEN as usize];
+
+/// Bytes of the stored file read at a time while scanning record headers
+const SCAN_WINDOW: u32 = 256 * 1024;
+
+/// Dead space below which files are never compacted
+const COMPACT_MIN: u64 = 1024 * 1024;
+
+/// Bytes of records copied at a time while compacting a file
+const COMPACT_WINDOW: u32 = 1024 * 1024;
+
+/// Maximum number of files whose layout is remembered
+const MAX_LAYOUTS: usize = 1024;
+
+/// Options of a [`Compressed`] file system
+#[derive(Debug, Clone)]
+pub struct CompressOptions {
+    /// Size of the chunks new files are split into; a power of two from 4 KiB to
+    /// 1 MiB
+    ///
+    /// Larger chunks compress better, smaller chunks make partial writes cheaper.
+    /// Every file records its own chunk size, so the option can be changed for
+    /// existing exports.
+    pub chunk_size: u32,
+    /// Deflate compression level from 0 (store only) to 10 (smallest)
+    pub level: u8,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            chunk_size: 64 * 1024,
+            level: 6,
+        }
+    }
+}
+
+/// Location of the latest record of a chunk
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    /// Offset of the record header in the stored file
+    offset: u64,
+    /// Length of the stored data
+    stored: u32,
+    /// Length of the data once decompressed
+    plain: u32,
+}
+
+impl Record {
+    /// Returns the length of the record including its header
+    fn len(&self) -> u64 {
+        RECORD_LEN + self.stored as u64
+    }
+}
+
+/// Latest records of the chunks of a file
+#[derive(Debug, Clone, Default)]
+struct Index {
+    /// Record of every chunk holding data by chunk number
+    chunks: HashMap<u64, Record>,
+    /// Offset the next record is appended at
+    end: u64,
+    /// Total length of the records in `chunks`
+    live: u64,
+}
+
+impl Index {
+    /// Sets the record of a chunk, or removes it if `record` is `None`
+    fn set(&mut self, chunk: u64, record: Option<Record>) {
+        let old = match record {
+            Some(record) => {
+                self.live += record.len();
+                self.chunks.insert(chunk, record)
+            }
+            None => self.chunks.remove(&chunk),
+        };
+        if let Some(old) = old {
+            self.live -= old.len();
+        }
+    }
+
+    /// Returns the length of the records replaced by later ones
+    ///
+    /// # Arguments
+    /// * `start` - Offset of the first record
+    fn dead(&self, start: u64) -> u64 {
+        self.end - start - self.live
+    }
+}
+
+/// What is known about a compressed file
+#[derive(Debug, Clone)]
+struct Chunked {
+    /// Size of the chunks of the file
+    chunk_size: u64,
+    /// Logical size of the file
+    size: u64,
+    /// Offset of the first record in the stored file
+    start: u64,
+    /// Records of the file, unless only its header has been read
+    index: Option<Index>,
+}
+
+impl Chunked {
+    /// Returns the header of a file with this layout
+    fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.push(self.chunk_size.trailing_zeros() as u8);
+        header.extend_from_slice(&self.size.to_le_bytes());
+        header.extend_from_slice(&self.start.to_le_bytes());
+        header
+    }
+}
+
+/// Stored size and modification time of a file, identifying the state of its data
+type Stamp = (nfs3::size3, u32, u32);
+
+/// Returns the stamp of a file from its stored attributes
+fn stamp(attr: &nfs3::fattr3) -> Stamp {
+    (attr.size, attr.mtime.seconds, attr.mtime.nseconds)
+}
+
+/// Remembered layout of a file
+#[derive(Debug)]
+struct Layout {
+    /// State of the stored file the layout was read from
+    stamp: Stamp,
+    /// Layout of the contents, or `None` for files stored as they are
+    chunked: Option<Arc<Chunked>>,
+}
+
+/// Per-file locks ordering reads against record appends and compaction
+type FileLock = Arc<tokio::sync::RwLock<()>>;
+
+/// Compressing wrapper around a file system
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Compressed<F> {
+    /// The wrapped file system
+    inner: F,
+    /// Compression settings
+    options: CompressOptions,
+    /// Layouts of recently used files by file ID
+    layouts: Mutex<HashMap<nfs3::fileid3, Layout>>,
+    /// Locks of files being read or written by file ID
+    locks: Mutex<HashMap<nfs3::fileid3, FileLock>>,
+}
+
+impl<F: NFSFileSystem> Compressed<F> {
+    /// Creates a compressing wrapper with default options
+    pub fn new(inner: F) -> Self {
+        Self::with_options(inner, CompressOptions::default())
+    }
+
+    /// Creates a compressing wrapper
+    ///
+    /// # Arguments
+    /// * `inner` - The file system to store compressed data in
+    /// * `options` - Chunk size and compression level
+    pub fn with_options(inner: F, options: CompressOptions) -> Self {
+        assert!(
+            options.chunk_size.is_power_of_two()
+                && (4096..=1024 * 1024).contains(&options.chunk_size),
+            "chunk size must be a power of two from 4 KiB to 1 MiB"
+        );
+        assert!(options.level <= 10, "compression level must be at most 10");
+        Compressed {
+            inner,
+            options,
+            layouts: Mutex::new(HashMap::new()),
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Returns the lock of a file
+    fn lock(&self, id: nfs3::fileid3) -> FileLock {
+        self.locks.lock().unwrap().entry(id).or_default().clone()
+    }
+
+    /// Drops the lock of a file if it is not in use
+    ///
+    /// Must be called without holding a reference to the lock.
+    fn unlock(&self, id: nfs3::fileid3) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.get(&id).is_some_and(|l| Arc::strong_count(l) == 1) {
+            locks.remove(&id);
+        }
+    }
+
+    /// Remembers the layout of a file
+    fn remember(&self, id: nfs3::fileid3, attr: &nfs3::fattr3, chunked: Option<Arc<Chunked>>) {
+        let mut layouts = self.layouts.lock().unwrap();
+        if layouts.len() >= MAX_LAYOUTS {
+            layouts.clear();
+        }
+        let layout = Layout {
+            stamp: stamp(attr),
+            chunked,
+        };
+        layouts.insert(id, layout);
+    }
+
+    /// Returns the layout of a regular file, or `None` if it is stored as it is
+    ///
+    /// # Arguments
+    /// * `attr` - Current attributes of the stored file
+    /// * `with_index` - Whether the records of the file are needed
+    async fn layout(
+        &self,
+        attr: &nfs3::fattr3,
+        with_index: bool,
+    ) -> Result<Option<Arc<Chunked>>, nfs3::nfsstat3> {
+        let id = attr.fileid;
+        if attr.size < HEADER_LEN {
+            return Ok(None);
+        }
+        if let Some(layout) = self.layouts.lock().unwrap().get(&id) {
+            if layout.stamp == stamp(attr) {
+                match &layout.chunked {
+                    None => return Ok(None),
+                    Some(chunked) if !with_index || chunked.index.is_some() => {
+                        return Ok(Some(chunked.clone()))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        let (header, _) = self.inner.read(id, 0, HEADER_LEN as u32).await?;
+        let shift = header.get(MAGIC.len()).copied().unwrap_or(0);
+        if header.len() != HEADER_LEN as usize
+            || header[..MAGIC.len()] != MAGIC[..]
+            || !(12..=20).contains(&shift)
+        {
+            self.remember(id, attr, None);
+            return Ok(None);
+        }
+        let field = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        let mut chunked = Chunked {
+            chunk_size: 1 << shift,
+            size: field(MAGIC.len() + 1),
+            start: field(MAGIC.len() + 9),
+            index: None,
+        };
+        if chunked.start < HEADER_LEN {
+            self.remember(id, attr, None);
+            return Ok(None);
+        }
+        if with_index {
+            chunked.index = Some(self.scan(id, attr.size, &chunked).await?);
+        }
+        let chunked = Arc::new(chunked);
+        self.remember(id, attr, Some(chunked.clone()));
+        Ok(Some(chunked))
+    }
+
+    /// Builds the index of a file by reading its record headers
+    ///
+    /// A damaged record ends the scan; its space is reused by the next write, as is
+    /// the space after an end record left by an interrupted compaction.
+    async fn scan(
+        &self,
+        id: nfs3::fileid3,
+        stored_size: u64,
+        chunked: &Chunked,
+    ) -> Result<Index, nfs3::nfsstat3> {
+        let mut index = Index {
+            end: chunked.start,
+            ..Default::default()
+        };
+        let (mut window, mut window_start) = (Vec::new(), 0);
+        while index.end < stored_size {
+            let pos = index.end;
+            if pos < window_start || pos + RECORD_LEN > window_start + window.len() as u64 {
+                window = self.inner.read(id, pos, SCAN_WINDOW).await?.0;
+                window_start = pos;
+            }
+            let at = (pos - window_start) as usize;
+            let Some(header) = window.get(at..at + RECORD_LEN as usize) else {
+                warn!("file {id} ends in a partial record at {pos}");
+                break;
+            };
+            if header == END_RECORD {
+                break;
+            }
+            let chunk = u64::from_le_bytes(header[..8].try_into().unwrap());
+            let stored = u32::from_le_bytes(header[8..12].try_into().unwrap());
+            let plain = u32::from_le_bytes(header[12..].try_into().unwrap());
+            let record = Record {
+                offset: pos,
+                stored,
+                plain,
+            };
+            if stored > plain
+                || plain as u64 > chunked.chunk_size
+                || chunk > u64::MAX / chunked.chunk_size
+                || pos + record.len() > stored_size
+            {
+                warn!("file {id} has a damaged record at {pos}");
+                break;
+            }
+            // records of chunks beyond the end are left from a truncation
+            let live = plain > 0 && chunk * chunked.chunk_size < chunked.size;
+            index.set(chunk, live.then_some(record));
+            index.end += record.len();
+        }
+        Ok(index)
+    }
+
+    /// Reads and decompresses a chunk
+    ///
+    /// Returns the logical data of the chunk, which is shorter than the chunk size
+    /// if the rest of it reads as zeros.
+    async fn read_chunk(
+        &self,
+        id: nfs3::fileid3,
+        chunked: &Chunked,
+        chunk: u64,
+    ) -> Result<Vec<u8>, nfs3::nfsstat3> {
+        let index = chunked.index.as_ref().expect("index is loaded");
+        let Some(record) = index.chunks.get(&chunk) else {
+            return Ok(Vec::new());
+        };
+        let (stored, _) = self
+            .inner
+            .read(id, record.offset + RECORD_LEN, record.stored)
+            .await?;
+        if stored.len() != record.stored as usize {
+            warn!("chunk {chunk} of file {id} is cut short");
+            return Err(nfs3::nfsstat3::NFS3ERR_IO);
+        }
+        let mut data = if record.stored == record.plain {
+            stored
+        } else {
+            match miniz_oxide::inflate::decompress_to_vec_with_limit(&stored, record.plain as usize)
+            {
+                Ok(data) if data.len() == record.plain as usize => data,
+                _ => {
+                    warn!("chunk {chunk} of file {id} cannot be decompressed");
+                    return Err(nfs3::nfsstat3::NFS3ERR_IO);
+                }
+            }
+        };
+        let start = chunk * chunked.chunk_size;
+        data.truncate(chunked.size.saturating_sub(start) as usize);
+        Ok(data)
+    }
+
+    /// Builds the record of a chunk, compressing its data unless that does not help
+    fn record(&self, chunk: u64, data: &[u8]) -> Vec<u8> {
+        let compressed = miniz_oxide::deflate::compress_to_vec(data, self.options.level);
+        let stored = if compressed.len() < data.len() {
+            &compressed[..]
+        } else {
+            data
+        };
+        let mut record = Vec::with_capacity(RECORD_LEN as usize + stored.len());
+        record.extend_from_slice(&chunk.to_le_bytes());
+        record.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(stored);
+        record
+    }
+
+    /// Returns the layout of a file for changing it, removed from the remembered
+    /// layouts so that it can be modified without copying
+    ///
+    /// Returns `None` for files stored as they are. Empty files get a new layout.
+    /// Must be called with the file locked for writing.
+    async fn take_layout(&self, attr: &nfs3::fattr3) -> Result<Option<Chunked>, nfs3::nfsstat3> {
+        if attr.size == 0 {
+            self.layouts.lock().unwrap().remove(&attr.fileid);
+            return Ok(Some(Chunked {
+                chunk_size: self.options.chunk_size as u64,
+                size: 0,
+                start: HEADER_LEN,
+                index: Some(Index {
+                    end: HEADER_LEN,
+                    ..Default::default()
+                }),
+            }));
+        }
+        let chunked = self.layout(attr, true).await?;
+        self.layouts.lock().unwrap().remove(&attr.fileid);
+        Ok(chunked.map(Arc::unwrap_or_clone))
+    }
+
+    /// Appends records to a file and updates its header
+    ///
+    /// Returns the attributes of the stored file afterwards.
+    /// Must be called with the file locked for writing.
+    async fn append(
+        &self,
+        attr: &nfs3::fattr3,
+        chunked: &mut Chunked,
+        records: Vec<(u64, Vec<u8>)>,
+        old_size: u64,
+        stable: nfs3::file::stable_how,
+    ) -> Result<(nfs3::fattr3, nfs3::file::stable_how), nfs3::nfsstat3> {
+        let id = attr.fileid;
+        let index = chunked.index.as_mut().expect("index is loaded");
+        let mut after = (*attr, stable);
+        if index.end < attr.size {
+            // drop a damaged tail found by the scan
+            let setattr = nfs3::sattr3 {
+                size: nfs3::set_size3::size(index.end),
+                ..Default::default()
+            };
+            after.0 = self.inner.setattr(id, setattr).await?;
+        }
+        if !records.is_empty() {
+            let mut data = Vec::new();
+            let mut placed = Vec::with_capacity(records.len());
+            for (chunk, record) in records {
+                let stored = u32::from_le_bytes(record[8..12].try_into().unwrap());
+                let plain = u32::from_le_bytes(record[12..16].try_into().unwrap());
+                let location = Record {
+                    offset: index.end + data.len() as u64,
+                    stored,
+                    plain,
+                };
+                placed.push((chunk, (plain > 0).then_some(location)));
+                data.extend(record);
+            }
+            let res = self.inner.write_wcc(id, index.end, &data, stable).await;
+            after = res.result?;
+            index.end += data.len() as u64;
+            for (chunk, record) in placed {
+                index.set(chunk, record);
+            }
+        }
+        if chunked.size != old_size || attr.size == 0 {
+            // the header is as durable as the data it describes
+            let res = self.inner.write_wcc(id, 0, &chunked.header(), stable).await;
+            let (attr, committed) = res.result?;
+            after.0 = attr;
+            if (committed as u32) < (after.1 as u32) {
+                after.1 = committed;
+            }
+        }
+        let index = chunked.index.as_ref().expect("index is loaded");
+        if index.dead(chunked.start) > index.live.max(COMPACT_MIN) {
+            after.0 = self.compact(id, chunked).await?;
+        }
+        Ok(after)
+    }
+
+    /// Writes to the stored file and waits until the data is on stable storage
+    async fn write_stable(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let res = self
+            .inner
+            .write_wcc(id, offset, data, nfs3::file::stable_how::FILE_SYNC)
+            .await;
+        match res.result? {
+            (_, nfs3::file::stable_how::UNSTABLE) => self.inner.commit(id, 0, 0).await,
+            (attr, _) => Ok(attr),
+        }
+    }
+
+    /// Copies a range of the stored file to another offset, in windows
+    ///
+    /// The ranges must not overlap.
+    async fn copy_range(
+        &self,
+        id: nfs3::fileid3,
+        from: u64,
+        to: u64,
+        len: u64,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let mut done = 0;
+        while done < len {
+            let count = (len - done).min(COMPACT_WINDOW as u64) as u32;
+            let (data, _) = self.inner.read(id, from + done, count).await?;
+            if data.len() != count as usize {
+                return Err(nfs3::nfsstat3::NFS3ERR_IO);
+            }
+            self.write_stable(id, to + done, &data).await?;
+            done += count as u64;
+        }
+        Ok(())
+    }
+
+    /// Moves the live records of a file to its front and drops the rest
+    ///
+    /// The file reads the same after each step in case the server stops in
+    /// between; see the [module documentation](self).
+    /// Must be called with the file locked for writing.
+    async fn compact(
+        &self,
+        id: nfs3::fileid3,
+        chunked: &mut Chunked,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let index = chunked.index.as_mut().expect("index is loaded");
+        let mut records: Vec<_> = index.chunks.values_mut().collect();
+        records.sort_by_key(|r| r.offset);
+
+        // copy the live records to the end; a scan from the old start finds every
+        // record twice with the same data
+        let copy = index.end;
+        let mut batch = Vec::new();
+        let mut len = 0;
+        for record in records {
+            let (data, _) = self
+                .inner
+                .read(id, record.offset, record.len() as u32)
+                .await?;
+            if data.len() as u64 != record.len() {
+                return Err(nfs3::nfsstat3::NFS3ERR_IO);
+            }
+            record.offset = HEADER_LEN + len;
+            len += record.len();
+            batch.extend(data);
+            if batch.len() >= COMPACT_WINDOW as usize {
+                self.write_stable(id, copy + len - batch.len() as u64, &batch)
+                    .await?;
+                batch.clear();
+            }
+        }
+        if !batch.is_empty() {
+            self.write_stable(id, copy + len - batch.len() as u64, &batch)
+                .await?;
+        }
+        chunked.start = copy;
+        self.write_stable(id, 0, &chunked.header()).await?;
+
+        // move the copy to the front, where it fits with room to spare since there
+        // was more dead than live data, and end the records after it
+        self.copy_range(id, copy, HEADER_LEN, len).await?;
+        self.write_stable(id, HEADER_LEN + len, &END_RECORD).await?;
+        chunked.start = HEADER_LEN;
+        self.write_stable(id, 0, &chunked.header()).await?;
+
+        let index = chunked.index.as_mut().expect("index is loaded");
+        index.end = HEADER_LEN + len;
+        let setattr = nfs3::sattr3 {
+            size: nfs3::set_size3::size(index.end),
+            ..Default::default()
+        };
+        self.inner.setattr(id, setattr).await
+    }
+
+    /// Writes a logical range of a file, recompressing the chunks it touches
+    ///
+    /// Must be called with the file locked for writing.
+    async fn write_chunks(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let before = match self.inner.getattr(id).await {
+            Ok(attr) => attr,
+            Err(e) => {
+                return WccResult {
+                    result: Err(e),
+                    wcc: nfs3::wcc_data::default(),
+                }
+            }
+        };
+        if !matches!(before.ftype, nfs3::ftype3::NF3REG) {
+            return self.inner.write_wcc(id, offset, data, stable).await;
+        }
+        if data.is_empty() {
+            let result = self.map_attr(before).await;
+            return WccResult {
+                wcc: nfs3::wcc_data {
+                    before: nfs3::pre_op_attr::Void,
+                    after: match &result {
+                        Ok(attr) => nfs3::post_op_attr::attributes(*attr),
+                        Err(_) => nfs3::post_op_attr::Void,
+                    },
+                },
+                result: result.map(|attr| (attr, stable)),
+            };
+        }
+        let mut chunked = match self.take_layout(&before).await {
+            Ok(Some(chunked)) => chunked,
+            Ok(None) => return self.inner.write_wcc(id, offset, data, stable).await,
+            Err(e) => {
+                return WccResult {
+                    result: Err(e),
+                    wcc: nfs3::wcc_data::default(),
+                }
+            }
+        };
+        let mut logical = before;
+        logical.size = chunked.size;
+        let fail = |e| WccResult {
+            result: Err(e),
+            wcc: nfs3::wcc_data {
+                before: nfs3::pre_op_attr::attributes(logical.into()),
+                after: nfs3::post_op_attr::Void,
+            },
+        };
+
+        let old_size = chunked.size;
+        let Some(end) = offset.checked_add(data.len() as u64) else {
+            return fail(nfs3::nfsstat3::NFS3ERR_FBIG);
+        };
+        let new_size = old_size.max(end);
+        let chunk_size = chunked.chunk_size;
+        let mut records = Vec::new();
+        chunked.size = new_size;
+        for chunk in offset / chunk_size..=(end - 1) / chunk_size {
+            let start = chunk * chunk_size;
+            let stop = (start + chunk_size).min(new_size);
+            let mut plain = vec![0; (stop - start) as usize];
+            // chunks only partly overwritten keep the rest of their data
+            if offset > start || end < stop {
+                match self.read_chunk(id, &chunked, chunk).await {
+                    Ok(old) => plain[..old.len()].copy_from_slice(&old),
+                    Err(e) => return fail(e),
+                }
+            }
+            let (from, to) = (offset.max(start), end.min(stop));
+            plain[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            records.push((chunk, self.record(chunk, &plain)));
+        }
+        let result = self
+            .append(&before, &mut chunked, records, old_size, stable)
+            .await;
+        let result = match result {
+            Ok((attr, committed)) => {
+                self.remember(id, &attr, Some(Arc::new(chunked)));
+                let mut attr = attr;
+                attr.size = new_size;
+                Ok((attr, committed))
+            }
+            Err(e) => return fail(e),
+        };
+        WccResult {
+            wcc: nfs3::wcc_data {
+                before: nfs3::pre_op_attr::attributes(logical.into()),
+                after: match &result {
+                    Ok((attr, _)) => nfs3::post_op_attr::attributes(*attr),
+                    Err(_) => nfs3::post_op_attr::Void,
+                },
+            },
+            result,
+        }
+    }
+
+    /// Changes the logical size of a file
+    ///
+    /// Must be called with the file locked for writing.
+    async fn resize(&self, id: nfs3::fileid3, new_size: u64) -> Result<(), nfs3::nfsstat3> {
+        let attr = self.inner.getattr(id).await?;
+        let truncate = |size| nfs3::sattr3 {
+            size: nfs3::set_size3::size(size),
+            ..Default::default()
+        };
+        if !matches!(attr.ftype, nfs3::ftype3::NF3REG) || new_size == 0 {
+            self.layouts.lock().unwrap().remove(&id);
+            self.inner.setattr(id, truncate(new_size)).await?;
+            return Ok(());
+        }
+        let Some(mut chunked) = self.take_layout(&attr).await? else {
+            self.inner.setattr(id, truncate(new_size)).await?;
+            return Ok(());
+        };
+        let old_size = chunked.size;
+        let chunk_size = chunked.chunk_size;
+        let mut records = Vec::new();
+        if new_size < old_size {
+            let index = chunked.index.as_ref().expect("index is loaded");
+            let mut dropped: Vec<u64> = index
+                .chunks
+                .keys()
+                .copied()
+                .filter(|chunk| chunk * chunk_size >= new_size)
+                .collect();
+            dropped.sort_unstable();
+            // a record without data marks a chunk as a hole again
+            records.extend(dropped.into_iter().map(|c| (c, self.record(c, &[]))));
+            let last = new_size / chunk_size;
+            let keep = (new_size - last * chunk_size) as usize;
+            if keep > 0 {
+                let old = self.read_chunk(id, &chunked, last).await?;
+                if old.len() > keep {
+                    records.push((last, self.record(last, &old[..keep])));
+                }
+            }
+        }
+        chunked.size = new_size;
+        let stable = nfs3::file::stable_how::FILE_SYNC;
+        let (attr, _) = self
+            .append(&attr, &mut chunked, records, old_size, stable)
+            .await?;
+        self.remember(id, &attr, Some(Arc::new(chunked)));
+        Ok(())
+    }
+
+    /// Converts attributes of the wrapped file system to logical attributes
+    async fn map_attr(&self, mut attr: nfs3::fattr3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        if matches!(attr.ftype, nfs3::ftype3::NF3REG) {
+            if let Some(chunked) = self.layout(&attr, false).await? {
+                attr.size = chunked.size;
+            }
+        }
+        Ok(attr)
+    }
+
+    /// Reads a logical range of a file
+    async fn read_chunks(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let attr = self.inner.getattr(id).await?;
+        if !matches!(attr.ftype, nfs3::ftype3::NF3REG) {
+            return self.inner.read(id, offset, count).await;
+        }
+        let Some(chunked) = self.layout(&attr, true).await? else {
+            return self.inner.read(id, offset, count).await;
+        };
+        let size = chunked.size;
+        let end = size.min(offset.saturating_add(count as u64));
+        if offset >= end {
+            return Ok((Vec::new(), offset >= size));
+        }
+        let chunk_size = chunked.chunk_size;
+        let mut data = Vec::with_capacity((end - offset) as usize);
+        for chunk in offset / chunk_size..=(end - 1) / chunk_size {
+            let start = chunk * chunk_size;
+            let (from, to) = (
+                offset.max(start) - start,
+                end.min(start + chunk_size) - start,
+            );
+            let plain = self.read_chunk(id, &chunked, chunk).await?;
+            // the chunk reads as zeros past its data
+            let have = (plain.len() as u64).clamp(from, to);
+            if have > from {
+                data.extend_from_slice(&plain[from as usize..have as usize]);
+            }
+            data.resize(data.len() + (to - have) as usize, 0);
+        }
+        Ok((data, end >= size))
+    }
+}
+
+#[async_trait]
+impl<F: NFSFileSystem> NFSFileSystem for Compressed<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.inner.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.lookup(dirid, filename).await
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.map_attr(self.inner.getattr(id).await?).await
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        mut setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        if let nfs3::set_size3::size(size) = setattr.size {
+            let lock = self.lock(id);
+            let result = {
+                let _guard = lock.write().await;
+                self.resize(id, size).await
+            };
+            drop(lock);
+            self.unlock(id);
+            result?;
+            setattr.size = nfs3::set_size3::Void;
+        }
+        let attr = self.inner.setattr(id, setattr).await?;
+        self.map_attr(attr).await
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let lock = self.lock(id);
+        let result = {
+            let _guard = lock.read().await;
+            self.read_chunks(id, offset, count).await
+        };
+        drop(lock);
+        self.unlock(id);
+        result
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let (data, eof) = self.read(id, offset, count).await?;
+        Ok((Bytes::from(data), eof))
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let res = self
+            .write_wcc(id, offset, data, nfs3::file::stable_how::FILE_SYNC)
+            .await;
+        res.result.map(|(attr, _)| attr)
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let lock = self.lock(id);
+        let res = {
+            let _guard = lock.write().await;
+            self.write_chunks(id, offset, data, stable).await
+        };
+        drop(lock);
+        self.unlock(id);
+        res
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        mut attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let size = std::mem::replace(&mut attr.size, nfs3::set_size3::Void);
+        let (id, fattr) = self.inner.create(dirid, filename, attr).await?;
+        if let nfs3::set_size3::size(_) = size {
+            let setattr = nfs3::sattr3 {
+                size,
+                ..Default::default()
+            };
+            return Ok((id, self.setattr(id, setattr).await?));
+        }
+        Ok((id, self.map_attr(fattr).await?))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.create_exclusive(dirid, filename).await
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.inner.mkdir(dirid, dirname).await
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.inner.remove(dirid, filename).await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        self.inner.remove_wcc(dirid, filename, kind).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.inner
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let mut result = self.inner.readdir(dirid, start_after, max_entries).await?;
+        for entry in &mut result.entries {
+            entry.attr = self.map_attr(entry.attr).await?;
+        }
+        Ok(result)
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        let mut result = self
+            .inner
+            .readdir_simple_after(dirid, start_after, count)
+            .await?;
+        for entry in &mut result.entries {
+            if let Some(attr) = entry.attr {
+                // READDIRPLUS asks for attributes it does not get here
+                entry.attr = self.map_attr(attr).await.ok();
+            }
+        }
+        Ok(result)
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        let stream = self.inner.readdir_stream(dirid, start_after).await?;
+        Ok(stream
+            .then(move |entry| async move {
+                let mut entry = entry?;
+                if let Some(attr) = entry.attr {
+                    entry.attr = self.map_attr(attr).await.ok();
+                }
+                Ok(entry)
+            })
+            .boxed())
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let mut attrs = self.inner.getattr_batch(ids).await;
+        for attr in &mut attrs {
+            if let Ok(a) = attr {
+                *attr = self.map_attr(*a).await;
+            }
+        }
+        attrs
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.inner.symlink(dirid, linkname, symlink, attr).await
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.inner.readlink(id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let attr = self.inner.link(file_id, link_dir_id, link_name).await?;
+        self.map_attr(attr).await
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.inner.mknod(dir_id, name, ftype, specdata, attrs).await
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        // records are appended wherever there is room, so the whole file is committed
+        let _ = (offset, count);
+        let attr = self.inner.commit(file_id, 0, 0).await?;
+        self.map_attr(attr).await
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        self.inner.fsinfo(root_fileid).await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        let mut stat = self.inner.fsstat(id).await?;
+        if let nfs3::post_op_attr::attributes(attr) = stat.obj_attributes {
+            stat.obj_attributes = nfs3::post_op_attr::attributes(self.map_attr(attr).await?);
+        }
+        Ok(stat)
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.inner.serverid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::adapters::fault::{Fault, FaultRule, Faulty, Operation};
+    use crate::adapters::WriteBack;
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    #[tokio::test]
+    async fn writes_far_past_the_end_leave_holes() {
+        let fs = Compressed::new(MemFS::new());
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        let res = fs
+            .write_wcc(
+                file,
+                u64::MAX - 1,
+                b"data",
+                nfs3::file::stable_how::UNSTABLE,
+            )
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_FBIG)));
+        fs.write(file, 1 << 40, b"data").await.unwrap();
+        assert_eq!(fs.getattr(file).await.unwrap().size, (1 << 40) + 4);
+        assert!(fs.inner().getattr(file).await.unwrap().size < 4096);
+        let (data, eof) = fs.read(file, (1 << 40) - 2, 16).await.unwrap();
+        assert_eq!((&data[..], eof), (&b"\0\0data"[..], true));
+    }
+
+    #[tokio::test]
+    async fn unstable_appends_are_not_synced() {
+        let fs = Compressed::new(WriteBack::new(MemFS::new()));
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        for i in 0..4u64 {
+            let res = fs
+                .write_wcc(file, i * 100, &[7; 100], nfs3::file::stable_how::UNSTABLE)
+                .await;
+            assert!(matches!(
+                res.result,
+                Ok((_, nfs3::file::stable_how::UNSTABLE))
+            ));
+        }
+        // neither the records nor the header were written through
+        let stored = fs.inner().inner().getattr(file).await.unwrap();
+        assert_eq!(stored.size, 0);
+        fs.commit(file, 0, 0).await.unwrap();
+        assert!(fs.inner().inner().getattr(file).await.unwrap().size > 0);
+        let (data, _) = fs.read(file, 0, 1000).await.unwrap();
+        assert_eq!(data, vec![7; 400]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_keep_each_other() {
+        let faulty = Faulty::new(MemFS::new());
+        faulty.control().add_rule(FaultRule {
+            ops: vec![Operation::Write],
+            ..FaultRule::new(Fault::Delay(Duration::from_millis(10)))
+        });
+        let fs = Compressed::new(faulty);
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        // writes of 3000 bytes share chunks
+        let writes = (0..8u8).map(|i| {
+            let data = vec![i + 1; 3000];
+            let fs = &fs;
+            async move {
+                fs.write_wcc(
+                    file,
+                    i as u64 * 3000,
+                    &data,
+                    nfs3::file::stable_how::UNSTABLE,
+                )
+                .await
+            }
+        });
+        for res in futures::future::join_all(writes).await {
+            res.result.unwrap();
+        }
+        let (data, _) = fs.read(file, 0, 65536).await.unwrap();
+        assert_eq!(data.len(), 24000);
+        for (i, chunk) in data.chunks(3000).enumerate() {
+            assert!(
+                chunk.iter().all(|&b| b == i as u8 + 1),
+                "write {i} was lost"
+            );
+        }
+    }
+}
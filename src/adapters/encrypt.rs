@@ -0,0 +1,1239 @@
+//! Encryption at rest for any file system.
+//!
+//! [`Encrypted`] wraps an [`NFSFileSystem`] and encrypts file contents before they
+//! reach it, while NFS clients read and write plaintext. Each file is stored as a
+//! header followed by its contents in fixed-size blocks:
+//! - The header holds a format tag, the block size and a random salt
+//! - Every block is encrypted with XChaCha20-Poly1305 under a fresh random nonce,
+//!   stored with the block, and authenticated together with the salt of its file,
+//!   its position and whether it is the last block, so blocks cannot be altered,
+//!   moved or cut off the end undetected
+//! - The last block is as long as the data it holds, so the plaintext size follows
+//!   from the stored size, and attributes report plaintext sizes
+//!
+//! Reads and writes at any offset only touch the blocks they overlap; a write that
+//! covers part of a block re-encrypts the whole block. Holes are filled with
+//! encrypted zeros, so sparse files are stored densely. A file cut short by removing
+//! whole blocks fails to read at its new end, as that block was not sealed as the last.
+//!
+//! File names and symbolic link targets are encrypted too if enabled. They are
+//! encrypted deterministically, so that lookups can find them, and encoded in
+//! lowercase base32. This leaks which names are equal and limits names to 143
+//! bytes. Entries whose names cannot be decrypted are hidden from listings.
+//!
+//! Keys are derived from a 256-bit master key read from a key file holding either
+//! the 32 raw key bytes or 64 hexadecimal digits; see [`Encrypted::generate_key`].
+//!
+//! Requires the `encryption` feature.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::XChaCha20;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::{AeadInPlace, KeyInit, Tag, XChaCha20Poly1305, XNonce};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{debug, warn};
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, WccResult};
+
+/// Format tag at the start of every encrypted file
+const MAGIC: &[u8; 7] = b"NFSENC1";
+
+/// Length of the salt in the file header
+const SALT_LEN: usize = 16;
+
+/// Length of the file header: format tag, block size exponent and salt
+const HEADER_LEN: u64 = (MAGIC.len() + 1 + SALT_LEN) as u64;
+
+/// Length of the nonce stored with every block
+const NONCE_LEN: usize = 24;
+
+/// Length of the authentication tag stored with every block
+const TAG_LEN: usize = 16;
+
+/// Bytes stored with every block in addition to its data
+const OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+
+/// Length of the synthetic IV prefixed to encrypted names
+const NAME_IV_LEN: usize = 16;
+
+/// Maximum length of a file name in the wrapped file system
+const MAX_NAME_LEN: usize = 255;
+
+/// Maximum number of file salts remembered
+const MAX_SALTS: usize = 4096;
+
+/// Plaintext written at a time when a file is extended with zeros
+const ZERO_CHUNK: u64 = 1024 * 1024;
+
+/// Alphabet of the base32 encoding of encrypted names
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Options of an [`Encrypted`] file system
+#[derive(Debug, Clone)]
+pub struct EncryptOptions {
+    /// Size of the plaintext blocks file contents are encrypted in; a power of
+    /// two from 512 bytes to 1 MiB
+    ///
+    /// Every file records the block size it was written with, and files written
+    /// with a different block size cannot be read.
+    pub block_size: u32,
+    /// Encrypt file names and symbolic link targets as well
+    pub encrypt_names: bool,
+}
+
+impl Default for EncryptOptions {
+    fn default() -> Self {
+        EncryptOptions {
+            block_size: 4096,
+            encrypt_names: false,
+        }
+    }
+}
+
+/// Computes an HMAC-SHA256
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Encodes bytes in unpadded lowercase base32
+fn base32_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut bits, mut value) = (0, 0u32);
+    for &b in data {
+        value = (value << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((value >> bits) & 31) as usize]);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((value << (5 - bits)) & 31) as usize]);
+    }
+    out
+}
+
+/// Decodes unpadded lowercase base32
+fn base32_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 5 / 8);
+    let (mut bits, mut value) = (0, 0u32);
+    for &c in data {
+        let digit = BASE32.iter().position(|&d| d == c)? as u32;
+        value = (value << 5) | digit;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((value >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Reads a master key from a key file
+fn read_key(path: &Path) -> std::io::Result<[u8; 32]> {
+    let data = std::fs::read(path)?;
+    if let Ok(key) = <[u8; 32]>::try_from(data.as_slice()) {
+        return Ok(key);
+    }
+    let text = String::from_utf8_lossy(&data);
+    let hex = text.trim();
+    let mut key = [0; 32];
+    if hex.len() == 64 && hex.is_ascii() {
+        let digits: Option<Vec<u8>> = (0..32)
+            .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+            .collect();
+        if let Some(digits) = digits {
+            key.copy_from_slice(&digits);
+            return Ok(key);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "key file must hold 32 bytes or 64 hexadecimal digits",
+    ))
+}
+
+/// Per-file locks ordering reads against block rewrites
+type FileLock = Arc<tokio::sync::RwLock<()>>;
+
+/// Encrypting wrapper around a file system
+///
+/// See the [module documentation](self) for details.
+pub struct Encrypted<F> {
+    /// The wrapped file system
+    inner: F,
+    /// Encryption settings
+    options: EncryptOptions,
+    /// Cipher of file contents
+    content: XChaCha20Poly1305,
+    /// Key authenticating names, which doubles as their synthetic IV
+    name_mac_key: [u8; 32],
+    /// Key encrypting names
+    name_key: [u8; 32],
+    /// Salts of recently used files by file ID
+    salts: Mutex<HashMap<nfs3::fileid3, [u8; SALT_LEN]>>,
+    /// Locks of files being read or written by file ID
+    locks: Mutex<HashMap<nfs3::fileid3, FileLock>>,
+}
+
+impl<F> std::fmt::Debug for Encrypted<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // leave the keys out of logs
+        f.debug_struct("Encrypted")
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F: NFSFileSystem> Encrypted<F> {
+    /// Creates an encrypting wrapper with default options
+    ///
+    /// # Arguments
+    /// * `inner` - The file system to store encrypted data in
+    /// * `key_file` - Path of the file holding the master key
+    pub fn new(inner: F, key_file: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::with_options(inner, key_file, EncryptOptions::default())
+    }
+
+    /// Creates an encrypting wrapper with custom options
+    ///
+    /// # Arguments
+    /// * `inner` - The file system to store encrypted data in
+    /// * `key_file` - Path of the file holding the master key
+    /// * `options` - Block size and whether to encrypt names
+    pub fn with_options(
+        inner: F,
+        key_file: impl AsRef<Path>,
+        options: EncryptOptions,
+    ) -> std::io::Result<Self> {
+        let block_size = options.block_size;
+        if !block_size.is_power_of_two() || !(512..=1024 * 1024).contains(&block_size) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "block size must be a power of two from 512 bytes to 1 MiB",
+            ));
+        }
+        let master = read_key(key_file.as_ref())?;
+        let content_key = hmac(&master, b"nfsserve encrypt contents");
+        Ok(Encrypted {
+            inner,
+            options,
+            content: XChaCha20Poly1305::new(&content_key.into()),
+            name_mac_key: hmac(&master, b"nfsserve encrypt name iv"),
+            name_key: hmac(&master, b"nfsserve encrypt names"),
+            salts: Mutex::new(HashMap::new()),
+            locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Writes a new random master key to a key file
+    ///
+    /// The file is created with permissions for its owner only and must not exist.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the key file to create
+    pub fn generate_key(path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        let hex: String = key.iter().map(|b| format!("{b:02x}")).collect();
+        writeln!(file, "{hex}")?;
+        file.sync_all()
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Returns the plaintext block size
+    fn block_size(&self) -> u64 {
+        self.options.block_size as u64
+    }
+
+    /// Returns the plaintext size of a file from its stored size
+    fn plain_size(&self, stored: u64) -> u64 {
+        let block = self.block_size();
+        let body = stored.saturating_sub(HEADER_LEN);
+        let full = body / (block + OVERHEAD);
+        let rest = body % (block + OVERHEAD);
+        full * block + rest.saturating_sub(OVERHEAD)
+    }
+
+    /// Returns the stored size of a file from its plaintext size
+    fn stored_size(&self, plain: u64) -> u64 {
+        if plain == 0 {
+            return 0;
+        }
+        let block = self.block_size();
+        let rest = plain % block;
+        HEADER_LEN + plain / block * (block + OVERHEAD) + if rest > 0 { rest + OVERHEAD } else { 0 }
+    }
+
+    /// Returns the index of the last block of a file that is not empty
+    fn last_block(&self, plain_size: u64) -> u64 {
+        (plain_size - 1) / self.block_size()
+    }
+
+    /// Returns the stored offset of a block
+    fn block_offset(&self, index: u64) -> u64 {
+        HEADER_LEN + index * (self.block_size() + OVERHEAD)
+    }
+
+    /// Converts attributes of the wrapped file system to plaintext attributes
+    fn map_attr(&self, mut attr: nfs3::fattr3) -> nfs3::fattr3 {
+        match attr.ftype {
+            nfs3::ftype3::NF3REG => attr.size = self.plain_size(attr.size),
+            nfs3::ftype3::NF3LNK if self.options.encrypt_names => {
+                attr.size = (attr.size * 5 / 8).saturating_sub(NAME_IV_LEN as u64)
+            }
+            _ => {}
+        }
+        attr
+    }
+
+    /// Converts weak cache consistency data of the wrapped file system
+    fn map_wcc(&self, mut wcc: nfs3::wcc_data, ftype: nfs3::ftype3) -> nfs3::wcc_data {
+        if let nfs3::pre_op_attr::attributes(attr) = &mut wcc.before {
+            if matches!(ftype, nfs3::ftype3::NF3REG) {
+                attr.size = self.plain_size(attr.size);
+            }
+        }
+        if let nfs3::post_op_attr::attributes(attr) = wcc.after {
+            wcc.after = nfs3::post_op_attr::attributes(self.map_attr(attr));
+        }
+        wcc
+    }
+
+    /// Encrypts a name deterministically
+    fn seal_name(&self, name: &[u8]) -> Vec<u8> {
+        let iv = hmac(&self.name_mac_key, name);
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..NAME_IV_LEN].copy_from_slice(&iv[..NAME_IV_LEN]);
+        let mut data = iv[..NAME_IV_LEN].to_vec();
+        let start = data.len();
+        data.extend_from_slice(name);
+        XChaCha20::new(&self.name_key.into(), &nonce.into()).apply_keystream(&mut data[start..]);
+        base32_encode(&data)
+    }
+
+    /// Decrypts a name, returning `None` if it was not encrypted with this key
+    fn open_name(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let data = base32_decode(sealed)?;
+        if data.len() <= NAME_IV_LEN {
+            return None;
+        }
+        let (iv, rest) = data.split_at(NAME_IV_LEN);
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..NAME_IV_LEN].copy_from_slice(iv);
+        let mut name = rest.to_vec();
+        XChaCha20::new(&self.name_key.into(), &nonce.into()).apply_keystream(&mut name);
+        (hmac(&self.name_mac_key, &name)[..NAME_IV_LEN] == *iv).then_some(name)
+    }
+
+    /// Converts a file name to the name stored in the wrapped file system
+    fn encrypt_name(&self, name: &[u8]) -> Result<nfs3::filename3, nfs3::nfsstat3> {
+        if !self.options.encrypt_names || name == b"." || name == b".." {
+            return Ok(name.into());
+        }
+        let sealed = self.seal_name(name);
+        if sealed.len() > MAX_NAME_LEN {
+            return Err(nfs3::nfsstat3::NFS3ERR_NAMETOOLONG);
+        }
+        Ok(sealed.into())
+    }
+
+    /// Decrypts the name of a listed entry, or returns `None` to hide the entry
+    fn decrypt_entry(&self, entry: vfs::DirEntrySimple) -> Option<vfs::DirEntrySimple> {
+        let Some(name) = self.decrypt_name(&entry.name) else {
+            debug!("hiding entry {} with an undecryptable name", entry.fileid);
+            return None;
+        };
+        Some(vfs::DirEntrySimple {
+            fileid: entry.fileid,
+            name,
+            attr: entry.attr.map(|attr| self.map_attr(attr)),
+        })
+    }
+
+    /// Converts a name stored in the wrapped file system to the plaintext name
+    fn decrypt_name(&self, name: &[u8]) -> Option<nfs3::filename3> {
+        if !self.options.encrypt_names {
+            return Some(name.into());
+        }
+        self.open_name(name).map(Into::into)
+    }
+
+    /// Encrypts a block of file contents
+    ///
+    /// # Arguments
+    /// * `salt` - Salt of the file
+    /// * `index` - Position of the block in the file
+    /// * `last` - Whether the block is the last one of the file
+    /// * `data` - Plaintext of the block
+    fn seal_block(&self, salt: &[u8; SALT_LEN], index: u64, last: bool, data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut out = Vec::with_capacity(data.len() + OVERHEAD as usize);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(data);
+        let tag = self
+            .content
+            .encrypt_in_place_detached(
+                XNonce::from_slice(&nonce),
+                &block_aad(salt, index, last),
+                &mut out[NONCE_LEN..],
+            )
+            .expect("blocks are far below the size limit of the cipher");
+        out.extend_from_slice(&tag);
+        out
+    }
+
+    /// Decrypts a block of file contents
+    fn open_block(
+        &self,
+        salt: &[u8; SALT_LEN],
+        index: u64,
+        last: bool,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, nfs3::nfsstat3> {
+        if sealed.len() < OVERHEAD as usize {
+            return Err(nfs3::nfsstat3::NFS3ERR_IO);
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (data, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let mut data = data.to_vec();
+        self.content
+            .decrypt_in_place_detached(
+                XNonce::from_slice(nonce),
+                &block_aad(salt, index, last),
+                &mut data,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| nfs3::nfsstat3::NFS3ERR_IO)?;
+        Ok(data)
+    }
+
+    /// Returns the lock of a file
+    fn lock(&self, id: nfs3::fileid3) -> FileLock {
+        self.locks.lock().unwrap().entry(id).or_default().clone()
+    }
+
+    /// Drops the lock of a file if it is not in use
+    ///
+    /// Must be called without holding a reference to the lock.
+    fn unlock(&self, id: nfs3::fileid3) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.get(&id).is_some_and(|l| Arc::strong_count(l) == 1) {
+            locks.remove(&id);
+        }
+    }
+
+    /// Returns the salt of a file, reading its header unless it is remembered
+    async fn salt(&self, id: nfs3::fileid3) -> Result<[u8; SALT_LEN], nfs3::nfsstat3> {
+        if let Some(salt) = self.salts.lock().unwrap().get(&id) {
+            return Ok(*salt);
+        }
+        let (header, _) = self.inner.read(id, 0, HEADER_LEN as u32).await?;
+        let expected_shift = self.options.block_size.trailing_zeros() as u8;
+        if header.len() != HEADER_LEN as usize || header[..MAGIC.len()] != MAGIC[..] {
+            warn!("file {id} is not encrypted");
+            return Err(nfs3::nfsstat3::NFS3ERR_IO);
+        }
+        if header[MAGIC.len()] != expected_shift {
+            warn!(
+                "file {id} was encrypted with blocks of {} bytes",
+                1u64 << header[MAGIC.len()].min(63)
+            );
+            return Err(nfs3::nfsstat3::NFS3ERR_IO);
+        }
+        let salt: [u8; SALT_LEN] = header[MAGIC.len() + 1..].try_into().unwrap();
+        self.remember_salt(id, salt);
+        Ok(salt)
+    }
+
+    /// Remembers the salt of a file
+    fn remember_salt(&self, id: nfs3::fileid3, salt: [u8; SALT_LEN]) {
+        let mut salts = self.salts.lock().unwrap();
+        if salts.len() >= MAX_SALTS {
+            salts.clear();
+        }
+        salts.insert(id, salt);
+    }
+
+    /// Reads and decrypts the blocks holding a plaintext range of a file
+    ///
+    /// Returns the plaintext of the blocks from the start of the first one.
+    async fn read_blocks(
+        &self,
+        id: nfs3::fileid3,
+        salt: &[u8; SALT_LEN],
+        plain_size: u64,
+        first: u64,
+        last: u64,
+    ) -> Result<Vec<u8>, nfs3::nfsstat3> {
+        let block = self.block_size();
+        let start = self.block_offset(first);
+        let end = self.block_offset(last) + (plain_size - last * block).min(block) + OVERHEAD;
+        let (sealed, _) = self.inner.read(id, start, (end - start) as u32).await?;
+        if sealed.len() as u64 != end - start {
+            // the file changed size behind our back
+            return Err(nfs3::nfsstat3::NFS3ERR_IO);
+        }
+        let mut plain = Vec::with_capacity(((last - first + 1) * block) as usize);
+        let final_block = self.last_block(plain_size);
+        for (i, chunk) in sealed.chunks((block + OVERHEAD) as usize).enumerate() {
+            let index = first + i as u64;
+            let last = index == final_block;
+            plain.extend(self.open_block(salt, index, last, chunk).inspect_err(|_| {
+                warn!("block {index} of file {id} failed authentication");
+            })?);
+        }
+        Ok(plain)
+    }
+
+    /// Reads a plaintext range of a file
+    async fn read_plain(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let attr = self.inner.getattr(id).await?;
+        if !matches!(attr.ftype, nfs3::ftype3::NF3REG) {
+            return self.inner.read(id, offset, count).await;
+        }
+        let size = self.plain_size(attr.size);
+        let end = size.min(offset.saturating_add(count as u64));
+        if offset >= end {
+            return Ok((Vec::new(), offset >= size));
+        }
+        let block = self.block_size();
+        let (first, last) = (offset / block, (end - 1) / block);
+        let salt = self.salt(id).await?;
+        let plain = match self.read_blocks(id, &salt, size, first, last).await {
+            Ok(plain) => plain,
+            Err(_) => {
+                // the file may have been replaced since its salt was read
+                self.salts.lock().unwrap().remove(&id);
+                let salt = self.salt(id).await?;
+                self.read_blocks(id, &salt, size, first, last).await?
+            }
+        };
+        let from = (offset - first * block) as usize;
+        let to = (end - first * block) as usize;
+        Ok((plain[from..to].to_vec(), end >= size))
+    }
+
+    /// Writes a plaintext range of a file, re-encrypting the blocks it touches
+    ///
+    /// Must be called with the file locked for writing.
+    async fn write_plain(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let before = match self.inner.getattr(id).await {
+            Ok(attr) => attr,
+            Err(e) => {
+                return WccResult {
+                    result: Err(e),
+                    wcc: nfs3::wcc_data::default(),
+                }
+            }
+        };
+        let fail = |e| WccResult {
+            result: Err(e),
+            wcc: nfs3::wcc_data {
+                before: nfs3::pre_op_attr::attributes(self.map_attr(before).into()),
+                after: nfs3::post_op_attr::attributes(self.map_attr(before)),
+            },
+        };
+        if !matches!(before.ftype, nfs3::ftype3::NF3REG) {
+            return fail(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        if data.is_empty() {
+            return WccResult {
+                result: Ok((self.map_attr(before), stable)),
+                wcc: self.map_wcc(nfs3::wcc_data::default(), before.ftype),
+            };
+        }
+        // the stored size of the file must fit in 64 bits as well
+        let end = offset.checked_add(data.len() as u64);
+        if end.is_none_or(|end| end > self.plain_size(u64::MAX)) {
+            return fail(nfs3::nfsstat3::NFS3ERR_FBIG);
+        }
+
+        // a file without data gets a new header and salt
+        let salt = if before.size == 0 {
+            let mut salt = [0; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let mut header = MAGIC.to_vec();
+            header.push(self.options.block_size.trailing_zeros() as u8);
+            header.extend_from_slice(&salt);
+            if let Err(e) = self.inner.write(id, 0, &header).await {
+                return fail(e);
+            }
+            self.remember_salt(id, salt);
+            salt
+        } else {
+            match self.salt(id).await {
+                Ok(salt) => salt,
+                Err(e) => return fail(e),
+            }
+        };
+
+        // a gap before the data is filled with zeros a bounded chunk at a time
+        let mut size = self.plain_size(before.size);
+        while size < offset {
+            let len = (offset - size).min(ZERO_CHUNK);
+            let zeros = vec![0; len as usize];
+            match self
+                .store_blocks(id, &salt, size, size, &zeros, stable)
+                .await
+            {
+                Ok(WccResult { result: Ok(_), .. }) => size += len,
+                Ok(WccResult { result: Err(e), .. }) | Err(e) => return fail(e),
+            }
+        }
+        let res = match self
+            .store_blocks(id, &salt, size, offset, data, stable)
+            .await
+        {
+            Ok(res) => res,
+            Err(e) => return fail(e),
+        };
+        let mut wcc = self.map_wcc(res.wcc, before.ftype);
+        wcc.before = nfs3::pre_op_attr::attributes(self.map_attr(before).into());
+        WccResult {
+            result: res
+                .result
+                .map(|(attr, committed)| (self.map_attr(attr), committed)),
+            wcc,
+        }
+    }
+
+    /// Encrypts and stores data starting at or before the end of a file
+    ///
+    /// Re-encrypts the blocks the data touches, and the last block of the file if
+    /// the data extends past it, since it is no longer the last one. Fails before
+    /// writing anything if a block to keep cannot be read.
+    ///
+    /// # Arguments
+    /// * `id` - The file, which has a header
+    /// * `salt` - Salt of the file
+    /// * `size` - Plaintext size of the file; at least `offset`
+    /// * `offset` - Plaintext offset of the data
+    /// * `data` - The data to write; not empty
+    /// * `stable` - How durably to write the blocks
+    async fn store_blocks(
+        &self,
+        id: nfs3::fileid3,
+        salt: &[u8; SALT_LEN],
+        size: u64,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> Result<WccResult<(nfs3::fattr3, nfs3::file::stable_how)>, nfs3::nfsstat3> {
+        let block = self.block_size();
+        let end = offset + data.len() as u64;
+        let new_size = size.max(end);
+        let mut first = offset / block;
+        if end > size && size > 0 {
+            first = first.min(self.last_block(size));
+        }
+        let (last, final_block) = ((end - 1) / block, self.last_block(new_size));
+        let mut sealed = Vec::with_capacity(((last - first + 1) * (block + OVERHEAD)) as usize);
+        for index in first..=last {
+            let start = index * block;
+            let stop = (start + block).min(new_size);
+            let mut plain = vec![0; (stop - start) as usize];
+            // blocks only partly overwritten keep the rest of their data
+            let covered = offset <= start && end >= stop;
+            if !covered && start < size {
+                let old = self.read_blocks(id, salt, size, index, index).await?;
+                plain[..old.len()].copy_from_slice(&old);
+            }
+            let (from, to) = (offset.max(start), end.min(stop));
+            if from < to {
+                plain[(from - start) as usize..(to - start) as usize]
+                    .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            }
+            sealed.extend(self.seal_block(salt, index, index == final_block, &plain));
+        }
+        Ok(self
+            .inner
+            .write_wcc(id, self.block_offset(first), &sealed, stable)
+            .await)
+    }
+
+    /// Changes the plaintext size of a file
+    ///
+    /// Must be called with the file locked for writing.
+    async fn resize(&self, id: nfs3::fileid3, new_size: u64) -> Result<(), nfs3::nfsstat3> {
+        let attr = self.inner.getattr(id).await?;
+        if !matches!(attr.ftype, nfs3::ftype3::NF3REG) {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        let size = self.plain_size(attr.size);
+        if new_size > size {
+            let mut pos = size;
+            while pos < new_size {
+                let len = (new_size - pos).min(ZERO_CHUNK);
+                let zeros = vec![0; len as usize];
+                let stable = nfs3::file::stable_how::FILE_SYNC;
+                self.write_plain(id, pos, &zeros, stable).await.result?;
+                pos += len;
+            }
+            return Ok(());
+        }
+        if new_size < size {
+            // the new last block is re-encrypted at its new length, as the last one
+            if new_size > 0 {
+                let index = self.last_block(new_size);
+                let salt = self.salt(id).await?;
+                let old = self.read_blocks(id, &salt, size, index, index).await?;
+                let len = (new_size - index * self.block_size()) as usize;
+                let sealed = self.seal_block(&salt, index, true, &old[..len]);
+                self.inner
+                    .write(id, self.block_offset(index), &sealed)
+                    .await?;
+            } else {
+                self.salts.lock().unwrap().remove(&id);
+            }
+            let setattr = nfs3::sattr3 {
+                size: nfs3::set_size3::size(self.stored_size(new_size)),
+                ..Default::default()
+            };
+            self.inner.setattr(id, setattr).await?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts a symbolic link target if names are encrypted
+    fn encrypt_target(&self, target: &[u8]) -> nfs3::nfspath3 {
+        if self.options.encrypt_names {
+            self.seal_name(target).into()
+        } else {
+            target.into()
+        }
+    }
+}
+
+/// Returns the associated data authenticating a block: its file salt, its position
+/// and whether it is the last block
+fn block_aad(salt: &[u8; SALT_LEN], index: u64, last: bool) -> [u8; SALT_LEN + 9] {
+    let mut aad = [0; SALT_LEN + 9];
+    aad[..SALT_LEN].copy_from_slice(salt);
+    aad[SALT_LEN..SALT_LEN + 8].copy_from_slice(&index.to_le_bytes());
+    aad[SALT_LEN + 8] = last as u8;
+    aad
+}
+
+#[async_trait]
+impl<F: NFSFileSystem> NFSFileSystem for Encrypted<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.inner.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let name = self.encrypt_name(filename)?;
+        self.inner.lookup(dirid, &name).await
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Ok(self.map_attr(self.inner.getattr(id).await?))
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        mut setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        if let nfs3::set_size3::size(size) = setattr.size {
+            let lock = self.lock(id);
+            let result = {
+                let _guard = lock.write().await;
+                self.resize(id, size).await
+            };
+            drop(lock);
+            self.unlock(id);
+            result?;
+            setattr.size = nfs3::set_size3::Void;
+        }
+        Ok(self.map_attr(self.inner.setattr(id, setattr).await?))
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let lock = self.lock(id);
+        let result = {
+            let _guard = lock.read().await;
+            self.read_plain(id, offset, count).await
+        };
+        drop(lock);
+        self.unlock(id);
+        result
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let (data, eof) = self.read(id, offset, count).await?;
+        Ok((Bytes::from(data), eof))
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let res = self
+            .write_wcc(id, offset, data, nfs3::file::stable_how::FILE_SYNC)
+            .await;
+        res.result.map(|(attr, _)| attr)
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let lock = self.lock(id);
+        let res = {
+            let _guard = lock.write().await;
+            self.write_plain(id, offset, data, stable).await
+        };
+        drop(lock);
+        self.unlock(id);
+        res
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        mut attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = self.encrypt_name(filename)?;
+        let size = std::mem::replace(&mut attr.size, nfs3::set_size3::Void);
+        let (id, fattr) = self.inner.create(dirid, &name, attr).await?;
+        if let nfs3::set_size3::size(_) = size {
+            let setattr = nfs3::sattr3 {
+                size,
+                ..Default::default()
+            };
+            return Ok((id, self.setattr(id, setattr).await?));
+        }
+        Ok((id, self.map_attr(fattr)))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let name = self.encrypt_name(filename)?;
+        self.inner.create_exclusive(dirid, &name).await
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = self.encrypt_name(dirname)?;
+        self.inner.mkdir(dirid, &name).await
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let name = self.encrypt_name(filename)?;
+        self.inner.remove(dirid, &name).await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        match self.encrypt_name(filename) {
+            Ok(name) => self.inner.remove_wcc(dirid, &name, kind).await,
+            Err(e) => WccResult {
+                result: Err(e),
+                wcc: nfs3::wcc_data::default(),
+            },
+        }
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let from = self.encrypt_name(from_filename)?;
+        let to = self.encrypt_name(to_filename)?;
+        self.inner.rename(from_dirid, &from, to_dirid, &to).await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let mut result = self.inner.readdir(dirid, start_after, max_entries).await?;
+        result.entries = result
+            .entries
+            .into_iter()
+            .filter_map(|entry| {
+                let Some(name) = self.decrypt_name(&entry.name) else {
+                    debug!("hiding entry {} with an undecryptable name", entry.fileid);
+                    return None;
+                };
+                Some(vfs::DirEntry {
+                    fileid: entry.fileid,
+                    name,
+                    attr: self.map_attr(entry.attr),
+                })
+            })
+            .collect();
+        Ok(result)
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        let result = self
+            .inner
+            .readdir_simple_after(dirid, start_after, count)
+            .await?;
+        // continue after the last entry listed, even if it is hidden
+        let resume_after = result
+            .resume_after
+            .or(result.entries.last().map(|entry| entry.fileid));
+        let entries = result
+            .entries
+            .into_iter()
+            .filter_map(|entry| self.decrypt_entry(entry))
+            .collect();
+        Ok(vfs::ReadDirSimpleResult::new(entries, result.end).with_resume_after(resume_after))
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        let stream = self.inner.readdir_stream(dirid, start_after).await?;
+        Ok(stream
+            .filter_map(move |entry| {
+                futures::future::ready(match entry {
+                    Ok(entry) => self.decrypt_entry(entry).map(Ok),
+                    Err(stat) => Some(Err(stat)),
+                })
+            })
+            .boxed())
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        self.inner
+            .getattr_batch(ids)
+            .await
+            .into_iter()
+            .map(|attr| attr.map(|attr| self.map_attr(attr)))
+            .collect()
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = self.encrypt_name(linkname)?;
+        let target = self.encrypt_target(symlink);
+        let (id, fattr) = self.inner.symlink(dirid, &name, &target, attr).await?;
+        Ok((id, self.map_attr(fattr)))
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        let target = self.inner.readlink(id).await?;
+        if !self.options.encrypt_names {
+            return Ok(target);
+        }
+        match self.open_name(&target) {
+            Some(target) => Ok(target.into()),
+            None => {
+                warn!("symbolic link {id} has an undecryptable target");
+                Err(nfs3::nfsstat3::NFS3ERR_IO)
+            }
+        }
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let name = self.encrypt_name(link_name)?;
+        Ok(self.map_attr(self.inner.link(file_id, link_dir_id, &name).await?))
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = self.encrypt_name(name)?;
+        self.inner
+            .mknod(dir_id, &name, ftype, specdata, attrs)
+            .await
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        // block boundaries differ, so the whole file is committed
+        let _ = (offset, count);
+        Ok(self.map_attr(self.inner.commit(file_id, 0, 0).await?))
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        let mut info = self.inner.fsinfo(root_fileid).await?;
+        if let nfs3::post_op_attr::attributes(attr) = info.obj_attributes {
+            info.obj_attributes = nfs3::post_op_attr::attributes(self.map_attr(attr));
+        }
+        Ok(info)
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        let mut stat = self.inner.fsstat(id).await?;
+        if let nfs3::post_op_attr::attributes(attr) = stat.obj_attributes {
+            stat.obj_attributes = nfs3::post_op_attr::attributes(self.map_attr(attr));
+        }
+        Ok(stat)
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.inner.serverid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::adapters::fault::{Fault, FaultRule, Faulty, Operation};
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    /// Wraps a file system with a fresh key and blocks of 512 bytes
+    fn encrypted<F: NFSFileSystem>(inner: F, test: &str) -> Encrypted<F> {
+        let path = std::env::temp_dir().join(format!(
+            "nfsserve-encrypt-{test}-{}.key",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Encrypted::<F>::generate_key(&path).unwrap();
+        let options = EncryptOptions {
+            block_size: 512,
+            ..Default::default()
+        };
+        let fs = Encrypted::with_options(inner, &path, options).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        fs
+    }
+
+    #[tokio::test]
+    async fn writes_at_huge_offsets_fail() {
+        let fs = encrypted(MemFS::new(), "huge");
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        for offset in [u64::MAX - 1, u64::MAX - 600, fs.plain_size(u64::MAX)] {
+            let res = fs
+                .write_wcc(file, offset, &[1; 4], nfs3::file::stable_how::UNSTABLE)
+                .await;
+            assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_FBIG)));
+        }
+        assert_eq!(fs.getattr(file).await.unwrap().size, 0);
+    }
+
+    #[tokio::test]
+    async fn writes_past_the_end_fill_the_gap_in_chunks() {
+        let faulty = Faulty::new(MemFS::new());
+        let writes = faulty.control().add_rule(FaultRule {
+            ops: vec![Operation::Write],
+            ..FaultRule::new(Fault::Delay(Duration::ZERO))
+        });
+        let fs = encrypted(faulty, "gap");
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        let offset = 3 * ZERO_CHUNK + ZERO_CHUNK / 2;
+        fs.write(file, offset, b"data").await.unwrap();
+        // the header, four chunks of zeros and the data
+        assert_eq!(fs.inner().control().hits(writes), Some(6));
+        assert_eq!(fs.getattr(file).await.unwrap().size, offset + 4);
+        let (data, eof) = fs.read(file, offset - 2, 16).await.unwrap();
+        assert_eq!((&data[..], eof), (&b"\0\0data"[..], true));
+        let (data, _) = fs.read(file, ZERO_CHUNK - 100, 200).await.unwrap();
+        assert_eq!(data, vec![0; 200]);
+    }
+
+    #[tokio::test]
+    async fn files_cut_short_fail_to_read_at_the_end() {
+        let fs = encrypted(MemFS::new(), "cut");
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        // the last block is full, so appending must seal it again as an inner block
+        fs.write(file, 0, &[1; 1024]).await.unwrap();
+        fs.write(file, 1024, &[2; 600]).await.unwrap();
+        let (data, eof) = fs.read(file, 0, 4096).await.unwrap();
+        assert_eq!((data.len(), eof), (1624, true));
+        assert!(data[..1024].iter().all(|&b| b == 1) && data[1024..].iter().all(|&b| b == 2));
+
+        // dropping the last blocks in storage is noticed
+        let setattr = nfs3::sattr3 {
+            size: nfs3::set_size3::size(fs.stored_size(1024)),
+            ..Default::default()
+        };
+        fs.inner().setattr(file, setattr).await.unwrap();
+        assert_eq!(fs.getattr(file).await.unwrap().size, 1024);
+        assert!(fs.read(file, 0, 512).await.is_ok());
+        assert!(matches!(
+            fs.read(file, 512, 512).await,
+            Err(nfs3::nfsstat3::NFS3ERR_IO)
+        ));
+
+        // truncating through the wrapper seals the new last block
+        let setattr = nfs3::sattr3 {
+            size: nfs3::set_size3::size(512),
+            ..Default::default()
+        };
+        fs.setattr(file, setattr).await.unwrap();
+        let (data, eof) = fs.read(file, 0, 4096).await.unwrap();
+        assert_eq!((data, eof), (vec![1; 512], true));
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_keep_each_other() {
+        let faulty = Faulty::new(MemFS::new());
+        faulty.control().add_rule(FaultRule {
+            ops: vec![Operation::Write],
+            ..FaultRule::new(Fault::Delay(Duration::from_millis(10)))
+        });
+        let fs = encrypted(faulty, "concurrent");
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        // writes of 300 bytes share blocks of 512 bytes
+        let writes = (0..8u8).map(|i| {
+            let data = vec![i + 1; 300];
+            let fs = &fs;
+            async move {
+                fs.write_wcc(
+                    file,
+                    i as u64 * 300,
+                    &data,
+                    nfs3::file::stable_how::UNSTABLE,
+                )
+                .await
+            }
+        });
+        for res in futures::future::join_all(writes).await {
+            res.result.unwrap();
+        }
+        let (data, _) = fs.read(file, 0, 4096).await.unwrap();
+        assert_eq!(data.len(), 2400);
+        for (i, chunk) in data.chunks(300).enumerate() {
+            assert!(
+                chunk.iter().all(|&b| b == i as u8 + 1),
+                "write {i} was lost"
+            );
+        }
+    }
+}
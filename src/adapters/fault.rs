@@ -0,0 +1,928 @@
+//! Fault injection for resilience testing.
+//!
+//! [`Faulty`] wraps an [`NFSFileSystem`] and makes it misbehave on purpose, to test
+//! how applications and NFS clients cope with a slow or failing server:
+//! - [`Fault::Delay`] adds latency before an operation is passed on
+//! - [`Fault::Error`] fails an operation with a status such as NFS3ERR_IO,
+//!   NFS3ERR_JUKEBOX or NFS3ERR_NOSPC, without passing it on
+//! - [`Fault::ShortRead`] returns less data than requested, and than available
+//!
+//! Faults are described by [`FaultRule`]s, which select operations by type, by the
+//! path or file ID of the objects involved and by chance. Rules are evaluated in
+//! the order they were added: the delays of all matching rules add up, and the
+//! first matching error ends the evaluation.
+//!
+//! Rules can be changed at any time through a [`FaultControl`], which also counts
+//! the faults that were injected. The control is shared with the wrapper, so it
+//! keeps working after the file system was handed to the server.
+//!
+//! Paths are only known for objects that were looked up, created or listed through
+//! the wrapper; rules with a path pattern do not match other objects.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::debug;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// Maximum directory depth followed when building the path of an object
+const MAX_DEPTH: usize = 256;
+
+/// File system operation a rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// LOOKUP
+    Lookup,
+    /// GETATTR, including attributes fetched for directory listings
+    Getattr,
+    /// SETATTR
+    Setattr,
+    /// READ
+    Read,
+    /// WRITE
+    Write,
+    /// CREATE in any mode
+    Create,
+    /// MKDIR
+    Mkdir,
+    /// REMOVE and RMDIR
+    Remove,
+    /// RENAME
+    Rename,
+    /// READDIR and READDIRPLUS
+    Readdir,
+    /// SYMLINK
+    Symlink,
+    /// READLINK
+    Readlink,
+    /// LINK
+    Link,
+    /// MKNOD
+    Mknod,
+    /// COMMIT
+    Commit,
+    /// FSINFO
+    Fsinfo,
+    /// FSSTAT
+    Fsstat,
+}
+
+/// Misbehavior injected into an operation
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    /// Waits before passing the operation on
+    Delay(Duration),
+    /// Fails the operation with a status
+    Error(nfs3::nfsstat3),
+    /// Returns a random part of the data a read would return, at least one byte;
+    /// only applies to [`Operation::Read`]
+    ShortRead,
+}
+
+/// When and how to misbehave
+#[derive(Debug, Clone)]
+pub struct FaultRule {
+    /// Operations the rule applies to; empty for all operations
+    pub ops: Vec<Operation>,
+    /// Pattern the path of an object involved must match, where `*` matches within
+    /// a path component, `**` across components and `?` a single character;
+    /// paths start at the export root, such as `/data/**/*.bin`
+    pub path: Option<String>,
+    /// File IDs one of the objects involved must have; for operations on a
+    /// directory entry this is the ID of the directory
+    pub fileids: Option<RangeInclusive<nfs3::fileid3>>,
+    /// Chance that a matching operation is affected, between 0 and 1
+    pub probability: f64,
+    /// Number of times the fault is injected before the rule stops matching, or
+    /// `None` for no limit
+    pub limit: Option<u64>,
+    /// The misbehavior
+    pub fault: Fault,
+}
+
+impl FaultRule {
+    /// Creates a rule injecting a fault into every operation
+    ///
+    /// # Arguments
+    /// * `fault` - The misbehavior
+    pub fn new(fault: Fault) -> Self {
+        FaultRule {
+            ops: Vec::new(),
+            path: None,
+            fileids: None,
+            probability: 1.0,
+            limit: None,
+            fault,
+        }
+    }
+}
+
+/// Identifies a rule added to a [`FaultControl`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RuleId(u64);
+
+/// Counters of the injected faults
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultStats {
+    /// Number of delayed operations
+    pub delays: u64,
+    /// Total delay added
+    pub delay: Duration,
+    /// Number of failed operations
+    pub errors: u64,
+    /// Number of shortened reads
+    pub short_reads: u64,
+}
+
+/// A rule with its counter
+#[derive(Debug)]
+struct ActiveRule {
+    /// Identifies the rule
+    id: RuleId,
+    /// The rule itself
+    rule: FaultRule,
+    /// Number of times the rule injected its fault
+    hits: u64,
+}
+
+/// Rules, counters and random state shared between a wrapper and its controls
+#[derive(Debug, Default)]
+struct Rules {
+    /// Rules in evaluation order
+    rules: Vec<ActiveRule>,
+    /// ID of the next rule
+    next_id: u64,
+    /// Totals over all rules
+    stats: FaultStats,
+    /// State of the random number generator
+    rng: u64,
+}
+
+/// Returns the next pseudo-random number (SplitMix64)
+fn random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Returns true with the given probability
+fn chance(state: &mut u64, probability: f64) -> bool {
+    probability >= 1.0 || ((random(state) >> 11) as f64 / (1u64 << 53) as f64) < probability
+}
+
+/// Handle for changing the rules of a [`Faulty`] file system and reading its counters
+///
+/// Clones refer to the same rules.
+#[derive(Debug, Clone)]
+pub struct FaultControl {
+    /// The shared rules
+    rules: Arc<Mutex<Rules>>,
+}
+
+impl FaultControl {
+    /// Creates a control without rules, with the random number generator seeded
+    /// from the current time
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let rules = Rules {
+            rng: seed,
+            ..Default::default()
+        };
+        FaultControl {
+            rules: Arc::new(Mutex::new(rules)),
+        }
+    }
+
+    /// Adds a rule after the existing ones
+    ///
+    /// # Arguments
+    /// * `rule` - The rule; its probability must be between 0 and 1
+    pub fn add_rule(&self, rule: FaultRule) -> RuleId {
+        assert!(
+            (0.0..=1.0).contains(&rule.probability),
+            "probability must be between 0 and 1"
+        );
+        let mut rules = self.rules.lock().unwrap();
+        let id = RuleId(rules.next_id);
+        rules.next_id += 1;
+        rules.rules.push(ActiveRule { id, rule, hits: 0 });
+        id
+    }
+
+    /// Removes a rule, returning whether it existed
+    pub fn remove_rule(&self, id: RuleId) -> bool {
+        let mut rules = self.rules.lock().unwrap();
+        let len = rules.rules.len();
+        rules.rules.retain(|r| r.id != id);
+        rules.rules.len() != len
+    }
+
+    /// Removes all rules
+    pub fn clear_rules(&self) {
+        self.rules.lock().unwrap().rules.clear();
+    }
+
+    /// Returns the rules in evaluation order
+    pub fn rules(&self) -> Vec<(RuleId, FaultRule)> {
+        let rules = self.rules.lock().unwrap();
+        rules.rules.iter().map(|r| (r.id, r.rule.clone())).collect()
+    }
+
+    /// Returns how often a rule injected its fault, or `None` if it does not exist
+    pub fn hits(&self, id: RuleId) -> Option<u64> {
+        let rules = self.rules.lock().unwrap();
+        rules.rules.iter().find(|r| r.id == id).map(|r| r.hits)
+    }
+
+    /// Returns the counters of all faults injected
+    pub fn stats(&self) -> FaultStats {
+        self.rules.lock().unwrap().stats
+    }
+
+    /// Resets all counters, which also rearms rules that reached their limit
+    pub fn reset_stats(&self) {
+        let mut rules = self.rules.lock().unwrap();
+        rules.stats = FaultStats::default();
+        for rule in rules.rules.iter_mut() {
+            rule.hits = 0;
+        }
+    }
+
+    /// Restarts the random number generator from a seed, to make the faults
+    /// injected by rules with a probability reproducible
+    pub fn seed(&self, seed: u64) {
+        self.rules.lock().unwrap().rng = seed;
+    }
+}
+
+/// Checks if a path matches a pattern of a [`FaultRule`]
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => {
+            (0..=path.len()).any(|i| glob_match(&rest[1..], &path[i..]))
+        }
+        Some((b'*', rest)) => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| glob_match(rest, &path[i..])),
+        Some((b'?', rest)) => {
+            path.first().is_some_and(|&c| c != b'/') && glob_match(rest, &path[1..])
+        }
+        Some((c, rest)) => path.first() == Some(c) && glob_match(rest, &path[1..]),
+    }
+}
+
+/// An object involved in an operation: an object by file ID, or a directory entry
+#[derive(Debug, Clone, Copy)]
+struct Target<'a> {
+    /// File ID of the object, or of the directory
+    id: nfs3::fileid3,
+    /// Name of the entry in the directory
+    name: Option<&'a [u8]>,
+}
+
+impl<'a> Target<'a> {
+    /// An object by file ID
+    fn id(id: nfs3::fileid3) -> Self {
+        Target { id, name: None }
+    }
+
+    /// A directory entry
+    fn entry(dirid: nfs3::fileid3, name: &'a [u8]) -> Self {
+        Target {
+            id: dirid,
+            name: Some(name),
+        }
+    }
+}
+
+/// Faults decided for one operation
+#[derive(Debug, Default)]
+struct Injection {
+    /// Total delay
+    delay: Duration,
+    /// Status to fail with
+    error: Option<nfs3::nfsstat3>,
+    /// Whether to shorten a read
+    short_read: bool,
+}
+
+/// Fault-injecting wrapper around a file system
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Faulty<F> {
+    /// The wrapped file system
+    inner: F,
+    /// The rules
+    control: FaultControl,
+    /// Directory and name of the objects seen, by file ID
+    names: Mutex<HashMap<nfs3::fileid3, (nfs3::fileid3, Vec<u8>)>>,
+}
+
+impl<F: NFSFileSystem> Faulty<F> {
+    /// Creates a wrapper that passes everything on until rules are added
+    pub fn new(inner: F) -> Self {
+        Faulty {
+            inner,
+            control: FaultControl::new(),
+            names: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a handle for changing the rules and reading the counters
+    pub fn control(&self) -> FaultControl {
+        self.control.clone()
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Returns the wrapped file system
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Remembers the name of an object
+    fn learn(&self, dirid: nfs3::fileid3, name: &[u8], id: nfs3::fileid3) {
+        if name != b"." && name != b".." {
+            let mut names = self.names.lock().unwrap();
+            names.insert(id, (dirid, name.to_vec()));
+        }
+    }
+
+    /// Forgets the object with a name
+    fn forget(&self, dirid: nfs3::fileid3, name: &[u8]) {
+        let mut names = self.names.lock().unwrap();
+        names.retain(|_, (d, n)| *d != dirid || n.as_slice() != name);
+    }
+
+    /// Returns the path of an object, if the names leading to it are known
+    fn path(&self, target: &Target) -> Option<Vec<u8>> {
+        let names = self.names.lock().unwrap();
+        let root = self.inner.root_dir();
+        let mut parts: Vec<&[u8]> = target.name.into_iter().collect();
+        let mut id = target.id;
+        for _ in 0..MAX_DEPTH {
+            if id == root {
+                let mut path = Vec::new();
+                for part in parts.iter().rev() {
+                    path.push(b'/');
+                    path.extend_from_slice(part);
+                }
+                if path.is_empty() {
+                    path.push(b'/');
+                }
+                return Some(path);
+            }
+            let (parent, name) = names.get(&id)?;
+            parts.push(name);
+            id = *parent;
+        }
+        None
+    }
+
+    /// Decides which faults to inject into an operation and updates the counters
+    fn decide(&self, op: Operation, targets: &[Target]) -> Injection {
+        let mut injection = Injection::default();
+        let mut guard = self.control.rules.lock().unwrap();
+        let Rules {
+            rules, stats, rng, ..
+        } = &mut *guard;
+        for active in rules.iter_mut() {
+            let rule = &active.rule;
+            if (!rule.ops.is_empty() && !rule.ops.contains(&op))
+                || rule.limit.is_some_and(|limit| active.hits >= limit)
+                || (matches!(rule.fault, Fault::ShortRead) && op != Operation::Read)
+            {
+                continue;
+            }
+            if let Some(ids) = &rule.fileids {
+                if !targets.iter().any(|t| ids.contains(&t.id)) {
+                    continue;
+                }
+            }
+            if let Some(pattern) = &rule.path {
+                let matched = targets.iter().any(|t| {
+                    self.path(t)
+                        .is_some_and(|path| glob_match(pattern.as_bytes(), &path))
+                });
+                if !matched {
+                    continue;
+                }
+            }
+            if !chance(rng, rule.probability) {
+                continue;
+            }
+            active.hits += 1;
+            debug!("Injecting {:?} into {:?}", rule.fault, op);
+            match rule.fault {
+                Fault::Delay(delay) => {
+                    injection.delay += delay;
+                    stats.delays += 1;
+                    stats.delay += delay;
+                }
+                Fault::Error(status) => {
+                    injection.error = Some(status);
+                    stats.errors += 1;
+                    break;
+                }
+                Fault::ShortRead => {
+                    injection.short_read = true;
+                    stats.short_reads += 1;
+                }
+            }
+        }
+        injection
+    }
+
+    /// Injects the faults decided for an operation
+    ///
+    /// Returns whether a read should be shortened, or the status to fail with.
+    async fn inject(&self, op: Operation, targets: &[Target<'_>]) -> Result<bool, nfs3::nfsstat3> {
+        let injection = self.decide(op, targets);
+        if !injection.delay.is_zero() {
+            tokio::time::sleep(injection.delay).await;
+        }
+        match injection.error {
+            Some(status) => Err(status),
+            None => Ok(injection.short_read),
+        }
+    }
+
+    /// Returns the length to shorten a read of `len` bytes to
+    fn short_len(&self, len: usize) -> usize {
+        let mut rules = self.control.rules.lock().unwrap();
+        1 + (random(&mut rules.rng) % (len as u64 - 1)) as usize
+    }
+}
+
+/// Returns a failed result without weak cache consistency data
+fn failed<T>(status: nfs3::nfsstat3) -> WccResult<T> {
+    WccResult {
+        result: Err(status),
+        wcc: nfs3::wcc_data::default(),
+    }
+}
+
+#[async_trait]
+impl<F: NFSFileSystem> NFSFileSystem for Faulty<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.inner.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inject(Operation::Lookup, &[Target::entry(dirid, filename)])
+            .await?;
+        let id = self.inner.lookup(dirid, filename).await?;
+        self.learn(dirid, filename, id);
+        Ok(id)
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.inject(Operation::Getattr, &[Target::id(id)]).await?;
+        self.inner.getattr(id).await
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.setattr_wcc(id, setattr, None).await.result
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let short = self.inject(Operation::Read, &[Target::id(id)]).await?;
+        let (mut data, mut eof) = self.inner.read(id, offset, count).await?;
+        if short && data.len() > 1 {
+            data.truncate(self.short_len(data.len()));
+            eof = false;
+        }
+        Ok((data, eof))
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let short = self.inject(Operation::Read, &[Target::id(id)]).await?;
+        let (mut data, mut eof) = self.inner.read_bytes(id, offset, count).await?;
+        if short && data.len() > 1 {
+            data.truncate(self.short_len(data.len()));
+            eof = false;
+        }
+        Ok((data, eof))
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.inject(Operation::Write, &[Target::id(id)]).await?;
+        self.inner.write(id, offset, data).await
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.create_wcc(dirid, filename, attr).await.result
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.create_exclusive_wcc(dirid, filename).await.result
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mkdir_wcc(dirid, dirname).await.result
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.remove_wcc(dirid, filename, vfs::RemoveKind::Any)
+            .await
+            .result
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+            .result
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        self.inject(Operation::Readdir, &[Target::id(dirid)])
+            .await?;
+        let res = self.inner.readdir(dirid, start_after, max_entries).await?;
+        for entry in &res.entries {
+            self.learn(dirid, &entry.name, entry.fileid);
+        }
+        Ok(res)
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        self.inject(Operation::Readdir, &[Target::id(dirid)])
+            .await?;
+        let res = self
+            .inner
+            .readdir_simple_after(dirid, start_after, count)
+            .await?;
+        for entry in &res.entries {
+            self.learn(dirid, &entry.name, entry.fileid);
+        }
+        Ok(res)
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        self.inject(Operation::Readdir, &[Target::id(dirid)])
+            .await?;
+        self.inner.readdir_stream(dirid, start_after).await
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let targets: Vec<Target> = ids.iter().map(|id| Target::id(*id)).collect();
+        match self.inject(Operation::Getattr, &targets).await {
+            Ok(_) => self.inner.getattr_batch(ids).await,
+            Err(status) => ids.iter().map(|_| Err(status)).collect(),
+        }
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.symlink_wcc(dirid, linkname, symlink, attr)
+            .await
+            .result
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.inject(Operation::Readlink, &[Target::id(id)]).await?;
+        self.inner.readlink(id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.link_wcc(file_id, link_dir_id, link_name).await.result
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await
+            .result
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.inject(Operation::Commit, &[Target::id(file_id)])
+            .await?;
+        self.inner.commit(file_id, offset, count).await
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        if let Err(status) = self.inject(Operation::Setattr, &[Target::id(id)]).await {
+            return failed(status);
+        }
+        self.inner.setattr_wcc(id, setattr, guard).await
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        if let Err(status) = self.inject(Operation::Write, &[Target::id(id)]).await {
+            return failed(status);
+        }
+        self.inner.write_wcc(id, offset, data, stable).await
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let target = Target::entry(dirid, filename);
+        if let Err(status) = self.inject(Operation::Create, &[target]).await {
+            return failed(status);
+        }
+        let res = self.inner.create_wcc(dirid, filename, attr).await;
+        if let Ok((id, _)) = &res.result {
+            self.learn(dirid, filename, *id);
+        }
+        res
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        let target = Target::entry(dirid, filename);
+        if let Err(status) = self.inject(Operation::Create, &[target]).await {
+            return failed(status);
+        }
+        let res = self.inner.create_exclusive_wcc(dirid, filename).await;
+        if let Ok(id) = &res.result {
+            self.learn(dirid, filename, *id);
+        }
+        res
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let target = Target::entry(dirid, dirname);
+        if let Err(status) = self.inject(Operation::Mkdir, &[target]).await {
+            return failed(status);
+        }
+        let res = self.inner.mkdir_wcc(dirid, dirname).await;
+        if let Ok((id, _)) = &res.result {
+            self.learn(dirid, dirname, *id);
+        }
+        res
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        let target = Target::entry(dirid, filename);
+        if let Err(status) = self.inject(Operation::Remove, &[target]).await {
+            return failed(status);
+        }
+        let res = self.inner.remove_wcc(dirid, filename, kind).await;
+        if res.result.is_ok() {
+            self.forget(dirid, filename);
+        }
+        res
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        let targets = [
+            Target::entry(from_dirid, from_filename),
+            Target::entry(to_dirid, to_filename),
+        ];
+        if let Err(status) = self.inject(Operation::Rename, &targets).await {
+            return RenameWccResult {
+                result: Err(status),
+                from_dir_wcc: nfs3::wcc_data::default(),
+                to_dir_wcc: nfs3::wcc_data::default(),
+            };
+        }
+        let res = self
+            .inner
+            .rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await;
+        if res.result.is_ok() {
+            let mut names = self.names.lock().unwrap();
+            let moved = names
+                .iter()
+                .find(|(_, (d, n))| *d == from_dirid && n.as_slice() == from_filename.as_ref())
+                .map(|(id, _)| *id);
+            names.retain(|_, (d, n)| *d != to_dirid || n.as_slice() != to_filename.as_ref());
+            if let Some(id) = moved {
+                names.insert(id, (to_dirid, to_filename.to_vec()));
+            }
+        }
+        res
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let target = Target::entry(dirid, linkname);
+        if let Err(status) = self.inject(Operation::Symlink, &[target]).await {
+            return failed(status);
+        }
+        let res = self.inner.symlink_wcc(dirid, linkname, symlink, attr).await;
+        if let Ok((id, _)) = &res.result {
+            self.learn(dirid, linkname, *id);
+        }
+        res
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        let targets = [Target::id(file_id), Target::entry(link_dir_id, link_name)];
+        if let Err(status) = self.inject(Operation::Link, &targets).await {
+            return failed(status);
+        }
+        self.inner.link_wcc(file_id, link_dir_id, link_name).await
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let target = Target::entry(dir_id, name);
+        if let Err(status) = self.inject(Operation::Mknod, &[target]).await {
+            return failed(status);
+        }
+        let res = self
+            .inner
+            .mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await;
+        if let Ok((id, _)) = &res.result {
+            self.learn(dir_id, name, *id);
+        }
+        res
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        if let Err(status) = self.inject(Operation::Commit, &[Target::id(file_id)]).await {
+            return failed(status);
+        }
+        self.inner.commit_wcc(file_id, offset, count).await
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        self.inject(Operation::Fsinfo, &[Target::id(root_fileid)])
+            .await?;
+        self.inner.fsinfo(root_fileid).await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        self.inject(Operation::Fsstat, &[Target::id(id)]).await?;
+        self.inner.fsstat(id).await
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.path_to_id(path).await
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.inner.serverid()
+    }
+}
@@ -0,0 +1,39 @@
+//! Adapters that implement or extend file systems
+//!
+//! This module contains building blocks that implement the
+//! [`NFSFileSystem`](crate::vfs::NFSFileSystem) trait on top of simpler APIs,
+//! or wrap an existing file system to change its behavior.
+
+pub mod blocking;
+pub mod cache;
+#[cfg(feature = "compression")]
+pub mod compress;
+#[cfg(feature = "encryption")]
+pub mod encrypt;
+pub mod fault;
+pub mod mux;
+pub mod overlay;
+pub mod path;
+pub mod quota;
+pub mod readahead;
+pub mod readonly;
+pub mod subtree;
+pub mod trace;
+pub mod writeback;
+
+pub use blocking::{BlockingAdapter, BlockingDirEntryIter, BlockingNFSFileSystem};
+pub use cache::{CacheOptions, Cached};
+#[cfg(feature = "compression")]
+pub use compress::{CompressOptions, Compressed};
+#[cfg(feature = "encryption")]
+pub use encrypt::{EncryptOptions, Encrypted};
+pub use fault::{Fault, FaultControl, FaultRule, FaultStats, Faulty, Operation, RuleId};
+pub use mux::Mux;
+pub use overlay::Overlay;
+pub use path::{PathAdapter, PathDirEntry, PathFileSystem};
+pub use quota::{Limit, Quota, QuotaOptions, Usage};
+pub use readahead::{ReadAhead, ReadAheadOptions};
+pub use readonly::ReadOnly;
+pub use subtree::Subtree;
+pub use trace::{TraceOptions, Traced, Verbosity};
+pub use writeback::{WriteBack, WriteBackOptions};
@@ -0,0 +1,1107 @@
+//! Several file systems combined into one tree.
+//!
+//! [`Mux`] presents a synthetic root directory whose entries are the roots of
+//! other file systems ("children"), each grafted under a name of its own. Every
+//! operation is routed to the child owning the object, so clients see a single
+//! export where, for instance, `/a` is served by one backend and `/b` by another.
+//!
+//! File IDs of the children are kept apart by storing the child number in the top
+//! 8 bits of the file ID, so at most 255 children can be grafted. Child file IDs
+//! that do not fit in the remaining 56 bits (such as large XFS inode numbers) are
+//! hashed together with the child number into file IDs whose top 8 bits are zero.
+//! These file IDs are the same after a restart; a bounded table maps them back to
+//! the child's file IDs and is refilled from file handles. Should two objects hash
+//! to the same file ID, requests for the second one fail with NFS3ERR_SERVERFAULT
+//! instead of confusing the two. Attributes of the objects of each child report a
+//! distinct `fsid` (the child number, starting at 1), while the root directory has
+//! `fsid` 0.
+//!
+//! File handles of child objects are the handles of the child prefixed with the
+//! child number, so handles stay valid across restarts whenever the child's do.
+//! Readdir cookies are only valid in directories of the child they came from; others
+//! fail with NFS3ERR_BAD_COOKIE.
+//!
+//! The root directory is read-only: creating, removing or renaming its entries fails
+//! with NFS3ERR_ACCES. Renaming and linking between children fail with NFS3ERR_XDEV.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use tracing::warn;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// File ID of the synthetic root directory
+const ROOT_ID: nfs3::fileid3 = 1;
+
+/// Number of low file ID bits holding the file ID of the child
+const CHILD_SHIFT: u32 = 56;
+
+/// Mask of the file ID bits holding the file ID of the child
+const CHILD_MASK: nfs3::fileid3 = (1 << CHILD_SHIFT) - 1;
+
+/// Maximum number of children
+const MAX_CHILDREN: usize = 255;
+
+/// Number of hashed file IDs remembered
+const HASHED_IDS: usize = 1 << 20;
+
+/// A child file system
+type Child = Arc<dyn NFSFileSystem + Send + Sync>;
+
+/// Object a file ID refers to
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    /// The synthetic root directory
+    Root,
+    /// An object of a child, as (child number, file ID in the child)
+    Child(usize, nfs3::fileid3),
+}
+
+/// Child objects by the hashed file IDs they were given, kept for two generations
+///
+/// Once the current generation holds half of [`HASHED_IDS`] entries it replaces the
+/// previous one, so entries that are not used for a while are forgotten.
+#[derive(Debug, Default)]
+struct HashedIds {
+    /// Objects added or used since the last rotation
+    current: HashMap<nfs3::fileid3, (usize, nfs3::fileid3)>,
+    /// Objects added or used before the last rotation
+    previous: HashMap<nfs3::fileid3, (usize, nfs3::fileid3)>,
+}
+
+impl HashedIds {
+    /// Returns the object a hashed file ID was given to, marking it as recently used
+    fn get(&mut self, fileid: nfs3::fileid3) -> Option<(usize, nfs3::fileid3)> {
+        if let Some(target) = self.current.get(&fileid) {
+            return Some(*target);
+        }
+        let target = self.previous.remove(&fileid)?;
+        self.insert(fileid, target);
+        Some(target)
+    }
+
+    /// Remembers the object a hashed file ID was given to
+    fn insert(&mut self, fileid: nfs3::fileid3, target: (usize, nfs3::fileid3)) {
+        if self.current.len() >= HASHED_IDS / 2 {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.current.insert(fileid, target);
+    }
+}
+
+/// Hashes a child file ID that does not fit in the low bits into a file ID whose
+/// top bits are zero
+///
+/// The result only depends on the arguments, so it is the same after a restart.
+fn hashed_id(child: usize, id: nfs3::fileid3) -> nfs3::fileid3 {
+    // the finalizer of SplitMix64
+    let mut x = id ^ (child as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    // 0 is not a valid file ID and 1 is the root
+    (x & CHILD_MASK).max(ROOT_ID + 1)
+}
+
+/// Several file systems grafted under a synthetic root directory
+///
+/// See the [module documentation](self) for details.
+pub struct Mux {
+    /// The child file systems with the names they are grafted under
+    children: Vec<(nfs3::filename3, Child)>,
+    /// Child objects by the hashed file IDs of those that do not fit in the low bits
+    hashed: Mutex<HashedIds>,
+    /// Time reported for the root directory
+    created: nfs3::nfstime3,
+}
+
+impl Default for Mux {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mux {
+    /// Creates a multiplexer with an empty root directory
+    pub fn new() -> Self {
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Mux {
+            children: Vec::new(),
+            hashed: Mutex::new(HashedIds::default()),
+            created: nfs3::nfstime3 {
+                seconds: now.as_secs() as u32,
+                nseconds: now.subsec_nanos(),
+            },
+        }
+    }
+
+    /// Grafts a file system under a name of the root directory
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the root directory entry; must be a single path component
+    /// * `fs` - The file system to serve under that name
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is invalid or already in use, or if 255 file systems
+    /// have been grafted already.
+    pub fn graft<F: NFSFileSystem + Send + Sync + 'static>(mut self, name: &str, fs: F) -> Self {
+        assert!(
+            !name.is_empty() && name != "." && name != ".." && !name.contains('/'),
+            "invalid graft name {name:?}"
+        );
+        assert!(
+            self.children
+                .iter()
+                .all(|(n, _)| n.as_slice() != name.as_bytes()),
+            "graft name {name:?} already in use"
+        );
+        assert!(self.children.len() < MAX_CHILDREN, "too many grafts");
+        self.children
+            .push((name.as_bytes().to_vec().into(), Arc::new(fs)));
+        self
+    }
+
+    /// Returns the file ID of an object of a child
+    ///
+    /// Fails with NFS3ERR_SERVERFAULT if the object's hashed file ID is in use by
+    /// another object.
+    fn encode(&self, child: usize, id: nfs3::fileid3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        if id != 0 && id <= CHILD_MASK {
+            return Ok(((child as nfs3::fileid3 + 1) << CHILD_SHIFT) | id);
+        }
+        let fileid = hashed_id(child, id);
+        let mut hashed = self.hashed.lock().unwrap();
+        match hashed.get(fileid) {
+            Some(target) if target != (child, id) => {
+                warn!(
+                    "file ID {id} of child {child} hashes to {fileid}, \
+                     which is in use by file ID {} of child {}",
+                    target.1, target.0
+                );
+                Err(nfs3::nfsstat3::NFS3ERR_SERVERFAULT)
+            }
+            Some(_) => Ok(fileid),
+            None => {
+                hashed.insert(fileid, (child, id));
+                Ok(fileid)
+            }
+        }
+    }
+
+    /// Returns the object a file ID refers to
+    fn decode(&self, id: nfs3::fileid3) -> Result<Target, nfs3::nfsstat3> {
+        if id == ROOT_ID {
+            return Ok(Target::Root);
+        }
+        match (id >> CHILD_SHIFT) as usize {
+            0 => match self.hashed.lock().unwrap().get(id) {
+                Some((child, id)) => Ok(Target::Child(child, id)),
+                None => Err(nfs3::nfsstat3::NFS3ERR_STALE),
+            },
+            n if n <= self.children.len() => Ok(Target::Child(n - 1, id & CHILD_MASK)),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_STALE),
+        }
+    }
+
+    /// Returns the child object a file ID refers to, or `root_err` for the root directory
+    fn child_of(
+        &self,
+        id: nfs3::fileid3,
+        root_err: nfs3::nfsstat3,
+    ) -> Result<(usize, &Child, nfs3::fileid3), nfs3::nfsstat3> {
+        match self.decode(id)? {
+            Target::Root => Err(root_err),
+            Target::Child(child, id) => Ok((child, &self.children[child].1, id)),
+        }
+    }
+
+    /// Returns the child directory a file ID refers to, for operations creating
+    /// or removing directory entries
+    fn child_dir(
+        &self,
+        id: nfs3::fileid3,
+    ) -> Result<(usize, &Child, nfs3::fileid3), nfs3::nfsstat3> {
+        self.child_of(id, nfs3::nfsstat3::NFS3ERR_ACCES)
+    }
+
+    /// Converts the attributes of a child object
+    fn map_attr(
+        &self,
+        child: usize,
+        id: nfs3::fileid3,
+        attr: nfs3::fattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Ok(nfs3::fattr3 {
+            fileid: self.encode(child, id)?,
+            fsid: child as u64 + 1,
+            ..attr
+        })
+    }
+
+    /// Converts the optional attributes of a child object
+    fn map_post_op(
+        &self,
+        child: usize,
+        id: nfs3::fileid3,
+        attr: nfs3::post_op_attr,
+    ) -> nfs3::post_op_attr {
+        match attr {
+            nfs3::post_op_attr::attributes(attr) => match self.map_attr(child, id, attr) {
+                Ok(attr) => nfs3::post_op_attr::attributes(attr),
+                Err(_) => nfs3::post_op_attr::Void,
+            },
+            nfs3::post_op_attr::Void => nfs3::post_op_attr::Void,
+        }
+    }
+
+    /// Converts the weak cache consistency data of a child object
+    fn map_wcc(&self, child: usize, id: nfs3::fileid3, wcc: nfs3::wcc_data) -> nfs3::wcc_data {
+        nfs3::wcc_data {
+            before: wcc.before,
+            after: self.map_post_op(child, id, wcc.after),
+        }
+    }
+
+    /// Converts the result of an operation creating a child object
+    fn map_new(
+        &self,
+        child: usize,
+        result: Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3>,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (id, attr) = result?;
+        Ok((self.encode(child, id)?, self.map_attr(child, id, attr)?))
+    }
+
+    /// Converts the result of an operation creating a child object, with weak
+    /// cache consistency data of its directory
+    fn map_new_wcc(
+        &self,
+        child: usize,
+        dirid: nfs3::fileid3,
+        res: WccResult<(nfs3::fileid3, nfs3::fattr3)>,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        WccResult {
+            result: self.map_new(child, res.result),
+            wcc: self.map_wcc(child, dirid, res.wcc),
+        }
+    }
+
+    /// Returns a failed result for an operation that cannot be performed on an object
+    async fn refuse<T>(&self, id: nfs3::fileid3, stat: nfs3::nfsstat3) -> WccResult<T> {
+        let wcc = match self.getattr(id).await {
+            Ok(attr) => nfs3::wcc_data {
+                before: nfs3::pre_op_attr::attributes(attr.into()),
+                after: nfs3::post_op_attr::attributes(attr),
+            },
+            Err(_) => nfs3::wcc_data::default(),
+        };
+        WccResult {
+            result: Err(stat),
+            wcc,
+        }
+    }
+
+    /// Returns the attributes of the root directory
+    fn root_attr(&self) -> nfs3::fattr3 {
+        nfs3::fattr3 {
+            ftype: nfs3::ftype3::NF3DIR,
+            mode: 0o555,
+            nlink: 2 + self.children.len() as u32,
+            size: 4096,
+            used: 4096,
+            fileid: ROOT_ID,
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            ..Default::default()
+        }
+    }
+
+    /// Lists the root directory in file ID order
+    fn root_entries(&self) -> Vec<(nfs3::fileid3, usize)> {
+        let mut entries: Vec<_> = self
+            .children
+            .iter()
+            .enumerate()
+            .filter_map(|(child, (_, fs))| Some((self.encode(child, fs.root_dir()).ok()?, child)))
+            .collect();
+        entries.sort_by_key(|(id, _)| *id);
+        entries
+    }
+
+    /// Returns the file ID of a child directory entry from a readdir cookie
+    ///
+    /// Cookies of other children and unknown hashed file IDs fail with
+    /// NFS3ERR_BAD_COOKIE.
+    fn child_cookie(
+        &self,
+        child: usize,
+        start_after: nfs3::fileid3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        if start_after == 0 {
+            return Ok(0);
+        }
+        match self.decode(start_after) {
+            Ok(Target::Child(c, id)) if c == child => Ok(id),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_BAD_COOKIE),
+        }
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for Mux {
+    fn capabilities(&self) -> vfs::Capabilities {
+        let writable = self
+            .children
+            .iter()
+            .any(|(_, fs)| matches!(fs.capabilities(), vfs::Capabilities::ReadWrite));
+        if writable {
+            vfs::Capabilities::ReadWrite
+        } else {
+            vfs::Capabilities::ReadOnly
+        }
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        ROOT_ID
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        match self.decode(dirid)? {
+            Target::Root => {
+                if filename.as_slice() == b"." || filename.as_slice() == b".." {
+                    return Ok(ROOT_ID);
+                }
+                let child = self
+                    .children
+                    .iter()
+                    .position(|(name, _)| name.as_slice() == filename.as_slice())
+                    .ok_or(nfs3::nfsstat3::NFS3ERR_NOENT)?;
+                self.encode(child, self.children[child].1.root_dir())
+            }
+            Target::Child(child, id) => {
+                let fs = &self.children[child].1;
+                if id == fs.root_dir() && filename.as_slice() == b".." {
+                    return Ok(ROOT_ID);
+                }
+                self.encode(child, fs.lookup(id, filename).await?)
+            }
+        }
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        match self.decode(id)? {
+            Target::Root => Ok(self.root_attr()),
+            Target::Child(child, id) => {
+                let attr = self.children[child].1.getattr(id).await?;
+                self.map_attr(child, id, attr)
+            }
+        }
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (child, fs, id) = self.child_of(id, nfs3::nfsstat3::NFS3ERR_ACCES)?;
+        let attr = fs.setattr(id, setattr).await?;
+        self.map_attr(child, id, attr)
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let (_, fs, id) = self.child_of(id, nfs3::nfsstat3::NFS3ERR_ISDIR)?;
+        fs.read(id, offset, count).await
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let (_, fs, id) = self.child_of(id, nfs3::nfsstat3::NFS3ERR_ISDIR)?;
+        fs.read_bytes(id, offset, count).await
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        let (_, fs, id) = self.child_of(id, nfs3::nfsstat3::NFS3ERR_ISDIR)?;
+        fs.read_file_range(id, offset, count).await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (child, fs, id) = self.child_of(id, nfs3::nfsstat3::NFS3ERR_ISDIR)?;
+        let attr = fs.write(id, offset, data).await?;
+        self.map_attr(child, id, attr)
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (child, fs, dirid) = self.child_dir(dirid)?;
+        self.map_new(child, fs.create(dirid, filename, attr).await)
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let (child, fs, dirid) = self.child_dir(dirid)?;
+        let id = fs.create_exclusive(dirid, filename).await?;
+        self.encode(child, id)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (child, fs, dirid) = self.child_dir(dirid)?;
+        self.map_new(child, fs.mkdir(dirid, dirname).await)
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let (_, fs, dirid) = self.child_dir(dirid)?;
+        fs.remove(dirid, filename).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let (from_child, fs, from_dirid) = self.child_dir(from_dirid)?;
+        let (to_child, _, to_dirid) = self.child_dir(to_dirid)?;
+        if from_child != to_child {
+            return Err(nfs3::nfsstat3::NFS3ERR_XDEV);
+        }
+        fs.rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        match self.decode(dirid)? {
+            Target::Root => {
+                let mut listing = self
+                    .root_entries()
+                    .into_iter()
+                    .filter(|(id, _)| *id > start_after)
+                    .peekable();
+                let mut entries = Vec::new();
+                while entries.len() < max_entries {
+                    let Some((fileid, child)) = listing.next() else {
+                        break;
+                    };
+                    let (name, fs) = &self.children[child];
+                    // children whose root cannot be read are left out
+                    let Ok(attr) = fs.getattr(fs.root_dir()).await else {
+                        continue;
+                    };
+                    entries.push(vfs::DirEntry {
+                        fileid,
+                        name: name.clone(),
+                        attr: self.map_attr(child, fs.root_dir(), attr)?,
+                    });
+                }
+                Ok(vfs::ReadDirResult {
+                    entries,
+                    end: listing.peek().is_none(),
+                })
+            }
+            Target::Child(child, id) => {
+                let start_after = self.child_cookie(child, start_after)?;
+                let mut result = self.children[child]
+                    .1
+                    .readdir(id, start_after, max_entries)
+                    .await?;
+                for entry in result.entries.iter_mut() {
+                    entry.attr = self.map_attr(child, entry.fileid, entry.attr)?;
+                    entry.fileid = entry.attr.fileid;
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        match self.decode(dirid)? {
+            Target::Root => {
+                let mut listing = self
+                    .root_entries()
+                    .into_iter()
+                    .filter(|(id, _)| *id > start_after)
+                    .peekable();
+                let entries = listing
+                    .by_ref()
+                    .take(count)
+                    .map(|(fileid, child)| vfs::DirEntrySimple {
+                        fileid,
+                        name: self.children[child].0.clone(),
+                        attr: None,
+                    })
+                    .collect();
+                Ok(vfs::ReadDirSimpleResult::new(
+                    entries,
+                    listing.peek().is_none(),
+                ))
+            }
+            Target::Child(child, id) => {
+                let start_after = self.child_cookie(child, start_after)?;
+                let mut result = self.children[child]
+                    .1
+                    .readdir_simple_after(id, start_after, count)
+                    .await?;
+                for entry in result.entries.iter_mut() {
+                    entry.attr = entry
+                        .attr
+                        .map(|attr| self.map_attr(child, entry.fileid, attr))
+                        .transpose()?;
+                    entry.fileid = self.encode(child, entry.fileid)?;
+                }
+                result.resume_after = result
+                    .resume_after
+                    .map(|cookie| self.encode(child, cookie))
+                    .transpose()?;
+                Ok(result)
+            }
+        }
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        match self.decode(dirid)? {
+            Target::Root => {
+                let entries: Vec<_> = self
+                    .root_entries()
+                    .into_iter()
+                    .filter(|(id, _)| *id > start_after)
+                    .map(|(fileid, child)| {
+                        Ok(vfs::DirEntrySimple {
+                            fileid,
+                            name: self.children[child].0.clone(),
+                            attr: None,
+                        })
+                    })
+                    .collect();
+                Ok(futures::stream::iter(entries).boxed())
+            }
+            Target::Child(child, id) => {
+                let start_after = self.child_cookie(child, start_after)?;
+                let stream = self.children[child]
+                    .1
+                    .readdir_stream(id, start_after)
+                    .await?;
+                Ok(stream
+                    .map(move |entry| {
+                        let entry = entry?;
+                        Ok(vfs::DirEntrySimple {
+                            fileid: self.encode(child, entry.fileid)?,
+                            attr: entry
+                                .attr
+                                .map(|attr| self.map_attr(child, entry.fileid, attr))
+                                .transpose()?,
+                            name: entry.name,
+                        })
+                    })
+                    .boxed())
+            }
+        }
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let mut results: Vec<_> = ids
+            .iter()
+            .map(|_| Err(nfs3::nfsstat3::NFS3ERR_STALE))
+            .collect();
+        // positions and child file IDs of the requested objects, by child
+        let mut by_child: HashMap<usize, (Vec<usize>, Vec<nfs3::fileid3>)> = HashMap::new();
+        for (pos, id) in ids.iter().enumerate() {
+            match self.decode(*id) {
+                Ok(Target::Root) => results[pos] = Ok(self.root_attr()),
+                Ok(Target::Child(child, id)) => {
+                    let (positions, child_ids) = by_child.entry(child).or_default();
+                    positions.push(pos);
+                    child_ids.push(id);
+                }
+                Err(stat) => results[pos] = Err(stat),
+            }
+        }
+        for (child, (positions, child_ids)) in by_child {
+            let attrs = self.children[child].1.getattr_batch(&child_ids).await;
+            for ((pos, id), attr) in positions.into_iter().zip(child_ids).zip(attrs) {
+                results[pos] = attr.and_then(|attr| self.map_attr(child, id, attr));
+            }
+        }
+        results
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (child, fs, dirid) = self.child_dir(dirid)?;
+        self.map_new(child, fs.symlink(dirid, linkname, symlink, attr).await)
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        let (_, fs, id) = self.child_of(id, nfs3::nfsstat3::NFS3ERR_INVAL)?;
+        fs.readlink(id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (child, fs, file_id) = self.child_of(file_id, nfs3::nfsstat3::NFS3ERR_ISDIR)?;
+        let (dir_child, _, link_dir_id) = self.child_dir(link_dir_id)?;
+        if child != dir_child {
+            return Err(nfs3::nfsstat3::NFS3ERR_XDEV);
+        }
+        let attr = fs.link(file_id, link_dir_id, link_name).await?;
+        self.map_attr(child, file_id, attr)
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (child, fs, dir_id) = self.child_dir(dir_id)?;
+        self.map_new(child, fs.mknod(dir_id, name, ftype, specdata, attrs).await)
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        match self.decode(file_id)? {
+            Target::Root => Ok(self.root_attr()),
+            Target::Child(child, id) => {
+                let attr = self.children[child].1.commit(id, offset, count).await?;
+                self.map_attr(child, id, attr)
+            }
+        }
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        let (child, fs, child_id) = match self.child_of(id, nfs3::nfsstat3::NFS3ERR_ACCES) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(id, stat).await,
+        };
+        let res = fs.setattr_wcc(child_id, setattr, guard).await;
+        WccResult {
+            result: res
+                .result
+                .and_then(|attr| self.map_attr(child, child_id, attr)),
+            wcc: self.map_wcc(child, child_id, res.wcc),
+        }
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let (child, fs, child_id) = match self.child_of(id, nfs3::nfsstat3::NFS3ERR_ISDIR) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(id, stat).await,
+        };
+        let res = fs.write_wcc(child_id, offset, data, stable).await;
+        WccResult {
+            result: res
+                .result
+                .and_then(|(attr, how)| Ok((self.map_attr(child, child_id, attr)?, how))),
+            wcc: self.map_wcc(child, child_id, res.wcc),
+        }
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let (child, fs, child_dirid) = match self.child_dir(dirid) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(dirid, stat).await,
+        };
+        let res = fs.create_wcc(child_dirid, filename, attr).await;
+        self.map_new_wcc(child, child_dirid, res)
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        let (child, fs, child_dirid) = match self.child_dir(dirid) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(dirid, stat).await,
+        };
+        let res = fs.create_exclusive_wcc(child_dirid, filename).await;
+        WccResult {
+            result: res.result.and_then(|id| self.encode(child, id)),
+            wcc: self.map_wcc(child, child_dirid, res.wcc),
+        }
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let (child, fs, child_dirid) = match self.child_dir(dirid) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(dirid, stat).await,
+        };
+        let res = fs.mkdir_wcc(child_dirid, dirname).await;
+        self.map_new_wcc(child, child_dirid, res)
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        let (child, fs, child_dirid) = match self.child_dir(dirid) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(dirid, stat).await,
+        };
+        let res = fs.remove_wcc(child_dirid, filename, kind).await;
+        WccResult {
+            result: res.result,
+            wcc: self.map_wcc(child, child_dirid, res.wcc),
+        }
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        let dirs = self
+            .child_dir(from_dirid)
+            .and_then(|from| Ok((from, self.child_dir(to_dirid)?)))
+            .and_then(|(from, to)| match from.0 == to.0 {
+                true => Ok((from, to.2)),
+                false => Err(nfs3::nfsstat3::NFS3ERR_XDEV),
+            });
+        let ((child, fs, child_from), child_to) = match dirs {
+            Ok(v) => v,
+            Err(stat) => {
+                return RenameWccResult {
+                    result: Err(stat),
+                    from_dir_wcc: self.refuse::<()>(from_dirid, stat).await.wcc,
+                    to_dir_wcc: self.refuse::<()>(to_dirid, stat).await.wcc,
+                }
+            }
+        };
+        let res = fs
+            .rename_wcc(child_from, from_filename, child_to, to_filename)
+            .await;
+        RenameWccResult {
+            result: res.result,
+            from_dir_wcc: self.map_wcc(child, child_from, res.from_dir_wcc),
+            to_dir_wcc: self.map_wcc(child, child_to, res.to_dir_wcc),
+        }
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let (child, fs, child_dirid) = match self.child_dir(dirid) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(dirid, stat).await,
+        };
+        let res = fs.symlink_wcc(child_dirid, linkname, symlink, attr).await;
+        self.map_new_wcc(child, child_dirid, res)
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        let targets = self
+            .child_of(file_id, nfs3::nfsstat3::NFS3ERR_ISDIR)
+            .and_then(|file| Ok((file, self.child_dir(link_dir_id)?)))
+            .and_then(|(file, dir)| match file.0 == dir.0 {
+                true => Ok((file, dir.2)),
+                false => Err(nfs3::nfsstat3::NFS3ERR_XDEV),
+            });
+        let ((child, fs, child_file), child_dir) = match targets {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(link_dir_id, stat).await,
+        };
+        let res = fs.link_wcc(child_file, child_dir, link_name).await;
+        WccResult {
+            result: res
+                .result
+                .and_then(|attr| self.map_attr(child, child_file, attr)),
+            wcc: self.map_wcc(child, child_dir, res.wcc),
+        }
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let (child, fs, child_dirid) = match self.child_dir(dir_id) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(dir_id, stat).await,
+        };
+        let res = fs
+            .mknod_wcc(child_dirid, name, ftype, specdata, attrs)
+            .await;
+        self.map_new_wcc(child, child_dirid, res)
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        let (child, fs, child_id) = match self.child_of(file_id, nfs3::nfsstat3::NFS3ERR_ISDIR) {
+            Ok(v) => v,
+            Err(stat) => return self.refuse(file_id, stat).await,
+        };
+        let res = fs.commit_wcc(child_id, offset, count).await;
+        WccResult {
+            result: res
+                .result
+                .and_then(|attr| self.map_attr(child, child_id, attr)),
+            wcc: self.map_wcc(child, child_id, res.wcc),
+        }
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        match self.decode(id)? {
+            // the root reports the combined state of all children
+            Target::Root => {
+                let mut res = nfs3::fs::FSSTAT3resok {
+                    obj_attributes: nfs3::post_op_attr::attributes(self.root_attr()),
+                    invarsec: u32::MAX,
+                    ..Default::default()
+                };
+                for (_, fs) in &self.children {
+                    let Ok(child) = fs.fsstat(fs.root_dir()).await else {
+                        continue;
+                    };
+                    res.tbytes = res.tbytes.saturating_add(child.tbytes);
+                    res.fbytes = res.fbytes.saturating_add(child.fbytes);
+                    res.abytes = res.abytes.saturating_add(child.abytes);
+                    res.tfiles = res.tfiles.saturating_add(child.tfiles);
+                    res.ffiles = res.ffiles.saturating_add(child.ffiles);
+                    res.afiles = res.afiles.saturating_add(child.afiles);
+                    res.invarsec = res.invarsec.min(child.invarsec);
+                }
+                Ok(res)
+            }
+            Target::Child(child, id) => {
+                let mut res = self.children[child].1.fsstat(id).await?;
+                res.obj_attributes = self.map_post_op(child, id, res.obj_attributes);
+                Ok(res)
+            }
+        }
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        match self.decode(id) {
+            Ok(Target::Child(child, id)) => {
+                let fh = self.children[child].1.id_to_fh(id);
+                let mut data = Vec::with_capacity(fh.data.len() + 1);
+                data.push(child as u8 + 1);
+                data.extend_from_slice(&fh.data);
+                nfs3::nfs_fh3 { data }
+            }
+            // the root, and file IDs that were never handed out
+            _ => nfs3::nfs_fh3 {
+                data: [&[0], &id.to_le_bytes()[..]].concat(),
+            },
+        }
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        match id.data.split_first() {
+            Some((0, rest)) if rest == ROOT_ID.to_le_bytes() => Ok(ROOT_ID),
+            Some((&n, rest)) if n != 0 => {
+                let child = n as usize - 1;
+                let (_, fs) = self
+                    .children
+                    .get(child)
+                    .ok_or(nfs3::nfsstat3::NFS3ERR_STALE)?;
+                let id = fs.fh_to_id(&nfs3::nfs_fh3 {
+                    data: rest.to_vec(),
+                })?;
+                self.encode(child, id)
+            }
+            _ => Err(nfs3::nfsstat3::NFS3ERR_BADHANDLE),
+        }
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let path = path.strip_prefix(b"/").unwrap_or(path);
+        let (name, rest) = match path.iter().position(|&c| c == b'/') {
+            Some(pos) => (&path[..pos], &path[pos..]),
+            None => (path, &b""[..]),
+        };
+        if name.is_empty() {
+            return Ok(ROOT_ID);
+        }
+        let child = self
+            .children
+            .iter()
+            .position(|(n, _)| n.as_slice() == name)
+            .ok_or(nfs3::nfsstat3::NFS3ERR_NOENT)?;
+        let id = self.children[child].1.path_to_id(rest).await?;
+        self.encode(child, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    /// Creates a multiplexer whose only child is another multiplexer, so the file
+    /// IDs of the child do not fit in the low bits, and a file in it
+    async fn nested() -> (Mux, nfs3::fileid3) {
+        let mux = Mux::new().graft("inner", Mux::new().graft("fs", MemFS::new()));
+        let dir = mux.path_to_id(b"/inner/fs").await.unwrap();
+        let (file, _) = mux
+            .create(dir, &name("file"), Default::default())
+            .await
+            .unwrap();
+        (mux, file)
+    }
+
+    #[tokio::test]
+    async fn large_child_file_ids_survive_a_restart() {
+        let (mux, file) = nested().await;
+        assert_eq!(file >> CHILD_SHIFT, 0);
+        assert_eq!(mux.getattr(file).await.unwrap().fileid, file);
+        let fh = mux.id_to_fh(file);
+
+        let (restarted, _) = nested().await;
+        let restarted = Mux {
+            hashed: Mutex::new(HashedIds::default()),
+            ..restarted
+        };
+        assert_eq!(restarted.fh_to_id(&fh).unwrap(), file);
+        assert_eq!(restarted.getattr(file).await.unwrap().fileid, file);
+    }
+
+    #[tokio::test]
+    async fn cookies_of_other_children_are_rejected() {
+        let mux = Mux::new().graft("a", MemFS::new()).graft("b", MemFS::new());
+        let a = mux.lookup(ROOT_ID, &name("a")).await.unwrap();
+        let b = mux.lookup(ROOT_ID, &name("b")).await.unwrap();
+        for dir in [a, b] {
+            for file in ["1", "2"] {
+                mux.create(dir, &name(file), Default::default())
+                    .await
+                    .unwrap();
+            }
+        }
+        let page = mux.readdir(a, 0, 1).await.unwrap();
+        let cookie = page.entries[0].fileid;
+        assert_eq!(mux.readdir(a, cookie, 10).await.unwrap().entries.len(), 1);
+        assert!(matches!(
+            mux.readdir(b, cookie, 10).await,
+            Err(nfs3::nfsstat3::NFS3ERR_BAD_COOKIE)
+        ));
+        assert!(matches!(
+            mux.readdir_simple_after(b, cookie, 10).await,
+            Err(nfs3::nfsstat3::NFS3ERR_BAD_COOKIE)
+        ));
+    }
+}
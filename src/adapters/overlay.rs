@@ -0,0 +1,1185 @@
+//! Union of several file systems stacked on top of each other.
+//!
+//! [`Overlay`] merges one writable upper layer with any number of read-only
+//! lower layers, in the spirit of the Linux overlay file system:
+//! - A name resolves to the object in the top-most layer that has it. Directories
+//!   with the same path in several layers are merged; any other object hides
+//!   everything with the same name below it.
+//! - Lower layers are never modified. Changing an object that only exists in a
+//!   lower layer first copies it, and the directories leading to it, to the upper
+//!   layer ("copy-up").
+//! - Removing an object that exists in a lower layer leaves a whiteout in the
+//!   upper layer: an empty file named `.wh.<name>` that hides the name in all
+//!   layers below.
+//! - A directory containing a `.wh..wh..opq` entry is opaque: directories with the
+//!   same path in lower layers are not merged into it. Directories created in
+//!   place of a whiteout are made opaque.
+//!
+//! Whiteouts and opaque markers are ordinary files, so any backend can serve as
+//! a layer, and layers prepared by other tools using the same convention can be
+//! stacked as they are. Names starting with `.wh.` are hidden from clients and
+//! cannot be created through the overlay.
+//!
+//! The overlay assigns file IDs of its own, so they are unique across layers and
+//! stay the same when an object is copied up. Directory listings are ordered by
+//! these file IDs, which makes them usable as stable readdir cookies. File IDs are
+//! kept in memory only: file handles become stale when the server restarts.
+//! Hard links created through the overlay share the file ID of the linked file.
+//! Hard links that already exist in the layers get a file ID per name, and copying
+//! up a file breaks its hard links to other files of the lower layer. Listings are
+//! kept while clients page through them, and read again when a directory of one of
+//! the layers changes.
+//!
+//! Renaming a directory that has parts in lower layers fails with NFS3ERR_XDEV;
+//! clients fall back to copying it.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+
+use crate::file_ids::{ListingCache, NameTree, ROOT_ID};
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem};
+
+/// Index of the upper layer
+const UPPER: usize = 0;
+
+/// Name prefix of whiteouts
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+/// Name of the entry marking a directory as opaque
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq";
+
+/// Number of bytes copied per read when copying up a file
+const COPY_UP_CHUNK: u32 = 1024 * 1024;
+
+/// A layer of the overlay
+type Layer = Arc<dyn NFSFileSystem + Send + Sync>;
+
+/// Lock held while copying an object up
+type CopyUpLock = Arc<tokio::sync::Mutex<()>>;
+
+/// Objects of the layers an overlay file ID refers to
+#[derive(Debug)]
+struct LayerObjects {
+    /// Objects making up the node as (layer, file ID in the layer), top-most first
+    ///
+    /// Only the first object is used for anything but directories.
+    layers: Vec<(usize, nfs3::fileid3)>,
+    /// Whether `layers` lists all the lower directories merged into this one
+    merged: bool,
+}
+
+/// Mapping between overlay file IDs and the objects of the layers
+type Nodes = NameTree<[u8], LayerObjects>;
+
+impl Nodes {
+    /// Returns the file ID of a child, assigning a new one if the child is
+    /// unknown or has changed its type
+    ///
+    /// A child that is already known keeps the objects it was resolved to if
+    /// `merged` is false and its top-most object has not changed.
+    fn resolved_child(
+        &mut self,
+        parent: nfs3::fileid3,
+        name: &[u8],
+        ftype: nfs3::ftype3,
+        layers: Vec<(usize, nfs3::fileid3)>,
+        merged: bool,
+    ) -> nfs3::fileid3 {
+        let objects = LayerObjects {
+            layers: layers.clone(),
+            merged,
+        };
+        let id = self.child(parent, name, ftype, objects);
+        if let Ok(node) = self.get_mut(id) {
+            if merged || node.data.layers.first() != layers.first() {
+                node.data = LayerObjects { layers, merged };
+            }
+        }
+        id
+    }
+}
+
+/// Entry of a merged directory listing
+struct Listed {
+    /// Overlay file ID of the entry
+    fileid: nfs3::fileid3,
+    /// Name of the entry
+    name: Vec<u8>,
+    /// Attributes of the entry, if the layer listing had them
+    attr: Option<nfs3::fattr3>,
+}
+
+/// Merged directory listing in file ID order
+type Listing = Arc<Vec<Listed>>;
+
+/// Modification and change times of the layer directories making up a directory
+type ListingVerifier = Vec<[u32; 4]>;
+
+/// Recently read directory listings, by overlay directory file ID
+type Listings = ListingCache<ListingVerifier, Listed>;
+
+/// Returns the name of the whiteout hiding a name
+fn whiteout_name(name: &[u8]) -> nfs3::filename3 {
+    [WHITEOUT_PREFIX, name].concat().into()
+}
+
+/// Checks that a name can be used for a new directory entry
+fn check_new_name(name: &[u8]) -> Result<(), nfs3::nfsstat3> {
+    if name == b"." || name == b".." {
+        return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+    }
+    if name.starts_with(WHITEOUT_PREFIX) {
+        return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+    }
+    Ok(())
+}
+
+/// Checks that a name can refer to an existing directory entry that may be changed
+fn check_name(name: &[u8]) -> Result<(), nfs3::nfsstat3> {
+    if name == b"." || name == b".." {
+        return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+    }
+    if name.starts_with(WHITEOUT_PREFIX) {
+        return Err(nfs3::nfsstat3::NFS3ERR_NOENT);
+    }
+    Ok(())
+}
+
+/// Replaces the layer's file ID in attributes with the overlay's
+fn overlay_attr(id: nfs3::fileid3, attr: nfs3::fattr3) -> nfs3::fattr3 {
+    nfs3::fattr3 {
+        fileid: id,
+        fsid: 0,
+        ..attr
+    }
+}
+
+/// Maps an error for an object that was resolved before
+fn stale_if_gone(stat: nfs3::nfsstat3) -> nfs3::nfsstat3 {
+    match stat {
+        nfs3::nfsstat3::NFS3ERR_NOENT => nfs3::nfsstat3::NFS3ERR_STALE,
+        stat => stat,
+    }
+}
+
+/// Stack of a writable upper layer and read-only lower layers
+///
+/// See the [module documentation](self) for details.
+pub struct Overlay {
+    /// The upper layer followed by the lower layers, top-most first
+    layers: Vec<Layer>,
+    /// Mapping between overlay file IDs and the objects of the layers
+    nodes: RwLock<Nodes>,
+    /// Locks of objects being copied up by file ID, so an object is copied to
+    /// the upper layer only once
+    copy_ups: Mutex<HashMap<nfs3::fileid3, CopyUpLock>>,
+    /// Listings of directories clients are paging through
+    listings: Mutex<Listings>,
+}
+
+impl Overlay {
+    /// Creates an overlay with the given upper layer and no lower layers
+    ///
+    /// # Arguments
+    ///
+    /// * `upper` - The layer receiving all changes
+    pub fn new<F: NFSFileSystem + Send + Sync + 'static>(upper: F) -> Self {
+        let root = LayerObjects {
+            layers: vec![(UPPER, upper.root_dir())],
+            merged: false,
+        };
+        Overlay {
+            layers: vec![Arc::new(upper)],
+            nodes: RwLock::new(Nodes::new(root)),
+            copy_ups: Mutex::new(HashMap::new()),
+            listings: Mutex::new(Listings::default()),
+        }
+    }
+
+    /// Adds a read-only layer below the existing ones
+    ///
+    /// # Arguments
+    ///
+    /// * `lower` - The layer to add; it is never modified through the overlay
+    pub fn with_lower<F: NFSFileSystem + Send + Sync + 'static>(mut self, lower: F) -> Self {
+        self.layers.push(Arc::new(lower));
+        self.nodes
+            .get_mut()
+            .unwrap()
+            .get_mut(ROOT_ID)
+            .unwrap()
+            .data
+            .merged = false;
+        self
+    }
+
+    /// Returns the upper layer
+    fn upper(&self) -> &Layer {
+        &self.layers[UPPER]
+    }
+
+    /// Returns the top-most object of a node as (layer, file ID in the layer)
+    fn top(&self, id: nfs3::fileid3) -> Result<(usize, nfs3::fileid3), nfs3::nfsstat3> {
+        Ok(self.nodes.read().unwrap().get(id)?.data.layers[0])
+    }
+
+    /// Checks whether a name exists in a directory of a layer
+    async fn exists(&self, layer: usize, dirid: nfs3::fileid3, name: &[u8]) -> bool {
+        self.layers[layer]
+            .lookup(dirid, &name.to_vec().into())
+            .await
+            .is_ok()
+    }
+
+    /// Checks whether a directory of a layer hides the directories below it
+    async fn is_opaque(&self, layer: usize, dirid: nfs3::fileid3) -> bool {
+        layer + 1 < self.layers.len() && self.exists(layer, dirid, OPAQUE_MARKER).await
+    }
+
+    /// Resolves a name in a merged directory to the objects making it up, top-most first
+    async fn resolve(
+        &self,
+        dir_layers: &[(usize, nfs3::fileid3)],
+        name: &[u8],
+    ) -> Result<Vec<(usize, nfs3::fileid3, nfs3::fattr3)>, nfs3::nfsstat3> {
+        let filename: nfs3::filename3 = name.to_vec().into();
+        let mut found: Vec<(usize, nfs3::fileid3, nfs3::fattr3)> = Vec::new();
+        for &(layer, dirid) in dir_layers {
+            let fs = &self.layers[layer];
+            match fs.lookup(dirid, &filename).await {
+                Ok(id) => {
+                    let attr = fs.getattr(id).await?;
+                    let is_dir = matches!(attr.ftype, nfs3::ftype3::NF3DIR);
+                    // only directories are merged, anything else hides what is below
+                    if !found.is_empty() && !is_dir {
+                        break;
+                    }
+                    found.push((layer, id, attr));
+                    if !is_dir || self.is_opaque(layer, id).await {
+                        break;
+                    }
+                }
+                Err(nfs3::nfsstat3::NFS3ERR_NOENT) => {
+                    if self.exists(layer, dirid, &whiteout_name(name)).await {
+                        break;
+                    }
+                }
+                Err(stat) => return Err(stat),
+            }
+        }
+        Ok(found)
+    }
+
+    /// Returns the objects making up a directory, resolving lower directories if needed
+    async fn dir_layers(
+        &self,
+        dirid: nfs3::fileid3,
+    ) -> Result<Vec<(usize, nfs3::fileid3)>, nfs3::nfsstat3> {
+        let (parent, name) = {
+            let nodes = self.nodes.read().unwrap();
+            let node = nodes.get(dirid)?;
+            if !matches!(node.ftype, nfs3::ftype3::NF3DIR) {
+                return Err(nfs3::nfsstat3::NFS3ERR_NOTDIR);
+            }
+            if node.data.merged {
+                return Ok(node.data.layers.clone());
+            }
+            (node.parent, node.name.clone())
+        };
+        if dirid == ROOT_ID {
+            let mut layers = Vec::new();
+            for (layer, fs) in self.layers.iter().enumerate() {
+                let root = fs.root_dir();
+                layers.push((layer, root));
+                if self.is_opaque(layer, root).await {
+                    break;
+                }
+            }
+            let mut nodes = self.nodes.write().unwrap();
+            nodes.get_mut(ROOT_ID)?.data = LayerObjects {
+                layers: layers.clone(),
+                merged: true,
+            };
+            return Ok(layers);
+        }
+        if self.lookup(parent, &name.into()).await? != dirid {
+            return Err(nfs3::nfsstat3::NFS3ERR_STALE);
+        }
+        Ok(self.nodes.read().unwrap().get(dirid)?.data.layers.clone())
+    }
+
+    /// Returns the timestamps that tell whether a merged directory changed since
+    /// it was listed
+    async fn listing_verifier(
+        &self,
+        dirid: nfs3::fileid3,
+    ) -> Result<ListingVerifier, nfs3::nfsstat3> {
+        let mut verifier = Vec::new();
+        for (layer, layer_dirid) in self.dir_layers(dirid).await? {
+            let attr = self.layers[layer]
+                .getattr(layer_dirid)
+                .await
+                .map_err(stale_if_gone)?;
+            verifier.push([
+                attr.mtime.seconds,
+                attr.mtime.nseconds,
+                attr.ctime.seconds,
+                attr.ctime.nseconds,
+            ]);
+        }
+        Ok(verifier)
+    }
+
+    /// Returns the listing of a merged directory in file ID order
+    ///
+    /// A listing that starts at the beginning of the directory is always read from
+    /// the layers; continuing listings reuse it while the layer directories are
+    /// unchanged.
+    async fn listing(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<Listing, nfs3::nfsstat3> {
+        let verifier = self.listing_verifier(dirid).await?;
+        if start_after != 0 {
+            if let Some(entries) = self.listings.lock().unwrap().get(dirid, &verifier) {
+                return Ok(entries);
+            }
+        }
+        let entries = Arc::new(self.list(dirid).await?);
+        self.listings
+            .lock()
+            .unwrap()
+            .insert(dirid, verifier, entries.clone());
+        Ok(entries)
+    }
+
+    /// Discards the cached listing of a directory after changing it
+    fn invalidate_listing(&self, dirid: nfs3::fileid3) {
+        self.listings.lock().unwrap().invalidate(dirid);
+    }
+
+    /// Discards the cached listings containing an object after changing it
+    fn invalidate_parents(&self, id: nfs3::fileid3) {
+        let parents = self.nodes.read().unwrap().parents(id);
+        let mut listings = self.listings.lock().unwrap();
+        for parent in parents {
+            listings.invalidate(parent);
+        }
+    }
+
+    /// Lists a merged directory, assigning file IDs to its entries and returning
+    /// them in file ID order
+    async fn list(&self, dirid: nfs3::fileid3) -> Result<Vec<Listed>, nfs3::nfsstat3> {
+        let dir_layers = self.dir_layers(dirid).await?;
+        // top-most object of every visible name
+        let mut tops: Vec<(Vec<u8>, usize, nfs3::fileid3, Option<nfs3::fattr3>)> = Vec::new();
+        let mut seen: HashSet<Vec<u8>> = HashSet::new();
+        for &(layer, layer_dirid) in dir_layers.iter() {
+            let mut whiteouts = Vec::new();
+            let mut stream = self.layers[layer]
+                .readdir_stream(layer_dirid, 0)
+                .await
+                .map_err(stale_if_gone)?;
+            while let Some(entry) = stream.next().await {
+                let entry = entry?;
+                let name = entry.name.0;
+                if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    if name.as_slice() != OPAQUE_MARKER {
+                        whiteouts.push(hidden.to_vec());
+                    }
+                } else if !seen.contains(&name) {
+                    seen.insert(name.clone());
+                    tops.push((name, layer, entry.fileid, entry.attr));
+                }
+            }
+            // whiteouts hide names in the layers below only
+            seen.extend(whiteouts);
+        }
+        let known = self.nodes.read().unwrap().get(dirid)?.children.clone();
+        // new entries need their type before they can be assigned a file ID
+        for (name, layer, id, attr) in tops.iter_mut() {
+            let unchanged = known.get(name).is_some_and(|child| {
+                self.nodes
+                    .read()
+                    .unwrap()
+                    .get(*child)
+                    .is_ok_and(|n| n.data.layers[0] == (*layer, *id))
+            });
+            if attr.is_none() && !unchanged {
+                *attr = self.layers[*layer].getattr(*id).await.ok();
+            }
+        }
+        let mut entries = Vec::with_capacity(tops.len());
+        {
+            let mut nodes = self.nodes.write().unwrap();
+            let mut vanished = known;
+            for (name, layer, id, attr) in tops {
+                vanished.remove(&name);
+                let fileid = match attr {
+                    Some(attr) => {
+                        nodes.resolved_child(dirid, &name, attr.ftype, vec![(layer, id)], false)
+                    }
+                    // known entry, its type is checked when the attributes are fetched
+                    None => match nodes.get(dirid)?.children.get(&name) {
+                        Some(id) => *id,
+                        // removed while listing
+                        None => continue,
+                    },
+                };
+                entries.push(Listed {
+                    fileid,
+                    name,
+                    attr: attr.map(|attr| overlay_attr(fileid, attr)),
+                });
+            }
+            for name in vanished.into_keys() {
+                nodes.forget_child(dirid, &name);
+            }
+        }
+        entries.sort_by_key(|entry| entry.fileid);
+        Ok(entries)
+    }
+
+    /// Makes sure an object exists in the upper layer, copying it and the
+    /// directories leading to it up if needed, and returns its upper file ID
+    ///
+    /// Objects already in the upper layer are returned without locking; copying
+    /// an object only waits for copy-ups of the same object.
+    async fn copy_up(&self, id: nfs3::fileid3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        // objects to copy, nearest to the upper layer last
+        let mut pending = Vec::new();
+        let mut cur = id;
+        let mut upper_dir = loop {
+            let (layer, layer_id) = self.top(cur)?;
+            if layer == UPPER {
+                break layer_id;
+            }
+            pending.push(cur);
+            cur = self.nodes.read().unwrap().get(cur)?.parent;
+        };
+        while let Some(cur) = pending.pop() {
+            let lock = self.copy_up_lock(cur);
+            let result = {
+                let _guard = lock.lock().await;
+                // another request may have copied the object while this one waited
+                match self.top(cur) {
+                    Ok((UPPER, upper_id)) => Ok(upper_id),
+                    Ok(_) => self.copy_up_one(cur, upper_dir).await,
+                    Err(stat) => Err(stat),
+                }
+            };
+            drop(lock);
+            self.copy_up_unlock(cur);
+            upper_dir = result?;
+        }
+        Ok(upper_dir)
+    }
+
+    /// Returns the copy-up lock of an object
+    fn copy_up_lock(&self, id: nfs3::fileid3) -> CopyUpLock {
+        self.copy_ups.lock().unwrap().entry(id).or_default().clone()
+    }
+
+    /// Drops the copy-up lock of an object if it is not in use
+    ///
+    /// Must be called without holding a reference to the lock.
+    fn copy_up_unlock(&self, id: nfs3::fileid3) {
+        let mut copy_ups = self.copy_ups.lock().unwrap();
+        if copy_ups.get(&id).is_some_and(|l| Arc::strong_count(l) == 1) {
+            copy_ups.remove(&id);
+        }
+    }
+
+    /// Copies one object to a directory of the upper layer, returning its upper file ID
+    async fn copy_up_one(
+        &self,
+        id: nfs3::fileid3,
+        upper_dir: nfs3::fileid3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let is_dir = matches!(
+            self.nodes.read().unwrap().get(id)?.ftype,
+            nfs3::ftype3::NF3DIR
+        );
+        if is_dir {
+            // the lower directories stay merged into the copy
+            self.dir_layers(id).await?;
+        }
+        let (name, (layer, layer_id)) = {
+            let nodes = self.nodes.read().unwrap();
+            let node = nodes.get(id)?;
+            (
+                nfs3::filename3::from(node.name.clone()),
+                node.data.layers[0],
+            )
+        };
+        let fs = &self.layers[layer];
+        let attr = fs.getattr(layer_id).await.map_err(stale_if_gone)?;
+        let upper = self.upper();
+        let new_id = match attr.ftype {
+            nfs3::ftype3::NF3DIR => upper.mkdir(upper_dir, &name).await?.0,
+            nfs3::ftype3::NF3REG => {
+                let (new_id, _) = upper
+                    .create(upper_dir, &name, nfs3::sattr3::default())
+                    .await?;
+                if let Err(stat) = self.copy_data(layer, layer_id, new_id).await {
+                    let _ = upper.remove(upper_dir, &name).await;
+                    return Err(stat);
+                }
+                new_id
+            }
+            nfs3::ftype3::NF3LNK => {
+                let target = fs.readlink(layer_id).await?;
+                upper
+                    .symlink(upper_dir, &name, &target, &nfs3::sattr3::default())
+                    .await?
+                    .0
+            }
+            ftype => {
+                upper
+                    .mknod(upper_dir, &name, ftype, attr.rdev, &nfs3::sattr3::default())
+                    .await?
+                    .0
+            }
+        };
+        let times = nfs3::sattr3 {
+            mode: nfs3::set_mode3::mode(attr.mode),
+            atime: nfs3::set_atime::SET_TO_CLIENT_TIME(attr.atime),
+            mtime: nfs3::set_mtime::SET_TO_CLIENT_TIME(attr.mtime),
+            ..Default::default()
+        };
+        let owner = nfs3::sattr3 {
+            uid: nfs3::set_uid3::uid(attr.uid),
+            gid: nfs3::set_gid3::gid(attr.gid),
+            ..Default::default()
+        };
+        // the upper layer may not allow changing the owner
+        let _ = upper.setattr(new_id, owner).await;
+        if !matches!(attr.ftype, nfs3::ftype3::NF3LNK) {
+            let _ = upper.setattr(new_id, times).await;
+        }
+        let mut nodes = self.nodes.write().unwrap();
+        if let Ok(node) = nodes.get_mut(id) {
+            if !is_dir {
+                node.data.layers.clear();
+            }
+            node.data.layers.insert(0, (UPPER, new_id));
+        }
+        Ok(new_id)
+    }
+
+    /// Copies the contents of a file of a lower layer to a file of the upper layer
+    ///
+    /// The data is written unstably and committed once at the end.
+    async fn copy_data(
+        &self,
+        layer: usize,
+        from: nfs3::fileid3,
+        to: nfs3::fileid3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let mut offset = 0;
+        loop {
+            let (data, eof) = self.layers[layer]
+                .read_bytes(from, offset, COPY_UP_CHUNK)
+                .await?;
+            if !data.is_empty() {
+                self.upper()
+                    .write_wcc(to, offset, &data, nfs3::file::stable_how::UNSTABLE)
+                    .await
+                    .result?;
+                offset += data.len() as u64;
+            }
+            if eof || data.is_empty() {
+                if offset > 0 {
+                    self.upper().commit(to, 0, 0).await?;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// Removes the whiteout of a name from a directory of the upper layer,
+    /// returning whether there was one
+    async fn clear_whiteout(&self, upper_dir: nfs3::fileid3, name: &[u8]) -> bool {
+        self.upper()
+            .remove(upper_dir, &whiteout_name(name))
+            .await
+            .is_ok()
+    }
+
+    /// Hides a name of a directory in all lower layers
+    async fn add_whiteout(
+        &self,
+        upper_dir: nfs3::fileid3,
+        name: &[u8],
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.upper()
+            .create(upper_dir, &whiteout_name(name), nfs3::sattr3::default())
+            .await?;
+        Ok(())
+    }
+
+    /// Removes whiteouts and the opaque marker from a directory of the upper layer,
+    /// so that it can be removed or replaced
+    async fn clear_dir(&self, upper_dir: nfs3::fileid3) -> Result<(), nfs3::nfsstat3> {
+        let mut names = Vec::new();
+        let mut stream = self.upper().readdir_stream(upper_dir, 0).await?;
+        while let Some(entry) = stream.next().await {
+            let entry = entry?;
+            if entry.name.starts_with(WHITEOUT_PREFIX) {
+                names.push(entry.name);
+            }
+        }
+        drop(stream);
+        for name in names {
+            self.upper().remove(upper_dir, &name).await?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether a name of a directory exists in the lower layers, so that
+    /// removing it from the upper layer needs a whiteout
+    async fn exists_below(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+    ) -> Result<bool, nfs3::nfsstat3> {
+        let lower: Vec<_> = self
+            .dir_layers(dirid)
+            .await?
+            .into_iter()
+            .filter(|(layer, _)| *layer != UPPER)
+            .collect();
+        Ok(!self.resolve(&lower, name).await?.is_empty())
+    }
+
+    /// Checks that a directory is empty in the merged view
+    async fn check_empty(&self, dirid: nfs3::fileid3) -> Result<(), nfs3::nfsstat3> {
+        if self.list(dirid).await?.is_empty() {
+            Ok(())
+        } else {
+            Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY)
+        }
+    }
+
+    /// Prepares the upper layer for a new entry and returns the upper file ID of the
+    /// directory, and whether a whiteout was removed to make room for the name
+    async fn prepare_new(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, bool), nfs3::nfsstat3> {
+        check_new_name(name)?;
+        match self.lookup(dirid, name).await {
+            Ok(_) => return Err(nfs3::nfsstat3::NFS3ERR_EXIST),
+            Err(nfs3::nfsstat3::NFS3ERR_NOENT) => {}
+            Err(stat) => return Err(stat),
+        }
+        let upper_dir = self.copy_up(dirid).await?;
+        let whiteout = self.clear_whiteout(upper_dir, name).await;
+        Ok((upper_dir, whiteout))
+    }
+
+    /// Assigns a file ID to an object created in the upper layer and discards the
+    /// cached listing of its directory
+    fn register_new(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        upper_id: nfs3::fileid3,
+        attr: nfs3::fattr3,
+    ) -> (nfs3::fileid3, nfs3::fattr3) {
+        let id = self.nodes.write().unwrap().resolved_child(
+            dirid,
+            name,
+            attr.ftype,
+            vec![(UPPER, upper_id)],
+            true,
+        );
+        self.invalidate_listing(dirid);
+        (id, overlay_attr(id, attr))
+    }
+
+    /// Removes a directory entry of the given kind
+    async fn remove_entry(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> Result<(), nfs3::nfsstat3> {
+        check_name(filename)?;
+        let id = self.lookup(dirid, filename).await?;
+        let is_dir = matches!(
+            self.nodes.read().unwrap().get(id)?.ftype,
+            nfs3::ftype3::NF3DIR
+        );
+        kind.check(is_dir)?;
+        if is_dir {
+            self.check_empty(id).await?;
+        }
+        let top = self.top(id)?;
+        let below = self.exists_below(dirid, filename).await?;
+        let upper_dir = self.copy_up(dirid).await?;
+        if let (UPPER, upper_id) = top {
+            if is_dir {
+                self.clear_dir(upper_id).await?;
+            }
+            self.upper().remove(upper_dir, filename).await?;
+        }
+        let result = if below {
+            self.add_whiteout(upper_dir, filename).await
+        } else {
+            Ok(())
+        };
+        self.invalidate_parents(id);
+        self.invalidate_listing(dirid);
+        self.nodes.write().unwrap().forget_child(dirid, filename);
+        result
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for Overlay {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.upper().capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        ROOT_ID
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let dir_layers = self.dir_layers(dirid).await?;
+        match filename.as_slice() {
+            b"." => return Ok(dirid),
+            b".." => return Ok(self.nodes.read().unwrap().get(dirid)?.parent),
+            name if name.starts_with(WHITEOUT_PREFIX) => return Err(nfs3::nfsstat3::NFS3ERR_NOENT),
+            _ => {}
+        }
+        let found = self.resolve(&dir_layers, filename).await?;
+        let mut nodes = self.nodes.write().unwrap();
+        let Some((_, _, attr)) = found.first() else {
+            nodes.forget_child(dirid, filename);
+            return Err(nfs3::nfsstat3::NFS3ERR_NOENT);
+        };
+        let ftype = attr.ftype;
+        let layers = found.iter().map(|(layer, id, _)| (*layer, *id)).collect();
+        Ok(nodes.resolved_child(dirid, filename, ftype, layers, true))
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (layer, layer_id) = self.top(id)?;
+        let attr = self.layers[layer]
+            .getattr(layer_id)
+            .await
+            .map_err(stale_if_gone)?;
+        Ok(overlay_attr(id, attr))
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let upper_id = self.copy_up(id).await?;
+        let attr = self.upper().setattr(upper_id, setattr).await?;
+        self.invalidate_parents(id);
+        Ok(overlay_attr(id, attr))
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let (layer, layer_id) = self.top(id)?;
+        self.layers[layer].read(layer_id, offset, count).await
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let (layer, layer_id) = self.top(id)?;
+        self.layers[layer].read_bytes(layer_id, offset, count).await
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        let (layer, layer_id) = self.top(id)?;
+        self.layers[layer]
+            .read_file_range(layer_id, offset, count)
+            .await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let upper_id = self.copy_up(id).await?;
+        let attr = self.upper().write(upper_id, offset, data).await?;
+        self.invalidate_parents(id);
+        Ok(overlay_attr(id, attr))
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        check_new_name(filename)?;
+        // an unchecked create of an existing file only applies the attributes
+        if let Ok(id) = self.lookup(dirid, filename).await {
+            let is_file = matches!(
+                self.nodes.read().unwrap().get(id)?.ftype,
+                nfs3::ftype3::NF3REG
+            );
+            if !is_file {
+                return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+            }
+            return Ok((id, self.setattr(id, attr).await?));
+        }
+        let (upper_dir, _) = self.prepare_new(dirid, filename).await?;
+        let (upper_id, new_attr) = self.upper().create(upper_dir, filename, attr).await?;
+        Ok(self.register_new(dirid, filename, upper_id, new_attr))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let (upper_dir, _) = self.prepare_new(dirid, filename).await?;
+        let upper = self.upper();
+        let upper_id = upper.create_exclusive(upper_dir, filename).await?;
+        let attr = upper.getattr(upper_id).await?;
+        Ok(self.register_new(dirid, filename, upper_id, attr).0)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (upper_dir, whiteout) = self.prepare_new(dirid, dirname).await?;
+        let upper = self.upper();
+        let (upper_id, attr) = upper.mkdir(upper_dir, dirname).await?;
+        // a directory replacing a removed one must not show the old contents
+        if whiteout {
+            upper
+                .create(
+                    upper_id,
+                    &OPAQUE_MARKER.to_vec().into(),
+                    nfs3::sattr3::default(),
+                )
+                .await?;
+        }
+        Ok(self.register_new(dirid, dirname, upper_id, attr))
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.remove_entry(dirid, filename, vfs::RemoveKind::Any)
+            .await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> vfs::WccResult<()> {
+        let remove = self.remove_entry(dirid, filename, kind);
+        vfs::dir_op_wcc(self, dirid, remove).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        check_name(from_filename)?;
+        check_new_name(to_filename)?;
+        let from_id = self.lookup(from_dirid, from_filename).await?;
+        if from_dirid == to_dirid && from_filename.as_slice() == to_filename.as_slice() {
+            return Ok(());
+        }
+        let from_is_dir = matches!(
+            self.nodes.read().unwrap().get(from_id)?.ftype,
+            nfs3::ftype3::NF3DIR
+        );
+        if from_is_dir {
+            let layers = self.dir_layers(from_id).await?;
+            if layers.iter().any(|(layer, _)| *layer != UPPER) {
+                return Err(nfs3::nfsstat3::NFS3ERR_XDEV);
+            }
+        }
+        // upper directory being replaced, if any
+        let mut replaced_dir = None;
+        match self.lookup(to_dirid, to_filename).await {
+            Ok(to_id) => {
+                let to_is_dir = matches!(
+                    self.nodes.read().unwrap().get(to_id)?.ftype,
+                    nfs3::ftype3::NF3DIR
+                );
+                match (from_is_dir, to_is_dir) {
+                    (true, false) => return Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+                    (false, true) => return Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+                    (true, true) => {
+                        self.check_empty(to_id).await?;
+                        if let (UPPER, upper_id) = self.top(to_id)? {
+                            replaced_dir = Some(upper_id);
+                        }
+                    }
+                    (false, false) => {}
+                }
+            }
+            Err(nfs3::nfsstat3::NFS3ERR_NOENT) => {}
+            Err(stat) => return Err(stat),
+        }
+        let from_below = self.exists_below(from_dirid, from_filename).await?;
+        let to_below = self.exists_below(to_dirid, to_filename).await?;
+        let upper_id = self.copy_up(from_id).await?;
+        let upper_from = self.copy_up(from_dirid).await?;
+        let upper_to = self.copy_up(to_dirid).await?;
+        if let Some(replaced_id) = replaced_dir {
+            self.clear_dir(replaced_id).await?;
+        }
+        let upper = self.upper();
+        upper
+            .rename(upper_from, from_filename, upper_to, to_filename)
+            .await?;
+        let to_whiteout = self.clear_whiteout(upper_to, to_filename).await;
+        // a directory taking the place of another must not show the old contents
+        if from_is_dir && (to_below || to_whiteout) {
+            upper
+                .create(
+                    upper_id,
+                    &OPAQUE_MARKER.to_vec().into(),
+                    nfs3::sattr3::default(),
+                )
+                .await?;
+        }
+        let result = if from_below {
+            self.add_whiteout(upper_from, from_filename).await
+        } else {
+            Ok(())
+        };
+        self.invalidate_listing(from_dirid);
+        self.invalidate_listing(to_dirid);
+        self.nodes
+            .write()
+            .unwrap()
+            .rename(from_dirid, from_filename, to_dirid, to_filename);
+        result
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let listing = self.listing(dirid, start_after).await?;
+        let start = listing.partition_point(|entry| entry.fileid <= start_after);
+        let mut listing = listing[start..].iter().peekable();
+        let mut entries = Vec::new();
+        let mut last = None;
+        while let Some(entry) = listing.next_if(|entry| {
+            // all names of a hard-linked file must be returned together,
+            // since the cookie is the file ID
+            entries.len() < max_entries || last == Some(entry.fileid)
+        }) {
+            last = Some(entry.fileid);
+            let attr = match entry.attr {
+                Some(attr) => attr,
+                // entries removed since they were listed are skipped
+                None => match self.getattr(entry.fileid).await {
+                    Ok(attr) => attr,
+                    Err(_) => continue,
+                },
+            };
+            entries.push(vfs::DirEntry {
+                fileid: entry.fileid,
+                name: entry.name.clone().into(),
+                attr,
+            });
+        }
+        Ok(vfs::ReadDirResult {
+            entries,
+            end: listing.peek().is_none(),
+        })
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        let listing = self.listing(dirid, start_after).await?;
+        let start = listing.partition_point(|entry| entry.fileid <= start_after);
+        let mut listing = listing[start..].iter().peekable();
+        let mut entries: Vec<vfs::DirEntrySimple> = Vec::new();
+        while let Some(entry) = listing.next_if(|entry| {
+            entries.len() < count
+                || entries
+                    .last()
+                    .is_some_and(|last| last.fileid == entry.fileid)
+        }) {
+            entries.push(vfs::DirEntrySimple {
+                fileid: entry.fileid,
+                name: entry.name.clone().into(),
+                attr: entry.attr,
+            });
+        }
+        Ok(vfs::ReadDirSimpleResult::new(
+            entries,
+            listing.peek().is_none(),
+        ))
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (upper_dir, _) = self.prepare_new(dirid, linkname).await?;
+        let (upper_id, new_attr) = self
+            .upper()
+            .symlink(upper_dir, linkname, symlink, attr)
+            .await?;
+        Ok(self.register_new(dirid, linkname, upper_id, new_attr))
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        let (layer, layer_id) = self.top(id)?;
+        self.layers[layer].readlink(layer_id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (upper_dir, _) = self.prepare_new(link_dir_id, link_name).await?;
+        let upper_id = self.copy_up(file_id).await?;
+        let attr = self.upper().link(upper_id, upper_dir, link_name).await?;
+        self.invalidate_listing(link_dir_id);
+        self.invalidate_parents(file_id);
+        self.nodes
+            .write()
+            .unwrap()
+            .link(file_id, link_dir_id, link_name);
+        Ok(overlay_attr(file_id, attr))
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (upper_dir, _) = self.prepare_new(dir_id, name).await?;
+        let (upper_id, attr) = self
+            .upper()
+            .mknod(upper_dir, name, ftype, specdata, attrs)
+            .await?;
+        Ok(self.register_new(dir_id, name, upper_id, attr))
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        match self.top(file_id)? {
+            (UPPER, upper_id) => {
+                let attr = self.upper().commit(upper_id, offset, count).await?;
+                Ok(overlay_attr(file_id, attr))
+            }
+            // nothing was written to the file
+            _ => self.getattr(file_id).await,
+        }
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        // new data only ever goes to the upper layer
+        let upper = self.upper();
+        let mut res = upper.fsstat(upper.root_dir()).await?;
+        res.obj_attributes = match self.getattr(id).await {
+            Ok(attr) => nfs3::post_op_attr::attributes(attr),
+            Err(_) => nfs3::post_op_attr::Void,
+        };
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_copy_a_file_up_once() {
+        let lower = MemFS::new();
+        let (dir, _) = lower.mkdir(lower.root_dir(), &name("dir")).await.unwrap();
+        let (file, _) = lower
+            .create(dir, &name("file"), Default::default())
+            .await
+            .unwrap();
+        lower.write(file, 0, b"lower data").await.unwrap();
+        let overlay = Overlay::new(MemFS::new()).with_lower(lower);
+
+        let dir = overlay.lookup(ROOT_ID, &name("dir")).await.unwrap();
+        let file = overlay.lookup(dir, &name("file")).await.unwrap();
+        let (first, second) = tokio::join!(
+            overlay.write(file, 0, b"LOWER"),
+            overlay.write(file, 6, b"DATA"),
+        );
+        first.unwrap();
+        second.unwrap();
+
+        let (data, _) = overlay.read(file, 0, 100).await.unwrap();
+        assert_eq!(data, b"LOWER DATA");
+        let upper = overlay.upper();
+        let upper_dir = upper.lookup(upper.root_dir(), &name("dir")).await.unwrap();
+        let listing = upper.readdir(upper_dir, 0, 10).await.unwrap();
+        assert_eq!(listing.entries.len(), 1);
+        assert!(overlay.copy_ups.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,762 @@
+//! Path-based file system API.
+//!
+//! Many storage systems address objects by path (key-value stores, object stores,
+//! HTTP APIs) and have no notion of inode numbers. The [`PathFileSystem`] trait lets
+//! such a backend be written in terms of paths only, and [`PathAdapter`] implements
+//! [`NFSFileSystem`] on top of it by taking care of:
+//! - File ID allocation: every path gets a file ID on first sight, which stays valid
+//!   for as long as the object exists under that path
+//! - Parent tracking, so that file IDs can be turned back into paths
+//! - Rename bookkeeping: renaming a directory keeps the file IDs of everything below it
+//! - Invalidation: objects found to be missing, or replaced by an object of another
+//!   type, lose their file IDs and their file handles become stale
+//!
+//! Paths are relative to the root of the file system, use `/` as a separator and
+//! have no leading or trailing separators; the root directory is the empty path.
+//! Names that are not valid UTF-8 are rejected with NFS3ERR_INVAL.
+//!
+//! Hard links created with [`PathFileSystem::link`] share the file ID of the linked
+//! file. Links created by other means cannot be told apart from separate files and
+//! get file IDs of their own.
+//!
+//! Paging through a directory lists it once: the sorted listing taken when a client
+//! starts reading the directory is reused for the following pages as long as the
+//! directory's modification and change times stay the same and the directory is not
+//! changed through the adapter.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+
+use crate::file_ids::{ListingCache, NameTree, ROOT_ID};
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem};
+
+/// Entry of a directory listing returned by [`PathFileSystem::readdir`]
+#[derive(Debug, Clone)]
+pub struct PathDirEntry {
+    /// Name of the entry
+    pub name: String,
+    /// Attributes of the entry, if the backend has them at hand
+    ///
+    /// Missing attributes are fetched with [`PathFileSystem::getattr`] when needed.
+    /// The `fileid` field is ignored.
+    pub attr: Option<nfs3::fattr3>,
+}
+
+/// A file system whose objects are addressed by path
+///
+/// All attributes returned by this trait have their `fileid` field ignored;
+/// [`PathAdapter`] fills in the file IDs it assigns. Methods that modify the
+/// file system return NFS3ERR_ROFS by default, and the less common object types
+/// NFS3ERR_NOTSUPP, so a read-only backend only needs to implement
+/// `getattr`, `read` and `readdir`.
+#[async_trait]
+pub trait PathFileSystem: Sync {
+    /// Returns the capabilities of the file system
+    fn capabilities(&self) -> vfs::Capabilities {
+        vfs::Capabilities::ReadOnly
+    }
+
+    /// Gets the attributes of an object
+    ///
+    /// Returns NFS3ERR_NOENT if the object does not exist.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the object
+    async fn getattr(&self, path: &str) -> Result<nfs3::fattr3, nfs3::nfsstat3>;
+
+    /// Sets the attributes of an object, returning the new attributes
+    ///
+    /// # Arguments
+    /// * `path` - Path of the object
+    /// * `setattr` - Attributes to change
+    async fn setattr(
+        &self,
+        _path: &str,
+        _setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    /// Reads data from a file, returning the data and whether the end of file was reached
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file
+    /// * `offset` - Byte offset to start reading at
+    /// * `count` - Maximum number of bytes to read
+    async fn read(
+        &self,
+        path: &str,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3>;
+
+    /// Writes data to a file, returning the new attributes
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file
+    /// * `offset` - Byte offset to start writing at
+    /// * `data` - Data to write
+    async fn write(
+        &self,
+        _path: &str,
+        _offset: u64,
+        _data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    /// Creates a file, or sets the attributes of an existing one
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file
+    /// * `attr` - Attributes of the file
+    async fn create(
+        &self,
+        _path: &str,
+        _attr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    /// Creates a file, failing with NFS3ERR_EXIST if the path is taken
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file
+    async fn create_exclusive(&self, _path: &str) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    /// Creates a directory
+    ///
+    /// # Arguments
+    /// * `path` - Path of the directory
+    async fn mkdir(&self, _path: &str) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    /// Removes a file or an empty directory
+    ///
+    /// # Arguments
+    /// * `path` - Path of the object
+    async fn remove(&self, _path: &str) -> Result<(), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    /// Renames an object, replacing the target if it exists
+    ///
+    /// # Arguments
+    /// * `from` - Current path of the object
+    /// * `to` - New path of the object
+    async fn rename(&self, _from: &str, _to: &str) -> Result<(), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    /// Lists all entries of a directory, excluding "." and ".."
+    ///
+    /// # Arguments
+    /// * `path` - Path of the directory
+    async fn readdir(&self, path: &str) -> Result<Vec<PathDirEntry>, nfs3::nfsstat3>;
+
+    /// Creates a symbolic link, returning its attributes
+    ///
+    /// # Arguments
+    /// * `path` - Path of the link
+    /// * `target` - Target of the link
+    /// * `attr` - Attributes of the link
+    async fn symlink(
+        &self,
+        _path: &str,
+        _target: &nfs3::nfspath3,
+        _attr: &nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    /// Reads the target of a symbolic link
+    ///
+    /// # Arguments
+    /// * `path` - Path of the link
+    async fn readlink(&self, _path: &str) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    /// Creates a hard link, returning the attributes of the file
+    ///
+    /// # Arguments
+    /// * `path` - Path of the existing file
+    /// * `link_path` - Path of the new link
+    async fn link(&self, _path: &str, _link_path: &str) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    /// Creates a special file (device, FIFO or socket), returning its attributes
+    ///
+    /// # Arguments
+    /// * `path` - Path of the special file
+    /// * `ftype` - Type of the special file
+    /// * `specdata` - Device numbers for character and block devices
+    /// * `attr` - Attributes of the special file
+    async fn mknod(
+        &self,
+        _path: &str,
+        _ftype: nfs3::ftype3,
+        _specdata: nfs3::specdata3,
+        _attr: &nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    /// Commits written data of a file to stable storage, returning its attributes
+    ///
+    /// The default implementation assumes writes are stable and returns the attributes.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file
+    async fn commit(&self, path: &str) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.getattr(path).await
+    }
+}
+
+/// Mapping between file IDs and paths
+type Inodes = NameTree<str, ()>;
+
+impl Inodes {
+    /// Returns the path of an object
+    fn path(&self, id: nfs3::fileid3) -> Result<String, nfs3::nfsstat3> {
+        let mut names = Vec::new();
+        let mut cur = id;
+        while cur != ROOT_ID {
+            let node = self.get(cur)?;
+            names.push(node.name.as_str());
+            cur = node.parent;
+        }
+        names.reverse();
+        Ok(names.join("/"))
+    }
+}
+
+/// Sorted directory listing with file IDs assigned
+type Listing = Arc<Vec<(nfs3::fileid3, PathDirEntry)>>;
+
+/// Modification and change time of a directory
+type ListingVerifier = [u32; 4];
+
+/// Recently read directory listings
+type Listings = ListingCache<ListingVerifier, (nfs3::fileid3, PathDirEntry)>;
+
+/// Returns the timestamps that tell whether a directory changed since it was listed
+fn listing_verifier(attr: &nfs3::fattr3) -> ListingVerifier {
+    [
+        attr.mtime.seconds,
+        attr.mtime.nseconds,
+        attr.ctime.seconds,
+        attr.ctime.nseconds,
+    ]
+}
+
+/// Converts a file name to UTF-8, rejecting names that are not a single path component
+fn check_name(name: &[u8]) -> Result<&str, nfs3::nfsstat3> {
+    let name = std::str::from_utf8(name).map_err(|_| nfs3::nfsstat3::NFS3ERR_INVAL)?;
+    if name.is_empty() || name.contains('/') || name.contains('\0') {
+        return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+    }
+    Ok(name)
+}
+
+/// Checks that a name can be used for a new directory entry
+fn check_new_name(name: &[u8]) -> Result<&str, nfs3::nfsstat3> {
+    if name == b"." || name == b".." {
+        return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+    }
+    check_name(name)
+}
+
+/// Joins a directory path and a name
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Implements [`NFSFileSystem`] on top of a [`PathFileSystem`]
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct PathAdapter<F> {
+    /// The path-based file system
+    fs: F,
+    /// Mapping between file IDs and paths
+    inodes: RwLock<Inodes>,
+    /// Listings of directories clients are paging through
+    listings: Mutex<Listings>,
+}
+
+impl<F: PathFileSystem> PathAdapter<F> {
+    /// Creates an adapter for a path-based file system
+    pub fn new(fs: F) -> Self {
+        PathAdapter {
+            fs,
+            inodes: RwLock::new(Inodes::new(())),
+            listings: Mutex::new(Listings::default()),
+        }
+    }
+
+    /// Returns a reference to the path-based file system
+    pub fn inner(&self) -> &F {
+        &self.fs
+    }
+
+    /// Returns the path of an object
+    fn path(&self, id: nfs3::fileid3) -> Result<String, nfs3::nfsstat3> {
+        self.inodes.read().unwrap().path(id)
+    }
+
+    /// Returns the path of a child of a directory
+    fn child_path(&self, dirid: nfs3::fileid3, name: &str) -> Result<String, nfs3::nfsstat3> {
+        Ok(join(&self.path(dirid)?, name))
+    }
+
+    /// Discards the cached listing of a directory after changing it
+    fn invalidate_listing(&self, dirid: nfs3::fileid3) {
+        self.listings.lock().unwrap().invalidate(dirid);
+    }
+
+    /// Discards the cached listings containing an object after changing it
+    fn invalidate_parents(&self, id: nfs3::fileid3) {
+        let parents = self.inodes.read().unwrap().parents(id);
+        let mut listings = self.listings.lock().unwrap();
+        for parent in parents {
+            listings.invalidate(parent);
+        }
+    }
+
+    /// Assigns a file ID to a child and fills it into its attributes
+    fn register(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &str,
+        mut attr: nfs3::fattr3,
+    ) -> (nfs3::fileid3, nfs3::fattr3) {
+        let id = self
+            .inodes
+            .write()
+            .unwrap()
+            .child(dirid, name, attr.ftype, ());
+        attr.fileid = id;
+        (id, attr)
+    }
+
+    /// Converts a backend error for an existing object, invalidating its file ID if it is gone
+    fn check_exists(&self, id: nfs3::fileid3, stat: nfs3::nfsstat3) -> nfs3::nfsstat3 {
+        match stat {
+            nfs3::nfsstat3::NFS3ERR_NOENT if id != ROOT_ID => {
+                self.inodes.write().unwrap().forget(id);
+                nfs3::nfsstat3::NFS3ERR_STALE
+            }
+            stat => stat,
+        }
+    }
+
+    /// Gets the attributes of an object by file ID
+    async fn attr_of(&self, id: nfs3::fileid3, path: &str) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        match self.fs.getattr(path).await {
+            Ok(mut attr) => {
+                attr.fileid = id;
+                Ok(attr)
+            }
+            Err(stat) => Err(self.check_exists(id, stat)),
+        }
+    }
+
+    /// Returns the listing of a directory in file ID order
+    ///
+    /// A listing that starts at the beginning of the directory is always read from
+    /// the backend; continuing listings reuse it while the directory is unchanged.
+    async fn listing(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<Listing, nfs3::nfsstat3> {
+        let path = self.path(dirid)?;
+        let verifier = listing_verifier(&self.attr_of(dirid, &path).await?);
+        if start_after != 0 {
+            if let Some(entries) = self.listings.lock().unwrap().get(dirid, &verifier) {
+                return Ok(entries);
+            }
+        }
+        let entries = Arc::new(self.list(dirid).await?);
+        self.listings
+            .lock()
+            .unwrap()
+            .insert(dirid, verifier, entries.clone());
+        Ok(entries)
+    }
+
+    /// Lists a directory, assigning file IDs to its entries and returning them in file ID order
+    async fn list(
+        &self,
+        dirid: nfs3::fileid3,
+    ) -> Result<Vec<(nfs3::fileid3, PathDirEntry)>, nfs3::nfsstat3> {
+        let path = self.path(dirid)?;
+        let mut listing = self
+            .fs
+            .readdir(&path)
+            .await
+            .map_err(|stat| self.check_exists(dirid, stat))?;
+        let known = self.inodes.read().unwrap().get(dirid)?.children.clone();
+        // new entries need their type before they can be assigned a file ID
+        for entry in listing.iter_mut() {
+            if entry.attr.is_none() && !known.contains_key(&entry.name) {
+                entry.attr = self.fs.getattr(&join(&path, &entry.name)).await.ok();
+            }
+        }
+        let mut entries = Vec::with_capacity(listing.len());
+        {
+            let mut inodes = self.inodes.write().unwrap();
+            let mut vanished = known;
+            for entry in listing {
+                vanished.remove(&entry.name);
+                let id = match entry.attr {
+                    Some(attr) => inodes.child(dirid, &entry.name, attr.ftype, ()),
+                    // known entry, its type is checked when the attributes are fetched
+                    None => match inodes.get(dirid)?.children.get(&entry.name) {
+                        Some(id) => *id,
+                        // removed while listing
+                        None => continue,
+                    },
+                };
+                entries.push((id, entry));
+            }
+            for id in vanished.into_values() {
+                inodes.forget(id);
+            }
+        }
+        entries.sort_by_key(|(id, _)| *id);
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl<F: PathFileSystem + Send> NFSFileSystem for PathAdapter<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.fs.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        ROOT_ID
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        match filename.as_slice() {
+            b"." => Ok(dirid),
+            b".." => Ok(self.inodes.read().unwrap().get(dirid)?.parent),
+            name => {
+                let name = check_name(name)?;
+                let path = self.child_path(dirid, name)?;
+                match self.fs.getattr(&path).await {
+                    Ok(attr) => Ok(self.register(dirid, name, attr).0),
+                    Err(stat) => {
+                        if matches!(stat, nfs3::nfsstat3::NFS3ERR_NOENT) {
+                            self.inodes.write().unwrap().forget_child(dirid, name);
+                        }
+                        Err(stat)
+                    }
+                }
+            }
+        }
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let path = self.path(id)?;
+        let attr = self.attr_of(id, &path).await?;
+        let changed_type = {
+            let inodes = self.inodes.read().unwrap();
+            inodes
+                .get(id)
+                .is_ok_and(|node| node.ftype as u32 != attr.ftype as u32)
+        };
+        if changed_type && id != ROOT_ID {
+            // the object was replaced by one of another type
+            self.inodes.write().unwrap().forget(id);
+            return Err(nfs3::nfsstat3::NFS3ERR_STALE);
+        }
+        Ok(attr)
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let path = self.path(id)?;
+        let mut attr = self
+            .fs
+            .setattr(&path, setattr)
+            .await
+            .map_err(|stat| self.check_exists(id, stat))?;
+        self.invalidate_parents(id);
+        attr.fileid = id;
+        Ok(attr)
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let path = self.path(id)?;
+        self.fs
+            .read(&path, offset, count)
+            .await
+            .map_err(|stat| self.check_exists(id, stat))
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let path = self.path(id)?;
+        let mut attr = self
+            .fs
+            .write(&path, offset, data)
+            .await
+            .map_err(|stat| self.check_exists(id, stat))?;
+        self.invalidate_parents(id);
+        attr.fileid = id;
+        Ok(attr)
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = check_new_name(filename)?;
+        let path = self.child_path(dirid, name)?;
+        let attr = self.fs.create(&path, attr).await?;
+        self.invalidate_listing(dirid);
+        Ok(self.register(dirid, name, attr))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let name = check_new_name(filename)?;
+        let path = self.child_path(dirid, name)?;
+        let attr = self.fs.create_exclusive(&path).await?;
+        self.invalidate_listing(dirid);
+        Ok(self.register(dirid, name, attr).0)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = check_new_name(dirname)?;
+        let path = self.child_path(dirid, name)?;
+        let attr = self.fs.mkdir(&path).await?;
+        self.invalidate_listing(dirid);
+        Ok(self.register(dirid, name, attr))
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        if filename.as_slice() == b"." || filename.as_slice() == b".." {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        let name = check_name(filename)?;
+        let path = self.child_path(dirid, name)?;
+        let result = self.fs.remove(&path).await;
+        self.invalidate_listing(dirid);
+        if matches!(result, Ok(()) | Err(nfs3::nfsstat3::NFS3ERR_NOENT)) {
+            self.inodes.write().unwrap().forget_child(dirid, name);
+        }
+        result
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        if from_filename.as_slice() == b"." || from_filename.as_slice() == b".." {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        let from_name = check_name(from_filename)?;
+        let to_name = check_new_name(to_filename)?;
+        let from = self.child_path(from_dirid, from_name)?;
+        let to = self.child_path(to_dirid, to_name)?;
+        if from == to {
+            return Ok(());
+        }
+        self.fs.rename(&from, &to).await?;
+        self.invalidate_listing(from_dirid);
+        self.invalidate_listing(to_dirid);
+        self.inodes
+            .write()
+            .unwrap()
+            .rename(from_dirid, from_name, to_dirid, to_name);
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let listing = self.listing(dirid, start_after).await?;
+        let start = listing.partition_point(|(id, _)| *id <= start_after);
+        let mut listing = listing[start..].iter().peekable();
+        let mut entries = Vec::new();
+        let mut last = None;
+        while let Some((fileid, entry)) = listing.next_if(|(id, _)| {
+            // all names of a hard-linked file must be returned together,
+            // since the cookie is the file ID
+            entries.len() < max_entries || last == Some(*id)
+        }) {
+            let fileid = *fileid;
+            last = Some(fileid);
+            let attr = match entry.attr {
+                Some(attr) => nfs3::fattr3 { fileid, ..attr },
+                // entries removed since they were listed are skipped
+                None => match self.getattr(fileid).await {
+                    Ok(attr) => attr,
+                    Err(_) => continue,
+                },
+            };
+            entries.push(vfs::DirEntry {
+                fileid,
+                name: entry.name.as_bytes().into(),
+                attr,
+            });
+        }
+        Ok(vfs::ReadDirResult {
+            entries,
+            end: listing.peek().is_none(),
+        })
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        let listing = self.listing(dirid, start_after).await?;
+        let start = listing.partition_point(|(id, _)| *id <= start_after);
+        let mut listing = listing[start..].iter().peekable();
+        let mut entries: Vec<vfs::DirEntrySimple> = Vec::new();
+        while let Some((fileid, entry)) = listing.next_if(|(id, _)| {
+            entries.len() < count || entries.last().is_some_and(|last| last.fileid == *id)
+        }) {
+            let fileid = *fileid;
+            entries.push(vfs::DirEntrySimple {
+                fileid,
+                name: entry.name.as_bytes().into(),
+                attr: entry.attr.map(|attr| nfs3::fattr3 { fileid, ..attr }),
+            });
+        }
+        Ok(vfs::ReadDirSimpleResult::new(
+            entries,
+            listing.peek().is_none(),
+        ))
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = check_new_name(linkname)?;
+        let path = self.child_path(dirid, name)?;
+        let attr = self.fs.symlink(&path, symlink, attr).await?;
+        self.invalidate_listing(dirid);
+        Ok(self.register(dirid, name, attr))
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        let path = self.path(id)?;
+        self.fs
+            .readlink(&path)
+            .await
+            .map_err(|stat| self.check_exists(id, stat))
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let name = check_new_name(link_name)?;
+        let path = self.path(file_id)?;
+        let link_path = self.child_path(link_dir_id, name)?;
+        let mut attr = self
+            .fs
+            .link(&path, &link_path)
+            .await
+            .map_err(|stat| self.check_exists(file_id, stat))?;
+        self.invalidate_listing(link_dir_id);
+        self.invalidate_parents(file_id);
+        self.inodes
+            .write()
+            .unwrap()
+            .link(file_id, link_dir_id, name);
+        attr.fileid = file_id;
+        Ok(attr)
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = check_new_name(name)?;
+        let path = self.child_path(dir_id, name)?;
+        let attr = self.fs.mknod(&path, ftype, specdata, attrs).await?;
+        self.invalidate_listing(dir_id);
+        Ok(self.register(dir_id, name, attr))
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        _offset: u64,
+        _count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let path = self.path(file_id)?;
+        let mut attr = self
+            .fs
+            .commit(&path)
+            .await
+            .map_err(|stat| self.check_exists(file_id, stat))?;
+        attr.fileid = file_id;
+        Ok(attr)
+    }
+}
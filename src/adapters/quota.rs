@@ -0,0 +1,1233 @@
+//! Quota enforcement for file systems shared by several users.
+//!
+//! [`Quota`] wraps an [`NFSFileSystem`] and limits the bytes and inodes used per
+//! user, per group and by the whole export:
+//! - Bytes are the sizes of regular files, and every object counts as one inode
+//! - Usage is charged to the owner and group reported in the attributes of an object,
+//!   and hard links to an object are counted once
+//! - Growing a file with WRITE or SETATTR, changing its owner or group, and creating
+//!   objects fail with NFS3ERR_DQUOT if a limit would be exceeded
+//! - New objects are checked against the limits of the client making the request
+//!   (see [`vfs::caller`]), or of the owner and group given in their initial attributes
+//! - FSSTAT reports the space and inodes that are left to the client
+//!
+//! Usage is kept in memory and saved to [`QuotaOptions::usage_file`] periodically
+//! and by [`Quota::flush`], which should be called before the wrapper is dropped.
+//! Without a saved file, usage is rebuilt by scanning the whole tree. Changes made to the wrapped file system by other means, and
+//! changes since the last save if the server stops unexpectedly, are only picked
+//! up by [`Quota::rescan`].
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io::{ErrorKind, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::warn;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// First line of a usage file
+const USAGE_MAGIC: &str = "nfsserve-quota 1";
+
+/// Number of directory entries requested at a time while scanning
+const SCAN_BATCH: usize = 1024;
+
+/// Limits of a user, a group or an export; `None` means unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limit {
+    /// Maximum total size of regular files in bytes
+    pub bytes: Option<u64>,
+    /// Maximum number of objects
+    pub inodes: Option<u64>,
+}
+
+/// Resources used by a user, a group or an export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Total size of regular files in bytes
+    pub bytes: u64,
+    /// Number of objects
+    pub inodes: u64,
+}
+
+/// Options of a [`Quota`] file system
+#[derive(Debug, Clone)]
+pub struct QuotaOptions {
+    /// Limits of the whole export
+    pub export: Limit,
+    /// Limits of individual users
+    pub users: HashMap<nfs3::uid3, Limit>,
+    /// Limits of individual groups
+    pub groups: HashMap<nfs3::gid3, Limit>,
+    /// Limits of users without an entry in `users`
+    pub default_user: Limit,
+    /// Limits of groups without an entry in `groups`
+    pub default_group: Limit,
+    /// File the usage is saved to; usage is not persisted if `None`
+    pub usage_file: Option<PathBuf>,
+    /// Minimum time between saves of the usage
+    pub save_interval: Duration,
+}
+
+impl Default for QuotaOptions {
+    fn default() -> Self {
+        QuotaOptions {
+            export: Limit::default(),
+            users: HashMap::new(),
+            groups: HashMap::new(),
+            default_user: Limit::default(),
+            default_group: Limit::default(),
+            usage_file: None,
+            save_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl QuotaOptions {
+    /// Returns the limits of a user
+    fn user_limit(&self, uid: nfs3::uid3) -> Limit {
+        self.users.get(&uid).copied().unwrap_or(self.default_user)
+    }
+
+    /// Returns the limits of a group
+    fn group_limit(&self, gid: nfs3::gid3) -> Limit {
+        self.groups.get(&gid).copied().unwrap_or(self.default_group)
+    }
+}
+
+/// Resources used by one object, charged to its owner and group
+///
+/// The owner and group of an object that does not exist yet may be unknown,
+/// in which case only the export is charged.
+#[derive(Debug, Clone, Copy)]
+struct Charge {
+    /// Owner of the object
+    uid: Option<nfs3::uid3>,
+    /// Group of the object
+    gid: Option<nfs3::gid3>,
+    /// Bytes charged for the object
+    bytes: u64,
+}
+
+impl Charge {
+    /// Returns the charge of an object with the given attributes
+    fn of(attr: &nfs3::fattr3) -> Self {
+        let regular = matches!(attr.ftype, nfs3::ftype3::NF3REG);
+        Charge {
+            uid: Some(attr.uid),
+            gid: Some(attr.gid),
+            bytes: if regular { attr.size } else { 0 },
+        }
+    }
+}
+
+/// Changes of the usage of one user, group or export as (bytes, inodes)
+type Delta = (i128, i128);
+
+/// Applies a change to a usage, clamping it at zero
+fn apply(usage: &mut Usage, (bytes, inodes): Delta) {
+    usage.bytes = (usage.bytes as i128 + bytes).clamp(0, u64::MAX as i128) as u64;
+    usage.inodes = (usage.inodes as i128 + inodes).clamp(0, u64::MAX as i128) as u64;
+}
+
+/// Checks whether a change takes a usage over a limit
+///
+/// Only increases are checked, so that usage over a limit can always be reduced.
+fn exceeds(usage: Usage, limit: Limit, (bytes, inodes): Delta) -> bool {
+    let over = |used: u64, delta: i128, limit: Option<u64>| {
+        delta > 0 && limit.is_some_and(|limit| used as i128 + delta > limit as i128)
+    };
+    over(usage.bytes, bytes, limit.bytes) || over(usage.inodes, inodes, limit.inodes)
+}
+
+/// Current usage and its persistence state
+#[derive(Debug)]
+struct Ledger {
+    /// Usage of the whole export
+    total: Usage,
+    /// Usage by owner
+    users: HashMap<nfs3::uid3, Usage>,
+    /// Usage by group
+    groups: HashMap<nfs3::gid3, Usage>,
+    /// Whether the usage changed since it was last saved
+    dirty: bool,
+    /// When the usage was last saved
+    saved_at: Instant,
+}
+
+impl Ledger {
+    /// Creates an empty ledger
+    fn new() -> Self {
+        Ledger {
+            total: Usage::default(),
+            users: HashMap::new(),
+            groups: HashMap::new(),
+            dirty: false,
+            saved_at: Instant::now(),
+        }
+    }
+
+    /// Replaces the charge `from` of an object by `to`
+    ///
+    /// Fails with NFS3ERR_DQUOT without changing anything if `limits` are given and
+    /// the change takes the export, a user or a group over its limit.
+    fn transition(
+        &mut self,
+        from: Option<Charge>,
+        to: Option<Charge>,
+        limits: Option<&QuotaOptions>,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let mut total: Delta = (0, 0);
+        let mut users: HashMap<nfs3::uid3, Delta> = HashMap::new();
+        let mut groups: HashMap<nfs3::gid3, Delta> = HashMap::new();
+        for (charge, sign) in [(from, -1), (to, 1)] {
+            let Some(charge) = charge else {
+                continue;
+            };
+            let delta = (sign * charge.bytes as i128, sign);
+            total = (total.0 + delta.0, total.1 + delta.1);
+            if let Some(uid) = charge.uid {
+                let d = users.entry(uid).or_default();
+                *d = (d.0 + delta.0, d.1 + delta.1);
+            }
+            if let Some(gid) = charge.gid {
+                let d = groups.entry(gid).or_default();
+                *d = (d.0 + delta.0, d.1 + delta.1);
+            }
+        }
+        if let Some(limits) = limits {
+            let over = exceeds(self.total, limits.export, total)
+                || users.iter().any(|(uid, delta)| {
+                    let usage = self.users.get(uid).copied().unwrap_or_default();
+                    exceeds(usage, limits.user_limit(*uid), *delta)
+                })
+                || groups.iter().any(|(gid, delta)| {
+                    let usage = self.groups.get(gid).copied().unwrap_or_default();
+                    exceeds(usage, limits.group_limit(*gid), *delta)
+                });
+            if over {
+                return Err(nfs3::nfsstat3::NFS3ERR_DQUOT);
+            }
+        }
+        apply(&mut self.total, total);
+        for (uid, delta) in users {
+            let usage = self.users.entry(uid).or_default();
+            apply(usage, delta);
+            if *usage == Usage::default() {
+                self.users.remove(&uid);
+            }
+        }
+        for (gid, delta) in groups {
+            let usage = self.groups.entry(gid).or_default();
+            apply(usage, delta);
+            if *usage == Usage::default() {
+                self.groups.remove(&gid);
+            }
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Returns the contents of a usage file
+    fn serialize(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{}", USAGE_MAGIC);
+        let _ = writeln!(out, "T {} {}", self.total.bytes, self.total.inodes);
+        for (uid, usage) in &self.users {
+            let _ = writeln!(out, "U {} {} {}", uid, usage.bytes, usage.inodes);
+        }
+        for (gid, usage) in &self.groups {
+            let _ = writeln!(out, "G {} {} {}", gid, usage.bytes, usage.inodes);
+        }
+        out
+    }
+
+    /// Parses the contents of a usage file
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        if lines.next()? != USAGE_MAGIC {
+            return None;
+        }
+        let mut ledger = Ledger::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields[..] {
+                ["T", bytes, inodes] => {
+                    ledger.total = Usage {
+                        bytes: bytes.parse().ok()?,
+                        inodes: inodes.parse().ok()?,
+                    };
+                }
+                ["U", uid, bytes, inodes] => {
+                    let usage = Usage {
+                        bytes: bytes.parse().ok()?,
+                        inodes: inodes.parse().ok()?,
+                    };
+                    ledger.users.insert(uid.parse().ok()?, usage);
+                }
+                ["G", gid, bytes, inodes] => {
+                    let usage = Usage {
+                        bytes: bytes.parse().ok()?,
+                        inodes: inodes.parse().ok()?,
+                    };
+                    ledger.groups.insert(gid.parse().ok()?, usage);
+                }
+                _ => return None,
+            }
+        }
+        Some(ledger)
+    }
+}
+
+/// Writes a usage file, replacing the previous one atomically
+fn write_usage(path: &Path, contents: &str) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Loads a usage file, or returns `None` if it does not exist or cannot be used
+fn load_usage(path: &Path) -> Option<Ledger> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            let ledger = Ledger::parse(&contents);
+            if ledger.is_none() {
+                warn!("Ignoring malformed quota usage file {:?}", path);
+            }
+            ledger
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("Failed to read quota usage file {:?}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+/// Per-file locks ordering the charges of changes to the same object
+type FileLock = Arc<tokio::sync::Mutex<()>>;
+
+/// Quota enforcement around a file system
+///
+/// See the [module documentation](self) for details.
+pub struct Quota<F> {
+    /// The wrapped file system
+    inner: F,
+    /// Limits and persistence settings
+    options: QuotaOptions,
+    /// Current usage
+    ledger: Mutex<Ledger>,
+    /// Serializes saves of the usage
+    save_lock: tokio::sync::Mutex<()>,
+    /// Locks of files whose charge is being changed
+    locks: Mutex<HashMap<nfs3::fileid3, FileLock>>,
+}
+
+impl<F: NFSFileSystem> Quota<F> {
+    /// Creates a quota wrapper
+    ///
+    /// Loads the usage from [`QuotaOptions::usage_file`] if it exists, and scans
+    /// the file system otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The file system to limit
+    /// * `options` - Limits and persistence settings
+    pub async fn new(inner: F, options: QuotaOptions) -> Result<Self, nfs3::nfsstat3> {
+        let loaded = options.usage_file.as_deref().and_then(load_usage);
+        let scan = loaded.is_none();
+        let quota = Quota {
+            inner,
+            options,
+            ledger: Mutex::new(loaded.unwrap_or_else(Ledger::new)),
+            save_lock: tokio::sync::Mutex::new(()),
+            locks: Mutex::new(HashMap::new()),
+        };
+        if scan {
+            quota.rescan().await?;
+        }
+        Ok(quota)
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Returns the usage of the whole export
+    pub fn usage(&self) -> Usage {
+        self.ledger.lock().unwrap().total
+    }
+
+    /// Returns the usage of a user
+    pub fn user_usage(&self, uid: nfs3::uid3) -> Usage {
+        let ledger = self.ledger.lock().unwrap();
+        ledger.users.get(&uid).copied().unwrap_or_default()
+    }
+
+    /// Returns the usage of a group
+    pub fn group_usage(&self, gid: nfs3::gid3) -> Usage {
+        let ledger = self.ledger.lock().unwrap();
+        ledger.groups.get(&gid).copied().unwrap_or_default()
+    }
+
+    /// Rebuilds the usage by walking the whole file system, then saves it
+    ///
+    /// Changes made through the wrapper while the scan runs may be miscounted.
+    pub async fn rescan(&self) -> Result<(), nfs3::nfsstat3> {
+        let mut ledger = Ledger::new();
+        let root = self.inner.root_dir();
+        let attr = self.inner.getattr(root).await?;
+        ledger.transition(None, Some(Charge::of(&attr)), None)?;
+        let mut seen = HashSet::from([root]);
+        let mut dirs = vec![root];
+        while let Some(dirid) = dirs.pop() {
+            let mut start_after = 0;
+            loop {
+                let res = self.inner.readdir(dirid, start_after, SCAN_BATCH).await?;
+                for entry in &res.entries {
+                    start_after = entry.fileid;
+                    // hard links are charged once
+                    if !seen.insert(entry.fileid) {
+                        continue;
+                    }
+                    ledger.transition(None, Some(Charge::of(&entry.attr)), None)?;
+                    if matches!(entry.attr.ftype, nfs3::ftype3::NF3DIR) {
+                        dirs.push(entry.fileid);
+                    }
+                }
+                if res.end || res.entries.is_empty() {
+                    break;
+                }
+            }
+        }
+        *self.ledger.lock().unwrap() = ledger;
+        if let Err(e) = self.save().await {
+            warn!("Failed to save quota usage: {:?}", e);
+        }
+        Ok(())
+    }
+
+    /// Saves the usage to [`QuotaOptions::usage_file`], if it is set
+    pub async fn save(&self) -> std::io::Result<()> {
+        let Some(path) = self.options.usage_file.clone() else {
+            return Ok(());
+        };
+        let _guard = self.save_lock.lock().await;
+        let contents = {
+            let mut ledger = self.ledger.lock().unwrap();
+            ledger.dirty = false;
+            ledger.saved_at = Instant::now();
+            ledger.serialize()
+        };
+        let result = tokio::task::spawn_blocking(move || write_usage(&path, &contents))
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        if result.is_err() {
+            self.ledger.lock().unwrap().dirty = true;
+        }
+        result
+    }
+
+    /// Saves the usage if it changed since it was last saved
+    ///
+    /// Call this before dropping the wrapper, so that the usage does not have to
+    /// be rebuilt when the server starts again.
+    pub async fn flush(&self) -> std::io::Result<()> {
+        if self.ledger.lock().unwrap().dirty {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Saves the usage if it changed and the save interval has passed
+    async fn save_if_due(&self) {
+        let due = {
+            let ledger = self.ledger.lock().unwrap();
+            ledger.dirty && ledger.saved_at.elapsed() >= self.options.save_interval
+        };
+        if due {
+            if let Err(e) = self.save().await {
+                warn!("Failed to save quota usage: {:?}", e);
+            }
+        }
+    }
+
+    /// Reserves the resources for changing the charge of an object from `from` to `to`
+    fn reserve(&self, from: Option<Charge>, to: Option<Charge>) -> Result<(), nfs3::nfsstat3> {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.transition(from, to, Some(&self.options))
+    }
+
+    /// Replaces a reserved charge by the actual one, without checking the limits
+    async fn settle(&self, reserved: Option<Charge>, actual: Option<Charge>) {
+        let result = self
+            .ledger
+            .lock()
+            .unwrap()
+            .transition(reserved, actual, None);
+        debug_assert!(result.is_ok());
+        self.save_if_due().await;
+    }
+
+    /// Returns the charge of an object that is about to be created
+    ///
+    /// The owner and group are taken from the initial attributes if they are
+    /// set, and from the credentials of the client otherwise.
+    fn new_charge(&self, attr: &nfs3::sattr3) -> Charge {
+        let caller = vfs::caller();
+        let uid = match attr.uid {
+            nfs3::set_uid3::uid(uid) => Some(uid),
+            _ => caller.as_ref().map(|auth| auth.uid),
+        };
+        let gid = match attr.gid {
+            nfs3::set_gid3::gid(gid) => Some(gid),
+            _ => caller.as_ref().map(|auth| auth.gid),
+        };
+        let bytes = match attr.size {
+            nfs3::set_size3::size(size) => size,
+            _ => 0,
+        };
+        Charge { uid, gid, bytes }
+    }
+
+    /// Returns the lock of a file
+    fn lock(&self, id: nfs3::fileid3) -> FileLock {
+        self.locks.lock().unwrap().entry(id).or_default().clone()
+    }
+
+    /// Drops the lock of a file if it is not in use
+    ///
+    /// Must be called without holding a reference to the lock.
+    fn unlock(&self, id: nfs3::fileid3) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.get(&id).is_some_and(|l| Arc::strong_count(l) == 1) {
+            locks.remove(&id);
+        }
+    }
+
+    /// Runs an operation while holding the lock of a file, if a file is given
+    ///
+    /// Operations that read the charge of a file before changing it hold its lock
+    /// until the change is settled, so that concurrent changes do not start from
+    /// the same stale attributes.
+    async fn locked<T>(&self, id: Option<nfs3::fileid3>, op: impl Future<Output = T>) -> T {
+        let Some(id) = id else {
+            return op.await;
+        };
+        let lock = self.lock(id);
+        let result = {
+            let _guard = lock.lock().await;
+            op.await
+        };
+        drop(lock);
+        self.unlock(id);
+        result
+    }
+
+    /// Returns the file ID and attributes of an object, if it exists
+    async fn attributes(&self, id: Option<nfs3::fileid3>) -> Option<(nfs3::fileid3, nfs3::fattr3)> {
+        let id = id?;
+        let attr = self.inner.getattr(id).await.ok()?;
+        Some((id, attr))
+    }
+
+    /// Changes the attributes of a file, charging a change of its size, owner or group
+    ///
+    /// Must be called with the lock of the file held.
+    async fn charged_setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        let before = match self.inner.getattr(id).await {
+            Ok(attr) => attr,
+            Err(_) => return self.inner.setattr_wcc(id, setattr, guard).await,
+        };
+        let mut predicted = before;
+        if let nfs3::set_size3::size(size) = setattr.size {
+            predicted.size = size;
+        }
+        if let nfs3::set_uid3::uid(uid) = setattr.uid {
+            predicted.uid = uid;
+        }
+        if let nfs3::set_gid3::gid(gid) = setattr.gid {
+            predicted.gid = gid;
+        }
+        let from = Some(Charge::of(&before));
+        let to = Some(Charge::of(&predicted));
+        if let Err(stat) = self.reserve(from, to) {
+            return WccResult {
+                result: Err(stat),
+                wcc: unchanged(before),
+            };
+        }
+        let res = self.inner.setattr_wcc(id, setattr, guard).await;
+        let actual = match &res.result {
+            Ok(attr) => Some(Charge::of(attr)),
+            Err(_) => from,
+        };
+        self.settle(to, actual).await;
+        res
+    }
+
+    /// Writes to a file, charging the growth of its size
+    ///
+    /// Must be called with the lock of the file held.
+    async fn charged_write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let before = match self.inner.getattr(id).await {
+            Ok(attr) => attr,
+            // let the wrapped file system report the error
+            Err(_) => return self.inner.write_wcc(id, offset, data, stable).await,
+        };
+        let predicted = nfs3::fattr3 {
+            size: before.size.max(offset.saturating_add(data.len() as u64)),
+            ..before
+        };
+        let from = Some(Charge::of(&before));
+        let to = Some(Charge::of(&predicted));
+        if let Err(stat) = self.reserve(from, to) {
+            return WccResult {
+                result: Err(stat),
+                wcc: unchanged(before),
+            };
+        }
+        let res = self.inner.write_wcc(id, offset, data, stable).await;
+        let actual = match &res.result {
+            Ok((attr, _)) => Some(Charge::of(attr)),
+            Err(_) => from,
+        };
+        self.settle(to, actual).await;
+        res
+    }
+
+    /// Charges the removal of a directory entry once the operation has completed
+    ///
+    /// The object is uncharged if it no longer exists, and recharged with its
+    /// current attributes if it is still linked elsewhere.
+    async fn settle_unlinked(&self, entry: Option<(nfs3::fileid3, nfs3::fattr3)>) {
+        if let Some((id, attr)) = entry {
+            let after = self.inner.getattr(id).await.ok();
+            let after = after.filter(|a| a.nlink > 0).map(|a| Charge::of(&a));
+            self.settle(Some(Charge::of(&attr)), after).await;
+        }
+    }
+}
+
+/// Returns the weak cache consistency data of an object that was not changed
+fn unchanged(attr: nfs3::fattr3) -> nfs3::wcc_data {
+    nfs3::wcc_data {
+        before: nfs3::pre_op_attr::attributes(attr.into()),
+        after: nfs3::post_op_attr::attributes(attr),
+    }
+}
+
+#[async_trait]
+impl<F: NFSFileSystem> NFSFileSystem for Quota<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.inner.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.lookup(dirid, filename).await
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.inner.getattr(id).await
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.setattr_wcc(id, setattr, None).await.result
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        self.inner.read(id, offset, count).await
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        self.inner.read_bytes(id, offset, count).await
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        self.inner.read_file_range(id, offset, count).await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.write_wcc(id, offset, data, nfs3::file::stable_how::FILE_SYNC)
+            .await
+            .result
+            .map(|(attr, _)| attr)
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.create_wcc(dirid, filename, attr).await.result
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.create_exclusive_wcc(dirid, filename).await.result
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mkdir_wcc(dirid, dirname).await.result
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.remove_wcc(dirid, filename, vfs::RemoveKind::Any)
+            .await
+            .result
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+            .result
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        self.inner.readdir(dirid, start_after, max_entries).await
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        self.inner
+            .readdir_simple_after(dirid, start_after, count)
+            .await
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        self.inner.readdir_stream(dirid, start_after).await
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        self.inner.getattr_batch(ids).await
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.symlink_wcc(dirid, linkname, symlink, attr)
+            .await
+            .result
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.inner.readlink(id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.link_wcc(file_id, link_dir_id, link_name).await.result
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await
+            .result
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.inner.commit(file_id, offset, count).await
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        let changes_charge = matches!(setattr.size, nfs3::set_size3::size(_))
+            || matches!(setattr.uid, nfs3::set_uid3::uid(_))
+            || matches!(setattr.gid, nfs3::set_gid3::gid(_));
+        if !changes_charge {
+            return self.inner.setattr_wcc(id, setattr, guard).await;
+        }
+        self.locked(Some(id), self.charged_setattr(id, setattr, guard))
+            .await
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        self.locked(Some(id), self.charged_write(id, offset, data, stable))
+            .await
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        // an unchecked create of an existing file only applies the attributes
+        let id = self.inner.lookup(dirid, filename).await.ok();
+        let existing = self.attributes(id).await.map(|(_, attr)| attr);
+        let from = existing.as_ref().map(Charge::of);
+        let to = match existing {
+            Some(existing) => {
+                let mut predicted = existing;
+                if let nfs3::set_size3::size(size) = attr.size {
+                    predicted.size = size;
+                }
+                Charge::of(&predicted)
+            }
+            None => self.new_charge(&attr),
+        };
+        if let Err(stat) = self.reserve(from, Some(to)) {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        let res = self.inner.create_wcc(dirid, filename, attr).await;
+        let actual = match &res.result {
+            Ok((_, attr)) => Some(Charge::of(attr)),
+            Err(_) => from,
+        };
+        self.settle(Some(to), actual).await;
+        res
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        let to = self.new_charge(&nfs3::sattr3::default());
+        if let Err(stat) = self.reserve(None, Some(to)) {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        let res = self.inner.create_exclusive_wcc(dirid, filename).await;
+        let actual = match &res.result {
+            Ok(id) => self.inner.getattr(*id).await.ok().map(|a| Charge::of(&a)),
+            Err(_) => None,
+        };
+        self.settle(Some(to), actual).await;
+        res
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let to = self.new_charge(&nfs3::sattr3::default());
+        if let Err(stat) = self.reserve(None, Some(to)) {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        let res = self.inner.mkdir_wcc(dirid, dirname).await;
+        let actual = res.result.as_ref().ok().map(|(_, attr)| Charge::of(attr));
+        self.settle(Some(to), actual).await;
+        res
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        let id = self.inner.lookup(dirid, filename).await.ok();
+        self.locked(id, async {
+            let entry = self.attributes(id).await;
+            let res = self.inner.remove_wcc(dirid, filename, kind).await;
+            if res.result.is_ok() {
+                self.settle_unlinked(entry).await;
+            }
+            res
+        })
+        .await
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        // the rename may replace an existing target
+        let id = self.inner.lookup(to_dirid, to_filename).await.ok();
+        self.locked(id, async {
+            let target = self.attributes(id).await;
+            let res = self
+                .inner
+                .rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+                .await;
+            if res.result.is_ok() {
+                self.settle_unlinked(target).await;
+            }
+            res
+        })
+        .await
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let to = Charge {
+            bytes: 0,
+            ..self.new_charge(attr)
+        };
+        if let Err(stat) = self.reserve(None, Some(to)) {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        let res = self.inner.symlink_wcc(dirid, linkname, symlink, attr).await;
+        let actual = res.result.as_ref().ok().map(|(_, attr)| Charge::of(attr));
+        self.settle(Some(to), actual).await;
+        res
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        self.inner.link_wcc(file_id, link_dir_id, link_name).await
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let to = Charge {
+            bytes: 0,
+            ..self.new_charge(attrs)
+        };
+        if let Err(stat) = self.reserve(None, Some(to)) {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        let res = self
+            .inner
+            .mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await;
+        let actual = res.result.as_ref().ok().map(|(_, attr)| Charge::of(attr));
+        self.settle(Some(to), actual).await;
+        res
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        self.inner.commit_wcc(file_id, offset, count).await
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        self.inner.fsinfo(root_fileid).await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        let mut res = self.inner.fsstat(id).await?;
+        let ledger = self.ledger.lock().unwrap();
+        let export = self.options.export;
+        if let Some(limit) = export.bytes {
+            let left = limit.saturating_sub(ledger.total.bytes);
+            res.tbytes = res.tbytes.min(limit);
+            res.fbytes = res.fbytes.min(left);
+            res.abytes = res.abytes.min(left);
+        }
+        if let Some(limit) = export.inodes {
+            let left = limit.saturating_sub(ledger.total.inodes);
+            res.tfiles = res.tfiles.min(limit);
+            res.ffiles = res.ffiles.min(left);
+            res.afiles = res.afiles.min(left);
+        }
+        if let Some(caller) = vfs::caller() {
+            let user = ledger.users.get(&caller.uid).copied().unwrap_or_default();
+            let group = ledger.groups.get(&caller.gid).copied().unwrap_or_default();
+            let limits = [
+                (user, self.options.user_limit(caller.uid)),
+                (group, self.options.group_limit(caller.gid)),
+            ];
+            for (usage, limit) in limits {
+                if let Some(limit) = limit.bytes {
+                    res.abytes = res.abytes.min(limit.saturating_sub(usage.bytes));
+                }
+                if let Some(limit) = limit.inodes {
+                    res.afiles = res.afiles.min(limit.saturating_sub(usage.inodes));
+                }
+            }
+        }
+        // the values change with every write
+        res.invarsec = 0;
+        Ok(res)
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.path_to_id(path).await
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.inner.serverid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::fault::{Fault, FaultRule, Faulty, Operation};
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    fn limited(bytes: u64) -> QuotaOptions {
+        QuotaOptions {
+            export: Limit {
+                bytes: Some(bytes),
+                inodes: None,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_are_charged_once() {
+        let faulty = Faulty::new(MemFS::new());
+        faulty.control().add_rule(FaultRule {
+            ops: vec![Operation::Write],
+            ..FaultRule::new(Fault::Delay(Duration::from_millis(10)))
+        });
+        let fs = Quota::new(faulty, limited(4096)).await.unwrap();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        let writes = (0..8u64)
+            .map(|i| fs.write_wcc(file, i * 512, &[1; 512], nfs3::file::stable_how::UNSTABLE));
+        for res in futures::future::join_all(writes).await {
+            res.result.unwrap();
+        }
+        assert_eq!(fs.usage().bytes, 4096);
+        // full
+        let res = fs
+            .write_wcc(file, 4096, &[1], nfs3::file::stable_how::UNSTABLE)
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_DQUOT)));
+        // overwriting needs no space
+        let res = fs
+            .write_wcc(file, 0, &[2; 4096], nfs3::file::stable_how::UNSTABLE)
+            .await;
+        assert!(res.result.is_ok());
+        assert_eq!(fs.usage().bytes, 4096);
+        assert!(fs.locks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn hard_links_are_charged_once() {
+        let fs = Quota::new(MemFS::new(), QuotaOptions::default())
+            .await
+            .unwrap();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("a"), Default::default())
+            .await
+            .unwrap();
+        fs.write(file, 0, &[0; 100]).await.unwrap();
+        fs.link(file, root, &name("b")).await.unwrap();
+        let usage = fs.usage();
+        assert_eq!((usage.bytes, usage.inodes), (100, 2));
+
+        fs.remove(root, &name("a")).await.unwrap();
+        assert_eq!(fs.usage().bytes, 100);
+        fs.remove(root, &name("b")).await.unwrap();
+        let usage = fs.usage();
+        assert_eq!((usage.bytes, usage.inodes), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn growing_past_the_limit_fails() {
+        let fs = Quota::new(MemFS::new(), limited(1000)).await.unwrap();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        let grow = nfs3::sattr3 {
+            size: nfs3::set_size3::size(1001),
+            ..Default::default()
+        };
+        let res = fs.setattr_wcc(file, grow, None).await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_DQUOT)));
+        let res = fs
+            .write_wcc(
+                file,
+                u64::MAX - 1,
+                b"data",
+                nfs3::file::stable_how::FILE_SYNC,
+            )
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_DQUOT)));
+        assert_eq!(fs.usage().bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn flushed_usage_is_loaded_again() {
+        let path = std::env::temp_dir().join(format!("nfsserve-quota-{}", std::process::id()));
+        let options = QuotaOptions {
+            usage_file: Some(path.clone()),
+            save_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let fs = Quota::new(MemFS::new(), options.clone()).await.unwrap();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        fs.write(file, 0, &[0; 10]).await.unwrap();
+        fs.flush().await.unwrap();
+        drop(fs);
+
+        // an empty file system, so the usage can only come from the file
+        let fs = Quota::new(MemFS::new(), options).await.unwrap();
+        assert_eq!(fs.usage().bytes, 10);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,890 @@
+//! Block cache with read-ahead for file systems with expensive reads.
+//!
+//! Clients read files in chunks of the preferred read size, and for backends such
+//! as object stores every READ becomes a separate ranged request. [`ReadAhead`]
+//! wraps an [`NFSFileSystem`] and reads files in fixed-size blocks instead:
+//! - Blocks are kept in a least-recently-used cache bounded by its total size
+//! - Sequential reads of a file are detected, and the blocks following the read
+//!   position are fetched in the background before the client asks for them
+//! - Concurrent requests for the same block share a single backend read
+//!
+//! The cached blocks of a file are dropped when it is written through the wrapper,
+//! when its size is changed with SETATTR, when it is removed or replaced by a rename,
+//! and when `getattr` reports a modification time or size that differs from the one
+//! seen when the blocks were cached. Files created through the wrapper start without
+//! cached blocks, even if the backend reuses the file ID of a removed file.
+//!
+//! Reads are passed through unchanged for backends that serve
+//! [`NFSFileSystem::read_file_range`], since their data is local already.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::future::{BoxFuture, FutureExt, Shared};
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// Options of a [`ReadAhead`] file system
+#[derive(Debug, Clone)]
+pub struct ReadAheadOptions {
+    /// Size of the blocks files are read and cached in
+    pub block_size: u32,
+    /// Maximum total size of the cached blocks in bytes
+    pub cache_size: usize,
+    /// Number of blocks to fetch ahead of a sequential reader
+    pub window: u32,
+}
+
+impl Default for ReadAheadOptions {
+    fn default() -> Self {
+        ReadAheadOptions {
+            block_size: 256 * 1024,
+            cache_size: 64 * 1024 * 1024,
+            window: 8,
+        }
+    }
+}
+
+/// Number of files whose read state is kept while they have no cached blocks
+const MAX_IDLE_FILES: usize = 1024;
+
+/// A block of a file as (file ID, block number)
+type BlockKey = (nfs3::fileid3, u64);
+
+/// Outcome of reading a block from the backend: the data and whether it ends the file
+type BlockData = Result<(Bytes, bool), nfs3::nfsstat3>;
+
+/// A backend read of a block that can be awaited by several readers
+type Fetch = Shared<BoxFuture<'static, BlockData>>;
+
+/// A cached block
+#[derive(Debug)]
+struct Block {
+    /// Data of the block; shorter than the block size only at the end of the file
+    data: Bytes,
+    /// Whether the block ends the file
+    eof: bool,
+    /// Position of the block in the LRU order
+    stamp: u64,
+}
+
+/// What is known about a file that has been read through the cache
+#[derive(Debug, Default)]
+struct FileState {
+    /// Modification time and size when the cached blocks were read
+    version: Option<(nfs3::nfstime3, nfs3::size3)>,
+    /// Changes whenever the cached blocks are dropped, so that reads started
+    /// before do not cache outdated data
+    generation: u64,
+    /// Offset a sequential reader would read next
+    next_offset: u64,
+    /// Number of sequential reads in a row
+    sequential: u32,
+    /// Numbers of the cached blocks
+    blocks: BTreeSet<u64>,
+}
+
+/// The cached blocks and per-file state
+#[derive(Default)]
+struct Cache {
+    /// Cached blocks
+    blocks: HashMap<BlockKey, Block>,
+    /// Cached blocks by LRU stamp, least recently used first
+    lru: BTreeMap<u64, BlockKey>,
+    /// Files with cached blocks or ongoing reads, and a bounded number of other
+    /// recently read files
+    files: HashMap<nfs3::fileid3, FileState>,
+    /// Last generation given to a file
+    generation: u64,
+    /// Backend reads in progress
+    fetching: HashMap<BlockKey, Fetch>,
+    /// Total size of the cached blocks
+    bytes: usize,
+    /// Next LRU stamp
+    clock: u64,
+}
+
+impl Cache {
+    /// Returns the state of a file, creating it with a new generation if needed
+    fn file(&mut self, id: nfs3::fileid3) -> &mut FileState {
+        if !self.files.contains_key(&id) {
+            if self.files.len() >= MAX_IDLE_FILES {
+                self.drop_idle_files();
+            }
+            self.generation += 1;
+            let file = FileState {
+                generation: self.generation,
+                ..Default::default()
+            };
+            self.files.insert(id, file);
+        }
+        self.files.get_mut(&id).unwrap()
+    }
+
+    /// Forgets the files without cached blocks or ongoing reads
+    fn drop_idle_files(&mut self) {
+        let fetching: HashSet<_> = self.fetching.keys().map(|key| key.0).collect();
+        self.files
+            .retain(|id, file| !file.blocks.is_empty() || fetching.contains(id));
+    }
+
+    /// Returns a cached block, marking it as recently used
+    fn get(&mut self, key: BlockKey) -> Option<(Bytes, bool)> {
+        let block = self.blocks.get_mut(&key)?;
+        self.lru.remove(&block.stamp);
+        block.stamp = self.clock;
+        self.lru.insert(self.clock, key);
+        self.clock += 1;
+        Some((block.data.clone(), block.eof))
+    }
+
+    /// Caches a block, evicting the least recently used ones to stay within `capacity`
+    fn insert(&mut self, key: BlockKey, data: Bytes, eof: bool, capacity: usize) {
+        self.remove(key);
+        self.bytes += data.len();
+        self.blocks.insert(
+            key,
+            Block {
+                data,
+                eof,
+                stamp: self.clock,
+            },
+        );
+        self.lru.insert(self.clock, key);
+        self.clock += 1;
+        self.file(key.0).blocks.insert(key.1);
+        while self.bytes > capacity {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break;
+            };
+            self.remove(key);
+        }
+    }
+
+    /// Drops a cached block
+    fn remove(&mut self, key: BlockKey) {
+        let Some(block) = self.blocks.remove(&key) else {
+            return;
+        };
+        self.lru.remove(&block.stamp);
+        self.bytes -= block.data.len();
+        if let Some(file) = self.files.get_mut(&key.0) {
+            file.blocks.remove(&key.1);
+            if file.blocks.is_empty() && !self.fetching.keys().any(|k| k.0 == key.0) {
+                self.files.remove(&key.0);
+            }
+        }
+    }
+
+    /// Drops the cached blocks of a file and forgets the file and its ongoing reads
+    ///
+    /// Reads still in progress see a different generation when they complete and
+    /// do not cache their data.
+    fn invalidate(&mut self, id: nfs3::fileid3) {
+        let Some(file) = self.files.remove(&id) else {
+            return;
+        };
+        for index in file.blocks {
+            if let Some(block) = self.blocks.remove(&(id, index)) {
+                self.lru.remove(&block.stamp);
+                self.bytes -= block.data.len();
+            }
+        }
+        self.fetching.retain(|key, _| key.0 != id);
+    }
+
+    /// Checks the cached blocks of a file against its current attributes,
+    /// dropping them if the file has changed
+    fn check_version(&mut self, attr: &nfs3::fattr3) {
+        let Some(file) = self.files.get_mut(&attr.fileid) else {
+            return;
+        };
+        let current = (attr.mtime, attr.size);
+        match file.version {
+            Some((mtime, size))
+                if mtime.seconds == attr.mtime.seconds
+                    && mtime.nseconds == attr.mtime.nseconds
+                    && size == attr.size => {}
+            Some(_) => {
+                self.invalidate(attr.fileid);
+                self.file(attr.fileid).version = Some(current);
+            }
+            None => file.version = Some(current),
+        }
+    }
+}
+
+/// State shared with the background reads
+struct State<F> {
+    /// The wrapped file system
+    inner: F,
+    /// Cache settings
+    options: ReadAheadOptions,
+    /// The cached blocks and per-file state
+    cache: Mutex<Cache>,
+}
+
+/// Block cache with read-ahead around a file system
+///
+/// See the [module documentation](self) for details.
+pub struct ReadAhead<F> {
+    /// State shared with the background reads
+    state: Arc<State<F>>,
+}
+
+impl<F: NFSFileSystem + Send + 'static> ReadAhead<F> {
+    /// Creates a read-ahead wrapper with default options
+    pub fn new(inner: F) -> Self {
+        Self::with_options(inner, ReadAheadOptions::default())
+    }
+
+    /// Creates a read-ahead wrapper
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The file system to read from
+    /// * `options` - Cache settings
+    pub fn with_options(inner: F, options: ReadAheadOptions) -> Self {
+        assert!(options.block_size > 0, "block size must not be zero");
+        ReadAhead {
+            state: Arc::new(State {
+                inner,
+                options,
+                cache: Mutex::new(Cache::default()),
+            }),
+        }
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.state.inner
+    }
+
+    /// Drops the cached blocks of a file, for changes made outside the server
+    pub fn invalidate(&self, id: nfs3::fileid3) {
+        self.state.cache.lock().unwrap().invalidate(id);
+    }
+
+    /// Returns the file a name refers to before it is removed or replaced, so
+    /// its blocks can be dropped afterwards
+    ///
+    /// Nothing is looked up while no file is cached.
+    async fn replaced(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &nfs3::filename3,
+    ) -> Option<nfs3::fileid3> {
+        if self.state.cache.lock().unwrap().files.is_empty() {
+            return None;
+        }
+        self.state.inner.lookup(dirid, name).await.ok()
+    }
+
+    /// Drops the cached blocks of a file that was removed or replaced
+    fn note_gone(&self, id: Option<nfs3::fileid3>) {
+        if let Some(id) = id {
+            self.invalidate(id);
+        }
+    }
+
+    /// Returns the cached block or an ongoing or new backend read of it
+    fn block(&self, key: BlockKey) -> Result<(Bytes, bool), Fetch> {
+        let mut cache = self.state.cache.lock().unwrap();
+        if let Some(block) = cache.get(key) {
+            return Ok(block);
+        }
+        Err(self.fetch(&mut cache, key))
+    }
+
+    /// Returns the ongoing backend read of a block, starting one if there is none
+    fn fetch(&self, cache: &mut Cache, key: BlockKey) -> Fetch {
+        if let Some(fetch) = cache.fetching.get(&key) {
+            return fetch.clone();
+        }
+        let generation = cache.file(key.0).generation;
+        let state = self.state.clone();
+        let fetch = async move {
+            let block_size = state.options.block_size;
+            let offset = key.1 * block_size as u64;
+            let result = state.inner.read_bytes(key.0, offset, block_size).await;
+            let mut cache = state.cache.lock().unwrap();
+            let current = cache.files.get(&key.0).map(|f| f.generation);
+            // the file was invalidated while reading, so the data may be outdated
+            if current == Some(generation) {
+                cache.fetching.remove(&key);
+                if let Ok((data, eof)) = &result {
+                    // short reads are returned but not cached, and neither are
+                    // empty blocks, which would not count towards the cache size
+                    if !data.is_empty() && (*eof || data.len() == block_size as usize) {
+                        cache.insert(key, data.clone(), *eof, state.options.cache_size);
+                    }
+                }
+            }
+            result
+        }
+        .boxed()
+        .shared();
+        cache.fetching.insert(key, fetch.clone());
+        fetch
+    }
+
+    /// Records a read of a file and starts fetching the blocks following it if
+    /// the file is read sequentially
+    fn read_ahead(&self, id: nfs3::fileid3, offset: u64, len: u64, eof: bool) {
+        let block_size = self.state.options.block_size as u64;
+        let mut cache = self.state.cache.lock().unwrap();
+        let file = cache.file(id);
+        if offset > 0 && offset == file.next_offset {
+            file.sequential += 1;
+        } else {
+            file.sequential = 0;
+        }
+        file.next_offset = offset + len;
+        if file.sequential == 0 || eof {
+            return;
+        }
+        let end = file.version.map(|(_, size)| size).unwrap_or(u64::MAX);
+        let first = (offset + len).div_ceil(block_size);
+        for index in first..first + self.state.options.window as u64 {
+            if index * block_size >= end {
+                break;
+            }
+            let key = (id, index);
+            if cache.blocks.contains_key(&key) || cache.fetching.contains_key(&key) {
+                continue;
+            }
+            let fetch = self.fetch(&mut cache, key);
+            tokio::spawn(fetch);
+        }
+    }
+
+    /// Caches the attributes returned by an operation that changed the data of a file
+    fn note_changed(&self, id: nfs3::fileid3, attr: Option<&nfs3::fattr3>) {
+        let mut cache = self.state.cache.lock().unwrap();
+        let known = cache.files.contains_key(&id);
+        cache.invalidate(id);
+        if let (Some(attr), true) = (attr, known) {
+            cache.file(id).version = Some((attr.mtime, attr.size));
+        }
+    }
+}
+
+#[async_trait]
+impl<F: NFSFileSystem + Send + 'static> NFSFileSystem for ReadAhead<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.state.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.state.inner.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.state.inner.lookup(dirid, filename).await
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let attr = self.state.inner.getattr(id).await?;
+        self.state.cache.lock().unwrap().check_version(&attr);
+        Ok(attr)
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let result = self.state.inner.setattr(id, setattr).await;
+        if let nfs3::set_size3::size(_) = setattr.size {
+            self.note_changed(id, result.as_ref().ok());
+        }
+        result
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let (data, eof) = self.read_bytes(id, offset, count).await?;
+        Ok((data.to_vec(), eof))
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        if count == 0 {
+            return self.state.inner.read_bytes(id, offset, count).await;
+        }
+        let known = self.state.cache.lock().unwrap().file(id).version.is_some();
+        // blocks are only cached together with the version of the file they belong to
+        if !known {
+            self.getattr(id).await?;
+        }
+        let block_size = self.state.options.block_size as u64;
+        // parts of the blocks covering the request, only copied if there are several
+        let mut parts: Vec<Bytes> = Vec::new();
+        let mut len = 0;
+        let mut eof = false;
+        let mut pos = offset;
+        while len < count as usize {
+            let index = pos / block_size;
+            let (data, block_eof) = match self.block((id, index)) {
+                Ok(block) => block,
+                Err(fetch) => fetch.await?,
+            };
+            let start = (pos - index * block_size) as usize;
+            if start >= data.len() {
+                eof = block_eof;
+                break;
+            }
+            let end = data.len().min(start + count as usize - len);
+            parts.push(data.slice(start..end));
+            len += end - start;
+            pos += (end - start) as u64;
+            eof = block_eof && end == data.len();
+            if end == data.len() && data.len() < block_size as usize {
+                break;
+            }
+        }
+        self.read_ahead(id, offset, len as u64, eof);
+        let data = match parts.len() {
+            0 => Bytes::new(),
+            1 => parts.pop().unwrap(),
+            _ => {
+                let mut out = BytesMut::with_capacity(len);
+                for part in parts {
+                    out.extend_from_slice(&part);
+                }
+                out.freeze()
+            }
+        };
+        Ok((data, eof))
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        self.state.inner.read_file_range(id, offset, count).await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let result = self.state.inner.write(id, offset, data).await;
+        self.note_changed(id, result.as_ref().ok());
+        result
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let result = self.state.inner.create(dirid, filename, attr).await;
+        // an unchecked create may truncate an existing file
+        if let Ok((id, attr)) = &result {
+            self.note_changed(*id, Some(attr));
+        }
+        result
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let result = self.state.inner.create_exclusive(dirid, filename).await;
+        // the backend may have reused the file ID of a removed file
+        self.note_gone(result.as_ref().ok().copied());
+        result
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.state.inner.mkdir(dirid, dirname).await
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let removed = self.replaced(dirid, filename).await;
+        let result = self.state.inner.remove(dirid, filename).await;
+        self.note_gone(removed);
+        result
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let replaced = self.replaced(to_dirid, to_filename).await;
+        let result = self
+            .state
+            .inner
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await;
+        self.note_gone(replaced);
+        result
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        self.state
+            .inner
+            .readdir(dirid, start_after, max_entries)
+            .await
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        self.state
+            .inner
+            .readdir_simple_after(dirid, start_after, count)
+            .await
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        self.state.inner.readdir_stream(dirid, start_after).await
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let results = self.state.inner.getattr_batch(ids).await;
+        let mut cache = self.state.cache.lock().unwrap();
+        for attr in results.iter().flatten() {
+            cache.check_version(attr);
+        }
+        results
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.state
+            .inner
+            .symlink(dirid, linkname, symlink, attr)
+            .await
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.state.inner.readlink(id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.state.inner.link(file_id, link_dir_id, link_name).await
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.state
+            .inner
+            .mknod(dir_id, name, ftype, specdata, attrs)
+            .await
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.state.inner.commit(file_id, offset, count).await
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        let res = self.state.inner.setattr_wcc(id, setattr, guard).await;
+        if let nfs3::set_size3::size(_) = setattr.size {
+            self.note_changed(id, res.result.as_ref().ok());
+        }
+        res
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let res = self.state.inner.write_wcc(id, offset, data, stable).await;
+        self.note_changed(id, res.result.as_ref().ok().map(|(attr, _)| attr));
+        res
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let res = self.state.inner.create_wcc(dirid, filename, attr).await;
+        // an unchecked create may truncate an existing file
+        if let Ok((id, attr)) = &res.result {
+            self.note_changed(*id, Some(attr));
+        }
+        res
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        let res = self.state.inner.create_exclusive_wcc(dirid, filename).await;
+        // the backend may have reused the file ID of a removed file
+        self.note_gone(res.result.as_ref().ok().copied());
+        res
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.state.inner.mkdir_wcc(dirid, dirname).await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        let removed = self.replaced(dirid, filename).await;
+        let res = self.state.inner.remove_wcc(dirid, filename, kind).await;
+        self.note_gone(removed);
+        res
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        let replaced = self.replaced(to_dirid, to_filename).await;
+        let res = self
+            .state
+            .inner
+            .rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await;
+        self.note_gone(replaced);
+        res
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.state
+            .inner
+            .symlink_wcc(dirid, linkname, symlink, attr)
+            .await
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        self.state
+            .inner
+            .link_wcc(file_id, link_dir_id, link_name)
+            .await
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.state
+            .inner
+            .mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        self.state.inner.commit_wcc(file_id, offset, count).await
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        self.state.inner.fsinfo(root_fileid).await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        self.state.inner.fsstat(id).await
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.state.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.state.inner.fh_to_id(id)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.state.inner.path_to_id(path).await
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.state.inner.serverid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    fn options() -> ReadAheadOptions {
+        ReadAheadOptions {
+            block_size: 4096,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_within_a_block_share_its_data() {
+        let fs = ReadAhead::with_options(MemFS::new(), options());
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        fs.write(file, 0, &[7; 10000]).await.unwrap();
+
+        let (first, _) = fs.read_bytes(file, 100, 1000).await.unwrap();
+        let (second, _) = fs.read_bytes(file, 100, 1000).await.unwrap();
+        assert_eq!(first.len(), 1000);
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        // spanning two blocks
+        let (data, eof) = fs.read_bytes(file, 4000, 5000).await.unwrap();
+        assert_eq!(data.len(), 5000);
+        assert!(!eof);
+        let (data, eof) = fs.read_bytes(file, 9000, 5000).await.unwrap();
+        assert_eq!(data.len(), 1000);
+        assert!(eof);
+    }
+
+    #[tokio::test]
+    async fn removed_and_replaced_files_are_forgotten() {
+        let fs = ReadAhead::with_options(MemFS::new(), options());
+        let root = fs.root_dir();
+        let mut ids = Vec::new();
+        for file in ["a", "b", "c"] {
+            let (id, _) = fs
+                .create(root, &name(file), Default::default())
+                .await
+                .unwrap();
+            fs.write(id, 0, file.as_bytes()).await.unwrap();
+            fs.read_bytes(id, 0, 100).await.unwrap();
+            ids.push(id);
+        }
+        fs.remove(root, &name("a")).await.unwrap();
+        fs.rename(root, &name("b"), root, &name("c")).await.unwrap();
+
+        let cache = fs.state.cache.lock().unwrap();
+        assert!(!cache.files.contains_key(&ids[0]));
+        assert!(!cache.files.contains_key(&ids[2]));
+        assert!(cache.files.contains_key(&ids[1]));
+        assert_eq!(cache.blocks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn files_without_cached_blocks_are_not_kept() {
+        let fs = ReadAhead::with_options(MemFS::new(), options());
+        let root = fs.root_dir();
+        for i in 0..MAX_IDLE_FILES + 100 {
+            let (id, _) = fs
+                .create(root, &name(&i.to_string()), Default::default())
+                .await
+                .unwrap();
+            let (data, eof) = fs.read_bytes(id, 0, 100).await.unwrap();
+            assert!(data.is_empty() && eof);
+        }
+        assert!(fs.state.cache.lock().unwrap().files.len() <= MAX_IDLE_FILES);
+    }
+}
@@ -0,0 +1,654 @@
+//! Export of a directory of another file system as its root.
+//!
+//! [`Subtree`] wraps an [`NFSFileSystem`] and presents one of its directories as
+//! the root of the export, similar to `chroot`:
+//! - `root_dir` returns the subtree root, so MOUNT path resolution starts there
+//! - Looking up ".." in the subtree root returns the subtree root itself
+//! - Only objects inside the subtree can be reached; operations on any other object
+//!   fail with NFS3ERR_STALE, even if the client forges its file handle
+//!
+//! File handles are the handles of the wrapped file system prefixed with the file ID
+//! of the subtree root, which tells apart handles of different exports. File IDs are
+//! passed through unchanged.
+//!
+//! Every operation checks that the objects it is given are inside the subtree. The
+//! file IDs of the most recently handed out handles are remembered and accepted
+//! directly. Other objects are accepted if looking up ".." from them repeatedly leads
+//! to the subtree root. This way handles of directories stay valid across restarts
+//! whenever the handles of the wrapped file system do. Handles of other objects that
+//! are no longer remembered, for example after a restart, fail with NFS3ERR_STALE until
+//! the client looks the object up again, unless the wrapped file system supports
+//! looking up ".." from them. An object moved out of the subtree through another export
+//! of the same backend stays reachable while its file ID is remembered.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::debug;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// Number of file IDs remembered as being inside the subtree
+const KNOWN_IDS: usize = 1 << 18;
+
+/// Maximum number of ".." lookups made to check that an object is inside the subtree
+const MAX_DEPTH: usize = 4096;
+
+/// File IDs recently handed out or found to be inside the subtree
+///
+/// The IDs are kept in two generations; when the current one is full, the previous
+/// one is dropped. IDs found in the previous generation move to the current one.
+#[derive(Debug, Default)]
+struct KnownIds {
+    /// IDs added or used since the last rotation
+    current: HashSet<nfs3::fileid3>,
+    /// IDs added or used before the last rotation
+    previous: HashSet<nfs3::fileid3>,
+}
+
+impl KnownIds {
+    /// Checks if an ID is remembered, marking it as recently used
+    fn contains(&mut self, id: nfs3::fileid3) -> bool {
+        if self.current.contains(&id) {
+            return true;
+        }
+        if self.previous.remove(&id) {
+            self.insert(id);
+            return true;
+        }
+        false
+    }
+
+    /// Remembers an ID
+    fn insert(&mut self, id: nfs3::fileid3) {
+        if self.current.len() >= KNOWN_IDS / 2 {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.current.insert(id);
+    }
+}
+
+/// A directory of a file system exported as the root
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Subtree<F> {
+    /// The wrapped file system
+    inner: F,
+    /// File ID of the subtree root in the wrapped file system
+    root: nfs3::fileid3,
+    /// File IDs recently handed out or found to be inside the subtree
+    known: Mutex<KnownIds>,
+}
+
+impl<F: NFSFileSystem> Subtree<F> {
+    /// Creates a subtree view rooted at the given directory
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The file system to export a part of
+    /// * `root` - File ID of the directory to use as the root
+    pub fn new(inner: F, root: nfs3::fileid3) -> Self {
+        Subtree {
+            inner,
+            root,
+            known: Mutex::new(KnownIds::default()),
+        }
+    }
+
+    /// Creates a subtree view rooted at the directory with the given path
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The file system to export a part of
+    /// * `path` - Path of the directory to use as the root, relative to the root of `inner`
+    pub async fn from_path(inner: F, path: &[u8]) -> Result<Self, nfs3::nfsstat3> {
+        let root = inner.path_to_id(path).await?;
+        let attr = inner.getattr(root).await?;
+        if !matches!(attr.ftype, nfs3::ftype3::NF3DIR) {
+            return Err(nfs3::nfsstat3::NFS3ERR_NOTDIR);
+        }
+        Ok(Self::new(inner, root))
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Returns the wrapped file system
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Checks that objects are inside the subtree
+    ///
+    /// Objects whose file IDs are not remembered are accepted if looking up ".."
+    /// from them leads to the subtree root or to a remembered directory.
+    /// Returns NFS3ERR_STALE for any other object.
+    ///
+    /// # Arguments
+    ///
+    /// * `ids` - File IDs of the objects
+    async fn check(&self, ids: &[nfs3::fileid3]) -> Result<(), nfs3::nfsstat3> {
+        for &id in ids {
+            if id == self.root || self.known.lock().unwrap().contains(id) {
+                continue;
+            }
+            let dotdot: nfs3::filename3 = b"..".as_slice().into();
+            let mut cur = id;
+            let mut inside = false;
+            for _ in 0..MAX_DEPTH {
+                let Ok(parent) = self.inner.lookup(cur, &dotdot).await else {
+                    break;
+                };
+                if parent == self.root || self.known.lock().unwrap().contains(parent) {
+                    inside = true;
+                    break;
+                }
+                if parent == cur {
+                    break;
+                }
+                cur = parent;
+            }
+            if !inside {
+                debug!("File {} is not inside the subtree of {}", id, self.root);
+                return Err(nfs3::nfsstat3::NFS3ERR_STALE);
+            }
+            self.known.lock().unwrap().insert(id);
+        }
+        Ok(())
+    }
+
+    /// Checks that objects are inside the subtree, for operations that return wcc data
+    async fn check_wcc<T>(&self, ids: &[nfs3::fileid3]) -> Result<(), WccResult<T>> {
+        self.check(ids).await.map_err(|stat| WccResult {
+            result: Err(stat),
+            wcc: nfs3::wcc_data::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl<F: NFSFileSystem> NFSFileSystem for Subtree<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.root
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        if dirid == self.root && filename.as_slice() == b".." {
+            return Ok(self.root);
+        }
+        self.inner.lookup(dirid, filename).await
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.check(&[id]).await?;
+        self.inner.getattr(id).await
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.check(&[id]).await?;
+        self.inner.setattr(id, setattr).await
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        self.check(&[id]).await?;
+        self.inner.read(id, offset, count).await
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        self.check(&[id]).await?;
+        self.inner.read_bytes(id, offset, count).await
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        self.check(&[id]).await?;
+        self.inner.read_file_range(id, offset, count).await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.check(&[id]).await?;
+        self.inner.write(id, offset, data).await
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        self.inner.create(dirid, filename, attr).await
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        self.inner.create_exclusive(dirid, filename).await
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        self.inner.mkdir(dirid, dirname).await
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        self.inner.remove(dirid, filename).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.check(&[from_dirid, to_dirid]).await?;
+        self.inner
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        self.inner.readdir(dirid, start_after, max_entries).await
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        self.inner
+            .readdir_simple_after(dirid, start_after, count)
+            .await
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        self.inner.readdir_stream(dirid, start_after).await
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        // called with the entries of a directory that was checked when it was listed
+        self.inner.getattr_batch(ids).await
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.check(&[dirid]).await?;
+        self.inner.symlink(dirid, linkname, symlink, attr).await
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.check(&[id]).await?;
+        self.inner.readlink(id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.check(&[file_id, link_dir_id]).await?;
+        self.inner.link(file_id, link_dir_id, link_name).await
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.check(&[dir_id]).await?;
+        self.inner.mknod(dir_id, name, ftype, specdata, attrs).await
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.check(&[file_id]).await?;
+        self.inner.commit(file_id, offset, count).await
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        if let Err(res) = self.check_wcc(&[id]).await {
+            return res;
+        }
+        self.inner.setattr_wcc(id, setattr, guard).await
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        if let Err(res) = self.check_wcc(&[id]).await {
+            return res;
+        }
+        self.inner.write_wcc(id, offset, data, stable).await
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        if let Err(res) = self.check_wcc(&[dirid]).await {
+            return res;
+        }
+        self.inner.create_wcc(dirid, filename, attr).await
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        if let Err(res) = self.check_wcc(&[dirid]).await {
+            return res;
+        }
+        self.inner.create_exclusive_wcc(dirid, filename).await
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        if let Err(res) = self.check_wcc(&[dirid]).await {
+            return res;
+        }
+        self.inner.mkdir_wcc(dirid, dirname).await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        if let Err(res) = self.check_wcc(&[dirid]).await {
+            return res;
+        }
+        self.inner.remove_wcc(dirid, filename, kind).await
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        if let Err(stat) = self.check(&[from_dirid, to_dirid]).await {
+            return RenameWccResult {
+                result: Err(stat),
+                from_dir_wcc: nfs3::wcc_data::default(),
+                to_dir_wcc: nfs3::wcc_data::default(),
+            };
+        }
+        self.inner
+            .rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        if let Err(res) = self.check_wcc(&[dirid]).await {
+            return res;
+        }
+        self.inner.symlink_wcc(dirid, linkname, symlink, attr).await
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        if let Err(res) = self.check_wcc(&[file_id, link_dir_id]).await {
+            return res;
+        }
+        self.inner.link_wcc(file_id, link_dir_id, link_name).await
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        if let Err(res) = self.check_wcc(&[dir_id]).await {
+            return res;
+        }
+        self.inner
+            .mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        if let Err(res) = self.check_wcc(&[file_id]).await {
+            return res;
+        }
+        self.inner.commit_wcc(file_id, offset, count).await
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        self.check(&[root_fileid]).await?;
+        self.inner.fsinfo(root_fileid).await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        self.check(&[id]).await?;
+        self.inner.fsstat(id).await
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.known.lock().unwrap().insert(id);
+        let fh = self.inner.id_to_fh(id);
+        let mut data = Vec::with_capacity(fh.data.len() + 8);
+        data.extend_from_slice(&self.root.to_le_bytes());
+        data.extend_from_slice(&fh.data);
+        nfs3::nfs_fh3 { data }
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        if id.data.len() < 8 {
+            return Err(nfs3::nfsstat3::NFS3ERR_BADHANDLE);
+        }
+        let (root, rest) = id.data.split_at(8);
+        if root != self.root.to_le_bytes() {
+            return Err(nfs3::nfsstat3::NFS3ERR_STALE);
+        }
+        self.inner.fh_to_id(&nfs3::nfs_fh3 {
+            data: rest.to_vec(),
+        })
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.inner.serverid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    /// Creates a file system with `/outside/secret` and `/sub/dir/file`,
+    /// returning the file IDs of `secret`, `dir` and `file`
+    async fn tree() -> (MemFS, [nfs3::fileid3; 3]) {
+        let fs = MemFS::new();
+        let root = fs.root_dir();
+        let (outside, _) = fs.mkdir(root, &name("outside")).await.unwrap();
+        let (secret, _) = fs
+            .create(outside, &name("secret"), Default::default())
+            .await
+            .unwrap();
+        let (sub, _) = fs.mkdir(root, &name("sub")).await.unwrap();
+        let (dir, _) = fs.mkdir(sub, &name("dir")).await.unwrap();
+        let (file, _) = fs
+            .create(dir, &name("file"), Default::default())
+            .await
+            .unwrap();
+        (fs, [secret, dir, file])
+    }
+
+    #[tokio::test]
+    async fn forged_handles_cannot_leave_the_subtree() {
+        let (fs, [secret, _, _]) = tree().await;
+        let forged_inner = fs.id_to_fh(secret);
+        let subtree = Subtree::from_path(fs, b"sub").await.unwrap();
+
+        let mut forged = subtree.root_dir().to_le_bytes().to_vec();
+        forged.extend_from_slice(&forged_inner.data);
+        let id = subtree.fh_to_id(&nfs3::nfs_fh3 { data: forged }).unwrap();
+        assert!(matches!(
+            subtree.getattr(id).await,
+            Err(nfs3::nfsstat3::NFS3ERR_STALE)
+        ));
+        assert!(matches!(
+            subtree.write(id, 0, b"x").await,
+            Err(nfs3::nfsstat3::NFS3ERR_STALE)
+        ));
+        assert!(matches!(
+            subtree
+                .remove_wcc(
+                    subtree.inner().root_dir(),
+                    &name("outside"),
+                    vfs::RemoveKind::Any
+                )
+                .await
+                .result,
+            Err(nfs3::nfsstat3::NFS3ERR_STALE)
+        ));
+    }
+
+    #[tokio::test]
+    async fn handles_are_checked_again_after_a_restart() {
+        let (fs, [_, dir, file]) = tree().await;
+        let subtree = Subtree::from_path(fs, b"sub").await.unwrap();
+        let dir_fh = subtree.id_to_fh(dir);
+        let file_fh = subtree.id_to_fh(file);
+        subtree.getattr(file).await.unwrap();
+
+        // a new subtree remembers no handles, but finds the directory by walking up
+        let subtree = Subtree::new(subtree.into_inner(), subtree_root(&dir_fh));
+        let dir = subtree.fh_to_id(&dir_fh).unwrap();
+        subtree.getattr(dir).await.unwrap();
+        let file = subtree.fh_to_id(&file_fh).unwrap();
+        assert!(matches!(
+            subtree.getattr(file).await,
+            Err(nfs3::nfsstat3::NFS3ERR_STALE)
+        ));
+        assert_eq!(subtree.lookup(dir, &name("file")).await.unwrap(), file);
+        subtree.id_to_fh(file);
+        subtree.getattr(file).await.unwrap();
+    }
+
+    fn subtree_root(fh: &nfs3::nfs_fh3) -> nfs3::fileid3 {
+        u64::from_le_bytes(fh.data[..8].try_into().unwrap())
+    }
+}
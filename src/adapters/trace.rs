@@ -0,0 +1,756 @@
+//! Tracing of file system calls.
+//!
+//! [`Traced`] wraps an [`NFSFileSystem`] and records every call as a
+//! [`tracing`] span named after the operation, so that backends can be debugged
+//! without adding `tracing` calls to each method:
+//! - Spans carry the arguments of the call: file IDs, names, offsets and counts
+//! - When the call returns, its status, latency and the number of bytes or directory
+//!   entries moved are recorded on the span, and an event is emitted inside it
+//! - Events emitted by the wrapped file system are nested in the span of the call
+//!
+//! File contents are never recorded, only their length. File names and symlink
+//! targets are recorded unless [`TraceOptions::names`] is off, in which case they
+//! are redacted.
+//!
+//! Spans and events use the level set in [`TraceOptions::level`], and
+//! [`TraceOptions::verbosity`] selects the calls that emit an event, which makes
+//! it possible to only see failing calls or calls that change something.
+//! Subscribers that report closed spans (such as `FmtSpan::CLOSE` of
+//! `tracing-subscriber`) show every call regardless of the verbosity.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::{field, Instrument, Level, Span};
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// Replaces redacted names
+const REDACTED: &str = "[redacted]";
+
+/// Calls that emit an event when they return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only calls that fail
+    Errors,
+    /// Calls that modify the file system, and calls that fail
+    Changes,
+    /// All calls
+    All,
+}
+
+/// Options of a [`Traced`] file system
+#[derive(Debug, Clone)]
+pub struct TraceOptions {
+    /// Level of the spans and events
+    pub level: Level,
+    /// Calls that emit an event when they return
+    pub verbosity: Verbosity,
+    /// Whether to record file names and symlink targets; they are redacted otherwise
+    pub names: bool,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions {
+            level: Level::DEBUG,
+            verbosity: Verbosity::All,
+            names: true,
+        }
+    }
+}
+
+/// Creates the span of a call at a level chosen at runtime
+macro_rules! call_span {
+    ($level:expr, $op:literal) => {{
+        let level = $level;
+        if level == Level::ERROR {
+            call_span!(@ Level::ERROR, $op)
+        } else if level == Level::WARN {
+            call_span!(@ Level::WARN, $op)
+        } else if level == Level::INFO {
+            call_span!(@ Level::INFO, $op)
+        } else if level == Level::DEBUG {
+            call_span!(@ Level::DEBUG, $op)
+        } else {
+            call_span!(@ Level::TRACE, $op)
+        }
+    }};
+    (@ $level:expr, $op:literal) => {
+        tracing::span!(
+            $level,
+            $op,
+            fileid = field::Empty,
+            dirid = field::Empty,
+            name = field::Empty,
+            to_dirid = field::Empty,
+            to_name = field::Empty,
+            target = field::Empty,
+            offset = field::Empty,
+            count = field::Empty,
+            status = field::Empty,
+            latency_us = field::Empty,
+            bytes = field::Empty,
+            entries = field::Empty,
+        )
+    };
+}
+
+/// Emits an event at a level chosen at runtime
+macro_rules! call_event {
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if level == Level::ERROR {
+            tracing::event!(Level::ERROR, $($arg)+)
+        } else if level == Level::WARN {
+            tracing::event!(Level::WARN, $($arg)+)
+        } else if level == Level::INFO {
+            tracing::event!(Level::INFO, $($arg)+)
+        } else if level == Level::DEBUG {
+            tracing::event!(Level::DEBUG, $($arg)+)
+        } else {
+            tracing::event!(Level::TRACE, $($arg)+)
+        }
+    }};
+}
+
+/// What a call returned, as far as it is recorded
+#[derive(Debug, Default)]
+struct Outcome {
+    /// Status of a failed call
+    error: Option<nfs3::nfsstat3>,
+    /// Bytes read or written
+    bytes: Option<u64>,
+    /// Directory entries returned
+    entries: Option<u64>,
+    /// File ID of the object found or created
+    fileid: Option<nfs3::fileid3>,
+}
+
+impl Outcome {
+    /// Returns the outcome of a call without data
+    fn of<T>(result: &Result<T, nfs3::nfsstat3>) -> Self {
+        Outcome {
+            error: result.as_ref().err().copied(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the outcome of a call moving data, counting the bytes if it succeeded
+    fn moved<T>(result: &Result<T, nfs3::nfsstat3>, bytes: impl FnOnce(&T) -> u64) -> Self {
+        Outcome {
+            error: result.as_ref().err().copied(),
+            bytes: result.as_ref().ok().map(bytes),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the outcome of a call finding or creating an object
+    fn found<T>(
+        result: &Result<T, nfs3::nfsstat3>,
+        fileid: impl FnOnce(&T) -> nfs3::fileid3,
+    ) -> Self {
+        Outcome {
+            error: result.as_ref().err().copied(),
+            fileid: result.as_ref().ok().map(fileid),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the outcome of a directory listing
+    fn listed<T>(result: &Result<T, nfs3::nfsstat3>, entries: impl FnOnce(&T) -> usize) -> Self {
+        Outcome {
+            error: result.as_ref().err().copied(),
+            entries: result.as_ref().ok().map(|r| entries(r) as u64),
+            ..Default::default()
+        }
+    }
+}
+
+/// Tracing wrapper around a file system
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Traced<F> {
+    /// The wrapped file system
+    inner: F,
+    /// Tracing settings
+    options: TraceOptions,
+}
+
+impl<F: NFSFileSystem> Traced<F> {
+    /// Creates a tracing wrapper with default options
+    pub fn new(inner: F) -> Self {
+        Self::with_options(inner, TraceOptions::default())
+    }
+
+    /// Creates a tracing wrapper
+    ///
+    /// # Arguments
+    /// * `inner` - The file system to trace
+    /// * `options` - Level, verbosity and redaction settings
+    pub fn with_options(inner: F, options: TraceOptions) -> Self {
+        Traced { inner, options }
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Returns the wrapped file system
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Records a name on a span, or its redaction
+    fn record_name(&self, span: &Span, field: &str, name: &[u8]) {
+        if self.options.names {
+            span.record(field, String::from_utf8_lossy(name).as_ref());
+        } else {
+            span.record(field, REDACTED);
+        }
+    }
+
+    /// Runs a call inside its span and records what it returned
+    ///
+    /// # Arguments
+    /// * `span` - The span of the call, with the arguments recorded
+    /// * `changes` - Whether the call modifies the file system
+    /// * `call` - The call to the wrapped file system
+    /// * `outcome` - Extracts what to record from the result
+    async fn run<T>(
+        &self,
+        span: Span,
+        changes: bool,
+        call: impl Future<Output = T>,
+        outcome: impl FnOnce(&T) -> Outcome,
+    ) -> T {
+        let start = Instant::now();
+        let result = call.instrument(span.clone()).await;
+        self.finish(&span, changes, start.elapsed(), outcome(&result));
+        result
+    }
+
+    /// Records the outcome of a call on its span and emits its event
+    fn finish(&self, span: &Span, changes: bool, latency: Duration, outcome: Outcome) {
+        let status = match outcome.error {
+            Some(status) => format!("{:?}", status),
+            None => "NFS3_OK".to_string(),
+        };
+        span.record("status", status.as_str());
+        span.record("latency_us", latency.as_micros() as u64);
+        if let Some(bytes) = outcome.bytes {
+            span.record("bytes", bytes);
+        }
+        if let Some(entries) = outcome.entries {
+            span.record("entries", entries);
+        }
+        if let Some(fileid) = outcome.fileid {
+            span.record("fileid", fileid);
+        }
+        let failed = outcome.error.is_some();
+        let emit = match self.options.verbosity {
+            Verbosity::All => true,
+            Verbosity::Changes => changes || failed,
+            Verbosity::Errors => failed,
+        };
+        if emit {
+            let _entered = span.enter();
+            if failed {
+                call_event!(self.options.level, "failed");
+            } else {
+                call_event!(self.options.level, "done");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<F: NFSFileSystem> NFSFileSystem for Traced<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.inner.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "lookup");
+        span.record("dirid", dirid);
+        self.record_name(&span, "name", filename);
+        let call = self.inner.lookup(dirid, filename);
+        self.run(span, false, call, |r| Outcome::found(r, |id| *id))
+            .await
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "getattr");
+        span.record("fileid", id);
+        self.run(span, false, self.inner.getattr(id), Outcome::of)
+            .await
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.setattr_wcc(id, setattr, None).await.result
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "read");
+        span.record("fileid", id);
+        span.record("offset", offset);
+        span.record("count", count);
+        let call = self.inner.read(id, offset, count);
+        self.run(span, false, call, |r| {
+            Outcome::moved(r, |(d, _)| d.len() as u64)
+        })
+        .await
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "read");
+        span.record("fileid", id);
+        span.record("offset", offset);
+        span.record("count", count);
+        let call = self.inner.read_bytes(id, offset, count);
+        self.run(span, false, call, |r| {
+            Outcome::moved(r, |(d, _)| d.len() as u64)
+        })
+        .await
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "read_file_range");
+        span.record("fileid", id);
+        span.record("offset", offset);
+        span.record("count", count);
+        let call = self.inner.read_file_range(id, offset, count);
+        self.run(span, false, call, |r| {
+            // without a range the data is read with read_bytes instead
+            let mut outcome = Outcome::of(r);
+            if let Ok(Some(range)) = r {
+                outcome.bytes = Some(range.len as u64);
+            }
+            outcome
+        })
+        .await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "write");
+        span.record("fileid", id);
+        span.record("offset", offset);
+        span.record("count", data.len());
+        let call = self.inner.write(id, offset, data);
+        self.run(span, true, call, |r| {
+            Outcome::moved(r, |_| data.len() as u64)
+        })
+        .await
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.create_wcc(dirid, filename, attr).await.result
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.create_exclusive_wcc(dirid, filename).await.result
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mkdir_wcc(dirid, dirname).await.result
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.remove_wcc(dirid, filename, vfs::RemoveKind::Any)
+            .await
+            .result
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+            .result
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "readdir");
+        span.record("dirid", dirid);
+        span.record("offset", start_after);
+        span.record("count", max_entries);
+        let call = self.inner.readdir(dirid, start_after, max_entries);
+        self.run(span, false, call, |r| {
+            Outcome::listed(r, |r| r.entries.len())
+        })
+        .await
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "readdir_simple_after");
+        span.record("dirid", dirid);
+        span.record("offset", start_after);
+        span.record("count", count);
+        let call = self.inner.readdir_simple_after(dirid, start_after, count);
+        self.run(span, false, call, |r| {
+            Outcome::listed(r, |r| r.entries.len())
+        })
+        .await
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        // only the opening of the stream is traced, entries are produced later
+        let span = call_span!(self.options.level, "readdir_stream");
+        span.record("dirid", dirid);
+        span.record("offset", start_after);
+        let call = self.inner.readdir_stream(dirid, start_after);
+        self.run(span, false, call, Outcome::of).await
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let span = call_span!(self.options.level, "getattr_batch");
+        span.record("count", ids.len());
+        let call = self.inner.getattr_batch(ids);
+        self.run(span, false, call, |results| Outcome {
+            error: results.iter().find_map(|r| r.as_ref().err().copied()),
+            entries: Some(results.len() as u64),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.symlink_wcc(dirid, linkname, symlink, attr)
+            .await
+            .result
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "readlink");
+        span.record("fileid", id);
+        let call = self.inner.readlink(id);
+        self.run(span.clone(), false, call, |r| {
+            if let Ok(target) = r {
+                self.record_name(&span, "target", target);
+            }
+            Outcome::of(r)
+        })
+        .await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.link_wcc(file_id, link_dir_id, link_name).await.result
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await
+            .result
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.commit_wcc(file_id, offset, count).await.result
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        let span = call_span!(self.options.level, "setattr");
+        span.record("fileid", id);
+        if let nfs3::set_size3::size(size) = setattr.size {
+            span.record("offset", size);
+        }
+        let call = self.inner.setattr_wcc(id, setattr, guard);
+        self.run(span, true, call, |r| Outcome::of(&r.result)).await
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let span = call_span!(self.options.level, "write");
+        span.record("fileid", id);
+        span.record("offset", offset);
+        span.record("count", data.len());
+        let call = self.inner.write_wcc(id, offset, data, stable);
+        self.run(span, true, call, |r| {
+            Outcome::moved(&r.result, |_| data.len() as u64)
+        })
+        .await
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let span = call_span!(self.options.level, "create");
+        span.record("dirid", dirid);
+        self.record_name(&span, "name", filename);
+        let call = self.inner.create_wcc(dirid, filename, attr);
+        self.run(span, true, call, |r| {
+            Outcome::found(&r.result, |(id, _)| *id)
+        })
+        .await
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        let span = call_span!(self.options.level, "create_exclusive");
+        span.record("dirid", dirid);
+        self.record_name(&span, "name", filename);
+        let call = self.inner.create_exclusive_wcc(dirid, filename);
+        self.run(span, true, call, |r| Outcome::found(&r.result, |id| *id))
+            .await
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let span = call_span!(self.options.level, "mkdir");
+        span.record("dirid", dirid);
+        self.record_name(&span, "name", dirname);
+        let call = self.inner.mkdir_wcc(dirid, dirname);
+        self.run(span, true, call, |r| {
+            Outcome::found(&r.result, |(id, _)| *id)
+        })
+        .await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        let span = call_span!(self.options.level, "remove");
+        span.record("dirid", dirid);
+        self.record_name(&span, "name", filename);
+        let call = self.inner.remove_wcc(dirid, filename, kind);
+        self.run(span, true, call, |r| Outcome::of(&r.result)).await
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        let span = call_span!(self.options.level, "rename");
+        span.record("dirid", from_dirid);
+        self.record_name(&span, "name", from_filename);
+        span.record("to_dirid", to_dirid);
+        self.record_name(&span, "to_name", to_filename);
+        let call = self
+            .inner
+            .rename_wcc(from_dirid, from_filename, to_dirid, to_filename);
+        self.run(span, true, call, |r| Outcome::of(&r.result)).await
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let span = call_span!(self.options.level, "symlink");
+        span.record("dirid", dirid);
+        self.record_name(&span, "name", linkname);
+        self.record_name(&span, "target", symlink);
+        let call = self.inner.symlink_wcc(dirid, linkname, symlink, attr);
+        self.run(span, true, call, |r| {
+            Outcome::found(&r.result, |(id, _)| *id)
+        })
+        .await
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        let span = call_span!(self.options.level, "link");
+        span.record("fileid", file_id);
+        span.record("dirid", link_dir_id);
+        self.record_name(&span, "name", link_name);
+        let call = self.inner.link_wcc(file_id, link_dir_id, link_name);
+        self.run(span, true, call, |r| Outcome::of(&r.result)).await
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let span = call_span!(self.options.level, "mknod");
+        span.record("dirid", dir_id);
+        self.record_name(&span, "name", name);
+        let call = self.inner.mknod_wcc(dir_id, name, ftype, specdata, attrs);
+        self.run(span, true, call, |r| {
+            Outcome::found(&r.result, |(id, _)| *id)
+        })
+        .await
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        let span = call_span!(self.options.level, "commit");
+        span.record("fileid", file_id);
+        span.record("offset", offset);
+        span.record("count", count);
+        let call = self.inner.commit_wcc(file_id, offset, count);
+        self.run(span, true, call, |r| Outcome::of(&r.result)).await
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "fsinfo");
+        span.record("fileid", root_fileid);
+        self.run(span, false, self.inner.fsinfo(root_fileid), Outcome::of)
+            .await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "fsstat");
+        span.record("fileid", id);
+        self.run(span, false, self.inner.fsstat(id), Outcome::of)
+            .await
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.inner.fh_to_id(id)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let span = call_span!(self.options.level, "path_to_id");
+        self.record_name(&span, "name", path);
+        let call = self.inner.path_to_id(path);
+        self.run(span, false, call, |r| Outcome::found(r, |id| *id))
+            .await
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.inner.serverid()
+    }
+}
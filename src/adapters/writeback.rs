@@ -0,0 +1,908 @@
+//! Write-back buffering that coalesces small writes.
+//!
+//! Clients often send many small or unaligned WRITE requests, and for backends
+//! such as append-only logs or object stores each of them costs a round-trip and
+//! a rewrite. [`WriteBack`] wraps an [`NFSFileSystem`] and keeps the data of
+//! WRITE requests sent with `UNSTABLE` in memory instead, per file, merging
+//! ranges that overlap or touch. The replies report `UNSTABLE`, which tells the
+//! client to keep its copy of the data until it has sent a COMMIT.
+//!
+//! The buffered data of a file is written to the wrapped file system:
+//! - On COMMIT, and before SETATTR and operations that may replace or remove the file
+//! - When a `DATA_SYNC` or `FILE_SYNC` write arrives for the file
+//! - Once the file has [`WriteBackOptions::flush_size`] bytes buffered
+//! - Once its oldest buffered write is [`WriteBackOptions::max_age`] old
+//! - When all files together have more than [`WriteBackOptions::max_bytes`] buffered
+//!
+//! Reads and attributes include the buffered data. Buffered data is lost if the
+//! server stops without [`WriteBack::flush_all`]; clients notice this by the
+//! changed write verifier after a restart and send their uncommitted data again.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use tracing::warn;
+
+use crate::protocol::xdr::nfs3;
+use crate::protocol::xdr::nfs3::file::stable_how;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+use crate::write_ranges::WriteRanges;
+
+/// Options of a [`WriteBack`] file system
+#[derive(Debug, Clone)]
+pub struct WriteBackOptions {
+    /// Number of buffered bytes at which a file is flushed
+    pub flush_size: usize,
+    /// Maximum time data stays buffered
+    pub max_age: Duration,
+    /// Maximum number of buffered bytes of all files together
+    pub max_bytes: usize,
+}
+
+impl Default for WriteBackOptions {
+    fn default() -> Self {
+        WriteBackOptions {
+            flush_size: 4 * 1024 * 1024,
+            max_age: Duration::from_secs(5),
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Buffered writes of a file
+#[derive(Debug)]
+struct Pending {
+    /// Buffered data
+    data: WriteRanges,
+    /// Attributes of the file including the buffered writes
+    attr: nfs3::fattr3,
+    /// Time of the oldest buffered write
+    since: Instant,
+}
+
+impl Pending {
+    /// Applies the buffered size and times to attributes fetched from the wrapped file system
+    fn apply(&self, attr: &mut nfs3::fattr3) {
+        attr.size = attr.size.max(self.attr.size);
+        attr.mtime = self.attr.mtime;
+        attr.ctime = self.attr.ctime;
+    }
+}
+
+/// Buffered writes of a file, locked while they are changed or flushed
+type Slot = Arc<tokio::sync::Mutex<Option<Pending>>>;
+
+/// Returns the current time as an NFS timestamp
+fn now() -> nfs3::nfstime3 {
+    let d = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    nfs3::nfstime3 {
+        seconds: d.as_secs() as u32,
+        nseconds: d.subsec_nanos(),
+    }
+}
+
+/// Returns the weaker of two stability levels
+fn weaker(a: stable_how, b: stable_how) -> stable_how {
+    if (a as u32) < (b as u32) {
+        a
+    } else {
+        b
+    }
+}
+
+/// State shared with the background flushes
+struct State<F> {
+    /// The wrapped file system
+    inner: F,
+    /// Buffering settings
+    options: WriteBackOptions,
+    /// Buffered writes by file ID
+    files: Mutex<HashMap<nfs3::fileid3, Slot>>,
+    /// Number of buffered bytes of all files
+    bytes: AtomicUsize,
+}
+
+impl<F: NFSFileSystem> State<F> {
+    /// Returns the buffered writes of a file, creating an empty entry if there is none
+    fn slot(&self, id: nfs3::fileid3) -> Slot {
+        self.files.lock().unwrap().entry(id).or_default().clone()
+    }
+
+    /// Returns the buffered writes of a file if there is an entry for it
+    fn existing(&self, id: nfs3::fileid3) -> Option<Slot> {
+        self.files.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Drops the entry of a file if it is empty and not in use
+    ///
+    /// Must be called without holding a reference to the entry.
+    fn release(&self, id: nfs3::fileid3) {
+        let mut files = self.files.lock().unwrap();
+        if let Some(slot) = files.get(&id) {
+            let unused = Arc::strong_count(slot) == 1
+                && slot.try_lock().map(|p| p.is_none()).unwrap_or(false);
+            if unused {
+                files.remove(&id);
+            }
+        }
+    }
+
+    /// Applies the buffered size and times of a file to its attributes, if it has buffered writes
+    async fn apply(&self, attr: &mut nfs3::fattr3) {
+        if let Some(slot) = self.existing(attr.fileid) {
+            if let Some(p) = slot.lock().await.as_ref() {
+                p.apply(attr);
+            }
+        }
+    }
+
+    /// Writes the buffered data of a file to the wrapped file system
+    ///
+    /// Returns how far the data was committed and the attributes after the last write.
+    /// On failure the data that was not written stays buffered.
+    async fn flush(
+        &self,
+        id: nfs3::fileid3,
+        pending: &mut Option<Pending>,
+        stable: stable_how,
+    ) -> Result<(stable_how, Option<nfs3::fattr3>), nfs3::nfsstat3> {
+        let mut committed = stable_how::FILE_SYNC;
+        let mut attr = None;
+        let Some(p) = pending.as_mut() else {
+            return Ok((committed, attr));
+        };
+        while let Some((offset, data)) = p.data.pop_first() {
+            let res = self.inner.write_wcc(id, offset, &data, stable).await;
+            match res.result {
+                Ok((new_attr, how)) => {
+                    self.bytes.fetch_sub(data.len(), Ordering::Relaxed);
+                    committed = weaker(committed, how);
+                    attr = Some(new_attr);
+                }
+                Err(stat) => {
+                    p.data.put_back(offset, data);
+                    return Err(stat);
+                }
+            }
+        }
+        *pending = None;
+        Ok((committed, attr))
+    }
+
+    /// Writes the buffered data of a file to the wrapped file system, if there is any
+    async fn flush_file(&self, id: nfs3::fileid3) -> Result<(), nfs3::nfsstat3> {
+        let Some(slot) = self.existing(id) else {
+            return Ok(());
+        };
+        let mut pending = slot.lock().await;
+        let result = self.flush(id, &mut pending, stable_how::UNSTABLE).await;
+        drop(pending);
+        drop(slot);
+        self.release(id);
+        result.map(|_| ())
+    }
+
+    /// Flushes files until the buffered data fits into the memory limit again
+    async fn relieve(&self) {
+        let slots: Vec<_> = {
+            let files = self.files.lock().unwrap();
+            files.keys().copied().collect()
+        };
+        for id in slots {
+            if self.bytes.load(Ordering::Relaxed) <= self.options.max_bytes {
+                break;
+            }
+            if let Err(stat) = self.flush_file(id).await {
+                warn!("Failed to flush buffered writes of {}: {:?}", id, stat);
+            }
+        }
+    }
+}
+
+/// Write-back buffering around a file system
+///
+/// See the [module documentation](self) for details.
+pub struct WriteBack<F> {
+    /// State shared with the background flushes
+    state: Arc<State<F>>,
+}
+
+impl<F: NFSFileSystem + Send + 'static> WriteBack<F> {
+    /// Creates a write-back wrapper with default options
+    pub fn new(inner: F) -> Self {
+        Self::with_options(inner, WriteBackOptions::default())
+    }
+
+    /// Creates a write-back wrapper
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The file system to write to
+    /// * `options` - Buffering settings
+    pub fn with_options(inner: F, options: WriteBackOptions) -> Self {
+        WriteBack {
+            state: Arc::new(State {
+                inner,
+                options,
+                files: Mutex::new(HashMap::new()),
+                bytes: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns a reference to the wrapped file system
+    pub fn inner(&self) -> &F {
+        &self.state.inner
+    }
+
+    /// Writes the buffered data of all files to the wrapped file system
+    ///
+    /// Returns the last error if some of the data could not be written; that data
+    /// stays buffered.
+    pub async fn flush_all(&self) -> Result<(), nfs3::nfsstat3> {
+        let ids: Vec<_> = self.state.files.lock().unwrap().keys().copied().collect();
+        let mut result = Ok(());
+        for id in ids {
+            if let Err(stat) = self.state.flush_file(id).await {
+                result = Err(stat);
+            }
+        }
+        result
+    }
+
+    /// Flushes the buffered data of the file a directory entry refers to
+    async fn flush_entry(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        if self.state.files.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        match self.state.inner.lookup(dirid, name).await {
+            Ok(id) => self.state.flush_file(id).await,
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Flushes a file once its oldest buffered write has reached the maximum age
+    fn schedule(&self, id: nfs3::fileid3) {
+        let state = Arc::downgrade(&self.state);
+        let max_age = self.state.options.max_age;
+        tokio::spawn(async move {
+            let mut delay = max_age;
+            loop {
+                tokio::time::sleep(delay).await;
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+                let Some(slot) = state.existing(id) else {
+                    return;
+                };
+                let mut pending = slot.lock().await;
+                let Some(age) = pending.as_ref().map(|p| p.since.elapsed()) else {
+                    return;
+                };
+                if age < max_age {
+                    delay = max_age - age;
+                    continue;
+                }
+                match state.flush(id, &mut pending, stable_how::UNSTABLE).await {
+                    Ok(_) => {
+                        drop(pending);
+                        drop(slot);
+                        state.release(id);
+                        return;
+                    }
+                    Err(stat) => {
+                        warn!("Failed to flush buffered writes of {}: {:?}", id, stat);
+                        delay = max_age;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl<F: NFSFileSystem + Send + 'static> NFSFileSystem for WriteBack<F> {
+    fn capabilities(&self) -> vfs::Capabilities {
+        self.state.inner.capabilities()
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.state.inner.root_dir()
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.state.inner.lookup(dirid, filename).await
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let mut attr = self.state.inner.getattr(id).await?;
+        self.state.apply(&mut attr).await;
+        Ok(attr)
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.state.flush_file(id).await?;
+        self.state.inner.setattr(id, setattr).await
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let (data, eof) = self.read_bytes(id, offset, count).await?;
+        Ok((data.to_vec(), eof))
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let Some(slot) = self.state.existing(id) else {
+            return self.state.inner.read_bytes(id, offset, count).await;
+        };
+        let pending = slot.lock().await;
+        let Some(p) = pending.as_ref() else {
+            return self.state.inner.read_bytes(id, offset, count).await;
+        };
+        let size = p.attr.size;
+        if offset >= size {
+            return Ok((Bytes::new(), true));
+        }
+        let end = size.min(offset.saturating_add(count as u64));
+        let mut out = vec![0; (end - offset) as usize];
+        // the stored data, with zeros beyond its end up to the buffered size
+        let mut pos = offset;
+        while pos < end {
+            let (data, eof) = self
+                .state
+                .inner
+                .read_bytes(id, pos, (end - pos) as u32)
+                .await?;
+            let at = (pos - offset) as usize;
+            out[at..at + data.len()].copy_from_slice(&data);
+            pos += data.len() as u64;
+            if eof || data.is_empty() {
+                break;
+            }
+        }
+        p.data.overlay(offset, &mut out);
+        Ok((out.into(), end == size))
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        // the stored file lacks the buffered data
+        if let Some(slot) = self.state.existing(id) {
+            if slot.lock().await.is_some() {
+                return Ok(None);
+            }
+        }
+        self.state.inner.read_file_range(id, offset, count).await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.write_wcc(id, offset, data, stable_how::FILE_SYNC)
+            .await
+            .result
+            .map(|(attr, _)| attr)
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.flush_entry(dirid, filename).await?;
+        self.state.inner.create(dirid, filename, attr).await
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.state.inner.create_exclusive(dirid, filename).await
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.state.inner.mkdir(dirid, dirname).await
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.flush_entry(dirid, filename).await?;
+        self.state.inner.remove(dirid, filename).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.flush_entry(to_dirid, to_filename).await?;
+        self.state
+            .inner
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let mut result = self
+            .state
+            .inner
+            .readdir(dirid, start_after, max_entries)
+            .await?;
+        for entry in &mut result.entries {
+            self.state.apply(&mut entry.attr).await;
+        }
+        Ok(result)
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        let mut result = self
+            .state
+            .inner
+            .readdir_simple_after(dirid, start_after, count)
+            .await?;
+        for attr in result.entries.iter_mut().filter_map(|e| e.attr.as_mut()) {
+            self.state.apply(attr).await;
+        }
+        Ok(result)
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        let stream = self.state.inner.readdir_stream(dirid, start_after).await?;
+        Ok(stream
+            .then(move |entry| async move {
+                let mut entry = entry?;
+                if let Some(attr) = entry.attr.as_mut() {
+                    self.state.apply(attr).await;
+                }
+                Ok(entry)
+            })
+            .boxed())
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let mut results = self.state.inner.getattr_batch(ids).await;
+        for attr in results.iter_mut().flatten() {
+            self.state.apply(attr).await;
+        }
+        results
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.state
+            .inner
+            .symlink(dirid, linkname, symlink, attr)
+            .await
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        self.state.inner.readlink(id).await
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.state.inner.link(file_id, link_dir_id, link_name).await
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.state
+            .inner
+            .mknod(dir_id, name, ftype, specdata, attrs)
+            .await
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.state.flush_file(file_id).await?;
+        self.state.inner.commit(file_id, offset, count).await
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        if let Err(stat) = self.state.flush_file(id).await {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        self.state.inner.setattr_wcc(id, setattr, guard).await
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: stable_how,
+    ) -> WccResult<(nfs3::fattr3, stable_how)> {
+        let Some(end) = offset
+            .checked_add(data.len() as u64)
+            .filter(|_| !data.is_empty())
+        else {
+            return self.state.inner.write_wcc(id, offset, data, stable).await;
+        };
+        let slot = self.state.slot(id);
+        let mut pending = slot.lock().await;
+        let created = pending.is_none();
+        if created {
+            let attr = match self.state.inner.getattr(id).await {
+                Ok(attr) if matches!(attr.ftype, nfs3::ftype3::NF3REG) => attr,
+                // let the wrapped file system report the error
+                _ => {
+                    drop(pending);
+                    drop(slot);
+                    self.state.release(id);
+                    return self.state.inner.write_wcc(id, offset, data, stable).await;
+                }
+            };
+            *pending = Some(Pending {
+                data: WriteRanges::default(),
+                attr,
+                since: Instant::now(),
+            });
+        }
+        let p = pending.as_mut().unwrap();
+        let before = nfs3::pre_op_attr::attributes(p.attr.into());
+        let buffered = p.data.bytes();
+        p.data.insert(offset, data);
+        self.state
+            .bytes
+            .fetch_add(p.data.bytes() - buffered, Ordering::Relaxed);
+        let time = now();
+        p.attr.size = p.attr.size.max(end);
+        p.attr.mtime = time;
+        p.attr.ctime = time;
+        let mut attr = p.attr;
+        let mut committed = stable_how::UNSTABLE;
+        if !matches!(stable, stable_how::UNSTABLE)
+            || p.data.bytes() >= self.state.options.flush_size
+        {
+            match self.state.flush(id, &mut pending, stable).await {
+                Ok((how, flushed)) => {
+                    committed = how;
+                    attr = flushed.unwrap_or(attr);
+                }
+                Err(stat) => {
+                    return WccResult {
+                        result: Err(stat),
+                        wcc: nfs3::wcc_data {
+                            before,
+                            after: nfs3::post_op_attr::attributes(attr),
+                        },
+                    };
+                }
+            }
+        }
+        let buffering = pending.is_some();
+        drop(pending);
+        drop(slot);
+        if created && buffering {
+            self.schedule(id);
+        }
+        if !buffering {
+            self.state.release(id);
+        }
+        if self.state.bytes.load(Ordering::Relaxed) > self.state.options.max_bytes {
+            self.state.relieve().await;
+        }
+        WccResult {
+            result: Ok((attr, committed)),
+            wcc: nfs3::wcc_data {
+                before,
+                after: nfs3::post_op_attr::attributes(attr),
+            },
+        }
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        if let Err(stat) = self.flush_entry(dirid, filename).await {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        self.state.inner.create_wcc(dirid, filename, attr).await
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        self.state.inner.create_exclusive_wcc(dirid, filename).await
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.state.inner.mkdir_wcc(dirid, dirname).await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        if let Err(stat) = self.flush_entry(dirid, filename).await {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        self.state.inner.remove_wcc(dirid, filename, kind).await
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        if let Err(stat) = self.flush_entry(to_dirid, to_filename).await {
+            return RenameWccResult {
+                result: Err(stat),
+                from_dir_wcc: nfs3::wcc_data::default(),
+                to_dir_wcc: nfs3::wcc_data::default(),
+            };
+        }
+        self.state
+            .inner
+            .rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.state
+            .inner
+            .symlink_wcc(dirid, linkname, symlink, attr)
+            .await
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        self.state
+            .inner
+            .link_wcc(file_id, link_dir_id, link_name)
+            .await
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.state
+            .inner
+            .mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await
+    }
+
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        if let Err(stat) = self.state.flush_file(file_id).await {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            };
+        }
+        self.state.inner.commit_wcc(file_id, offset, count).await
+    }
+
+    async fn fsinfo(
+        &self,
+        root_fileid: nfs3::fileid3,
+    ) -> Result<nfs3::fs::fsinfo3, nfs3::nfsstat3> {
+        self.state.inner.fsinfo(root_fileid).await
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        self.state.inner.fsstat(id).await
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        self.state.inner.id_to_fh(id)
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.state.inner.fh_to_id(id)
+    }
+
+    async fn path_to_id(&self, path: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.state.inner.path_to_id(path).await
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.state.inner.serverid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::MemFS;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    async fn create(fs: &WriteBack<MemFS>, file: &str) -> nfs3::fileid3 {
+        let root = fs.root_dir();
+        let (id, _) = fs
+            .create(root, &name(file), Default::default())
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn appends_are_buffered_until_commit() {
+        let fs = WriteBack::new(MemFS::new());
+        let file = create(&fs, "file").await;
+        for i in 0..100u8 {
+            let res = fs
+                .write_wcc(file, i as u64 * 10, &[i; 10], stable_how::UNSTABLE)
+                .await;
+            let (attr, how) = res.result.unwrap();
+            assert!(matches!(how, stable_how::UNSTABLE));
+            assert_eq!(attr.size, (i as u64 + 1) * 10);
+        }
+        assert_eq!(fs.inner().getattr(file).await.unwrap().size, 0);
+        let (data, eof) = fs.read(file, 995, 100).await.unwrap();
+        assert_eq!(data, vec![99; 5]);
+        assert!(eof);
+
+        fs.commit(file, 0, 0).await.unwrap();
+        let (data, _) = fs.inner().read(file, 0, 1000).await.unwrap();
+        assert_eq!(data.len(), 1000);
+        assert!(data.chunks(10).enumerate().all(|(i, c)| c == [i as u8; 10]));
+        assert_eq!(fs.state.bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn listings_include_buffered_sizes() {
+        let fs = WriteBack::new(MemFS::new());
+        let file = create(&fs, "file").await;
+        fs.write_wcc(file, 0, b"buffered", stable_how::UNSTABLE)
+            .await
+            .result
+            .unwrap();
+        let attr = fs.getattr(file).await.unwrap();
+        assert_eq!(attr.size, 8);
+
+        let root = fs.root_dir();
+        let listed = fs.readdir(root, 0, 10).await.unwrap();
+        assert_eq!(listed.entries[0].attr.size, 8);
+        let simple = fs.readdir_simple_after(root, 0, 10).await.unwrap();
+        assert_eq!(simple.entries[0].attr.unwrap().size, 8);
+        let streamed: Vec<_> = fs.readdir_stream(root, 0).await.unwrap().collect().await;
+        let entry = streamed[0].as_ref().unwrap();
+        assert_eq!(entry.attr.unwrap().size, 8);
+        assert_eq!(entry.attr.unwrap().mtime.seconds, attr.mtime.seconds);
+        assert_eq!(entry.attr.unwrap().mtime.nseconds, attr.mtime.nseconds);
+    }
+
+    #[tokio::test]
+    async fn writes_beyond_the_largest_offset_are_passed_on() {
+        let fs = WriteBack::new(MemFS::new());
+        let file = create(&fs, "file").await;
+        let res = fs
+            .write_wcc(file, u64::MAX - 1, b"data", stable_how::UNSTABLE)
+            .await;
+        assert!(res.result.is_err());
+        assert!(fs.state.files.lock().unwrap().is_empty());
+        assert_eq!(fs.getattr(file).await.unwrap().size, 0);
+    }
+}
@@ -0,0 +1,1309 @@
+//! Read-only file system serving the contents of a tar or zip archive.
+//!
+//! [`ArchiveFS`] indexes an archive once when it is opened and then serves lookups,
+//! attributes, directory listings, symbolic links and file data straight from the
+//! archive file, without unpacking it:
+//! - Tar archives in ustar, GNU and pax format, with regular files, directories,
+//!   symbolic links and hard links
+//! - Zip archives, including zip64, with members stored or compressed with deflate,
+//!   and the Unix permissions, owners, times and symbolic links recorded by Info-ZIP
+//!
+//! Tar members and stored zip members are read with random access, straight from
+//! their position in the archive. Deflated zip members are decompressed as they are
+//! read: a read continuing where the previous one stopped resumes the decompression,
+//! while a read further back starts it over from the beginning of the member.
+//! Compressed tar archives (such as `.tar.gz`) cannot be read at random and are not
+//! supported.
+//!
+//! Directories missing from the archive are created as needed. Leading `/` and `./`
+//! are removed from member paths, and members with `..` components are skipped. If a
+//! path occurs more than once, the last member wins, as when extracting the archive.
+//!
+//! Requires the `archive` feature.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZFlush, MZStatus};
+use tracing::{debug, warn};
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem};
+
+/// File ID of the root directory
+const ROOT_ID: nfs3::fileid3 = 1;
+
+/// Number of bytes of compressed data read at a time when decompressing
+const INFLATE_CHUNK: usize = 64 * 1024;
+
+/// Maximum number of decompression states kept for sequential reads
+const MAX_CURSORS: usize = 16;
+
+/// Maximum length of a symbolic link target read from a zip member
+const MAX_SYMLINK_LEN: u64 = 4096;
+
+/// Signature of a zip local file header
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+
+/// Signature of a zip central directory record
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+
+/// Signature of the zip end of central directory record
+const ZIP_END: u32 = 0x0605_4b50;
+
+/// Signature of the zip64 end of central directory record
+const ZIP64_END: u32 = 0x0606_4b50;
+
+/// Signature of the zip64 end of central directory locator
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+
+/// File type bits of a Unix mode
+const S_IFMT: u32 = 0o170000;
+
+/// Unix file type of a directory
+const S_IFDIR: u32 = 0o040000;
+
+/// Unix file type of a symbolic link
+const S_IFLNK: u32 = 0o120000;
+
+/// Location of the data of a regular file in the archive
+#[derive(Debug, Clone, Copy)]
+enum Content {
+    /// Uncompressed data at an offset in the archive
+    Stored { offset: u64 },
+    /// Raw deflate stream at an offset in the archive
+    Deflated { offset: u64, compressed: u64 },
+    /// Data that cannot be read, such as encrypted zip members
+    Unsupported,
+}
+
+/// Contents of a file system node
+#[derive(Debug)]
+enum NodeData {
+    /// Regular file data in the archive
+    File(Content),
+    /// Directory
+    Dir(Directory),
+    /// Symbolic link target
+    Symlink(Vec<u8>),
+}
+
+/// Directory contents
+#[derive(Debug, Default)]
+struct Directory {
+    /// Parent directory ID (the root is its own parent)
+    parent: nfs3::fileid3,
+    /// Entries by name
+    entries: HashMap<Vec<u8>, nfs3::fileid3>,
+    /// Entries in listing order; the file ID doubles as the directory cookie
+    order: BTreeSet<(nfs3::fileid3, Vec<u8>)>,
+}
+
+impl Directory {
+    /// Adds an entry to the directory
+    fn insert(&mut self, name: &[u8], id: nfs3::fileid3) {
+        self.entries.insert(name.to_vec(), id);
+        self.order.insert((id, name.to_vec()));
+    }
+
+    /// Removes an entry from the directory, returning its file ID
+    fn remove(&mut self, name: &[u8]) -> Option<nfs3::fileid3> {
+        let id = self.entries.remove(name)?;
+        self.order.remove(&(id, name.to_vec()));
+        Some(id)
+    }
+}
+
+/// A file system node (inode)
+#[derive(Debug)]
+struct Node {
+    /// Attributes of the node
+    attr: nfs3::fattr3,
+    /// Contents of the node
+    data: NodeData,
+}
+
+/// Type of an archive member
+#[derive(Debug)]
+enum MemberKind {
+    /// Regular file
+    File(Content),
+    /// Directory
+    Dir,
+    /// Symbolic link with its target
+    Symlink(Vec<u8>),
+    /// Hard link to the member with the given path
+    HardLink(Vec<u8>),
+}
+
+/// An archive member as described by the archive index
+#[derive(Debug)]
+struct Member {
+    /// Path of the member within the archive
+    path: Vec<u8>,
+    /// Type of the member
+    kind: MemberKind,
+    /// Permission bits
+    mode: u32,
+    /// Owner user ID
+    uid: u32,
+    /// Owner group ID
+    gid: u32,
+    /// Modification time in seconds since the epoch
+    mtime: u32,
+    /// Size of the file data
+    size: u64,
+}
+
+/// Tree of nodes built from the archive index
+#[derive(Debug)]
+struct Tree {
+    /// All nodes by file ID
+    nodes: HashMap<nfs3::fileid3, Node>,
+    /// Next file ID to allocate
+    next_id: nfs3::fileid3,
+    /// Time given to directories missing from the archive
+    time: nfs3::nfstime3,
+}
+
+/// Splits a member path into its components
+///
+/// Returns `None` if the path has `..` components.
+fn components(path: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut result = Vec::new();
+    for name in path.split(|&b| b == b'/') {
+        match name {
+            b"" | b"." => {}
+            b".." => return None,
+            _ => result.push(name),
+        }
+    }
+    Some(result)
+}
+
+impl Tree {
+    /// Creates a tree holding only the root directory
+    fn new(time: nfs3::nfstime3) -> Self {
+        let mut tree = Tree {
+            nodes: HashMap::new(),
+            next_id: ROOT_ID,
+            time,
+        };
+        let data = NodeData::Dir(Directory {
+            parent: ROOT_ID,
+            ..Default::default()
+        });
+        tree.add_node(nfs3::ftype3::NF3DIR, 0o755, 0, 0, time, 0, data);
+        tree
+    }
+
+    /// Allocates a node, returning its file ID
+    #[allow(clippy::too_many_arguments)]
+    fn add_node(
+        &mut self,
+        ftype: nfs3::ftype3,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        time: nfs3::nfstime3,
+        size: u64,
+        data: NodeData,
+    ) -> nfs3::fileid3 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let attr = nfs3::fattr3 {
+            ftype,
+            mode: mode & 0o7777,
+            nlink: if matches!(data, NodeData::Dir(_)) {
+                2
+            } else {
+                1
+            },
+            uid,
+            gid,
+            size,
+            used: size,
+            rdev: nfs3::specdata3::default(),
+            fsid: 0,
+            fileid: id,
+            atime: time,
+            mtime: time,
+            ctime: time,
+        };
+        self.nodes.insert(id, Node { attr, data });
+        id
+    }
+
+    /// Gets a directory by file ID
+    fn dir_mut(&mut self, id: nfs3::fileid3) -> Option<&mut Directory> {
+        match &mut self.nodes.get_mut(&id)?.data {
+            NodeData::Dir(dir) => Some(dir),
+            _ => None,
+        }
+    }
+
+    /// Looks up a name in a directory
+    fn child(&self, dirid: nfs3::fileid3, name: &[u8]) -> Option<nfs3::fileid3> {
+        match &self.nodes.get(&dirid)?.data {
+            NodeData::Dir(dir) => dir.entries.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// Links a node into a directory
+    fn link(&mut self, dirid: nfs3::fileid3, name: &[u8], id: nfs3::fileid3) {
+        let is_dir = matches!(self.nodes[&id].data, NodeData::Dir(_));
+        if let Some(dir) = self.dir_mut(dirid) {
+            dir.insert(name, id);
+        }
+        if is_dir {
+            self.nodes.get_mut(&dirid).unwrap().attr.nlink += 1;
+        }
+    }
+
+    /// Removes an entry from a directory, dropping the node when no links remain
+    fn unlink(&mut self, dirid: nfs3::fileid3, name: &[u8]) {
+        let Some(id) = self.dir_mut(dirid).and_then(|dir| dir.remove(name)) else {
+            return;
+        };
+        let node = self.nodes.get_mut(&id).unwrap();
+        if matches!(node.data, NodeData::Dir(_)) {
+            // the directory and everything below it become unreachable
+            self.nodes.get_mut(&dirid).unwrap().attr.nlink -= 1;
+            return;
+        }
+        node.attr.nlink -= 1;
+        if node.attr.nlink == 0 {
+            self.nodes.remove(&id);
+        }
+    }
+
+    /// Resolves the directory with the given path components, creating any
+    /// directories that are missing
+    fn make_dirs(&mut self, names: &[&[u8]]) -> nfs3::fileid3 {
+        let mut dirid = ROOT_ID;
+        for name in names {
+            dirid = match self.child(dirid, name) {
+                Some(id) if matches!(self.nodes[&id].data, NodeData::Dir(_)) => id,
+                existing => {
+                    if existing.is_some() {
+                        self.unlink(dirid, name);
+                    }
+                    let data = NodeData::Dir(Directory {
+                        parent: dirid,
+                        ..Default::default()
+                    });
+                    let id = self.add_node(nfs3::ftype3::NF3DIR, 0o755, 0, 0, self.time, 0, data);
+                    self.link(dirid, name, id);
+                    id
+                }
+            };
+        }
+        dirid
+    }
+
+    /// Resolves a member path to the file ID of an existing node
+    fn resolve(&self, path: &[u8]) -> Option<nfs3::fileid3> {
+        components(path)?
+            .into_iter()
+            .try_fold(ROOT_ID, |dirid, name| self.child(dirid, name))
+    }
+
+    /// Adds an archive member to the tree
+    fn add(&mut self, member: Member) {
+        let Some(names) = components(&member.path) else {
+            warn!(
+                "skipping archive member {:?} outside of the root",
+                String::from_utf8_lossy(&member.path)
+            );
+            return;
+        };
+        let time = nfs3::nfstime3 {
+            seconds: member.mtime,
+            nseconds: 0,
+        };
+        let Some((name, parents)) = names.split_last() else {
+            // an entry for the root directory itself only carries attributes
+            if matches!(member.kind, MemberKind::Dir) {
+                self.set_dir_attr(ROOT_ID, &member, time);
+            }
+            return;
+        };
+        let dirid = self.make_dirs(parents);
+        if let Some(existing) = self.child(dirid, name) {
+            if matches!(member.kind, MemberKind::Dir)
+                && matches!(self.nodes[&existing].data, NodeData::Dir(_))
+            {
+                // keep the contents of a directory that is listed again
+                self.set_dir_attr(existing, &member, time);
+                return;
+            }
+            self.unlink(dirid, name);
+        }
+        let id = match member.kind {
+            MemberKind::File(content) => self.add_node(
+                nfs3::ftype3::NF3REG,
+                member.mode,
+                member.uid,
+                member.gid,
+                time,
+                member.size,
+                NodeData::File(content),
+            ),
+            MemberKind::Dir => self.add_node(
+                nfs3::ftype3::NF3DIR,
+                member.mode,
+                member.uid,
+                member.gid,
+                time,
+                0,
+                NodeData::Dir(Directory {
+                    parent: dirid,
+                    ..Default::default()
+                }),
+            ),
+            MemberKind::Symlink(target) => self.add_node(
+                nfs3::ftype3::NF3LNK,
+                0o777,
+                member.uid,
+                member.gid,
+                time,
+                target.len() as u64,
+                NodeData::Symlink(target),
+            ),
+            MemberKind::HardLink(target) => {
+                let Some(id) = self
+                    .resolve(&target)
+                    .filter(|id| !matches!(self.nodes[id].data, NodeData::Dir(_)))
+                else {
+                    warn!(
+                        "skipping hard link {:?} to missing member {:?}",
+                        String::from_utf8_lossy(&member.path),
+                        String::from_utf8_lossy(&target)
+                    );
+                    return;
+                };
+                self.nodes.get_mut(&id).unwrap().attr.nlink += 1;
+                id
+            }
+        };
+        self.link(dirid, name, id);
+    }
+
+    /// Applies the attributes of a directory member to an existing directory
+    fn set_dir_attr(&mut self, id: nfs3::fileid3, member: &Member, time: nfs3::nfstime3) {
+        let attr = &mut self.nodes.get_mut(&id).unwrap().attr;
+        attr.mode = member.mode & 0o7777;
+        attr.uid = member.uid;
+        attr.gid = member.gid;
+        attr.atime = time;
+        attr.mtime = time;
+        attr.ctime = time;
+    }
+}
+
+/// Reads exactly `buf.len()` bytes at an offset of a file
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        let mut done = 0;
+        while done < buf.len() {
+            let n = std::os::windows::fs::FileExt::seek_read(
+                file,
+                &mut buf[done..],
+                offset + done as u64,
+            )?;
+            if n == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// Returns an error for a malformed archive
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads a little-endian 16-bit value
+fn le16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+/// Reads a little-endian 32-bit value
+fn le32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+/// Reads a little-endian 64-bit value
+fn le64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Reads a little-endian value of 1 to 8 bytes
+fn le_var(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |v, &b| (v << 8) | b as u64)
+}
+
+/// Converts an MS-DOS date and time to seconds since the epoch
+fn dos_time(date: u16, time: u16) -> u32 {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).clamp(1, 12) as i64;
+    let day = (date & 0x1f).max(1) as i64;
+    // days since the epoch of a proleptic Gregorian date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let seconds =
+        (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    (days * 86400 + seconds) as u32
+}
+
+/// Indexes the members of a tar archive
+fn index_tar(file: &File, tree: &mut Tree) -> std::io::Result<()> {
+    let mut archive = tar::Archive::new(file);
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        let header = entry.header();
+        let path = entry.path_bytes().into_owned();
+        let link_name = || entry.link_name_bytes().unwrap_or_default().into_owned();
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                MemberKind::File(Content::Stored {
+                    offset: entry.raw_file_position(),
+                })
+            }
+            tar::EntryType::Directory => MemberKind::Dir,
+            tar::EntryType::Symlink => MemberKind::Symlink(link_name()),
+            tar::EntryType::Link => MemberKind::HardLink(link_name()),
+            other => {
+                debug!(
+                    "skipping tar member {:?} of type {other:?}",
+                    String::from_utf8_lossy(&path)
+                );
+                continue;
+            }
+        };
+        tree.add(Member {
+            path,
+            kind,
+            mode: header.mode().unwrap_or(0o644),
+            uid: header.uid().unwrap_or(0) as u32,
+            gid: header.gid().unwrap_or(0) as u32,
+            mtime: header.mtime().unwrap_or(0) as u32,
+            size: entry.size(),
+        });
+    }
+    Ok(())
+}
+
+/// Locates the central directory of a zip archive
+///
+/// Returns the number of entries, the size and the offset of the central directory.
+fn zip_directory(file: &File, len: u64) -> std::io::Result<(u64, u64, u64)> {
+    // the end record is followed by a comment of up to 64 KiB
+    let tail_len = len.min(22 + 0xffff) as usize;
+    let tail_start = len - tail_len as u64;
+    let mut tail = vec![0; tail_len];
+    read_exact_at(file, &mut tail, tail_start)?;
+    let pos = (0..tail_len.saturating_sub(21))
+        .rev()
+        .find(|&i| le32(&tail, i) == ZIP_END)
+        .ok_or_else(|| invalid("missing zip end of central directory record"))?;
+    let end = &tail[pos..];
+    let entries = le16(end, 10) as u64;
+    let size = le32(end, 12) as u64;
+    let offset = le32(end, 16) as u64;
+    let end_offset = tail_start + pos as u64;
+    if (entries == 0xffff || size == 0xffff_ffff || offset == 0xffff_ffff) && end_offset >= 20 {
+        let mut locator = [0; 20];
+        read_exact_at(file, &mut locator, end_offset - 20)?;
+        if le32(&locator, 0) == ZIP64_LOCATOR {
+            let mut end = [0; 56];
+            read_exact_at(file, &mut end, le64(&locator, 8))?;
+            if le32(&end, 0) != ZIP64_END {
+                return Err(invalid("bad zip64 end of central directory record"));
+            }
+            return Ok((le64(&end, 32), le64(&end, 40), le64(&end, 48)));
+        }
+    }
+    Ok((entries, size, offset))
+}
+
+/// Indexes the members of a zip archive
+fn index_zip(file: &File, len: u64, tree: &mut Tree) -> std::io::Result<()> {
+    let (entries, size, offset) = zip_directory(file, len)?;
+    if offset.saturating_add(size) > len {
+        return Err(invalid("zip central directory out of bounds"));
+    }
+    let mut dir = vec![0; size as usize];
+    read_exact_at(file, &mut dir, offset)?;
+    let mut p = 0;
+    for _ in 0..entries {
+        if p + 46 > dir.len() || le32(&dir, p) != ZIP_CENTRAL_HEADER {
+            return Err(invalid("bad zip central directory record"));
+        }
+        let record = &dir[p..];
+        let made_by = le16(record, 4);
+        let flags = le16(record, 8);
+        let method = le16(record, 10);
+        let mut mtime = dos_time(le16(record, 14), le16(record, 12));
+        let mut compressed = le32(record, 20) as u64;
+        let mut size = le32(record, 24) as u64;
+        let name_len = le16(record, 28) as usize;
+        let extra_len = le16(record, 30) as usize;
+        let comment_len = le16(record, 32) as usize;
+        let external = le32(record, 38);
+        let mut local = le32(record, 42) as u64;
+        let next = p + 46 + name_len + extra_len + comment_len;
+        if next > dir.len() {
+            return Err(invalid("bad zip central directory record"));
+        }
+        let path = record[46..46 + name_len].to_vec();
+        let (mut uid, mut gid) = (0, 0);
+
+        let mut extra = &record[46 + name_len..46 + name_len + extra_len];
+        while extra.len() >= 4 {
+            let id = le16(extra, 0);
+            let data = &extra[4..(4 + le16(extra, 2) as usize).min(extra.len())];
+            match id {
+                // zip64 sizes and offset, present only where the record overflows
+                0x0001 => {
+                    let mut values = data.chunks_exact(8).map(le_var);
+                    if size == 0xffff_ffff {
+                        size = values.next().unwrap_or(size);
+                    }
+                    if compressed == 0xffff_ffff {
+                        compressed = values.next().unwrap_or(compressed);
+                    }
+                    if local == 0xffff_ffff {
+                        local = values.next().unwrap_or(local);
+                    }
+                }
+                // extended timestamp
+                0x5455 if data.len() >= 5 && data[0] & 1 != 0 => mtime = le32(data, 1),
+                // Info-ZIP Unix owner
+                0x7875 if data.len() >= 2 => {
+                    let uid_len = data[1] as usize;
+                    if let Some(uid_bytes) = data.get(2..2 + uid_len) {
+                        uid = le_var(uid_bytes) as u32;
+                        let gid_len = data.get(2 + uid_len).copied().unwrap_or(0) as usize;
+                        if let Some(gid_bytes) = data.get(3 + uid_len..3 + uid_len + gid_len) {
+                            gid = le_var(gid_bytes) as u32;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            extra = &extra[(4 + le16(extra, 2) as usize).min(extra.len())..];
+        }
+
+        let mut header = [0; 30];
+        read_exact_at(file, &mut header, local)?;
+        if le32(&header, 0) != ZIP_LOCAL_HEADER {
+            return Err(invalid("bad zip local file header"));
+        }
+        let data = local + 30 + le16(&header, 26) as u64 + le16(&header, 28) as u64;
+        let stored_len = if method == 8 { compressed } else { size };
+        if flags & 1 == 0 && data.checked_add(stored_len).is_none_or(|end| end > len) {
+            return Err(invalid("zip member data out of bounds"));
+        }
+        let content = if flags & 1 != 0 {
+            warn!(
+                "zip member {:?} is encrypted",
+                String::from_utf8_lossy(&path)
+            );
+            Content::Unsupported
+        } else {
+            match method {
+                0 => Content::Stored { offset: data },
+                8 => Content::Deflated {
+                    offset: data,
+                    compressed,
+                },
+                _ => {
+                    warn!(
+                        "zip member {:?} uses unsupported compression method {method}",
+                        String::from_utf8_lossy(&path)
+                    );
+                    Content::Unsupported
+                }
+            }
+        };
+
+        // Unix modes are recorded by archivers running on Unix hosts
+        let unix_mode = if made_by >> 8 == 3 { external >> 16 } else { 0 };
+        let is_dir = path.ends_with(b"/")
+            || unix_mode & S_IFMT == S_IFDIR
+            || (unix_mode == 0 && external & 0x10 != 0);
+        let kind = if is_dir {
+            MemberKind::Dir
+        } else if unix_mode & S_IFMT == S_IFLNK {
+            if size > MAX_SYMLINK_LEN {
+                warn!(
+                    "skipping zip member {:?} with a symbolic link target of {size} bytes",
+                    String::from_utf8_lossy(&path)
+                );
+                p = next;
+                continue;
+            }
+            MemberKind::Symlink(read_content(file, content, size, 0, size as usize, None)?.0)
+        } else {
+            MemberKind::File(content)
+        };
+        let mode = match unix_mode & 0o7777 {
+            0 if is_dir => 0o755,
+            0 => 0o644,
+            mode => mode,
+        };
+        tree.add(Member {
+            path,
+            kind,
+            mode,
+            uid,
+            gid,
+            mtime,
+            size,
+        });
+        p = next;
+    }
+    Ok(())
+}
+
+/// State of a decompression in progress
+struct Cursor {
+    /// Decompressor state
+    state: Box<InflateState>,
+    /// Number of compressed bytes consumed
+    consumed: u64,
+    /// Number of decompressed bytes produced
+    produced: u64,
+}
+
+impl Cursor {
+    /// Creates a cursor at the beginning of a raw deflate stream
+    fn new() -> Self {
+        Cursor {
+            state: InflateState::new_boxed(DataFormat::Raw),
+            consumed: 0,
+            produced: 0,
+        }
+    }
+}
+
+/// Reads a range of the data of a regular file
+///
+/// Deflated data is decompressed with the given cursor if it has not moved past
+/// the offset yet, or from the beginning otherwise. Returns the data and the cursor
+/// positioned after it, if any.
+fn read_content(
+    file: &File,
+    content: Content,
+    size: u64,
+    offset: u64,
+    len: usize,
+    cursor: Option<Cursor>,
+) -> std::io::Result<(Vec<u8>, Option<Cursor>)> {
+    match content {
+        Content::Stored { offset: start } => {
+            let mut buf = vec![0; len];
+            read_exact_at(file, &mut buf, start + offset)?;
+            Ok((buf, None))
+        }
+        Content::Deflated {
+            offset: start,
+            compressed,
+        } => {
+            let mut cursor = cursor
+                .filter(|c| c.produced <= offset)
+                .unwrap_or_else(Cursor::new);
+            let end = offset + len as u64;
+            let mut result = Vec::with_capacity(len);
+            let mut input = vec![0; INFLATE_CHUNK];
+            let mut output = vec![0; INFLATE_CHUNK];
+            while cursor.produced < end {
+                let avail = (compressed - cursor.consumed).min(INFLATE_CHUNK as u64) as usize;
+                read_exact_at(file, &mut input[..avail], start + cursor.consumed)?;
+                let res = inflate(
+                    &mut cursor.state,
+                    &input[..avail],
+                    &mut output,
+                    MZFlush::None,
+                );
+                let from = offset.max(cursor.produced);
+                let to = end.min(cursor.produced + res.bytes_written as u64);
+                if from < to {
+                    result.extend_from_slice(
+                        &output[(from - cursor.produced) as usize..(to - cursor.produced) as usize],
+                    );
+                }
+                cursor.consumed += res.bytes_consumed as u64;
+                cursor.produced += res.bytes_written as u64;
+                match res.status {
+                    Ok(MZStatus::StreamEnd) => break,
+                    Ok(_) if res.bytes_consumed > 0 || res.bytes_written > 0 => {}
+                    _ => return Err(invalid("corrupt deflate stream")),
+                }
+            }
+            if result.len() < len || cursor.produced > size {
+                return Err(invalid("deflate stream does not match the member size"));
+            }
+            Ok((result, Some(cursor)))
+        }
+        Content::Unsupported => Err(Error::new(
+            ErrorKind::Unsupported,
+            "unsupported archive member",
+        )),
+    }
+}
+
+/// Indexed archive shared with blocking tasks
+struct Archive {
+    /// The archive file
+    file: Arc<File>,
+    /// All nodes by file ID
+    nodes: HashMap<nfs3::fileid3, Node>,
+    /// Size of the archive file
+    size: u64,
+    /// Decompression states of recently read deflated files by file ID
+    cursors: Mutex<HashMap<nfs3::fileid3, Cursor>>,
+}
+
+impl Archive {
+    /// Gets a node by file ID
+    fn node(&self, id: nfs3::fileid3) -> Result<&Node, nfs3::nfsstat3> {
+        self.nodes.get(&id).ok_or(nfs3::nfsstat3::NFS3ERR_STALE)
+    }
+
+    /// Gets a directory by file ID
+    fn dir(&self, id: nfs3::fileid3) -> Result<&Directory, nfs3::nfsstat3> {
+        match &self.node(id)?.data {
+            NodeData::Dir(dir) => Ok(dir),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+        }
+    }
+
+    /// Gets the content and size of a regular file
+    fn file(&self, id: nfs3::fileid3) -> Result<(Content, u64), nfs3::nfsstat3> {
+        let node = self.node(id)?;
+        match node.data {
+            NodeData::File(content) => Ok((content, node.attr.size)),
+            NodeData::Dir(_) => Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+            NodeData::Symlink(_) => Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+
+    /// Reads a range of a regular file, resuming a previous decompression if possible
+    fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let (content, size) = self.file(id)?;
+        let start = offset.min(size);
+        let len = (size - start).min(count as u64) as usize;
+        let eof = start + len as u64 >= size;
+        if len == 0 {
+            return Ok((Vec::new(), eof));
+        }
+        let cursor = self.cursors.lock().unwrap().remove(&id);
+        let (data, cursor) =
+            read_content(&self.file, content, size, start, len, cursor).map_err(|e| {
+                warn!("cannot read archive member {id}: {e}");
+                nfs3::nfsstat3::NFS3ERR_IO
+            })?;
+        if let Some(cursor) = cursor {
+            let mut cursors = self.cursors.lock().unwrap();
+            if cursors.len() >= MAX_CURSORS {
+                let victim = *cursors.keys().next().unwrap();
+                cursors.remove(&victim);
+            }
+            cursors.insert(id, cursor);
+        }
+        Ok((data, eof))
+    }
+}
+
+/// Read-only file system serving the contents of a tar or zip archive
+///
+/// See the [module documentation](self) for details.
+pub struct ArchiveFS {
+    /// The indexed archive
+    archive: Arc<Archive>,
+}
+
+impl std::fmt::Debug for ArchiveFS {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiveFS")
+            .field("size", &self.archive.size)
+            .field("nodes", &self.archive.nodes.len())
+            .finish()
+    }
+}
+
+impl ArchiveFS {
+    /// Opens and indexes an archive
+    ///
+    /// Zip archives are recognized by their signature; any other file is read
+    /// as a tar archive.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the tar or zip archive
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        let size = meta.len();
+        let time = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let mut tree = Tree::new(nfs3::nfstime3 {
+            seconds: time.as_secs() as u32,
+            nseconds: time.subsec_nanos(),
+        });
+
+        let mut magic = [0; 4];
+        read_exact_at(&file, &mut magic[..size.min(4) as usize], 0)?;
+        match u32::from_le_bytes(magic) {
+            ZIP_LOCAL_HEADER | ZIP_END => index_zip(&file, size, &mut tree)?,
+            _ if magic[..2] == [0x1f, 0x8b] => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "compressed tar archives are not supported",
+                ))
+            }
+            _ => index_tar(&file, &mut tree)?,
+        }
+        debug!("indexed archive with {} nodes", tree.nodes.len());
+
+        Ok(ArchiveFS {
+            archive: Arc::new(Archive {
+                file: Arc::new(file),
+                nodes: tree.nodes,
+                size,
+                cursors: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for ArchiveFS {
+    fn capabilities(&self) -> vfs::Capabilities {
+        vfs::Capabilities::ReadOnly
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        ROOT_ID
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let dir = self.archive.dir(dirid)?;
+        match filename.as_ref() {
+            b"." => Ok(dirid),
+            b".." => Ok(dir.parent),
+            name => dir
+                .entries
+                .get(name)
+                .copied()
+                .ok_or(nfs3::nfsstat3::NFS3ERR_NOENT),
+        }
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Ok(self.archive.node(id)?.attr)
+    }
+
+    async fn setattr(
+        &self,
+        _id: nfs3::fileid3,
+        _setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        // stored data is read without blocking the runtime for long, but
+        // decompression can take a while
+        let archive = self.archive.clone();
+        tokio::task::spawn_blocking(move || archive.read(id, offset, count))
+            .await
+            .unwrap_or(Err(nfs3::nfsstat3::NFS3ERR_SERVERFAULT))
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let (data, eof) = self.read(id, offset, count).await?;
+        Ok((Bytes::from(data), eof))
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        let (content, size) = self.archive.file(id)?;
+        let Content::Stored { offset: start } = content else {
+            return Ok(None);
+        };
+        let offset = offset.min(size);
+        let len = (size - offset).min(count as u64) as u32;
+        Ok(Some(vfs::FileRange {
+            file: self.archive.file.clone(),
+            offset: start + offset,
+            len,
+            eof: offset + len as u64 >= size,
+        }))
+    }
+
+    async fn write(
+        &self,
+        _id: nfs3::fileid3,
+        _offset: u64,
+        _data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn create(
+        &self,
+        _dirid: nfs3::fileid3,
+        _filename: &nfs3::filename3,
+        _attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn create_exclusive(
+        &self,
+        _dirid: nfs3::fileid3,
+        _filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn mkdir(
+        &self,
+        _dirid: nfs3::fileid3,
+        _dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn remove(
+        &self,
+        _dirid: nfs3::fileid3,
+        _filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn rename(
+        &self,
+        _from_dirid: nfs3::fileid3,
+        _from_filename: &nfs3::filename3,
+        _to_dirid: nfs3::fileid3,
+        _to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let dir = self.archive.dir(dirid)?;
+        super::readdir_by_fileid(&dir.order, start_after, max_entries, |id| {
+            Ok(self.archive.node(id)?.attr)
+        })
+    }
+
+    async fn symlink(
+        &self,
+        _dirid: nfs3::fileid3,
+        _linkname: &nfs3::filename3,
+        _symlink: &nfs3::nfspath3,
+        _attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        match &self.archive.node(id)?.data {
+            NodeData::Symlink(target) => Ok(target.as_slice().into()),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+
+    async fn link(
+        &self,
+        _file_id: nfs3::fileid3,
+        _link_dir_id: nfs3::fileid3,
+        _link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn mknod(
+        &self,
+        _dir_id: nfs3::fileid3,
+        _name: &nfs3::filename3,
+        _ftype: nfs3::ftype3,
+        _specdata: nfs3::specdata3,
+        _attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn commit(
+        &self,
+        _file_id: nfs3::fileid3,
+        _offset: u64,
+        _count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_ROFS)
+    }
+
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        Ok(nfs3::fs::FSSTAT3resok {
+            obj_attributes: nfs3::post_op_attr::attributes(self.archive.node(id)?.attr),
+            tbytes: self.archive.size,
+            fbytes: 0,
+            abytes: 0,
+            tfiles: self.archive.nodes.len() as u64,
+            ffiles: 0,
+            afiles: 0,
+            invarsec: u32::MAX,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    /// Archive file that is removed when dropped
+    struct TempArchive(PathBuf);
+
+    impl TempArchive {
+        fn new(name: &str, contents: &[u8]) -> TempArchive {
+            let path = std::env::temp_dir().join(format!(
+                "nfsserve-archive-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::write(&path, contents).unwrap();
+            TempArchive(path)
+        }
+
+        fn open(&self) -> std::io::Result<ArchiveFS> {
+            ArchiveFS::open(&self.0)
+        }
+    }
+
+    impl Drop for TempArchive {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// A stored zip member
+    struct ZipMember {
+        path: &'static str,
+        data: Vec<u8>,
+        /// Unix mode including the file type
+        mode: u32,
+        /// Size recorded in the central directory instead of the data length
+        size: Option<u32>,
+    }
+
+    impl ZipMember {
+        fn new(path: &'static str, data: &[u8], mode: u32) -> Self {
+            ZipMember {
+                path,
+                data: data.to_vec(),
+                mode,
+                size: None,
+            }
+        }
+    }
+
+    /// Builds a zip archive of stored members made by a Unix archiver
+    fn zip(members: &[ZipMember]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut dir = Vec::new();
+        for m in members {
+            let local = out.len() as u32;
+            let size = m.size.unwrap_or(m.data.len() as u32);
+            out.extend_from_slice(&ZIP_LOCAL_HEADER.to_le_bytes());
+            out.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&(m.path.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(m.path.as_bytes());
+            out.extend_from_slice(&m.data);
+
+            dir.extend_from_slice(&ZIP_CENTRAL_HEADER.to_le_bytes());
+            dir.extend_from_slice(&(3u16 << 8 | 20).to_le_bytes());
+            dir.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            dir.extend_from_slice(&size.to_le_bytes());
+            dir.extend_from_slice(&size.to_le_bytes());
+            dir.extend_from_slice(&(m.path.len() as u16).to_le_bytes());
+            dir.extend_from_slice(&[0; 8]);
+            dir.extend_from_slice(&(m.mode << 16).to_le_bytes());
+            dir.extend_from_slice(&local.to_le_bytes());
+            dir.extend_from_slice(m.path.as_bytes());
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&dir);
+        out.extend_from_slice(&ZIP_END.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(dir.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&[0; 2]);
+        out
+    }
+
+    #[tokio::test]
+    async fn zip_members_are_served() {
+        let archive = TempArchive::new(
+            "served",
+            &zip(&[
+                ZipMember::new("dir/file", b"contents", 0o100640),
+                ZipMember::new("dir/link", b"file", S_IFLNK | 0o777),
+            ]),
+        );
+        let fs = archive.open().unwrap();
+        let dir = fs.lookup(fs.root_dir(), &name("dir")).await.unwrap();
+        let file = fs.lookup(dir, &name("file")).await.unwrap();
+        let (data, eof) = fs.read(file, 0, 100).await.unwrap();
+        assert_eq!((data.as_slice(), eof), (&b"contents"[..], true));
+        assert_eq!(fs.getattr(file).await.unwrap().mode, 0o640);
+        let link = fs.lookup(dir, &name("link")).await.unwrap();
+        assert_eq!(fs.readlink(link).await.unwrap().as_ref(), b"file");
+    }
+
+    #[tokio::test]
+    async fn long_symlink_targets_are_skipped() {
+        let target = vec![b'a'; MAX_SYMLINK_LEN as usize + 1];
+        let archive = TempArchive::new(
+            "symlink",
+            &zip(&[
+                ZipMember::new("link", &target, S_IFLNK | 0o777),
+                ZipMember::new("file", b"x", 0o100644),
+            ]),
+        );
+        let fs = archive.open().unwrap();
+        assert!(fs.lookup(fs.root_dir(), &name("link")).await.is_err());
+        assert!(fs.lookup(fs.root_dir(), &name("file")).await.is_ok());
+    }
+
+    #[test]
+    fn malformed_zip_archives_are_rejected() {
+        // a symbolic link claiming a size far beyond the archive
+        let huge = ZipMember {
+            size: Some(u32::MAX - 1),
+            ..ZipMember::new("link", b"target", S_IFLNK | 0o777)
+        };
+        let archive = TempArchive::new("huge", &zip(&[huge]));
+        let err = archive.open().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let valid = zip(&[ZipMember::new("file", b"contents", 0o100644)]);
+        // central directory offset beyond the end
+        let mut bad = valid.clone();
+        let at = bad.len() - 6;
+        bad[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(TempArchive::new("offset", &bad).open().is_err());
+        // local header offset beyond the end
+        let mut bad = valid.clone();
+        let central = valid.len() - 22 - (46 + 4);
+        bad[central + 42..central + 46].copy_from_slice(&0x7fff_0000u32.to_le_bytes());
+        let err = TempArchive::new("local", &bad).open().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        // more entries than the central directory holds
+        let mut bad = valid.clone();
+        let at = bad.len() - 12;
+        bad[at..at + 2].copy_from_slice(&2u16.to_le_bytes());
+        assert!(TempArchive::new("entries", &bad).open().is_err());
+        // truncated before the end record is complete
+        let bad = &valid[..valid.len() - 4];
+        assert!(TempArchive::new("truncated", bad).open().is_err());
+    }
+
+    #[tokio::test]
+    async fn hard_links_are_listed_on_one_page() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        builder.append_data(&mut header, "a", &b"data"[..]).unwrap();
+        for link in ["b", "c"] {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(0);
+            header.set_entry_type(tar::EntryType::Link);
+            builder.append_link(&mut header, link, "a").unwrap();
+        }
+        let archive = TempArchive::new("links", &builder.into_inner().unwrap());
+        let fs = archive.open().unwrap();
+        let root = fs.root_dir();
+
+        let page = fs.readdir(root, 0, 1).await.unwrap();
+        assert_eq!(page.entries.len(), 3);
+        assert!(page.end);
+        assert!(page.entries.iter().all(|e| e.attr.nlink == 3));
+        let id = page.entries[0].fileid;
+        let (data, _) = fs
+            .read(fs.lookup(root, &name("c")).await.unwrap(), 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(data, b"data");
+        let page = fs.readdir(root, id, 10).await.unwrap();
+        assert!(page.entries.is_empty() && page.end);
+        let page = fs.readdir(root, u64::MAX, 10).await.unwrap();
+        assert!(page.entries.is_empty() && page.end);
+    }
+}
@@ -0,0 +1,2247 @@
+//! Deduplicating file system backed by a local chunk store.
+//!
+//! `DedupFS` stores file contents as content-defined chunks:
+//! - File data is split at positions chosen by a rolling hash over the data itself,
+//!   so identical data yields identical chunks regardless of its offset, and a
+//!   change only affects the chunks around it
+//! - Every chunk is stored once in the chunk store, named by its SHA-256 hash, and
+//!   files keep the list of chunks they consist of
+//! - Hard links share the chunk list of a file, and copies reuse the chunks of the
+//!   original, so neither needs new chunk data
+//! - Holes that were never written read as zeros and take no space
+//!
+//! `used` in the attributes of a file is its share of the chunk store: every chunk
+//! counts with its size divided by the number of references to it, so the values
+//! of all files add up to the size of the store.
+//!
+//! Chunks that are no longer referenced stay in the store until the garbage
+//! collector removes them, periodically or through [`DedupFS::collect_garbage`].
+//!
+//! Everything but the chunks is kept in memory and persisted in a journal next to
+//! the chunk store, which is replayed and compacted when the file system is opened
+//! and whenever garbage is collected. Changes reach the journal before they are
+//! acknowledged; new chunks and the journal are synced to disk for stable writes
+//! and COMMIT. File handles remain valid across restarts.
+//!
+//! Requires the `dedup` feature.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use num_traits::cast::FromPrimitive;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// File ID of the root directory
+const ROOT_ID: nfs3::fileid3 = 1;
+
+/// Maximum length of a file name in bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// First word of a journal file
+const JOURNAL_MAGIC: &str = "nfsserve-dedup-v1";
+
+/// Maximum number of following chunks split again after a write to find back to
+/// the existing chunk boundaries
+const MAX_RESYNC: usize = 16;
+
+/// SHA-256 hash naming a chunk
+type Hash = [u8; 32];
+
+/// Options of a [`DedupFS`]
+///
+/// Chunk sizes can be changed for an existing store, but data written with other
+/// sizes is chunked differently and does not deduplicate against new data.
+#[derive(Debug, Clone)]
+pub struct DedupOptions {
+    /// Minimum size of a chunk in bytes
+    pub min_chunk: u32,
+    /// Average size of a chunk in bytes; a power of two
+    pub avg_chunk: u32,
+    /// Maximum size of a chunk in bytes
+    pub max_chunk: u32,
+    /// Interval of the background garbage collection, or `None` to only collect
+    /// garbage on request
+    pub gc_interval: Option<Duration>,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        DedupOptions {
+            min_chunk: 16 * 1024,
+            avg_chunk: 64 * 1024,
+            max_chunk: 256 * 1024,
+            gc_interval: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
+/// Outcome of a garbage collection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GcStats {
+    /// Number of chunks removed from the store
+    pub chunks: u64,
+    /// Total size of the removed chunks in bytes
+    pub bytes: u64,
+}
+
+/// Random values the rolling hash mixes in for every byte
+///
+/// Part of the chunking format: changing them changes all chunk boundaries.
+const GEAR: [u64; 256] = gear_table();
+
+/// Generates the gear table with SplitMix64 from a fixed seed
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6e66_7373_6572_7665;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Content-defined chunking with a gear rolling hash
+///
+/// Boundaries are harder to hit before the average size and easier after it, which
+/// keeps chunk sizes close to the average.
+#[derive(Debug, Clone, Copy)]
+struct Chunker {
+    /// Minimum chunk size
+    min: usize,
+    /// Average chunk size
+    avg: usize,
+    /// Maximum chunk size
+    max: usize,
+    /// Hash bits that must be zero for a boundary before the average size
+    mask_small: u64,
+    /// Hash bits that must be zero for a boundary after the average size
+    mask_large: u64,
+}
+
+impl Chunker {
+    /// Creates a chunker for the chunk sizes of the options
+    fn new(options: &DedupOptions) -> Self {
+        let bits = options.avg_chunk.trailing_zeros();
+        Chunker {
+            min: options.min_chunk as usize,
+            avg: options.avg_chunk as usize,
+            max: options.max_chunk as usize,
+            mask_small: !0 << (64 - (bits + 1)),
+            mask_large: !0 << (64 - (bits - 1)),
+        }
+    }
+
+    /// Returns the length of the first chunk of `data`, or `None` if `data` ends
+    /// before the next boundary
+    fn cut(&self, data: &[u8]) -> Option<usize> {
+        let end = data.len().min(self.max);
+        let mut hash = 0u64;
+        for (i, &b) in data.iter().enumerate().take(end).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+            let mask = if i < self.avg {
+                self.mask_small
+            } else {
+                self.mask_large
+            };
+            if hash & mask == 0 {
+                return Some(i + 1);
+            }
+        }
+        (data.len() >= self.max).then_some(self.max)
+    }
+}
+
+/// A chunk of a file
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    /// Length of the chunk
+    len: u32,
+    /// Hash of the chunk data
+    hash: Hash,
+    /// Whether the chunk ends before a boundary, at the end of the data written so
+    /// far; the next write after it splits it again
+    open: bool,
+}
+
+/// Data of a regular file
+#[derive(Debug, Default)]
+struct FileData {
+    /// Chunks by offset; gaps between them are holes
+    extents: BTreeMap<u64, Extent>,
+    /// Share of the chunk store as (reference count generation, bytes)
+    used: Mutex<Option<(u64, u64)>>,
+    /// Time of the last read, newer than the access time in the attributes; kept
+    /// apart so that reads only need the state locked for reading
+    accessed: Mutex<Option<nfs3::nfstime3>>,
+}
+
+impl Node {
+    /// Returns the attributes of the node with the time of its last read applied
+    fn current_attr(&self) -> nfs3::fattr3 {
+        let mut attr = self.attr;
+        if let NodeData::File(file) = &self.data {
+            if let Some(atime) = *file.accessed.lock().unwrap() {
+                attr.atime = atime;
+            }
+        }
+        attr
+    }
+}
+
+/// Contents of a file system node
+#[derive(Debug)]
+enum NodeData {
+    /// Regular file data
+    File(FileData),
+    /// Directory
+    Dir(Directory),
+    /// Symbolic link target
+    Symlink(Vec<u8>),
+    /// Device, FIFO or socket; the type and device numbers live in the attributes
+    Special,
+}
+
+/// Directory contents
+#[derive(Debug, Default)]
+struct Directory {
+    /// Parent directory ID (the root is its own parent)
+    parent: nfs3::fileid3,
+    /// Entries by name
+    entries: HashMap<Vec<u8>, nfs3::fileid3>,
+    /// Entries in listing order; the file ID doubles as the directory cookie
+    order: BTreeSet<(nfs3::fileid3, Vec<u8>)>,
+}
+
+impl Directory {
+    /// Adds an entry to the directory
+    fn insert(&mut self, name: &[u8], id: nfs3::fileid3) {
+        self.entries.insert(name.to_vec(), id);
+        self.order.insert((id, name.to_vec()));
+    }
+
+    /// Removes an entry from the directory, returning its file ID
+    fn remove(&mut self, name: &[u8]) -> Option<nfs3::fileid3> {
+        let id = self.entries.remove(name)?;
+        self.order.remove(&(id, name.to_vec()));
+        Some(id)
+    }
+}
+
+/// A file system node (inode)
+#[derive(Debug)]
+struct Node {
+    /// Attributes of the node; `used` of files is computed on demand
+    attr: nfs3::fattr3,
+    /// Contents of the node
+    data: NodeData,
+}
+
+/// A chunk in the store
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    /// Length of the chunk
+    len: u32,
+    /// Number of file extents referring to the chunk
+    refs: u64,
+}
+
+/// Mutable state of the file system
+#[derive(Debug)]
+struct State {
+    /// All nodes by file ID
+    nodes: HashMap<nfs3::fileid3, Node>,
+    /// Next file ID to allocate
+    next_id: nfs3::fileid3,
+    /// Referenced chunks by hash
+    chunks: HashMap<Hash, Chunk>,
+    /// Incremented whenever a reference count changes
+    generation: u64,
+    /// Nodes whose attributes changed since the journal was last written
+    dirty: BTreeSet<nfs3::fileid3>,
+    /// Journal lines for changes since the journal was last written
+    pending: String,
+}
+
+/// Returns the current time as an NFS timestamp
+fn now() -> nfs3::nfstime3 {
+    let d = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    nfs3::nfstime3 {
+        seconds: d.as_secs() as u32,
+        nseconds: d.subsec_nanos(),
+    }
+}
+
+/// Returns a new file handle generation number based on the current time
+fn new_generation() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Encodes bytes as lowercase hex
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes lowercase or uppercase hex
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Returns the hash of chunk data
+fn hash(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+/// Converts a chunk store I/O error to an NFS status
+fn io_error(e: std::io::Error) -> nfs3::nfsstat3 {
+    warn!("Chunk store error: {:?}", e);
+    match e.kind() {
+        ErrorKind::StorageFull => nfs3::nfsstat3::NFS3ERR_NOSPC,
+        _ => nfs3::nfsstat3::NFS3ERR_IO,
+    }
+}
+
+/// Checks that a name can be used for a new directory entry
+fn check_name(name: &[u8]) -> Result<(), nfs3::nfsstat3> {
+    if name.is_empty() || name.contains(&b'/') || name.contains(&0) {
+        return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+    }
+    if name == b"." || name == b".." {
+        return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(nfs3::nfsstat3::NFS3ERR_NAMETOOLONG);
+    }
+    Ok(())
+}
+
+/// Returns the attributes of a new node
+fn new_attr(id: nfs3::fileid3, ftype: nfs3::ftype3, mode: nfs3::mode3) -> nfs3::fattr3 {
+    let time = now();
+    nfs3::fattr3 {
+        ftype,
+        mode,
+        nlink: if matches!(ftype, nfs3::ftype3::NF3DIR) {
+            2
+        } else {
+            1
+        },
+        uid: 0,
+        gid: 0,
+        size: 0,
+        used: 0,
+        rdev: nfs3::specdata3::default(),
+        fsid: 0,
+        fileid: id,
+        atime: time,
+        mtime: time,
+        ctime: time,
+    }
+}
+
+impl State {
+    /// Creates a state holding only an empty root directory
+    fn new() -> Self {
+        let root = Node {
+            attr: new_attr(ROOT_ID, nfs3::ftype3::NF3DIR, 0o755),
+            data: NodeData::Dir(Directory {
+                parent: ROOT_ID,
+                ..Default::default()
+            }),
+        };
+        State {
+            nodes: HashMap::from([(ROOT_ID, root)]),
+            next_id: ROOT_ID + 1,
+            chunks: HashMap::new(),
+            generation: 0,
+            dirty: BTreeSet::new(),
+            pending: String::new(),
+        }
+    }
+
+    /// Gets a node by file ID
+    fn node(&self, id: nfs3::fileid3) -> Result<&Node, nfs3::nfsstat3> {
+        self.nodes.get(&id).ok_or(nfs3::nfsstat3::NFS3ERR_STALE)
+    }
+
+    /// Gets a mutable node by file ID
+    fn node_mut(&mut self, id: nfs3::fileid3) -> Result<&mut Node, nfs3::nfsstat3> {
+        self.nodes.get_mut(&id).ok_or(nfs3::nfsstat3::NFS3ERR_STALE)
+    }
+
+    /// Gets a directory by file ID
+    fn dir(&self, id: nfs3::fileid3) -> Result<&Directory, nfs3::nfsstat3> {
+        match &self.node(id)?.data {
+            NodeData::Dir(dir) => Ok(dir),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+        }
+    }
+
+    /// Gets a mutable directory by file ID
+    fn dir_mut(&mut self, id: nfs3::fileid3) -> Result<&mut Directory, nfs3::nfsstat3> {
+        match &mut self.node_mut(id)?.data {
+            NodeData::Dir(dir) => Ok(dir),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+        }
+    }
+
+    /// Gets the data of a regular file by file ID
+    fn file(&self, id: nfs3::fileid3) -> Result<&FileData, nfs3::nfsstat3> {
+        match &self.node(id)?.data {
+            NodeData::File(file) => Ok(file),
+            NodeData::Dir(_) => Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+
+    /// Returns the attributes of a node with the share of the chunk store filled in
+    fn attr(&self, node: &Node) -> nfs3::fattr3 {
+        let mut attr = node.current_attr();
+        if let NodeData::File(file) = &node.data {
+            let mut used = file.used.lock().unwrap();
+            attr.used = match *used {
+                Some((generation, bytes)) if generation == self.generation => bytes,
+                _ => {
+                    let bytes = file
+                        .extents
+                        .values()
+                        .map(|e| match self.chunks.get(&e.hash) {
+                            Some(chunk) => chunk.len as u64 / chunk.refs.max(1),
+                            None => 0,
+                        })
+                        .sum();
+                    *used = Some((self.generation, bytes));
+                    bytes
+                }
+            };
+        }
+        attr
+    }
+
+    /// Gets the attributes of a node by file ID
+    fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Ok(self.attr(self.node(id)?))
+    }
+
+    /// Returns the weak cache consistency attributes of a node, if it exists
+    fn pre_attr(&self, id: nfs3::fileid3) -> nfs3::pre_op_attr {
+        match self.getattr(id) {
+            Ok(attr) => nfs3::pre_op_attr::attributes(attr.into()),
+            Err(_) => nfs3::pre_op_attr::Void,
+        }
+    }
+
+    /// Returns the attributes of a node, if it exists
+    fn post_attr(&self, id: nfs3::fileid3) -> nfs3::post_op_attr {
+        match self.getattr(id) {
+            Ok(attr) => nfs3::post_op_attr::attributes(attr),
+            Err(_) => nfs3::post_op_attr::Void,
+        }
+    }
+
+    /// Marks a directory as modified
+    fn touch_dir(&mut self, id: nfs3::fileid3, time: nfs3::nfstime3) {
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.attr.mtime = time;
+            node.attr.ctime = time;
+            if let NodeData::Dir(dir) = &node.data {
+                node.attr.size = dir.entries.len() as u64;
+            }
+            self.dirty.insert(id);
+        }
+    }
+
+    /// Looks up a name in a directory, including "." and ".."
+    fn lookup(&self, dirid: nfs3::fileid3, name: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let dir = self.dir(dirid)?;
+        match name {
+            b"." => Ok(dirid),
+            b".." => Ok(dir.parent),
+            _ => dir
+                .entries
+                .get(name)
+                .copied()
+                .ok_or(nfs3::nfsstat3::NFS3ERR_NOENT),
+        }
+    }
+
+    /// Adds a directory entry and records it in the journal
+    fn insert_entry(
+        &mut self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        id: nfs3::fileid3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.dir_mut(dirid)?.insert(name, id);
+        let line = format!("E {} {} {}\n", dirid, id, hex_encode(name));
+        self.pending.push_str(&line);
+        Ok(())
+    }
+
+    /// Removes a directory entry and records it in the journal
+    fn remove_entry(&mut self, dirid: nfs3::fileid3, name: &[u8]) -> Result<(), nfs3::nfsstat3> {
+        self.dir_mut(dirid)?.remove(name);
+        let line = format!("U {} {}\n", dirid, hex_encode(name));
+        self.pending.push_str(&line);
+        Ok(())
+    }
+
+    /// Creates a new node in a directory
+    fn add_node(
+        &mut self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        ftype: nfs3::ftype3,
+        mode: nfs3::mode3,
+        data: NodeData,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        check_name(name)?;
+        let dir = self.dir(dirid)?;
+        if dir.entries.contains_key(name) {
+            return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+        }
+        let (uid, gid) = {
+            let parent = &self.node(dirid)?.attr;
+            (parent.uid, parent.gid)
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        let is_dir = matches!(data, NodeData::Dir(_));
+        let mut attr = new_attr(id, ftype, mode);
+        attr.uid = uid;
+        attr.gid = gid;
+        if let NodeData::Symlink(target) = &data {
+            attr.size = target.len() as u64;
+            attr.used = attr.size;
+            let line = format!("L {} {}\n", id, hex_encode(target));
+            self.pending.push_str(&line);
+        }
+        self.nodes.insert(id, Node { attr, data });
+        self.dirty.insert(id);
+        self.insert_entry(dirid, name, id)?;
+        if is_dir {
+            self.node_mut(dirid)?.attr.nlink += 1;
+        }
+        self.touch_dir(dirid, attr.ctime);
+        Ok((id, attr))
+    }
+
+    /// Sets or removes the chunk of a file at an offset, maintaining reference counts
+    fn set_extent(&mut self, id: nfs3::fileid3, start: u64, extent: Option<Extent>) {
+        let Some(Node {
+            data: NodeData::File(file),
+            ..
+        }) = self.nodes.get_mut(&id)
+        else {
+            return;
+        };
+        let old = match extent {
+            Some(extent) => {
+                let line = format!(
+                    "C {} {} {} {} {}\n",
+                    id,
+                    start,
+                    extent.len,
+                    hex_encode(&extent.hash),
+                    extent.open as u8
+                );
+                self.pending.push_str(&line);
+                let chunk = self.chunks.entry(extent.hash).or_insert(Chunk {
+                    len: extent.len,
+                    refs: 0,
+                });
+                chunk.refs += 1;
+                file.extents.insert(start, extent)
+            }
+            None => {
+                let old = file.extents.remove(&start);
+                if old.is_some() {
+                    self.pending.push_str(&format!("R {} {}\n", id, start));
+                }
+                old
+            }
+        };
+        if let Some(old) = old {
+            release(&mut self.chunks, &old.hash);
+        }
+        self.generation += 1;
+    }
+
+    /// Drops one link to a node, freeing it when no links remain
+    fn unlink_node(&mut self, id: nfs3::fileid3, time: nfs3::nfstime3) {
+        let Some(node) = self.nodes.get_mut(&id) else {
+            return;
+        };
+        if !matches!(node.data, NodeData::Dir(_)) {
+            node.attr.nlink = node.attr.nlink.saturating_sub(1);
+            node.attr.ctime = time;
+            if node.attr.nlink > 0 {
+                self.dirty.insert(id);
+                return;
+            }
+        }
+        if let Some(Node {
+            data: NodeData::File(file),
+            ..
+        }) = self.nodes.remove(&id)
+        {
+            for extent in file.extents.values() {
+                release(&mut self.chunks, &extent.hash);
+            }
+            self.generation += 1;
+        }
+        self.dirty.remove(&id);
+        self.pending.push_str(&format!("X {}\n", id));
+    }
+
+    /// Removes a directory entry of the given kind
+    fn remove(
+        &mut self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        kind: vfs::RemoveKind,
+    ) -> Result<(), nfs3::nfsstat3> {
+        if name == b"." || name == b".." {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        let id = self.lookup(dirid, name)?;
+        let node = self.node(id)?;
+        kind.check(matches!(node.data, NodeData::Dir(_)))?;
+        let is_dir = match &node.data {
+            NodeData::Dir(dir) if !dir.entries.is_empty() => {
+                return Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY)
+            }
+            NodeData::Dir(_) => true,
+            _ => false,
+        };
+        self.remove_entry(dirid, name)?;
+        let time = now();
+        if is_dir {
+            let parent = self.node_mut(dirid)?;
+            parent.attr.nlink = parent.attr.nlink.saturating_sub(1);
+        }
+        self.touch_dir(dirid, time);
+        self.unlink_node(id, time);
+        Ok(())
+    }
+
+    /// Checks if `id` is `ancestor` or lies below it
+    fn is_descendant(&self, mut id: nfs3::fileid3, ancestor: nfs3::fileid3) -> bool {
+        loop {
+            if id == ancestor {
+                return true;
+            }
+            match self.dir(id) {
+                Ok(dir) if dir.parent != id => id = dir.parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Renames a directory entry, replacing the target if allowed
+    fn rename(
+        &mut self,
+        from_dirid: nfs3::fileid3,
+        from_name: &[u8],
+        to_dirid: nfs3::fileid3,
+        to_name: &[u8],
+    ) -> Result<(), nfs3::nfsstat3> {
+        if from_name == b"." || from_name == b".." {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        check_name(to_name)?;
+        let id = self.lookup(from_dirid, from_name)?;
+        self.dir(to_dirid)?;
+        let moving_dir = matches!(self.node(id)?.data, NodeData::Dir(_));
+        if moving_dir && self.is_descendant(to_dirid, id) {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        if let Some(&target) = self.dir(to_dirid)?.entries.get(to_name) {
+            if target == id {
+                // both names refer to the same file: nothing to do
+                return Ok(());
+            }
+            match (&self.node(target)?.data, moving_dir) {
+                (NodeData::Dir(dir), true) if !dir.entries.is_empty() => {
+                    return Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY)
+                }
+                (NodeData::Dir(_), false) => return Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+                (_, true) if !matches!(self.node(target)?.data, NodeData::Dir(_)) => {
+                    return Err(nfs3::nfsstat3::NFS3ERR_NOTDIR)
+                }
+                _ => {}
+            }
+            self.remove(to_dirid, to_name, vfs::RemoveKind::Any)?;
+        }
+        let time = now();
+        self.remove_entry(from_dirid, from_name)?;
+        self.insert_entry(to_dirid, to_name, id)?;
+        if moving_dir && from_dirid != to_dirid {
+            self.dir_mut(id)?.parent = to_dirid;
+            let from = self.node_mut(from_dirid)?;
+            from.attr.nlink = from.attr.nlink.saturating_sub(1);
+            self.node_mut(to_dirid)?.attr.nlink += 1;
+        }
+        self.node_mut(id)?.attr.ctime = time;
+        self.dirty.insert(id);
+        self.touch_dir(from_dirid, time);
+        self.touch_dir(to_dirid, time);
+        Ok(())
+    }
+
+    /// Creates a hard link to a non-directory node
+    fn link(
+        &mut self,
+        id: nfs3::fileid3,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        check_name(name)?;
+        if matches!(self.node(id)?.data, NodeData::Dir(_)) {
+            return Err(nfs3::nfsstat3::NFS3ERR_ISDIR);
+        }
+        if self.dir(dirid)?.entries.contains_key(name) {
+            return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+        }
+        let time = now();
+        self.insert_entry(dirid, name, id)?;
+        self.touch_dir(dirid, time);
+        let node = self.node_mut(id)?;
+        node.attr.nlink += 1;
+        node.attr.ctime = time;
+        self.dirty.insert(id);
+        self.getattr(id)
+    }
+
+    /// Applies attribute changes other than the size of a regular file to a node
+    fn setattr(
+        &mut self,
+        id: nfs3::fileid3,
+        setattr: &nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let node = self.node_mut(id)?;
+        let time = now();
+        if let nfs3::set_size3::size(_) = setattr.size {
+            match &node.data {
+                NodeData::File(_) => {}
+                NodeData::Dir(_) => return Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+                _ => return Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+            }
+        }
+        if let nfs3::set_mode3::mode(mode) = setattr.mode {
+            node.attr.mode = mode & 0o7777;
+        }
+        if let nfs3::set_uid3::uid(uid) = setattr.uid {
+            node.attr.uid = uid;
+        }
+        if let nfs3::set_gid3::gid(gid) = setattr.gid {
+            node.attr.gid = gid;
+        }
+        match setattr.atime {
+            nfs3::set_atime::DONT_CHANGE => {}
+            nfs3::set_atime::SET_TO_SERVER_TIME => node.attr.atime = time,
+            nfs3::set_atime::SET_TO_CLIENT_TIME(t) => node.attr.atime = t,
+        }
+        if !matches!(setattr.atime, nfs3::set_atime::DONT_CHANGE) {
+            if let NodeData::File(file) = &mut node.data {
+                *file.accessed.get_mut().unwrap() = None;
+            }
+        }
+        match setattr.mtime {
+            nfs3::set_mtime::DONT_CHANGE => {}
+            nfs3::set_mtime::SET_TO_SERVER_TIME => node.attr.mtime = time,
+            nfs3::set_mtime::SET_TO_CLIENT_TIME(t) => node.attr.mtime = t,
+        }
+        node.attr.ctime = time;
+        self.dirty.insert(id);
+        self.getattr(id)
+    }
+
+    /// Returns the journal lines for all changes since the last call
+    fn take_journal(&mut self) -> String {
+        let mut text = String::new();
+        // attributes first, so that entries and chunks refer to existing nodes
+        for id in std::mem::take(&mut self.dirty) {
+            if let Some(node) = self.nodes.get(&id) {
+                text.push_str(&attr_line(&node.current_attr()));
+            }
+        }
+        text.push_str(&std::mem::take(&mut self.pending));
+        text
+    }
+
+    /// Returns journal lines describing the whole state
+    fn snapshot(&self) -> String {
+        let mut text = String::new();
+        let mut ids: Vec<_> = self.nodes.keys().copied().collect();
+        ids.sort_unstable();
+        for id in &ids {
+            text.push_str(&attr_line(&self.nodes[id].current_attr()));
+        }
+        for id in ids {
+            match &self.nodes[&id].data {
+                NodeData::File(file) => {
+                    for (start, e) in &file.extents {
+                        let line = format!(
+                            "C {} {} {} {} {}\n",
+                            id,
+                            start,
+                            e.len,
+                            hex_encode(&e.hash),
+                            e.open as u8
+                        );
+                        text.push_str(&line);
+                    }
+                }
+                NodeData::Dir(dir) => {
+                    for (child, name) in &dir.order {
+                        text.push_str(&format!("E {} {} {}\n", id, child, hex_encode(name)));
+                    }
+                }
+                NodeData::Symlink(target) => {
+                    text.push_str(&format!("L {} {}\n", id, hex_encode(target)));
+                }
+                NodeData::Special => {}
+            }
+        }
+        text
+    }
+
+    /// Applies one journal line, returning `None` if it is malformed
+    fn replay(&mut self, line: &str) -> Option<()> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            ["I", id, ftype, mode, uid, gid, size, rdev1, rdev2, at, an, mt, mn, ct, cn] => {
+                let id: nfs3::fileid3 = id.parse().ok()?;
+                let time = |s: &str, n: &str| -> Option<nfs3::nfstime3> {
+                    Some(nfs3::nfstime3 {
+                        seconds: s.parse().ok()?,
+                        nseconds: n.parse().ok()?,
+                    })
+                };
+                let ftype = nfs3::ftype3::from_u32(ftype.parse().ok()?)?;
+                let mut attr = new_attr(id, ftype, mode.parse().ok()?);
+                attr.uid = uid.parse().ok()?;
+                attr.gid = gid.parse().ok()?;
+                attr.size = size.parse().ok()?;
+                attr.rdev = nfs3::specdata3 {
+                    specdata1: rdev1.parse().ok()?,
+                    specdata2: rdev2.parse().ok()?,
+                };
+                attr.atime = time(at, an)?;
+                attr.mtime = time(mt, mn)?;
+                attr.ctime = time(ct, cn)?;
+                self.next_id = self.next_id.max(id + 1);
+                match self.nodes.get_mut(&id) {
+                    Some(node) => node.attr = attr,
+                    None => {
+                        let data = match ftype {
+                            nfs3::ftype3::NF3REG => NodeData::File(FileData::default()),
+                            nfs3::ftype3::NF3DIR => NodeData::Dir(Directory::default()),
+                            nfs3::ftype3::NF3LNK => NodeData::Symlink(Vec::new()),
+                            _ => NodeData::Special,
+                        };
+                        self.nodes.insert(id, Node { attr, data });
+                    }
+                }
+            }
+            ["E", dir, id, name] => {
+                let (dir, id, name) = (dir.parse().ok()?, id.parse().ok()?, hex_decode(name)?);
+                self.dir_mut(dir).ok()?.insert(&name, id);
+            }
+            ["U", dir, name] => {
+                let (dir, name) = (dir.parse().ok()?, hex_decode(name)?);
+                self.dir_mut(dir).ok()?.remove(&name);
+            }
+            ["X", id] => {
+                self.nodes.remove(&id.parse().ok()?);
+            }
+            ["L", id, target] => {
+                let target = hex_decode(target)?;
+                match &mut self.nodes.get_mut(&id.parse().ok()?)?.data {
+                    NodeData::Symlink(t) => *t = target,
+                    _ => return None,
+                }
+            }
+            ["C", id, start, len, hash, open] => {
+                let extent = Extent {
+                    len: len.parse().ok()?,
+                    hash: hex_decode(hash)?.try_into().ok()?,
+                    open: open == "1",
+                };
+                match &mut self.nodes.get_mut(&id.parse().ok()?)?.data {
+                    NodeData::File(file) => file.extents.insert(start.parse().ok()?, extent),
+                    _ => return None,
+                };
+            }
+            ["R", id, start] => match &mut self.nodes.get_mut(&id.parse().ok()?)?.data {
+                NodeData::File(file) => {
+                    file.extents.remove(&start.parse().ok()?);
+                }
+                _ => return None,
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Derives what the journal does not record after replaying it
+    ///
+    /// Restores parents, link counts, directory sizes and reference counts, and
+    /// drops nodes that cannot be reached from the root, which a crash between two
+    /// journal writes can leave behind.
+    fn finish_load(&mut self) {
+        if !matches!(
+            self.nodes.get(&ROOT_ID).map(|n| &n.data),
+            Some(NodeData::Dir(_))
+        ) {
+            warn!("Journal has no root directory, starting over");
+            *self = State::new();
+            return;
+        }
+        let mut reachable = HashSet::from([ROOT_ID]);
+        let mut parents = HashMap::from([(ROOT_ID, ROOT_ID)]);
+        let mut queue = vec![ROOT_ID];
+        let mut dropped = Vec::new();
+        while let Some(dirid) = queue.pop() {
+            let NodeData::Dir(dir) = &self.nodes[&dirid].data else {
+                continue;
+            };
+            for (child, name) in &dir.order {
+                match self.nodes.get(child).map(|n| &n.data) {
+                    None => dropped.push((dirid, name.clone())),
+                    // a directory can only have one parent
+                    Some(NodeData::Dir(_)) if parents.contains_key(child) => {
+                        dropped.push((dirid, name.clone()))
+                    }
+                    Some(NodeData::Dir(_)) => {
+                        parents.insert(*child, dirid);
+                        reachable.insert(*child);
+                        queue.push(*child);
+                    }
+                    Some(_) => {
+                        reachable.insert(*child);
+                    }
+                }
+            }
+        }
+        for (dirid, name) in dropped {
+            if let Ok(dir) = self.dir_mut(dirid) {
+                dir.remove(&name);
+            }
+        }
+        self.nodes.retain(|id, _| reachable.contains(id));
+
+        let mut links: HashMap<nfs3::fileid3, u32> = HashMap::new();
+        for node in self.nodes.values() {
+            if let NodeData::Dir(dir) = &node.data {
+                for child in dir.entries.values() {
+                    *links.entry(*child).or_default() += 1;
+                }
+            }
+        }
+        let mut subdirs: HashMap<nfs3::fileid3, u32> = HashMap::new();
+        for (id, parent) in &parents {
+            if id != parent {
+                *subdirs.entry(*parent).or_default() += 1;
+            }
+        }
+        self.chunks.clear();
+        for (id, node) in self.nodes.iter_mut() {
+            match &mut node.data {
+                NodeData::Dir(dir) => {
+                    dir.parent = parents[id];
+                    node.attr.nlink = 2 + subdirs.get(id).copied().unwrap_or(0);
+                    node.attr.size = dir.entries.len() as u64;
+                }
+                NodeData::File(file) => {
+                    node.attr.nlink = links.get(id).copied().unwrap_or(0);
+                    file.extents.split_off(&node.attr.size);
+                    for extent in file.extents.values() {
+                        let chunk = self.chunks.entry(extent.hash).or_insert(Chunk {
+                            len: extent.len,
+                            refs: 0,
+                        });
+                        chunk.refs += 1;
+                    }
+                }
+                _ => node.attr.nlink = links.get(id).copied().unwrap_or(0),
+            }
+        }
+    }
+}
+
+/// Drops one reference to a chunk
+fn release(chunks: &mut HashMap<Hash, Chunk>, hash: &Hash) {
+    if let Some(chunk) = chunks.get_mut(hash) {
+        chunk.refs -= 1;
+        if chunk.refs == 0 {
+            chunks.remove(hash);
+        }
+    }
+}
+
+/// Returns the journal line recording the attributes of a node
+fn attr_line(attr: &nfs3::fattr3) -> String {
+    format!(
+        "I {} {} {} {} {} {} {} {} {} {} {} {} {} {}\n",
+        attr.fileid,
+        attr.ftype as u32,
+        attr.mode,
+        attr.uid,
+        attr.gid,
+        attr.size,
+        attr.rdev.specdata1,
+        attr.rdev.specdata2,
+        attr.atime.seconds,
+        attr.atime.nseconds,
+        attr.mtime.seconds,
+        attr.mtime.nseconds,
+        attr.ctime.seconds,
+        attr.ctime.nseconds
+    )
+}
+
+/// Per-file locks serializing writes and truncations of a file
+type FileLock = Arc<tokio::sync::Mutex<()>>;
+
+/// State shared with blocking tasks and the garbage collector
+#[derive(Debug)]
+struct Shared {
+    /// Directory holding the chunks
+    chunk_dir: PathBuf,
+    /// Path of the journal
+    journal_path: PathBuf,
+    /// File system settings
+    options: DedupOptions,
+    /// Chunk boundary detection
+    chunker: Chunker,
+    /// File handle generation
+    generation: u64,
+    /// Namespace and chunk lists
+    state: RwLock<State>,
+    /// Journal being appended to
+    journal: Mutex<BufWriter<File>>,
+    /// Held shared by operations that store or read chunks, and exclusively by the
+    /// garbage collector
+    gc: tokio::sync::RwLock<()>,
+    /// Whether the background garbage collection is running
+    gc_started: AtomicBool,
+    /// Locks of files being written by file ID
+    locks: Mutex<HashMap<nfs3::fileid3, FileLock>>,
+    /// Chunks written by unstable writes and not yet synced, by file ID
+    unsynced: Mutex<HashMap<nfs3::fileid3, HashSet<Hash>>>,
+    /// Counter making temporary chunk file names unique
+    tmp_counter: AtomicU64,
+}
+
+impl Shared {
+    /// Returns the path of a chunk in the store
+    fn chunk_path(&self, hash: &Hash) -> PathBuf {
+        let name = hex_encode(hash);
+        self.chunk_dir.join(&name[..2]).join(name)
+    }
+
+    /// Stores a chunk unless the store already has it
+    ///
+    /// A chunk already in the store is only synced if an unstable write stored it
+    /// and no COMMIT synced it since.
+    fn store_chunk(&self, hash: &Hash, data: &[u8], sync: bool) -> std::io::Result<()> {
+        let path = self.chunk_path(hash);
+        if path.exists() {
+            let unsynced = || {
+                let unsynced = self.unsynced.lock().unwrap();
+                unsynced.values().any(|hashes| hashes.contains(hash))
+            };
+            if sync && unsynced() {
+                File::open(&path)?.sync_all()?;
+            }
+            return Ok(());
+        }
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let n = self.tmp_counter.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = path.with_extension(format!("{}.tmp", n));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        if sync {
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &path)
+    }
+
+    /// Reads part of a chunk from the store
+    fn read_chunk(&self, hash: &Hash, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut file = File::open(self.chunk_path(hash))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; len];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Writes the journal lines of the changes made to the state
+    fn persist(&self, state: &mut State, sync: bool) -> Result<(), nfs3::nfsstat3> {
+        let text = state.take_journal();
+        let mut journal = self.journal.lock().unwrap();
+        let result = journal
+            .write_all(text.as_bytes())
+            .and_then(|_| journal.flush());
+        let result = result.and_then(|_| match sync {
+            true => journal.get_ref().sync_data(),
+            false => Ok(()),
+        });
+        result.map_err(|e| {
+            warn!("Failed to write journal: {:?}", e);
+            nfs3::nfsstat3::NFS3ERR_IO
+        })
+    }
+
+    /// Rewrites the journal as a snapshot of the state
+    fn compact(&self, state: &State) -> std::io::Result<()> {
+        let file = write_journal(&self.journal_path, self.generation, state)?;
+        *self.journal.lock().unwrap() = BufWriter::new(file);
+        Ok(())
+    }
+
+    /// Runs an operation that modifies a directory, capturing its attributes around
+    /// the change and recording the change in the journal
+    fn dir_op<T>(
+        &self,
+        dirid: nfs3::fileid3,
+        op: impl FnOnce(&mut State) -> Result<T, nfs3::nfsstat3>,
+    ) -> WccResult<T> {
+        let mut state = self.state.write().unwrap();
+        let before = state.pre_attr(dirid);
+        let mut result = op(&mut state);
+        if let Err(e) = self.persist(&mut state, false) {
+            result = result.and(Err(e));
+        }
+        WccResult {
+            result,
+            wcc: nfs3::wcc_data {
+                before,
+                after: state.post_attr(dirid),
+            },
+        }
+    }
+
+    /// Returns the lock of a file
+    fn lock(&self, id: nfs3::fileid3) -> FileLock {
+        self.locks.lock().unwrap().entry(id).or_default().clone()
+    }
+
+    /// Drops the lock of a file if it is not in use
+    ///
+    /// Must be called without holding a reference to the lock.
+    fn unlock(&self, id: nfs3::fileid3) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.get(&id).is_some_and(|l| Arc::strong_count(l) == 1) {
+            locks.remove(&id);
+        }
+    }
+
+    /// Stores chunks, remembering them for COMMIT if they are not synced
+    fn store_chunks(
+        &self,
+        id: nfs3::fileid3,
+        chunks: &[(Hash, &[u8])],
+        sync: bool,
+    ) -> Result<(), nfs3::nfsstat3> {
+        for (hash, data) in chunks {
+            self.store_chunk(hash, data, sync).map_err(io_error)?;
+        }
+        if !sync {
+            let mut unsynced = self.unsynced.lock().unwrap();
+            unsynced
+                .entry(id)
+                .or_default()
+                .extend(chunks.iter().map(|(hash, _)| *hash));
+        }
+        Ok(())
+    }
+
+    /// Reads a range of a regular file, holes read as zeros
+    fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let (size, extents) = {
+            let state = self.state.read().unwrap();
+            let file = state.file(id)?;
+            let size = state.node(id)?.attr.size;
+            let end = offset.saturating_add(count as u64).min(size);
+            let mut extents = Vec::new();
+            for (&start, extent) in file.extents.range(..end).rev() {
+                if start + extent.len as u64 <= offset {
+                    break;
+                }
+                extents.push((start, *extent));
+            }
+            *file.accessed.lock().unwrap() = Some(now());
+            (size, extents)
+        };
+        let start = offset.min(size);
+        let end = offset.saturating_add(count as u64).min(size);
+        let mut buf = vec![0; (end - start) as usize];
+        for (chunk_start, extent) in extents {
+            let from = chunk_start.max(start);
+            let to = (chunk_start + extent.len as u64).min(end);
+            let data = self
+                .read_chunk(&extent.hash, from - chunk_start, (to - from) as usize)
+                .map_err(io_error)?;
+            buf[(from - start) as usize..(to - start) as usize].copy_from_slice(&data);
+        }
+        Ok((buf, end >= size))
+    }
+
+    /// Writes data to a regular file, splitting the chunks it touches again
+    ///
+    /// Must be called with the file locked.
+    fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        sync: bool,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(nfs3::nfsstat3::NFS3ERR_FBIG)?;
+        // chunks overlapping the write, and the open chunk right before it
+        let mut old = Vec::new();
+        {
+            let state = self.state.read().unwrap();
+            let file = state.file(id)?;
+            if data.is_empty() {
+                return state.getattr(id);
+            }
+            for (&start, extent) in file.extents.range(..end).rev() {
+                let extent_end = start + extent.len as u64;
+                if extent_end > offset || (extent_end == offset && extent.open) {
+                    old.push((start, *extent));
+                }
+                if extent_end <= offset {
+                    break;
+                }
+            }
+            old.reverse();
+        }
+        let region_start = old.first().map_or(offset, |(start, _)| offset.min(*start));
+        let mut region_end = old
+            .last()
+            .map_or(end, |(start, e)| end.max(start + e.len as u64));
+        let mut buf = vec![0; (region_end - region_start) as usize];
+        for (start, extent) in &old {
+            let chunk = self
+                .read_chunk(&extent.hash, 0, extent.len as usize)
+                .map_err(io_error)?;
+            let at = (start - region_start) as usize;
+            buf[at..at + chunk.len()].copy_from_slice(&chunk);
+        }
+        let at = (offset - region_start) as usize;
+        buf[at..at + data.len()].copy_from_slice(data);
+
+        // split the region, continuing into the following chunks until the new
+        // boundaries meet the existing ones
+        let mut pieces = Vec::new();
+        let mut pos = 0;
+        let mut resync = 0;
+        loop {
+            while let Some(len) = self.chunker.cut(&buf[pos..]) {
+                pieces.push((pos, len, false));
+                pos += len;
+            }
+            if pos == buf.len() {
+                break;
+            }
+            let next = match resync < MAX_RESYNC {
+                true => self
+                    .state
+                    .read()
+                    .unwrap()
+                    .file(id)?
+                    .extents
+                    .get(&region_end)
+                    .copied(),
+                false => None,
+            };
+            let Some(next) = next else {
+                pieces.push((pos, buf.len() - pos, true));
+                break;
+            };
+            let chunk = self
+                .read_chunk(&next.hash, 0, next.len as usize)
+                .map_err(io_error)?;
+            buf.extend_from_slice(&chunk);
+            old.push((region_end, next));
+            region_end += next.len as u64;
+            resync += 1;
+        }
+
+        let chunks: Vec<(Hash, &[u8])> = pieces
+            .iter()
+            .map(|&(pos, len, _)| (hash(&buf[pos..pos + len]), &buf[pos..pos + len]))
+            .collect();
+        self.store_chunks(id, &chunks, sync)?;
+
+        let mut state = self.state.write().unwrap();
+        state.file(id)?;
+        let new_starts: HashSet<u64> = pieces
+            .iter()
+            .map(|(pos, _, _)| region_start + *pos as u64)
+            .collect();
+        for (start, _) in &old {
+            if !new_starts.contains(start) {
+                state.set_extent(id, *start, None);
+            }
+        }
+        for ((pos, len, open), (hash, _)) in pieces.iter().zip(&chunks) {
+            let extent = Extent {
+                len: *len as u32,
+                hash: *hash,
+                open: *open,
+            };
+            state.set_extent(id, region_start + *pos as u64, Some(extent));
+        }
+        let time = now();
+        let node = state.node_mut(id)?;
+        node.attr.size = node.attr.size.max(end);
+        node.attr.mtime = time;
+        node.attr.ctime = time;
+        state.dirty.insert(id);
+        self.persist(&mut state, sync)?;
+        state.getattr(id)
+    }
+
+    /// Changes the size of a regular file, discarding data past the new end
+    ///
+    /// Must be called with the file locked.
+    fn truncate(&self, id: nfs3::fileid3, size: u64, sync: bool) -> Result<(), nfs3::nfsstat3> {
+        let (dropped, cut) = {
+            let state = self.state.read().unwrap();
+            let file = state.file(id)?;
+            let dropped: Vec<u64> = file.extents.range(size..).map(|(s, _)| *s).collect();
+            let cut = file
+                .extents
+                .range(..size)
+                .next_back()
+                .filter(|(s, e)| *s + e.len as u64 > size)
+                .map(|(s, e)| (*s, *e));
+            (dropped, cut)
+        };
+        // the chunk across the new end keeps its data up to the end
+        let mut piece = None;
+        if let Some((start, extent)) = cut {
+            let data = self
+                .read_chunk(&extent.hash, 0, (size - start) as usize)
+                .map_err(io_error)?;
+            let hash = hash(&data);
+            self.store_chunks(id, &[(hash, &data)], sync)?;
+            let extent = Extent {
+                len: data.len() as u32,
+                hash,
+                open: true,
+            };
+            piece = Some((start, extent));
+        }
+
+        let mut state = self.state.write().unwrap();
+        state.file(id)?;
+        for start in dropped {
+            state.set_extent(id, start, None);
+        }
+        if let Some((start, extent)) = piece {
+            state.set_extent(id, start, Some(extent));
+        }
+        let time = now();
+        let node = state.node_mut(id)?;
+        node.attr.size = size;
+        node.attr.mtime = time;
+        node.attr.ctime = time;
+        state.dirty.insert(id);
+        self.persist(&mut state, sync)
+    }
+
+    /// Syncs the chunks written by unstable writes to a file and the journal
+    fn commit(&self, id: nfs3::fileid3) -> Result<(), nfs3::nfsstat3> {
+        let hashes = self.unsynced.lock().unwrap().remove(&id);
+        for hash in hashes.into_iter().flatten() {
+            match File::open(self.chunk_path(&hash)) {
+                Ok(file) => file.sync_all().map_err(io_error)?,
+                // collected after the file dropped it
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(io_error(e)),
+            }
+        }
+        let journal = self.journal.lock().unwrap();
+        journal.get_ref().sync_data().map_err(io_error)
+    }
+
+    /// Removes unreferenced chunks from the store and compacts the journal
+    ///
+    /// Must be called with the garbage collection lock held exclusively.
+    fn collect_garbage(&self) -> std::io::Result<GcStats> {
+        let live: HashSet<Hash> = self.state.read().unwrap().chunks.keys().copied().collect();
+        let mut stats = GcStats::default();
+        for dir in std::fs::read_dir(&self.chunk_dir)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in std::fs::read_dir(dir.path())? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let hash = name
+                    .to_str()
+                    .and_then(hex_decode)
+                    .and_then(|h| Hash::try_from(h).ok());
+                // names that are not hashes are left over from interrupted writes
+                if hash.is_some_and(|h| live.contains(&h)) {
+                    continue;
+                }
+                let len = entry.metadata()?.len();
+                std::fs::remove_file(entry.path())?;
+                stats.chunks += 1;
+                stats.bytes += len;
+            }
+        }
+        let mut state = self.state.write().unwrap();
+        self.unsynced
+            .lock()
+            .unwrap()
+            .retain(|id, _| state.nodes.contains_key(id));
+        self.persist(&mut state, false)
+            .map_err(|_| std::io::Error::other("cannot write journal"))?;
+        self.compact(&state)?;
+        Ok(stats)
+    }
+}
+
+/// Writes a journal holding a snapshot of the state, returning it opened for appending
+///
+/// The snapshot is written to a temporary file first and replaces the old journal
+/// once it is on disk.
+fn write_journal(path: &Path, generation: u64, state: &State) -> std::io::Result<File> {
+    let tmp_path = path.with_extension("tmp");
+    let mut log = BufWriter::new(File::create(&tmp_path)?);
+    writeln!(log, "{} {} {}", JOURNAL_MAGIC, generation, state.next_id)?;
+    log.write_all(state.snapshot().as_bytes())?;
+    log.flush()?;
+    log.get_ref().sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+/// Runs blocking chunk store code on the blocking thread pool
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, nfs3::nfsstat3> + Send + 'static,
+) -> Result<T, nfs3::nfsstat3> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or(Err(nfs3::nfsstat3::NFS3ERR_SERVERFAULT))
+}
+
+/// Deduplicating file system
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct DedupFS {
+    /// State shared with blocking tasks and the garbage collector
+    shared: Arc<Shared>,
+}
+
+impl DedupFS {
+    /// Opens a file system stored in a directory with default options
+    ///
+    /// The directory is created if it does not exist.
+    ///
+    /// # Arguments
+    /// * `path` - Directory holding the chunk store and the journal
+    pub fn new(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::with_options(path, DedupOptions::default())
+    }
+
+    /// Opens a file system stored in a directory
+    ///
+    /// # Arguments
+    /// * `path` - Directory holding the chunk store and the journal
+    /// * `options` - Chunk sizes and garbage collection settings
+    pub fn with_options(path: impl AsRef<Path>, options: DedupOptions) -> std::io::Result<Self> {
+        let valid = options.avg_chunk.is_power_of_two()
+            && options.avg_chunk >= 256
+            && options.min_chunk < options.avg_chunk
+            && options.avg_chunk < options.max_chunk
+            && options.max_chunk <= 16 * 1024 * 1024;
+        if !valid {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "chunk sizes must satisfy min < avg < max <= 16 MiB with avg a power of two",
+            ));
+        }
+        let path = path.as_ref();
+        let chunk_dir = path.join("chunks");
+        std::fs::create_dir_all(&chunk_dir)?;
+        let journal_path = path.join("journal");
+
+        let mut state = State::new();
+        let mut generation = None;
+        match File::open(&journal_path) {
+            Ok(file) => {
+                state.nodes.clear();
+                let mut lines = BufReader::new(file).lines();
+                let header = lines.next().transpose()?.unwrap_or_default();
+                let fields: Vec<&str> = header.split(' ').collect();
+                if fields.len() != 3 || fields[0] != JOURNAL_MAGIC {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        "not a dedup journal",
+                    ));
+                }
+                generation = fields[1].parse().ok();
+                state.next_id = fields[2].parse().unwrap_or(ROOT_ID + 1);
+                for line in lines {
+                    let line = line?;
+                    if state.replay(&line).is_none() {
+                        warn!("Ignoring malformed journal line {:?}", line);
+                    }
+                }
+                state.finish_load();
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let generation = generation.unwrap_or_else(new_generation);
+        let journal = write_journal(&journal_path, generation, &state)?;
+        let shared = Shared {
+            chunk_dir,
+            journal_path,
+            chunker: Chunker::new(&options),
+            options,
+            generation,
+            state: RwLock::new(state),
+            journal: Mutex::new(BufWriter::new(journal)),
+            gc: tokio::sync::RwLock::new(()),
+            gc_started: AtomicBool::new(false),
+            locks: Mutex::new(HashMap::new()),
+            unsynced: Mutex::new(HashMap::new()),
+            tmp_counter: AtomicU64::new(0),
+        };
+        Ok(DedupFS {
+            shared: Arc::new(shared),
+        })
+    }
+
+    /// Removes chunks that no file refers to from the store and compacts the journal
+    ///
+    /// Waits for writes in progress and holds off new ones until it is done.
+    pub async fn collect_garbage(&self) -> std::io::Result<GcStats> {
+        collect(self.shared.clone()).await
+    }
+
+    /// Starts the background garbage collection if it is configured and not running
+    fn start_gc(&self) {
+        let Some(interval) = self.shared.options.gc_interval else {
+            return;
+        };
+        if self.shared.gc_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let shared = Arc::downgrade(&self.shared);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(shared) = shared.upgrade() else {
+                    return;
+                };
+                match collect(shared).await {
+                    Ok(stats) if stats.chunks > 0 => info!(
+                        "Garbage collection removed {} chunks ({} bytes)",
+                        stats.chunks, stats.bytes
+                    ),
+                    Ok(_) => {}
+                    Err(e) => warn!("Garbage collection failed: {:?}", e),
+                }
+            }
+        });
+    }
+
+    /// Changes the size of a regular file
+    async fn resize(&self, id: nfs3::fileid3, size: u64) -> Result<(), nfs3::nfsstat3> {
+        self.start_gc();
+        let shared = self.shared.clone();
+        let _gc = shared.gc.read().await;
+        let lock = shared.lock(id);
+        let result = {
+            let _guard = lock.lock().await;
+            let shared = shared.clone();
+            blocking(move || shared.truncate(id, size, true)).await
+        };
+        drop(lock);
+        shared.unlock(id);
+        result
+    }
+}
+
+/// Collects garbage with the garbage collection lock held exclusively
+async fn collect(shared: Arc<Shared>) -> std::io::Result<GcStats> {
+    let _gc = shared.gc.write().await;
+    let task = shared.clone();
+    tokio::task::spawn_blocking(move || task.collect_garbage())
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+#[async_trait]
+impl NFSFileSystem for DedupFS {
+    fn capabilities(&self) -> vfs::Capabilities {
+        vfs::Capabilities::ReadWrite
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        ROOT_ID
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.shared.state.read().unwrap().lookup(dirid, filename)
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.shared.state.read().unwrap().getattr(id)
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let state = self.shared.state.read().unwrap();
+        ids.iter().map(|id| state.getattr(*id)).collect()
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.setattr_wcc(id, setattr, None).await.result
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        let before = {
+            let state = self.shared.state.read().unwrap();
+            let before = state.pre_attr(id);
+            let checked = state.node(id).and_then(|node| {
+                let ctime = node.attr.ctime;
+                if let Some(c) = guard {
+                    if c.seconds != ctime.seconds || c.nseconds != ctime.nseconds {
+                        return Err(nfs3::nfsstat3::NFS3ERR_NOT_SYNC);
+                    }
+                }
+                match (&node.data, setattr.size) {
+                    (NodeData::File(_), nfs3::set_size3::size(size)) => Ok(Some(size)),
+                    _ => Ok(None),
+                }
+            });
+            match checked {
+                Ok(size) => (before, size),
+                Err(e) => {
+                    return WccResult {
+                        result: Err(e),
+                        wcc: nfs3::wcc_data {
+                            before,
+                            after: state.post_attr(id),
+                        },
+                    }
+                }
+            }
+        };
+        let (before, size) = before;
+        if let Some(size) = size {
+            if let Err(e) = self.resize(id, size).await {
+                let after = self.shared.state.read().unwrap().post_attr(id);
+                return WccResult {
+                    result: Err(e),
+                    wcc: nfs3::wcc_data { before, after },
+                };
+            }
+        }
+        let mut res = self.shared.dir_op(id, |state| state.setattr(id, &setattr));
+        res.wcc.before = before;
+        res
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let shared = self.shared.clone();
+        let _gc = self.shared.gc.read().await;
+        blocking(move || shared.read(id, offset, count)).await
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let res = self
+            .write_wcc(id, offset, data, nfs3::file::stable_how::FILE_SYNC)
+            .await;
+        res.result.map(|(attr, _)| attr)
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        self.start_gc();
+        let shared = self.shared.clone();
+        let _gc = shared.gc.read().await;
+        let lock = shared.lock(id);
+        let guard = lock.lock().await;
+        let before = shared.state.read().unwrap().pre_attr(id);
+        let sync = !matches!(stable, nfs3::file::stable_how::UNSTABLE);
+        let committed = match sync {
+            true => nfs3::file::stable_how::FILE_SYNC,
+            false => nfs3::file::stable_how::UNSTABLE,
+        };
+        let task = shared.clone();
+        let data = data.to_vec();
+        let result = blocking(move || task.write(id, offset, &data, sync)).await;
+        let after = shared.state.read().unwrap().post_attr(id);
+        drop(guard);
+        drop(lock);
+        shared.unlock(id);
+        WccResult {
+            result: result.map(|attr| (attr, committed)),
+            wcc: nfs3::wcc_data { before, after },
+        }
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.create_wcc(dirid, filename, attr).await.result
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        let mut res = self.shared.dir_op(dirid, |state| {
+            // an unchecked create of an existing file only applies the attributes
+            let id = match state.lookup(dirid, filename) {
+                Ok(id) => {
+                    if !matches!(state.node(id)?.data, NodeData::File(_)) {
+                        return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+                    }
+                    id
+                }
+                Err(_) => {
+                    let data = NodeData::File(FileData::default());
+                    state
+                        .add_node(dirid, filename, nfs3::ftype3::NF3REG, 0o644, data)?
+                        .0
+                }
+            };
+            Ok((id, state.setattr(id, &attr)?))
+        });
+        if let (Ok((id, fattr)), nfs3::set_size3::size(size)) = (&mut res.result, attr.size) {
+            match self.resize(*id, size).await {
+                Ok(()) => {
+                    *fattr = self
+                        .shared
+                        .state
+                        .read()
+                        .unwrap()
+                        .getattr(*id)
+                        .unwrap_or(*fattr)
+                }
+                Err(e) => res.result = Err(e),
+            }
+        }
+        res
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.create_exclusive_wcc(dirid, filename).await.result
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        self.shared.dir_op(dirid, |state| {
+            let data = NodeData::File(FileData::default());
+            let (id, _) = state.add_node(dirid, filename, nfs3::ftype3::NF3REG, 0o644, data)?;
+            Ok(id)
+        })
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mkdir_wcc(dirid, dirname).await.result
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.shared.dir_op(dirid, |state| {
+            let data = NodeData::Dir(Directory {
+                parent: dirid,
+                ..Default::default()
+            });
+            state.add_node(dirid, dirname, nfs3::ftype3::NF3DIR, 0o755, data)
+        })
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.remove_wcc(dirid, filename, vfs::RemoveKind::Any)
+            .await
+            .result
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        self.shared
+            .dir_op(dirid, |state| state.remove(dirid, filename, kind))
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+            .result
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        let mut state = self.shared.state.write().unwrap();
+        let from_before = state.pre_attr(from_dirid);
+        let to_before = state.pre_attr(to_dirid);
+        let mut result = state.rename(from_dirid, from_filename, to_dirid, to_filename);
+        if let Err(e) = self.shared.persist(&mut state, false) {
+            result = result.and(Err(e));
+        }
+        RenameWccResult {
+            result,
+            from_dir_wcc: nfs3::wcc_data {
+                before: from_before,
+                after: state.post_attr(from_dirid),
+            },
+            to_dir_wcc: nfs3::wcc_data {
+                before: to_before,
+                after: state.post_attr(to_dirid),
+            },
+        }
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let state = self.shared.state.read().unwrap();
+        let dir = state.dir(dirid)?;
+        super::readdir_by_fileid(&dir.order, start_after, max_entries, |id| state.getattr(id))
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.symlink_wcc(dirid, linkname, symlink, attr)
+            .await
+            .result
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.shared.dir_op(dirid, |state| {
+            let data = NodeData::Symlink(symlink.to_vec());
+            let (id, _) = state.add_node(dirid, linkname, nfs3::ftype3::NF3LNK, 0o777, data)?;
+            Ok((id, state.setattr(id, attr)?))
+        })
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        match &self.shared.state.read().unwrap().node(id)?.data {
+            NodeData::Symlink(target) => Ok(target.as_slice().into()),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.link_wcc(file_id, link_dir_id, link_name).await.result
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        self.shared.dir_op(link_dir_id, |state| {
+            state.link(file_id, link_dir_id, link_name)
+        })
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await
+            .result
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.shared.dir_op(dir_id, |state| {
+            let rdev = match ftype {
+                nfs3::ftype3::NF3CHR | nfs3::ftype3::NF3BLK => specdata,
+                nfs3::ftype3::NF3SOCK | nfs3::ftype3::NF3FIFO => nfs3::specdata3::default(),
+                _ => return Err(nfs3::nfsstat3::NFS3ERR_BADTYPE),
+            };
+            let (id, _) = state.add_node(dir_id, name, ftype, 0o644, NodeData::Special)?;
+            state.node_mut(id)?.attr.rdev = rdev;
+            Ok((id, state.setattr(id, attrs)?))
+        })
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        _offset: u64,
+        _count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let shared = self.shared.clone();
+        blocking(move || shared.commit(file_id)).await?;
+        self.getattr(file_id).await
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        let mut data = Vec::with_capacity(16);
+        data.extend_from_slice(&self.shared.generation.to_le_bytes());
+        data.extend_from_slice(&id.to_le_bytes());
+        nfs3::nfs_fh3 { data }
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        if id.data.len() != 16 {
+            return Err(nfs3::nfsstat3::NFS3ERR_BADHANDLE);
+        }
+        let generation = u64::from_le_bytes(id.data[0..8].try_into().unwrap());
+        if generation != self.shared.generation {
+            return Err(nfs3::nfsstat3::NFS3ERR_STALE);
+        }
+        Ok(u64::from_le_bytes(id.data[8..16].try_into().unwrap()))
+    }
+
+    fn serverid(&self) -> nfs3::cookieverf3 {
+        self.shared.generation.to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    /// Store directory that is removed when dropped
+    struct TempStore(PathBuf);
+
+    impl TempStore {
+        fn new(name: &str) -> TempStore {
+            let path = std::env::temp_dir().join(format!(
+                "nfsserve-dedup-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            TempStore(path)
+        }
+
+        /// Opens the store with small chunks and without background collection
+        fn open(&self) -> DedupFS {
+            let options = DedupOptions {
+                min_chunk: 256,
+                avg_chunk: 1024,
+                max_chunk: 4096,
+                gc_interval: None,
+            };
+            DedupFS::with_options(&self.0, options).unwrap()
+        }
+
+        /// Returns the number of chunks in the store
+        fn chunks(&self) -> usize {
+            std::fs::read_dir(self.0.join("chunks"))
+                .unwrap()
+                .map(|dir| std::fs::read_dir(dir.unwrap().path()).unwrap().count())
+                .sum()
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Returns data that does not repeat within itself
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn identical_data_is_stored_once_until_collected() {
+        let store = TempStore::new("collect");
+        let fs = store.open();
+        let root = fs.root_dir();
+        let data = noise(32 * 1024, 1);
+        let mut files = Vec::new();
+        for file in ["a", "b"] {
+            let (id, _) = fs
+                .create(root, &name(file), Default::default())
+                .await
+                .unwrap();
+            fs.write(id, 0, &data).await.unwrap();
+            files.push(id);
+        }
+        let chunks = store.chunks();
+        let (a, b) = (files[0], files[1]);
+        // each file holds half of every chunk, rounded down
+        let used = fs.getattr(a).await.unwrap().used + fs.getattr(b).await.unwrap().used;
+        assert!(used <= data.len() as u64 && used + chunks as u64 >= data.len() as u64);
+        assert_eq!(fs.read(b, 0, 1 << 20).await.unwrap().0, data);
+
+        fs.remove(root, &name("a")).await.unwrap();
+        assert_eq!(fs.collect_garbage().await.unwrap().chunks, 0);
+        fs.remove(root, &name("b")).await.unwrap();
+        let stats = fs.collect_garbage().await.unwrap();
+        assert_eq!(stats.chunks as usize, chunks);
+        assert_eq!(stats.bytes, data.len() as u64);
+        assert_eq!(store.chunks(), 0);
+    }
+
+    #[tokio::test]
+    async fn hard_links_share_data_and_are_listed_on_one_page() {
+        let store = TempStore::new("links");
+        let fs = store.open();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        fs.write(file, 0, b"shared").await.unwrap();
+        fs.link(file, root, &name("link1")).await.unwrap();
+        fs.link(file, root, &name("link2")).await.unwrap();
+        fs.create(root, &name("other"), Default::default())
+            .await
+            .unwrap();
+
+        let page = fs.readdir(root, 0, 1).await.unwrap();
+        assert_eq!(page.entries.len(), 3);
+        assert!(page.entries.iter().all(|entry| entry.fileid == file));
+        assert!(!page.end);
+        let page = fs.readdir(root, file, 1).await.unwrap();
+        assert_eq!((page.entries.len(), page.end), (1, true));
+        let page = fs.readdir(root, u64::MAX, 10).await.unwrap();
+        assert!(page.entries.is_empty() && page.end);
+
+        // the data stays as long as a name refers to it
+        fs.remove(root, &name("file")).await.unwrap();
+        fs.remove(root, &name("link1")).await.unwrap();
+        fs.collect_garbage().await.unwrap();
+        let link = fs.lookup(root, &name("link2")).await.unwrap();
+        assert_eq!(fs.read(link, 0, 100).await.unwrap().0, b"shared");
+        assert_eq!(fs.getattr(link).await.unwrap().nlink, 1);
+    }
+
+    #[tokio::test]
+    async fn writes_at_huge_offsets_fail() {
+        let store = TempStore::new("huge");
+        let fs = store.open();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        let res = fs
+            .write_wcc(
+                file,
+                u64::MAX - 1,
+                b"data",
+                nfs3::file::stable_how::UNSTABLE,
+            )
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_FBIG)));
+        fs.write(file, 1 << 40, b"data").await.unwrap();
+        let (data, eof) = fs.read(file, (1 << 40) - 2, 16).await.unwrap();
+        assert_eq!((&data[..], eof), (&b"\0\0data"[..], true));
+        assert_eq!(fs.getattr(file).await.unwrap().used, 4);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_keep_each_other() {
+        let store = TempStore::new("concurrent");
+        let fs = store.open();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        let parts: Vec<Vec<u8>> = (0..8).map(|i| noise(3000, i)).collect();
+        let writes = parts.iter().enumerate().map(|(i, data)| {
+            fs.write_wcc(
+                file,
+                i as u64 * 3000,
+                data,
+                nfs3::file::stable_how::UNSTABLE,
+            )
+        });
+        for res in futures::future::join_all(writes).await {
+            res.result.unwrap();
+        }
+        fs.commit(file, 0, 0).await.unwrap();
+        let (data, _) = fs.read(file, 0, 1 << 20).await.unwrap();
+        assert_eq!(data, parts.concat());
+    }
+
+    #[tokio::test]
+    async fn reads_update_the_access_time_without_locking_the_state() {
+        let store = TempStore::new("atime");
+        let fs = store.open();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("file"), Default::default())
+            .await
+            .unwrap();
+        fs.write(file, 0, b"data").await.unwrap();
+        let setattr = nfs3::sattr3 {
+            atime: nfs3::set_atime::SET_TO_CLIENT_TIME(nfs3::nfstime3::default()),
+            ..Default::default()
+        };
+        fs.setattr(file, setattr).await.unwrap();
+        {
+            // other readers of the state do not hold up the read
+            let _state = fs.shared.state.read().unwrap();
+            assert_eq!(fs.shared.read(file, 0, 4).unwrap().0, b"data");
+        }
+        assert!(fs.getattr(file).await.unwrap().atime.seconds > 0);
+        // the access time of the read is kept across restarts
+        fs.setattr(file, Default::default()).await.unwrap();
+        drop(fs);
+        let fs = store.open();
+        assert!(fs.getattr(file).await.unwrap().atime.seconds > 0);
+    }
+}
@@ -0,0 +1,1225 @@
+//! In-memory file system implementation.
+//!
+//! `MemFS` keeps the whole file system in memory and implements every operation of
+//! the [`NFSFileSystem`] trait with POSIX-like semantics:
+//! - Regular files, directories, symbolic links and special files (devices, FIFOs, sockets)
+//! - Hard links with correct link counts; directories count their subdirectories
+//! - Sparse files: data is stored in fixed-size blocks and holes are never allocated
+//! - Access, modification and change timestamps maintained on every operation
+//! - Stable directory cookies: entries are listed in file ID order, and file IDs
+//!   are never reused, so a listing can resume after concurrent modifications
+//!
+//! All operations that modify the file system report exact weak cache consistency
+//! data, captured atomically with the change.
+//!
+//! It is useful for tests, scratch exports and as a reference for implementing
+//! other backends.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, RenameWccResult, WccResult};
+
+/// Size of the blocks file data is stored in
+const BLOCK_SIZE: u64 = 4096;
+
+/// File ID of the root directory
+const ROOT_ID: nfs3::fileid3 = 1;
+
+/// Maximum length of a file name in bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// Contents of a file system node
+#[derive(Debug)]
+enum NodeData {
+    /// Regular file data stored as sparse blocks indexed by block number
+    File(BTreeMap<u64, Box<[u8]>>),
+    /// Directory
+    Dir(Directory),
+    /// Symbolic link target
+    Symlink(Vec<u8>),
+    /// Device, FIFO or socket; the type and device numbers live in the attributes
+    Special,
+}
+
+/// Directory contents
+#[derive(Debug, Default)]
+struct Directory {
+    /// Parent directory ID (the root is its own parent)
+    parent: nfs3::fileid3,
+    /// Entries by name
+    entries: HashMap<Vec<u8>, nfs3::fileid3>,
+    /// Entries in listing order; the file ID doubles as the directory cookie
+    order: BTreeSet<(nfs3::fileid3, Vec<u8>)>,
+}
+
+impl Directory {
+    /// Adds an entry to the directory
+    fn insert(&mut self, name: &[u8], id: nfs3::fileid3) {
+        self.entries.insert(name.to_vec(), id);
+        self.order.insert((id, name.to_vec()));
+    }
+
+    /// Removes an entry from the directory, returning its file ID
+    fn remove(&mut self, name: &[u8]) -> Option<nfs3::fileid3> {
+        let id = self.entries.remove(name)?;
+        self.order.remove(&(id, name.to_vec()));
+        Some(id)
+    }
+}
+
+/// A file system node (inode)
+#[derive(Debug)]
+struct Node {
+    /// Attributes of the node, except for the access time
+    attr: nfs3::fattr3,
+    /// Access time packed by [`pack_time`], so that reads can update it under a shared lock
+    atime: AtomicU64,
+    /// Contents of the node
+    data: NodeData,
+}
+
+impl Node {
+    /// Creates a node with the given attributes, including the access time
+    fn new(attr: nfs3::fattr3, data: NodeData) -> Node {
+        Node {
+            atime: AtomicU64::new(pack_time(attr.atime)),
+            attr,
+            data,
+        }
+    }
+
+    /// Returns the attributes of the node
+    fn attr(&self) -> nfs3::fattr3 {
+        nfs3::fattr3 {
+            atime: unpack_time(self.atime.load(Ordering::Relaxed)),
+            ..self.attr
+        }
+    }
+
+    /// Sets the access time of the node
+    fn set_atime(&self, time: nfs3::nfstime3) {
+        self.atime.store(pack_time(time), Ordering::Relaxed);
+    }
+}
+
+/// Packs a timestamp into a single integer
+fn pack_time(time: nfs3::nfstime3) -> u64 {
+    ((time.seconds as u64) << 32) | time.nseconds as u64
+}
+
+/// Unpacks a timestamp packed by [`pack_time`]
+fn unpack_time(packed: u64) -> nfs3::nfstime3 {
+    nfs3::nfstime3 {
+        seconds: (packed >> 32) as u32,
+        nseconds: packed as u32,
+    }
+}
+
+/// Mutable state of the file system
+#[derive(Debug)]
+struct State {
+    /// All nodes by file ID
+    nodes: HashMap<nfs3::fileid3, Node>,
+    /// Next file ID to allocate
+    next_id: nfs3::fileid3,
+}
+
+/// Returns the current time as an NFS timestamp
+fn now() -> nfs3::nfstime3 {
+    let d = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    nfs3::nfstime3 {
+        seconds: d.as_secs() as u32,
+        nseconds: d.subsec_nanos(),
+    }
+}
+
+/// Checks that a name can be used for a new directory entry
+fn check_name(name: &[u8]) -> Result<(), nfs3::nfsstat3> {
+    if name.is_empty() || name.contains(&b'/') || name.contains(&0) {
+        return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+    }
+    if name == b"." || name == b".." {
+        return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(nfs3::nfsstat3::NFS3ERR_NAMETOOLONG);
+    }
+    Ok(())
+}
+
+/// Checks that attribute changes can be applied to a node with the given contents
+///
+/// Called before anything is changed, so that a failed SETATTR or create
+/// leaves the file system untouched.
+fn check_setattr(data: &NodeData, setattr: &nfs3::sattr3) -> Result<(), nfs3::nfsstat3> {
+    if let nfs3::set_size3::size(_) = setattr.size {
+        match data {
+            NodeData::File(_) => {}
+            NodeData::Dir(_) => return Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+            _ => return Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of bytes allocated for file data
+fn used_bytes(blocks: &BTreeMap<u64, Box<[u8]>>) -> u64 {
+    blocks.len() as u64 * BLOCK_SIZE
+}
+
+impl State {
+    /// Gets a node by file ID
+    fn node(&self, id: nfs3::fileid3) -> Result<&Node, nfs3::nfsstat3> {
+        self.nodes.get(&id).ok_or(nfs3::nfsstat3::NFS3ERR_STALE)
+    }
+
+    /// Gets a mutable node by file ID
+    fn node_mut(&mut self, id: nfs3::fileid3) -> Result<&mut Node, nfs3::nfsstat3> {
+        self.nodes.get_mut(&id).ok_or(nfs3::nfsstat3::NFS3ERR_STALE)
+    }
+
+    /// Gets a directory by file ID
+    fn dir(&self, id: nfs3::fileid3) -> Result<&Directory, nfs3::nfsstat3> {
+        match &self.node(id)?.data {
+            NodeData::Dir(dir) => Ok(dir),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+        }
+    }
+
+    /// Gets a mutable directory by file ID
+    fn dir_mut(&mut self, id: nfs3::fileid3) -> Result<&mut Directory, nfs3::nfsstat3> {
+        match &mut self.node_mut(id)?.data {
+            NodeData::Dir(dir) => Ok(dir),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+        }
+    }
+
+    /// Returns the weak cache consistency attributes of a node, if it exists
+    fn pre_attr(&self, id: nfs3::fileid3) -> nfs3::pre_op_attr {
+        match self.nodes.get(&id) {
+            Some(node) => nfs3::pre_op_attr::attributes(node.attr().into()),
+            None => nfs3::pre_op_attr::Void,
+        }
+    }
+
+    /// Returns the attributes of a node, if it exists
+    fn post_attr(&self, id: nfs3::fileid3) -> nfs3::post_op_attr {
+        match self.nodes.get(&id) {
+            Some(node) => nfs3::post_op_attr::attributes(node.attr()),
+            None => nfs3::post_op_attr::Void,
+        }
+    }
+
+    /// Marks a directory as modified
+    fn touch_dir(&mut self, id: nfs3::fileid3, time: nfs3::nfstime3) {
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.attr.mtime = time;
+            node.attr.ctime = time;
+            if let NodeData::Dir(dir) = &node.data {
+                node.attr.size = dir.entries.len() as u64;
+            }
+        }
+    }
+
+    /// Looks up a name in a directory, including "." and ".."
+    fn lookup(&self, dirid: nfs3::fileid3, name: &[u8]) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let dir = self.dir(dirid)?;
+        match name {
+            b"." => Ok(dirid),
+            b".." => Ok(dir.parent),
+            _ => dir
+                .entries
+                .get(name)
+                .copied()
+                .ok_or(nfs3::nfsstat3::NFS3ERR_NOENT),
+        }
+    }
+
+    /// Creates a new node in a directory
+    fn add_node(
+        &mut self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        ftype: nfs3::ftype3,
+        mode: nfs3::mode3,
+        data: NodeData,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        check_name(name)?;
+        let dir = self.dir(dirid)?;
+        if dir.entries.contains_key(name) {
+            return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+        }
+        let (uid, gid) = {
+            let parent = &self.node(dirid)?.attr;
+            (parent.uid, parent.gid)
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        let time = now();
+        let is_dir = matches!(data, NodeData::Dir(_));
+        let size = match &data {
+            NodeData::Symlink(target) => target.len() as u64,
+            _ => 0,
+        };
+        let attr = nfs3::fattr3 {
+            ftype,
+            mode,
+            nlink: if is_dir { 2 } else { 1 },
+            uid,
+            gid,
+            size,
+            used: size,
+            rdev: nfs3::specdata3::default(),
+            fsid: 0,
+            fileid: id,
+            atime: time,
+            mtime: time,
+            ctime: time,
+        };
+        self.nodes.insert(id, Node::new(attr, data));
+        self.dir_mut(dirid)?.insert(name, id);
+        if is_dir {
+            self.node_mut(dirid)?.attr.nlink += 1;
+        }
+        self.touch_dir(dirid, time);
+        Ok((id, attr))
+    }
+
+    /// Drops one link to a node, freeing it when no links remain
+    fn unlink_node(&mut self, id: nfs3::fileid3, time: nfs3::nfstime3) {
+        let Some(node) = self.nodes.get_mut(&id) else {
+            return;
+        };
+        if matches!(node.data, NodeData::Dir(_)) {
+            self.nodes.remove(&id);
+            return;
+        }
+        node.attr.nlink = node.attr.nlink.saturating_sub(1);
+        node.attr.ctime = time;
+        if node.attr.nlink == 0 {
+            self.nodes.remove(&id);
+        }
+    }
+
+    /// Removes a directory entry of the given kind
+    fn remove(
+        &mut self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        kind: vfs::RemoveKind,
+    ) -> Result<(), nfs3::nfsstat3> {
+        if name == b"." || name == b".." {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        let id = self.lookup(dirid, name)?;
+        let node = self.node(id)?;
+        kind.check(matches!(node.data, NodeData::Dir(_)))?;
+        let is_dir = match &node.data {
+            NodeData::Dir(dir) if !dir.entries.is_empty() => {
+                return Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY)
+            }
+            NodeData::Dir(_) => true,
+            _ => false,
+        };
+        self.dir_mut(dirid)?.remove(name);
+        let time = now();
+        if is_dir {
+            let parent = self.node_mut(dirid)?;
+            parent.attr.nlink = parent.attr.nlink.saturating_sub(1);
+        }
+        self.touch_dir(dirid, time);
+        self.unlink_node(id, time);
+        Ok(())
+    }
+
+    /// Checks if `id` is `ancestor` or lies below it
+    fn is_descendant(&self, mut id: nfs3::fileid3, ancestor: nfs3::fileid3) -> bool {
+        loop {
+            if id == ancestor {
+                return true;
+            }
+            match self.dir(id) {
+                Ok(dir) if dir.parent != id => id = dir.parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Renames a directory entry, replacing the target if allowed
+    fn rename(
+        &mut self,
+        from_dirid: nfs3::fileid3,
+        from_name: &[u8],
+        to_dirid: nfs3::fileid3,
+        to_name: &[u8],
+    ) -> Result<(), nfs3::nfsstat3> {
+        if from_name == b"." || from_name == b".." {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        check_name(to_name)?;
+        let id = self.lookup(from_dirid, from_name)?;
+        self.dir(to_dirid)?;
+        let moving_dir = matches!(self.node(id)?.data, NodeData::Dir(_));
+        if moving_dir && self.is_descendant(to_dirid, id) {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        if let Some(&target) = self.dir(to_dirid)?.entries.get(to_name) {
+            if target == id {
+                // both names refer to the same file: nothing to do
+                return Ok(());
+            }
+            match (&self.node(target)?.data, moving_dir) {
+                (NodeData::Dir(dir), true) if !dir.entries.is_empty() => {
+                    return Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY)
+                }
+                (NodeData::Dir(_), false) => return Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+                (_, true) if !matches!(self.node(target)?.data, NodeData::Dir(_)) => {
+                    return Err(nfs3::nfsstat3::NFS3ERR_NOTDIR)
+                }
+                _ => {}
+            }
+            self.remove(to_dirid, to_name, vfs::RemoveKind::Any)?;
+        }
+        let time = now();
+        self.dir_mut(from_dirid)?.remove(from_name);
+        self.dir_mut(to_dirid)?.insert(to_name, id);
+        if moving_dir && from_dirid != to_dirid {
+            self.dir_mut(id)?.parent = to_dirid;
+            let from = self.node_mut(from_dirid)?;
+            from.attr.nlink = from.attr.nlink.saturating_sub(1);
+            self.node_mut(to_dirid)?.attr.nlink += 1;
+        }
+        self.node_mut(id)?.attr.ctime = time;
+        self.touch_dir(from_dirid, time);
+        self.touch_dir(to_dirid, time);
+        Ok(())
+    }
+
+    /// Creates a hard link to a non-directory node
+    fn link(
+        &mut self,
+        id: nfs3::fileid3,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        check_name(name)?;
+        if matches!(self.node(id)?.data, NodeData::Dir(_)) {
+            return Err(nfs3::nfsstat3::NFS3ERR_ISDIR);
+        }
+        if self.dir(dirid)?.entries.contains_key(name) {
+            return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+        }
+        let time = now();
+        self.dir_mut(dirid)?.insert(name, id);
+        self.touch_dir(dirid, time);
+        let node = self.node_mut(id)?;
+        node.attr.nlink += 1;
+        node.attr.ctime = time;
+        Ok(node.attr())
+    }
+
+    /// Applies attribute changes to a node
+    fn setattr(
+        &mut self,
+        id: nfs3::fileid3,
+        setattr: &nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let node = self.node_mut(id)?;
+        check_setattr(&node.data, setattr)?;
+        let time = now();
+        if let nfs3::set_size3::size(size) = setattr.size {
+            if let NodeData::File(blocks) = &mut node.data {
+                truncate(blocks, node.attr.size, size);
+                node.attr.size = size;
+                node.attr.used = used_bytes(blocks);
+                node.attr.mtime = time;
+            }
+        }
+        if let nfs3::set_mode3::mode(mode) = setattr.mode {
+            node.attr.mode = mode & 0o7777;
+        }
+        if let nfs3::set_uid3::uid(uid) = setattr.uid {
+            node.attr.uid = uid;
+        }
+        if let nfs3::set_gid3::gid(gid) = setattr.gid {
+            node.attr.gid = gid;
+        }
+        match setattr.atime {
+            nfs3::set_atime::DONT_CHANGE => {}
+            nfs3::set_atime::SET_TO_SERVER_TIME => node.set_atime(time),
+            nfs3::set_atime::SET_TO_CLIENT_TIME(t) => node.set_atime(t),
+        }
+        match setattr.mtime {
+            nfs3::set_mtime::DONT_CHANGE => {}
+            nfs3::set_mtime::SET_TO_SERVER_TIME => node.attr.mtime = time,
+            nfs3::set_mtime::SET_TO_CLIENT_TIME(t) => node.attr.mtime = t,
+        }
+        node.attr.ctime = time;
+        Ok(node.attr())
+    }
+
+    /// Writes data to a regular file
+    fn write(
+        &mut self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let node = self.node_mut(id)?;
+        let blocks = match &mut node.data {
+            NodeData::File(blocks) => blocks,
+            NodeData::Dir(_) => return Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+            _ => return Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+        };
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(nfs3::nfsstat3::NFS3ERR_FBIG)?;
+        let mut pos = offset;
+        while pos < end {
+            let block_idx = pos / BLOCK_SIZE;
+            let block_off = (pos % BLOCK_SIZE) as usize;
+            let len = std::cmp::min(BLOCK_SIZE as usize - block_off, (end - pos) as usize);
+            let src = &data[(pos - offset) as usize..(pos - offset) as usize + len];
+            let block = blocks
+                .entry(block_idx)
+                .or_insert_with(|| vec![0; BLOCK_SIZE as usize].into_boxed_slice());
+            block[block_off..block_off + len].copy_from_slice(src);
+            pos += len as u64;
+        }
+        let time = now();
+        node.attr.size = std::cmp::max(node.attr.size, end);
+        node.attr.used = used_bytes(blocks);
+        node.attr.mtime = time;
+        node.attr.ctime = time;
+        Ok(node.attr())
+    }
+
+    /// Reads data from a regular file, holes read as zeros
+    fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let node = self.node(id)?;
+        let blocks = match &node.data {
+            NodeData::File(blocks) => blocks,
+            NodeData::Dir(_) => return Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+            _ => return Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+        };
+        let size = node.attr.size;
+        let start = std::cmp::min(offset, size);
+        let end = std::cmp::min(offset.saturating_add(count as u64), size);
+        let mut buf = vec![0; (end - start) as usize];
+        for (&block_idx, block) in blocks.range(start / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE)) {
+            let block_start = block_idx * BLOCK_SIZE;
+            let copy_start = std::cmp::max(block_start, start);
+            let copy_end = std::cmp::min(block_start + BLOCK_SIZE, end);
+            buf[(copy_start - start) as usize..(copy_end - start) as usize].copy_from_slice(
+                &block[(copy_start - block_start) as usize..(copy_end - block_start) as usize],
+            );
+        }
+        node.set_atime(now());
+        Ok((buf, end >= size))
+    }
+}
+
+/// Changes the size of sparse file data, discarding or zeroing data past the new end
+fn truncate(blocks: &mut BTreeMap<u64, Box<[u8]>>, old_size: u64, new_size: u64) {
+    if new_size >= old_size {
+        return;
+    }
+    blocks.split_off(&new_size.div_ceil(BLOCK_SIZE));
+    let tail = (new_size % BLOCK_SIZE) as usize;
+    if tail > 0 {
+        if let Some(block) = blocks.get_mut(&(new_size / BLOCK_SIZE)) {
+            block[tail..].fill(0);
+        }
+    }
+}
+
+/// In-memory file system
+///
+/// See the [module documentation](self) for the supported semantics.
+/// Newly created objects inherit the owner of their parent directory;
+/// the root directory is owned by the user and group given to [`MemFS::with_owner`]
+/// (root by default).
+#[derive(Debug)]
+pub struct MemFS {
+    /// File system state
+    state: RwLock<State>,
+}
+
+impl Default for MemFS {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFS {
+    /// Creates an empty file system owned by root
+    pub fn new() -> Self {
+        Self::with_owner(0, 0)
+    }
+
+    /// Creates an empty file system whose root directory has the given owner
+    ///
+    /// # Arguments
+    ///
+    /// * `uid` - User ID of the root directory owner
+    /// * `gid` - Group ID of the root directory owner
+    pub fn with_owner(uid: nfs3::uid3, gid: nfs3::gid3) -> Self {
+        let time = now();
+        let root = Node::new(
+            nfs3::fattr3 {
+                ftype: nfs3::ftype3::NF3DIR,
+                mode: 0o755,
+                nlink: 2,
+                uid,
+                gid,
+                size: 0,
+                used: 0,
+                rdev: nfs3::specdata3::default(),
+                fsid: 0,
+                fileid: ROOT_ID,
+                atime: time,
+                mtime: time,
+                ctime: time,
+            },
+            NodeData::Dir(Directory {
+                parent: ROOT_ID,
+                ..Default::default()
+            }),
+        );
+        MemFS {
+            state: RwLock::new(State {
+                nodes: HashMap::from([(ROOT_ID, root)]),
+                next_id: ROOT_ID + 1,
+            }),
+        }
+    }
+
+    /// Runs an operation that modifies a directory, capturing its attributes around the change
+    fn dir_op<T>(
+        &self,
+        dirid: nfs3::fileid3,
+        op: impl FnOnce(&mut State) -> Result<T, nfs3::nfsstat3>,
+    ) -> WccResult<T> {
+        let mut state = self.state.write().unwrap();
+        let before = state.pre_attr(dirid);
+        let result = op(&mut state);
+        WccResult {
+            result,
+            wcc: nfs3::wcc_data {
+                before,
+                after: state.post_attr(dirid),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for MemFS {
+    fn capabilities(&self) -> vfs::Capabilities {
+        vfs::Capabilities::ReadWrite
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        ROOT_ID
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.state.read().unwrap().lookup(dirid, filename)
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Ok(self.state.read().unwrap().node(id)?.attr())
+    }
+
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let state = self.state.read().unwrap();
+        ids.iter()
+            .map(|id| state.node(*id).map(|node| node.attr()))
+            .collect()
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.setattr_wcc(id, setattr, None).await.result
+    }
+
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        self.dir_op(id, |state| {
+            let ctime = state.node(id)?.attr.ctime;
+            if let Some(c) = guard {
+                if c.seconds != ctime.seconds || c.nseconds != ctime.nseconds {
+                    return Err(nfs3::nfsstat3::NFS3ERR_NOT_SYNC);
+                }
+            }
+            state.setattr(id, &setattr)
+        })
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        self.state.read().unwrap().read(id, offset, count)
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.state.write().unwrap().write(id, offset, data)
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        _stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        self.dir_op(id, |state| {
+            let attr = state.write(id, offset, data)?;
+            Ok((attr, nfs3::file::stable_how::FILE_SYNC))
+        })
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.create_wcc(dirid, filename, attr).await.result
+    }
+
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.dir_op(dirid, |state| {
+            // an unchecked create of an existing file only applies the attributes
+            let id = match state.lookup(dirid, filename) {
+                Ok(id) => {
+                    if !matches!(state.node(id)?.data, NodeData::File(_)) {
+                        return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+                    }
+                    id
+                }
+                Err(_) => {
+                    let data = NodeData::File(BTreeMap::new());
+                    check_setattr(&data, &attr)?;
+                    state
+                        .add_node(dirid, filename, nfs3::ftype3::NF3REG, 0o644, data)?
+                        .0
+                }
+            };
+            Ok((id, state.setattr(id, &attr)?))
+        })
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        self.create_exclusive_wcc(dirid, filename).await.result
+    }
+
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        self.dir_op(dirid, |state| {
+            let data = NodeData::File(BTreeMap::new());
+            let (id, _) = state.add_node(dirid, filename, nfs3::ftype3::NF3REG, 0o644, data)?;
+            Ok(id)
+        })
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mkdir_wcc(dirid, dirname).await.result
+    }
+
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.dir_op(dirid, |state| {
+            let data = NodeData::Dir(Directory {
+                parent: dirid,
+                ..Default::default()
+            });
+            state.add_node(dirid, dirname, nfs3::ftype3::NF3DIR, 0o755, data)
+        })
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.remove_wcc(dirid, filename, vfs::RemoveKind::Any)
+            .await
+            .result
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        self.dir_op(dirid, |state| state.remove(dirid, filename, kind))
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.rename_wcc(from_dirid, from_filename, to_dirid, to_filename)
+            .await
+            .result
+    }
+
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        let mut state = self.state.write().unwrap();
+        let from_before = state.pre_attr(from_dirid);
+        let to_before = state.pre_attr(to_dirid);
+        let result = state.rename(from_dirid, from_filename, to_dirid, to_filename);
+        RenameWccResult {
+            result,
+            from_dir_wcc: nfs3::wcc_data {
+                before: from_before,
+                after: state.post_attr(from_dirid),
+            },
+            to_dir_wcc: nfs3::wcc_data {
+                before: to_before,
+                after: state.post_attr(to_dirid),
+            },
+        }
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let state = self.state.read().unwrap();
+        let dir = state.dir(dirid)?;
+        super::readdir_by_fileid(&dir.order, start_after, max_entries, |id| {
+            Ok(state.node(id)?.attr())
+        })
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.symlink_wcc(dirid, linkname, symlink, attr)
+            .await
+            .result
+    }
+
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.dir_op(dirid, |state| {
+            let data = NodeData::Symlink(symlink.to_vec());
+            check_setattr(&data, attr)?;
+            let (id, _) = state.add_node(dirid, linkname, nfs3::ftype3::NF3LNK, 0o777, data)?;
+            Ok((id, state.setattr(id, attr)?))
+        })
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        match &self.state.read().unwrap().node(id)?.data {
+            NodeData::Symlink(target) => Ok(target.as_slice().into()),
+            _ => Err(nfs3::nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        self.link_wcc(file_id, link_dir_id, link_name).await.result
+    }
+
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        self.dir_op(link_dir_id, |state| {
+            state.link(file_id, link_dir_id, link_name)
+        })
+    }
+
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.mknod_wcc(dir_id, name, ftype, specdata, attrs)
+            .await
+            .result
+    }
+
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        self.dir_op(dir_id, |state| {
+            let rdev = match ftype {
+                nfs3::ftype3::NF3CHR | nfs3::ftype3::NF3BLK => specdata,
+                nfs3::ftype3::NF3SOCK | nfs3::ftype3::NF3FIFO => nfs3::specdata3::default(),
+                _ => return Err(nfs3::nfsstat3::NFS3ERR_BADTYPE),
+            };
+            check_setattr(&NodeData::Special, attrs)?;
+            let (id, _) = state.add_node(dir_id, name, ftype, 0o644, NodeData::Special)?;
+            state.node_mut(id)?.attr.rdev = rdev;
+            Ok((id, state.setattr(id, attrs)?))
+        })
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        _offset: u64,
+        _count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        // all data is already as stable as it gets
+        self.getattr(file_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    fn size(size: u64) -> nfs3::sattr3 {
+        nfs3::sattr3 {
+            size: nfs3::set_size3::size(size),
+            ..Default::default()
+        }
+    }
+
+    async fn nlink(fs: &MemFS, id: nfs3::fileid3) -> u32 {
+        fs.getattr(id).await.unwrap().nlink
+    }
+
+    async fn list(
+        fs: &MemFS,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> (Vec<String>, bool) {
+        let result = fs.readdir(dirid, start_after, count).await.unwrap();
+        let names = result
+            .entries
+            .iter()
+            .map(|e| String::from_utf8(e.name.to_vec()).unwrap())
+            .collect();
+        (names, result.end)
+    }
+
+    #[tokio::test]
+    async fn hard_links_count_names() {
+        let fs = MemFS::new();
+        let (file, _) = fs
+            .create(ROOT_ID, &name("a"), Default::default())
+            .await
+            .unwrap();
+        let (dir, _) = fs.mkdir(ROOT_ID, &name("d")).await.unwrap();
+        assert_eq!(fs.link(file, dir, &name("b")).await.unwrap().nlink, 2);
+        assert_eq!(nlink(&fs, file).await, 2);
+
+        fs.remove(ROOT_ID, &name("a")).await.unwrap();
+        assert_eq!(nlink(&fs, file).await, 1);
+        fs.remove(dir, &name("b")).await.unwrap();
+        assert!(matches!(
+            fs.getattr(file).await,
+            Err(nfs3::nfsstat3::NFS3ERR_STALE)
+        ));
+    }
+
+    #[tokio::test]
+    async fn directories_count_subdirectories() {
+        let fs = MemFS::new();
+        let (a, _) = fs.mkdir(ROOT_ID, &name("a")).await.unwrap();
+        let (b, _) = fs.mkdir(ROOT_ID, &name("b")).await.unwrap();
+        let (c, _) = fs.mkdir(a, &name("c")).await.unwrap();
+        fs.create(a, &name("f"), Default::default()).await.unwrap();
+        assert_eq!(nlink(&fs, ROOT_ID).await, 4);
+        assert_eq!(nlink(&fs, a).await, 3);
+        assert_eq!(nlink(&fs, c).await, 2);
+
+        fs.rename(a, &name("c"), b, &name("c")).await.unwrap();
+        assert_eq!(nlink(&fs, a).await, 2);
+        assert_eq!(nlink(&fs, b).await, 3);
+        assert_eq!(fs.lookup(c, &name("..")).await.unwrap(), b);
+
+        assert!(matches!(
+            fs.remove(ROOT_ID, &name("b")).await,
+            Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY)
+        ));
+        fs.remove(b, &name("c")).await.unwrap();
+        fs.remove(ROOT_ID, &name("b")).await.unwrap();
+        assert_eq!(nlink(&fs, ROOT_ID).await, 3);
+    }
+
+    #[tokio::test]
+    async fn sparse_files_allocate_only_written_blocks() {
+        let fs = MemFS::new();
+        let (file, _) = fs
+            .create(ROOT_ID, &name("f"), Default::default())
+            .await
+            .unwrap();
+        let attr = fs.write(file, 10 * BLOCK_SIZE + 1, b"xyz").await.unwrap();
+        assert_eq!(attr.size, 10 * BLOCK_SIZE + 4);
+        assert_eq!(attr.used, BLOCK_SIZE);
+
+        let (data, eof) = fs.read(file, BLOCK_SIZE - 2, 4).await.unwrap();
+        assert_eq!(data, vec![0; 4]);
+        assert!(!eof);
+        let (data, eof) = fs.read(file, 10 * BLOCK_SIZE, 100).await.unwrap();
+        assert_eq!(data, b"\0xyz");
+        assert!(eof);
+    }
+
+    #[tokio::test]
+    async fn truncation_zeroes_discarded_data() {
+        let fs = MemFS::new();
+        let (file, _) = fs
+            .create(ROOT_ID, &name("f"), Default::default())
+            .await
+            .unwrap();
+        fs.write(file, 0, &[7; 10]).await.unwrap();
+        fs.write(file, 3 * BLOCK_SIZE, &[7; 10]).await.unwrap();
+
+        let attr = fs.setattr(file, size(5)).await.unwrap();
+        assert_eq!((attr.size, attr.used), (5, BLOCK_SIZE));
+        let attr = fs.setattr(file, size(10)).await.unwrap();
+        assert_eq!((attr.size, attr.used), (10, BLOCK_SIZE));
+        let (data, _) = fs.read(file, 0, 10).await.unwrap();
+        assert_eq!(data, [7, 7, 7, 7, 7, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn cookies_resume_after_changes() {
+        let fs = MemFS::new();
+        for n in ["a", "b", "c", "d", "e"] {
+            fs.create(ROOT_ID, &name(n), Default::default())
+                .await
+                .unwrap();
+        }
+        let page = fs.readdir(ROOT_ID, 0, 2).await.unwrap();
+        assert!(!page.end);
+        let cookie = page.entries.last().unwrap().fileid;
+
+        // removing listed and unlisted names and adding new ones does not
+        // repeat or skip any of the names that were there all along
+        fs.remove(ROOT_ID, &name("b")).await.unwrap();
+        fs.remove(ROOT_ID, &name("c")).await.unwrap();
+        fs.create(ROOT_ID, &name("f"), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            list(&fs, ROOT_ID, cookie, 10).await,
+            (vec!["d".into(), "e".into(), "f".into()], true)
+        );
+    }
+
+    #[tokio::test]
+    async fn the_largest_cookie_ends_the_listing() {
+        let fs = MemFS::new();
+        fs.create(ROOT_ID, &name("a"), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(list(&fs, ROOT_ID, u64::MAX, 10).await, (vec![], true));
+    }
+
+    #[tokio::test]
+    async fn names_of_one_file_share_a_page() {
+        let fs = MemFS::new();
+        let (file, _) = fs
+            .create(ROOT_ID, &name("a"), Default::default())
+            .await
+            .unwrap();
+        fs.link(file, ROOT_ID, &name("b")).await.unwrap();
+        fs.create(ROOT_ID, &name("c"), Default::default())
+            .await
+            .unwrap();
+
+        let (names, end) = list(&fs, ROOT_ID, 0, 1).await;
+        assert_eq!(names.len(), 2);
+        assert!(!end);
+        assert_eq!(list(&fs, ROOT_ID, file, 1).await, (vec!["c".into()], true));
+    }
+
+    #[tokio::test]
+    async fn failed_attributes_leave_no_node_behind() {
+        let fs = MemFS::new();
+        let target: nfs3::nfspath3 = b"target".as_slice().into();
+        let res = fs.symlink_wcc(ROOT_ID, &name("l"), &target, &size(1)).await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_INVAL)));
+        let res = fs
+            .mknod_wcc(
+                ROOT_ID,
+                &name("p"),
+                nfs3::ftype3::NF3FIFO,
+                Default::default(),
+                &size(1),
+            )
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_INVAL)));
+
+        assert!(matches!(
+            fs.lookup(ROOT_ID, &name("l")).await,
+            Err(nfs3::nfsstat3::NFS3ERR_NOENT)
+        ));
+        assert!(matches!(
+            fs.lookup(ROOT_ID, &name("p")).await,
+            Err(nfs3::nfsstat3::NFS3ERR_NOENT)
+        ));
+        assert_eq!(list(&fs, ROOT_ID, 0, 10).await, (vec![], true));
+    }
+
+    #[tokio::test]
+    async fn reads_update_the_access_time() {
+        let fs = MemFS::new();
+        let (file, _) = fs
+            .create(ROOT_ID, &name("f"), Default::default())
+            .await
+            .unwrap();
+        let past = nfs3::nfstime3 {
+            seconds: 1,
+            nseconds: 2,
+        };
+        let attr = nfs3::sattr3 {
+            atime: nfs3::set_atime::SET_TO_CLIENT_TIME(past),
+            ..Default::default()
+        };
+        assert_eq!(fs.setattr(file, attr).await.unwrap().atime.seconds, 1);
+        fs.read(file, 0, 1).await.unwrap();
+        assert!(fs.getattr(file).await.unwrap().atime.seconds > 1);
+    }
+
+    #[tokio::test]
+    async fn removals_check_the_entry_kind_without_extra_calls() {
+        use crate::adapters::fault::{Fault, FaultRule, Faulty, Operation};
+        use std::time::Duration;
+
+        let fs = Faulty::new(MemFS::new());
+        let (dir, _) = fs.mkdir(ROOT_ID, &name("d")).await.unwrap();
+        fs.create(dir, &name("inner"), Default::default())
+            .await
+            .unwrap();
+        fs.create(ROOT_ID, &name("f"), Default::default())
+            .await
+            .unwrap();
+        let calls = fs.control().add_rule(FaultRule {
+            ops: vec![Operation::Lookup, Operation::Getattr],
+            ..FaultRule::new(Fault::Delay(Duration::ZERO))
+        });
+
+        // the kind is checked before the directory is found not to be empty
+        let res = fs
+            .remove_wcc(ROOT_ID, &name("d"), vfs::RemoveKind::NonDir)
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_ISDIR)));
+        assert!(matches!(res.wcc.after, nfs3::post_op_attr::attributes(_)));
+        let res = fs
+            .remove_wcc(ROOT_ID, &name("f"), vfs::RemoveKind::Dir)
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_NOTDIR)));
+        let res = fs
+            .remove_wcc(ROOT_ID, &name("d"), vfs::RemoveKind::Dir)
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY)));
+        fs.remove_wcc(ROOT_ID, &name("f"), vfs::RemoveKind::NonDir)
+            .await
+            .result
+            .unwrap();
+        fs.remove_wcc(dir, &name("inner"), vfs::RemoveKind::Any)
+            .await
+            .result
+            .unwrap();
+        fs.remove_wcc(ROOT_ID, &name("d"), vfs::RemoveKind::Dir)
+            .await
+            .result
+            .unwrap();
+        assert_eq!(fs.control().hits(calls), Some(0));
+        assert_eq!(list(fs.inner(), ROOT_ID, 0, 10).await, (vec![], true));
+    }
+}
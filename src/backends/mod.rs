@@ -0,0 +1,72 @@
+//! Ready-to-use file system implementations
+//!
+//! This module contains implementations of the [`NFSFileSystem`](crate::vfs::NFSFileSystem)
+//! trait that can be exported directly or used as building blocks for custom file systems.
+
+use std::collections::BTreeSet;
+
+use crate::protocol::xdr::nfs3;
+use crate::vfs;
+
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "dedup")]
+pub mod dedup;
+pub mod memfs;
+#[cfg(unix)]
+pub mod passthrough;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+#[cfg(feature = "archive")]
+pub use archive::ArchiveFS;
+#[cfg(feature = "dedup")]
+pub use dedup::{DedupFS, DedupOptions, GcStats};
+pub use memfs::MemFS;
+#[cfg(unix)]
+pub use passthrough::{PassthroughFS, PassthroughOptions};
+#[cfg(feature = "s3")]
+pub use s3::{DirMarker, S3Options, S3FS};
+
+/// Lists one page of a directory whose names are ordered by file ID
+///
+/// The cookie is the file ID of the last entry sent, so all names of a hard-linked
+/// file in the directory are returned on the same page, even if that makes the page
+/// longer than `max_entries`.
+///
+/// # Arguments
+/// * `order` - Names of the directory keyed by (file ID, name)
+/// * `start_after` - Cookie to continue after; only larger file IDs are listed
+/// * `max_entries` - Number of entries after which the page may end
+/// * `attr` - Returns the attributes of a listed file ID
+pub(crate) fn readdir_by_fileid(
+    order: &BTreeSet<(nfs3::fileid3, Vec<u8>)>,
+    start_after: nfs3::fileid3,
+    max_entries: usize,
+    mut attr: impl FnMut(nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3>,
+) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+    // nothing can follow the largest cookie
+    let Some(first) = start_after.checked_add(1) else {
+        return Ok(vfs::ReadDirResult {
+            entries: Vec::new(),
+            end: true,
+        });
+    };
+    let mut entries: Vec<vfs::DirEntry> = Vec::new();
+    let mut iter = order.range((first, Vec::new())..).peekable();
+    while let Some((id, name)) = iter.next() {
+        entries.push(vfs::DirEntry {
+            fileid: *id,
+            name: name.as_slice().into(),
+            attr: attr(*id)?,
+        });
+        let same_file_next = matches!(iter.peek(), Some((next, _)) if next == id);
+        if entries.len() >= max_entries && !same_file_next {
+            break;
+        }
+    }
+    Ok(vfs::ReadDirResult {
+        entries,
+        end: iter.peek().is_none(),
+    })
+}
@@ -0,0 +1,1656 @@
+//! Passthrough file system exporting a local directory.
+//!
+//! `PassthroughFS` maps every NFS operation directly onto the corresponding operation
+//! of the host file system. Nothing is cached: attributes and directory listings are
+//! always read from the host, so changes made to the exported directory by other
+//! programs are visible to NFS clients immediately.
+//!
+//! File IDs are stable:
+//! - Objects on the same device as the exported root use their host inode number
+//! - Objects on other devices (below mount points), and the rare host inodes that
+//!   have the top bit set, get IDs with the top bit set, assigned on first sight
+//!
+//! The server remembers the parent directories and names under which it has seen
+//! each object it handed out, which it uses to find the object again. Removing one
+//! name of a hard-linked file keeps the object reachable through its other names.
+//! If the object was moved or deleted by another program, its file handle becomes
+//! stale until the object is looked up again under its new name. The least recently
+//! used objects are forgotten once about a million are known. When an ID map file is
+//! configured, this information is persisted together with the file handle
+//! generation, so file handles held by clients remain valid across server restarts.
+//! The file is compacted when it is opened and whenever it has grown to more than
+//! twice the size of the map.
+//!
+//! Sorted directory listings are kept for a small number of recently read
+//! directories, so paging through a large directory lists it only once. A listing
+//! is discarded as soon as the modification or change time of its directory differs.
+//!
+//! Stable writes are synced to disk before they are acknowledged. Unstable writes,
+//! and writes through the plain [`NFSFileSystem::write`] method, are left to the host
+//! page cache until the client sends COMMIT.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirEntryExt, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tracing::{debug, warn};
+
+use crate::file_ids;
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, WccResult};
+
+/// First file ID assigned to objects on devices other than the one of the root
+///
+/// Host inode numbers below this value are used as file IDs directly, so the
+/// two ranges never overlap.
+const FOREIGN_ID_BASE: nfs3::fileid3 = 1 << 63;
+
+/// Maximum number of path components followed when resolving a file ID
+const MAX_DEPTH: usize = 4096;
+
+/// Maximum length of a file name in bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// Maximum number of names remembered for one object
+const MAX_NAMES: usize = 16;
+
+/// Number of objects remembered before the least recently used ones are forgotten
+const MAX_RECORDS: usize = 1 << 20;
+
+/// Number of lines appended to an ID map file before it is compacted, on top of
+/// twice the number of known objects
+const ID_MAP_COMPACT_SLACK: usize = 4096;
+
+/// First line of an ID map file
+const ID_MAP_MAGIC: &str = "nfsserve-idmap-v2";
+
+/// First line of an ID map file that remembers a single name per object
+const ID_MAP_MAGIC_V1: &str = "nfsserve-idmap-v1";
+
+/// Host device and inode number identifying an object
+type InodeKey = (u64, u64);
+
+/// Directory entries sorted by file ID
+type Listing = Arc<Vec<(nfs3::fileid3, OsString)>>;
+
+/// Modification and change time of a directory, in seconds and nanoseconds
+type ListingVerifier = [i64; 4];
+
+/// Recently read directory listings
+type ListingCache = file_ids::ListingCache<ListingVerifier, (nfs3::fileid3, OsString)>;
+
+/// Options of a [`PassthroughFS`]
+#[derive(Debug, Clone, Default)]
+pub struct PassthroughOptions {
+    /// Present symbolic links as the objects they point to
+    ///
+    /// Dangling links are still presented as links. Note that links can point
+    /// outside of the exported directory.
+    pub follow_symlinks: bool,
+    /// Do not cross mount points
+    ///
+    /// Entries that reside on another file system than the exported root are
+    /// hidden from directory listings and cannot be looked up.
+    pub one_filesystem: bool,
+    /// File to persist the file ID map in
+    ///
+    /// When set, file handles remain valid across server restarts. The file is
+    /// created if it does not exist.
+    pub id_map_path: Option<PathBuf>,
+}
+
+/// Locations of an object the server has handed out a file ID for
+#[derive(Debug)]
+struct Record {
+    /// Host identity of the object
+    key: InodeKey,
+    /// Parent directory file IDs and names the object was seen under, most recent first
+    names: Vec<(nfs3::fileid3, OsString)>,
+    /// Value of the use counter when the object was last seen or resolved
+    last_used: u64,
+}
+
+/// Mapping between file IDs and host objects
+#[derive(Debug)]
+struct IdMap {
+    /// Device of the exported root
+    root_dev: u64,
+    /// File ID of the exported root
+    root_id: nfs3::fileid3,
+    /// Host identity of the exported root
+    root_key: InodeKey,
+    /// Known objects by file ID
+    records: HashMap<nfs3::fileid3, Record>,
+    /// File IDs assigned to objects on other devices or with large inode numbers
+    foreign: HashMap<InodeKey, nfs3::fileid3>,
+    /// Next file ID for objects on other devices
+    next_foreign: nfs3::fileid3,
+    /// Number of known objects above which the least recently used ones are forgotten
+    max_records: usize,
+    /// Counter incremented on every use, used to find the least recently used objects
+    uses: u64,
+    /// File handle generation stored in the persisted map
+    generation: u64,
+    /// File the map is persisted to
+    log_path: Option<PathBuf>,
+    /// Log the map is persisted to
+    log: Option<BufWriter<File>>,
+    /// Number of lines in the log
+    log_lines: usize,
+}
+
+impl IdMap {
+    /// Creates an empty map for the given root
+    fn new(root: InodeKey) -> Self {
+        let mut map = IdMap {
+            root_dev: root.0,
+            root_id: root.1,
+            root_key: root,
+            records: HashMap::new(),
+            foreign: HashMap::new(),
+            next_foreign: FOREIGN_ID_BASE,
+            max_records: MAX_RECORDS,
+            uses: 0,
+            generation: new_generation(),
+            log_path: None,
+            log: None,
+            log_lines: 0,
+        };
+        map.root_id = map.id_for(root);
+        map
+    }
+
+    /// Loads a persisted map, or starts a new one if the file does not exist
+    /// or belongs to another root
+    ///
+    /// The file is compacted while loading. Returns the map and the file handle generation.
+    fn load(path: &Path, root: InodeKey) -> std::io::Result<(Self, u64)> {
+        let mut map = IdMap::new(root);
+        match File::open(path) {
+            Ok(file) => {
+                let mut lines = BufReader::new(file).lines();
+                let header = lines.next().transpose()?.unwrap_or_default();
+                let fields: Vec<&str> = header.split(' ').collect();
+                let root_fields = [root.0.to_string(), root.1.to_string()];
+                let generation = fields.get(1).and_then(|g| g.parse().ok());
+                let v1 = fields[0] == ID_MAP_MAGIC_V1;
+                match generation {
+                    Some(generation)
+                        if fields.len() == 4
+                            && (v1 || fields[0] == ID_MAP_MAGIC)
+                            && fields[2..] == root_fields[..] =>
+                    {
+                        map.generation = generation;
+                        for line in lines {
+                            map.replay(&line?, v1);
+                        }
+                    }
+                    _ => warn!("ID map {:?} belongs to another export, starting over", path),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        map.log_path = Some(path.to_path_buf());
+        map.compact()?;
+        let generation = map.generation;
+        Ok((map, generation))
+    }
+
+    /// Applies one line of a persisted map
+    ///
+    /// In version 1 files a record replaces all names of the object.
+    fn replay(&mut self, line: &str, v1: bool) {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            ["R", id, dev, ino, parent, name] => {
+                let parsed = (
+                    id.parse(),
+                    dev.parse(),
+                    ino.parse(),
+                    parent.parse(),
+                    hex_decode(name),
+                );
+                if let (Ok(id), Ok(dev), Ok(ino), Ok(parent), Some(name)) = parsed {
+                    if id >= FOREIGN_ID_BASE {
+                        self.foreign.insert((dev, ino), id);
+                        self.next_foreign = self.next_foreign.max(id + 1);
+                    }
+                    if v1 {
+                        self.records.remove(&id);
+                    }
+                    self.add_name(id, (dev, ino), parent, OsString::from_vec(name));
+                }
+            }
+            ["U", id, parent, name] => {
+                if let (Ok(id), Ok(parent), Some(name)) =
+                    (id.parse(), parent.parse(), hex_decode(name))
+                {
+                    self.unlink_id(id, parent, OsStr::from_bytes(&name), true);
+                }
+            }
+            ["D", id] => {
+                if let Ok(id) = id.parse() {
+                    self.drop_record(id);
+                }
+            }
+            _ => warn!("Ignoring malformed ID map line {:?}", line),
+        }
+    }
+
+    /// Returns the file ID of an object, assigning one if necessary
+    fn id_for(&mut self, key: InodeKey) -> nfs3::fileid3 {
+        if let Some(id) = self.known_id(key) {
+            return id;
+        }
+        let id = self.next_foreign;
+        self.next_foreign += 1;
+        self.foreign.insert(key, id);
+        id
+    }
+
+    /// Returns the file ID of an object if it has one
+    fn known_id(&self, key: InodeKey) -> Option<nfs3::fileid3> {
+        if key.0 == self.root_dev && key.1 < FOREIGN_ID_BASE {
+            return Some(key.1);
+        }
+        self.foreign.get(&key).copied()
+    }
+
+    /// Records that an object was seen under the given name, returning its file ID
+    fn observe(&mut self, parent: nfs3::fileid3, name: &OsStr, key: InodeKey) -> nfs3::fileid3 {
+        let id = self.id_for(key);
+        if id == self.root_id {
+            return id;
+        }
+        if self.add_name(id, key, parent, name.to_os_string()) {
+            let line = format!("R {} {} {} {} {}", id, key.0, key.1, parent, hex(name));
+            self.append(line);
+            self.evict();
+        }
+        id
+    }
+
+    /// Adds a name to an object, creating its record if necessary
+    ///
+    /// Returns whether the name was new. The oldest name is forgotten if the object
+    /// has too many.
+    fn add_name(
+        &mut self,
+        id: nfs3::fileid3,
+        key: InodeKey,
+        parent: nfs3::fileid3,
+        name: OsString,
+    ) -> bool {
+        self.uses += 1;
+        let record = self.records.entry(id).or_insert_with(|| Record {
+            key,
+            names: Vec::new(),
+            last_used: 0,
+        });
+        record.last_used = self.uses;
+        if let Some(pos) = record
+            .names
+            .iter()
+            .position(|n| n.0 == parent && n.1 == name)
+        {
+            record.names[..=pos].rotate_right(1);
+            return false;
+        }
+        record.names.insert(0, (parent, name));
+        record.names.truncate(MAX_NAMES);
+        true
+    }
+
+    /// Forgets one name of an object after it was removed or renamed
+    ///
+    /// The object itself is forgotten when it has no other links on the host or no
+    /// other known names.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Host identity of the object
+    /// * `parent` - File ID of the directory the name was removed from
+    /// * `name` - Name that was removed
+    /// * `links_left` - Whether the host object still has other links
+    fn unlink(&mut self, key: InodeKey, parent: nfs3::fileid3, name: &OsStr, links_left: bool) {
+        if let Some(id) = self.known_id(key) {
+            if self.unlink_id(id, parent, name, links_left) {
+                self.append(format!("U {} {} {}", id, parent, hex(name)));
+            }
+        }
+    }
+
+    /// Forgets one name of an object by file ID, returning whether a name was forgotten
+    /// while the object is still remembered
+    fn unlink_id(
+        &mut self,
+        id: nfs3::fileid3,
+        parent: nfs3::fileid3,
+        name: &OsStr,
+        links_left: bool,
+    ) -> bool {
+        let Some(record) = self.records.get_mut(&id) else {
+            return false;
+        };
+        let count = record.names.len();
+        record.names.retain(|n| !(n.0 == parent && n.1 == name));
+        if !links_left || record.names.is_empty() {
+            self.drop_record(id);
+            self.append(format!("D {}", id));
+            return false;
+        }
+        record.names.len() != count
+    }
+
+    /// Forgets an object and the file ID assigned to it
+    fn drop_record(&mut self, id: nfs3::fileid3) {
+        if let Some(record) = self.records.remove(&id) {
+            if id >= FOREIGN_ID_BASE {
+                self.foreign.remove(&record.key);
+            }
+        }
+    }
+
+    /// Forgets the least recently used objects once more than `max_records` are known
+    ///
+    /// Only objects that are not the parent of another known object are forgotten, so
+    /// that the remaining objects can still be found. A tenth of the map is freed at once
+    /// to spread the cost of finding them.
+    fn evict(&mut self) {
+        if self.records.len() <= self.max_records {
+            return;
+        }
+        let parents: HashSet<nfs3::fileid3> = self
+            .records
+            .values()
+            .flat_map(|record| record.names.iter().map(|n| n.0))
+            .collect();
+        let mut leaves: Vec<(u64, nfs3::fileid3)> = self
+            .records
+            .iter()
+            .filter(|(id, _)| !parents.contains(id))
+            .map(|(id, record)| (record.last_used, *id))
+            .collect();
+        leaves.sort_unstable();
+        let excess = self.records.len() - self.max_records * 9 / 10;
+        for (_, id) in leaves.into_iter().take(excess) {
+            self.drop_record(id);
+            self.append(format!("D {}", id));
+        }
+    }
+
+    /// Appends a line to the persisted map
+    fn append(&mut self, line: String) {
+        if let Some(log) = self.log.as_mut() {
+            if let Err(e) = writeln!(log, "{}", line) {
+                warn!("Failed to persist ID map: {:?}", e);
+            }
+            self.log_lines += 1;
+        }
+    }
+
+    /// Writes buffered changes to the persisted map, compacting it once it has grown
+    fn flush(&mut self) {
+        let Some(log) = self.log.as_mut() else {
+            return;
+        };
+        let result = if self.log_lines > 2 * self.records.len() + ID_MAP_COMPACT_SLACK {
+            self.compact()
+        } else {
+            log.flush()
+        };
+        if let Err(e) = result {
+            warn!("Failed to persist ID map: {:?}", e);
+        }
+    }
+
+    /// Replaces the persisted map with a copy holding only the current records,
+    /// and continues appending to the copy
+    fn compact(&mut self) -> std::io::Result<()> {
+        let Some(path) = self.log_path.as_deref() else {
+            return Ok(());
+        };
+        let tmp_path = path.with_extension("tmp");
+        let mut log = BufWriter::new(File::create(&tmp_path)?);
+        let (dev, ino) = self.root_key;
+        writeln!(log, "{} {} {} {}", ID_MAP_MAGIC, self.generation, dev, ino)?;
+        let mut lines = 0;
+        for (id, record) in self.records.iter() {
+            // names are added in front when replayed, so the oldest goes first
+            for (parent, name) in record.names.iter().rev() {
+                let (dev, ino) = record.key;
+                writeln!(log, "R {} {} {} {} {}", id, dev, ino, parent, hex(name))?;
+                lines += 1;
+            }
+        }
+        log.flush()?;
+        log.get_ref().sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        self.log = Some(log);
+        self.log_lines = lines;
+        Ok(())
+    }
+
+    /// Returns the host paths an object may be found at, and its expected identity
+    ///
+    /// There is one path per known name of the object, most recently seen first.
+    /// Marks the object and its ancestors as used.
+    fn paths(
+        &mut self,
+        root: &Path,
+        id: nfs3::fileid3,
+    ) -> Result<(Vec<PathBuf>, InodeKey), nfs3::nfsstat3> {
+        if id == self.root_id {
+            return Ok((vec![root.to_path_buf()], self.root_key));
+        }
+        self.uses += 1;
+        let uses = self.uses;
+        let record = self
+            .records
+            .get_mut(&id)
+            .ok_or(nfs3::nfsstat3::NFS3ERR_STALE)?;
+        record.last_used = uses;
+        let key = record.key;
+        let names = record.names.clone();
+        let paths: Vec<PathBuf> = names
+            .into_iter()
+            .filter_map(|(parent, name)| Some(self.dir_path(root, parent, uses)?.join(name)))
+            .collect();
+        if paths.is_empty() {
+            return Err(nfs3::nfsstat3::NFS3ERR_STALE);
+        }
+        Ok((paths, key))
+    }
+
+    /// Returns the host path of a directory from the names it was last seen under
+    fn dir_path(&mut self, root: &Path, id: nfs3::fileid3, uses: u64) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut cur = id;
+        while cur != self.root_id {
+            if names.len() >= MAX_DEPTH {
+                return None;
+            }
+            let record = self.records.get_mut(&cur)?;
+            record.last_used = uses;
+            let (parent, name) = record.names.first()?;
+            names.push(name.clone());
+            cur = *parent;
+        }
+        let mut path = root.to_path_buf();
+        path.extend(names.iter().rev());
+        Some(path)
+    }
+}
+
+/// Encodes a host name as lowercase hex
+fn hex(name: &OsStr) -> String {
+    hex_encode(name.as_bytes())
+}
+
+/// Encodes bytes as lowercase hex
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes lowercase or uppercase hex
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Returns a new file handle generation number based on the current time
+fn new_generation() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Converts a host I/O error to an NFS status
+fn io_error(e: std::io::Error) -> nfs3::nfsstat3 {
+    match e.kind() {
+        ErrorKind::NotFound => nfs3::nfsstat3::NFS3ERR_NOENT,
+        ErrorKind::PermissionDenied => nfs3::nfsstat3::NFS3ERR_ACCES,
+        ErrorKind::AlreadyExists => nfs3::nfsstat3::NFS3ERR_EXIST,
+        ErrorKind::NotADirectory => nfs3::nfsstat3::NFS3ERR_NOTDIR,
+        ErrorKind::IsADirectory => nfs3::nfsstat3::NFS3ERR_ISDIR,
+        ErrorKind::DirectoryNotEmpty => nfs3::nfsstat3::NFS3ERR_NOTEMPTY,
+        ErrorKind::ReadOnlyFilesystem => nfs3::nfsstat3::NFS3ERR_ROFS,
+        ErrorKind::StorageFull => nfs3::nfsstat3::NFS3ERR_NOSPC,
+        ErrorKind::QuotaExceeded => nfs3::nfsstat3::NFS3ERR_DQUOT,
+        ErrorKind::FileTooLarge => nfs3::nfsstat3::NFS3ERR_FBIG,
+        ErrorKind::CrossesDevices => nfs3::nfsstat3::NFS3ERR_XDEV,
+        ErrorKind::InvalidFilename => nfs3::nfsstat3::NFS3ERR_NAMETOOLONG,
+        ErrorKind::TooManyLinks => nfs3::nfsstat3::NFS3ERR_MLINK,
+        ErrorKind::StaleNetworkFileHandle => nfs3::nfsstat3::NFS3ERR_STALE,
+        ErrorKind::InvalidInput => nfs3::nfsstat3::NFS3ERR_INVAL,
+        ErrorKind::Unsupported => nfs3::nfsstat3::NFS3ERR_NOTSUPP,
+        _ => {
+            debug!("Unmapped I/O error: {:?}", e);
+            nfs3::nfsstat3::NFS3ERR_IO
+        }
+    }
+}
+
+/// Checks that a name refers to a single directory entry and converts it to a host name
+fn check_name(name: &[u8]) -> Result<&OsStr, nfs3::nfsstat3> {
+    if name.is_empty() || name.contains(&b'/') || name.contains(&0) {
+        return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(nfs3::nfsstat3::NFS3ERR_NAMETOOLONG);
+    }
+    Ok(OsStr::from_bytes(name))
+}
+
+/// Checks that a name can be used for a new directory entry
+fn check_new_name(name: &[u8]) -> Result<&OsStr, nfs3::nfsstat3> {
+    if name == b"." || name == b".." {
+        return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+    }
+    check_name(name)
+}
+
+/// Returns the host identity of an object
+fn inode_key(meta: &Metadata) -> InodeKey {
+    (meta.dev(), meta.ino())
+}
+
+/// Converts host seconds and nanoseconds to an NFS timestamp
+fn nfstime(seconds: i64, nseconds: i64) -> nfs3::nfstime3 {
+    nfs3::nfstime3 {
+        seconds: seconds as u32,
+        nseconds: nseconds as u32,
+    }
+}
+
+/// Converts a host device number to NFS device numbers
+#[cfg(target_os = "linux")]
+fn rdev_to_specdata(rdev: u64) -> nfs3::specdata3 {
+    nfs3::specdata3 {
+        specdata1: libc::major(rdev as libc::dev_t),
+        specdata2: libc::minor(rdev as libc::dev_t),
+    }
+}
+
+/// Converts a host device number to NFS device numbers
+#[cfg(not(target_os = "linux"))]
+fn rdev_to_specdata(_rdev: u64) -> nfs3::specdata3 {
+    nfs3::specdata3::default()
+}
+
+/// Converts host metadata to NFS attributes, reporting them exactly as the host does
+fn metadata_to_fattr3(id: nfs3::fileid3, meta: &Metadata) -> nfs3::fattr3 {
+    let ft = meta.file_type();
+    let ftype = if ft.is_dir() {
+        nfs3::ftype3::NF3DIR
+    } else if ft.is_symlink() {
+        nfs3::ftype3::NF3LNK
+    } else if ft.is_block_device() {
+        nfs3::ftype3::NF3BLK
+    } else if ft.is_char_device() {
+        nfs3::ftype3::NF3CHR
+    } else if ft.is_fifo() {
+        nfs3::ftype3::NF3FIFO
+    } else if ft.is_socket() {
+        nfs3::ftype3::NF3SOCK
+    } else {
+        nfs3::ftype3::NF3REG
+    };
+    nfs3::fattr3 {
+        ftype,
+        mode: meta.mode() & 0o7777,
+        nlink: meta.nlink() as u32,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        size: meta.size(),
+        used: meta.blocks() * 512,
+        rdev: rdev_to_specdata(meta.rdev()),
+        fsid: 0,
+        fileid: id,
+        atime: nfstime(meta.atime(), meta.atime_nsec()),
+        mtime: nfstime(meta.mtime(), meta.mtime_nsec()),
+        ctime: nfstime(meta.ctime(), meta.ctime_nsec()),
+    }
+}
+
+/// Checks that an object is a regular file before reading or writing it
+fn check_regular(meta: &Metadata) -> Result<(), nfs3::nfsstat3> {
+    if meta.is_dir() {
+        Err(nfs3::nfsstat3::NFS3ERR_ISDIR)
+    } else if !meta.is_file() {
+        Err(nfs3::nfsstat3::NFS3ERR_INVAL)
+    } else {
+        Ok(())
+    }
+}
+
+/// Runs blocking file system code on the blocking thread pool
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, nfs3::nfsstat3> + Send + 'static,
+) -> Result<T, nfs3::nfsstat3> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or(Err(nfs3::nfsstat3::NFS3ERR_SERVERFAULT))
+}
+
+/// Reads the metadata of a host object, following symbolic links if requested
+///
+/// Dangling links are reported as links.
+fn stat(path: &Path, follow_symlinks: bool) -> std::io::Result<Metadata> {
+    if follow_symlinks {
+        if let Ok(meta) = std::fs::metadata(path) {
+            return Ok(meta);
+        }
+    }
+    std::fs::symlink_metadata(path)
+}
+
+/// Returns the identity of the object a directory entry refers to, and whether it
+/// keeps other links once the entry is removed
+///
+/// # Arguments
+///
+/// * `meta` - Metadata of the entry itself
+/// * `target` - Metadata of the object the entry is presented as, which differs from
+///   `meta` for followed symbolic links
+fn removed_link(meta: &Metadata, target: &Metadata) -> (InodeKey, bool) {
+    let key = inode_key(target);
+    // removing a followed link leaves its target alone
+    let links_left = key != inode_key(meta) || (!meta.is_dir() && meta.nlink() > 1);
+    (key, links_left)
+}
+
+/// Applies NFS attribute changes to a host object
+fn apply_sattr(
+    path: &Path,
+    meta: &Metadata,
+    setattr: &nfs3::sattr3,
+    follow_symlinks: bool,
+) -> Result<(), nfs3::nfsstat3> {
+    let is_link = meta.file_type().is_symlink();
+    let uid = match setattr.uid {
+        nfs3::set_uid3::uid(uid) => Some(uid),
+        nfs3::set_uid3::Void => None,
+    };
+    let gid = match setattr.gid {
+        nfs3::set_gid3::gid(gid) => Some(gid),
+        nfs3::set_gid3::Void => None,
+    };
+    if uid.is_some() || gid.is_some() {
+        if follow_symlinks {
+            std::os::unix::fs::chown(path, uid, gid).map_err(io_error)?;
+        } else {
+            std::os::unix::fs::lchown(path, uid, gid).map_err(io_error)?;
+        }
+    }
+    if let nfs3::set_mode3::mode(mode) = setattr.mode {
+        // the permissions of a link itself cannot be changed
+        if !is_link {
+            let perms = std::os::unix::fs::PermissionsExt::from_mode(mode & 0o7777);
+            std::fs::set_permissions(path, perms).map_err(io_error)?;
+        }
+    }
+    if let nfs3::set_size3::size(size) = setattr.size {
+        check_regular(meta)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(io_error)?;
+        file.set_len(size).map_err(io_error)?;
+    }
+    let now = filetime::FileTime::now();
+    let atime = match setattr.atime {
+        nfs3::set_atime::DONT_CHANGE => None,
+        nfs3::set_atime::SET_TO_SERVER_TIME => Some(now),
+        nfs3::set_atime::SET_TO_CLIENT_TIME(t) => Some(t.into()),
+    };
+    let mtime = match setattr.mtime {
+        nfs3::set_mtime::DONT_CHANGE => None,
+        nfs3::set_mtime::SET_TO_SERVER_TIME => Some(now),
+        nfs3::set_mtime::SET_TO_CLIENT_TIME(t) => Some(t.into()),
+    };
+    if atime.is_some() || mtime.is_some() {
+        let atime = atime.unwrap_or_else(|| filetime::FileTime::from_last_access_time(meta));
+        let mtime = mtime.unwrap_or_else(|| filetime::FileTime::from_last_modification_time(meta));
+        if is_link {
+            filetime::set_symlink_file_times(path, atime, mtime).map_err(io_error)?;
+        } else {
+            filetime::set_file_times(path, atime, mtime).map_err(io_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// File system that exports a local directory
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct PassthroughFS {
+    /// Exported host directory
+    root: Arc<Path>,
+    /// File ID of the exported directory
+    root_id: nfs3::fileid3,
+    /// Device of the exported directory
+    root_dev: u64,
+    /// Options
+    options: PassthroughOptions,
+    /// File handle generation number
+    generation: u64,
+    /// Mapping between file IDs and host objects
+    ids: Mutex<IdMap>,
+    /// Recently read directory listings
+    listings: Mutex<ListingCache>,
+}
+
+impl PassthroughFS {
+    /// Creates a passthrough file system with default options
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Directory to export
+    pub fn new(root: impl Into<PathBuf>) -> std::io::Result<Self> {
+        Self::with_options(root, PassthroughOptions::default())
+    }
+
+    /// Creates a passthrough file system
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Directory to export
+    /// * `options` - Options of the file system
+    pub fn with_options(
+        root: impl Into<PathBuf>,
+        options: PassthroughOptions,
+    ) -> std::io::Result<Self> {
+        let root = std::fs::canonicalize(root.into())?;
+        let meta = std::fs::metadata(&root)?;
+        if !meta.is_dir() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotADirectory,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        let key = inode_key(&meta);
+        let (ids, generation) = match &options.id_map_path {
+            Some(path) => IdMap::load(path, key)?,
+            None => (IdMap::new(key), new_generation()),
+        };
+        Ok(PassthroughFS {
+            root: root.into(),
+            root_id: ids.root_id,
+            root_dev: key.0,
+            options,
+            generation,
+            ids: Mutex::new(ids),
+            listings: Mutex::new(ListingCache::default()),
+        })
+    }
+
+    /// Runs an operation on the ID map, persisting its changes
+    fn with_ids<T>(&self, f: impl FnOnce(&mut IdMap) -> T) -> T {
+        let mut ids = self.ids.lock().unwrap();
+        let ret = f(&mut ids);
+        ids.flush();
+        ret
+    }
+
+    /// Finds the host object of a file ID, returning its path and current metadata
+    ///
+    /// Returns NFS3ERR_STALE if the object no longer exists under any of the names
+    /// it was seen under.
+    async fn resolve(&self, id: nfs3::fileid3) -> Result<(PathBuf, Metadata), nfs3::nfsstat3> {
+        let (paths, key) = self.ids.lock().unwrap().paths(&self.root, id)?;
+        let follow = self.options.follow_symlinks;
+        let found = blocking(move || {
+            for path in paths {
+                match stat(&path, follow).map_err(io_error) {
+                    Ok(meta) if inode_key(&meta) == key => return Ok(Some((path, meta))),
+                    Ok(_)
+                    | Err(nfs3::nfsstat3::NFS3ERR_NOENT)
+                    | Err(nfs3::nfsstat3::NFS3ERR_NOTDIR) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(None)
+        })
+        .await?;
+        found.ok_or_else(|| {
+            debug!("File {} is no longer where it was seen", id);
+            nfs3::nfsstat3::NFS3ERR_STALE
+        })
+    }
+
+    /// Finds a directory by file ID
+    async fn resolve_dir(&self, id: nfs3::fileid3) -> Result<(PathBuf, Metadata), nfs3::nfsstat3> {
+        let (path, meta) = self.resolve(id).await?;
+        if !meta.is_dir() {
+            return Err(nfs3::nfsstat3::NFS3ERR_NOTDIR);
+        }
+        Ok((path, meta))
+    }
+
+    /// Checks if an object is visible in the export
+    fn visible(&self, key: InodeKey) -> bool {
+        !self.options.one_filesystem || key.0 == self.root_dev
+    }
+
+    /// Looks up a directory entry on the host and records it
+    async fn lookup_child(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &OsStr,
+    ) -> Result<(nfs3::fileid3, Metadata), nfs3::nfsstat3> {
+        let (dir_path, _) = self.resolve_dir(dirid).await?;
+        let path = dir_path.join(name);
+        let follow = self.options.follow_symlinks;
+        let meta = blocking(move || stat(&path, follow).map_err(io_error)).await?;
+        let key = inode_key(&meta);
+        if !self.visible(key) {
+            return Err(nfs3::nfsstat3::NFS3ERR_NOENT);
+        }
+        let id = self.with_ids(|ids| ids.observe(dirid, name, key));
+        Ok((id, meta))
+    }
+
+    /// Creates an object in a directory with the given function and records it
+    async fn create_child(
+        &self,
+        dirid: nfs3::fileid3,
+        name: &[u8],
+        attr: Option<nfs3::sattr3>,
+        create: impl FnOnce(&Path) -> std::io::Result<()> + Send + 'static,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let name = check_new_name(name)?;
+        let (dir_path, _) = self.resolve_dir(dirid).await?;
+        let path = dir_path.join(name);
+        let follow = self.options.follow_symlinks;
+        let meta = blocking(move || {
+            create(&path).map_err(io_error)?;
+            if let Some(attr) = attr {
+                let meta = std::fs::symlink_metadata(&path).map_err(io_error)?;
+                apply_sattr(&path, &meta, &attr, false)?;
+            }
+            stat(&path, follow).map_err(io_error)
+        })
+        .await;
+        self.invalidate_listing(dirid);
+        let meta = meta?;
+        let id = self.with_ids(|ids| ids.observe(dirid, name, inode_key(&meta)));
+        Ok((id, metadata_to_fattr3(id, &meta)))
+    }
+
+    /// Discards the cached listing of a directory after changing it
+    ///
+    /// The directory timestamps would catch the change too, but not if it happens
+    /// within their granularity of the listing.
+    fn invalidate_listing(&self, dirid: nfs3::fileid3) {
+        self.listings.lock().unwrap().invalidate(dirid);
+    }
+
+    /// Lists a directory, returning entries in file ID order
+    ///
+    /// The listing is reused as long as the directory's timestamps do not change.
+    async fn list(&self, dirid: nfs3::fileid3) -> Result<Listing, nfs3::nfsstat3> {
+        let (dir_path, dir_meta) = self.resolve_dir(dirid).await?;
+        let verifier = [
+            dir_meta.mtime(),
+            dir_meta.mtime_nsec(),
+            dir_meta.ctime(),
+            dir_meta.ctime_nsec(),
+        ];
+        if let Some(entries) = self.listings.lock().unwrap().get(dirid, &verifier) {
+            return Ok(entries);
+        }
+        let dir_dev = dir_meta.dev();
+        let follow = self.options.follow_symlinks;
+        let found = blocking(move || {
+            let mut found = Vec::new();
+            for entry in std::fs::read_dir(&dir_path).map_err(io_error)? {
+                let entry = entry.map_err(io_error)?;
+                let Ok(ft) = entry.file_type() else {
+                    continue;
+                };
+                // directories may be mount points and followed links may point anywhere,
+                // so only those need a stat to find out what they are
+                let key = if ft.is_dir() || (follow && ft.is_symlink()) {
+                    match stat(&entry.path(), follow) {
+                        Ok(meta) => inode_key(&meta),
+                        Err(_) => continue,
+                    }
+                } else {
+                    (dir_dev, entry.ino())
+                };
+                found.push((key, entry.file_name()));
+            }
+            Ok(found)
+        })
+        .await?;
+        let mut entries: Vec<_> = self.with_ids(|ids| {
+            found
+                .into_iter()
+                .filter(|(key, _)| self.visible(*key))
+                .map(|(key, name)| (ids.observe(dirid, &name, key), name))
+                .collect()
+        });
+        entries.sort();
+        let entries = Arc::new(entries);
+        self.listings
+            .lock()
+            .unwrap()
+            .insert(dirid, verifier, entries.clone());
+        Ok(entries)
+    }
+
+    /// Removes a directory entry of the given kind
+    async fn remove_entry(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> Result<(), nfs3::nfsstat3> {
+        if filename.as_slice() == b"." || filename.as_slice() == b".." {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        let name = check_name(filename)?.to_os_string();
+        let (dir_path, _) = self.resolve_dir(dirid).await?;
+        let path = dir_path.join(&name);
+        let follow = self.options.follow_symlinks;
+        let removed = blocking(move || {
+            let followed = stat(&path, follow).ok();
+            let meta = std::fs::symlink_metadata(&path).map_err(io_error)?;
+            kind.check(meta.is_dir())?;
+            if meta.is_dir() {
+                std::fs::remove_dir(&path).map_err(io_error)?;
+            } else {
+                std::fs::remove_file(&path).map_err(io_error)?;
+            }
+            Ok(followed.map(|target| removed_link(&meta, &target)))
+        })
+        .await;
+        self.invalidate_listing(dirid);
+        if let Some((key, links_left)) = removed? {
+            self.with_ids(|ids| ids.unlink(key, dirid, &name, links_left));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for PassthroughFS {
+    fn capabilities(&self) -> vfs::Capabilities {
+        vfs::Capabilities::ReadWrite
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        self.root_id
+    }
+
+    fn id_to_fh(&self, id: nfs3::fileid3) -> nfs3::nfs_fh3 {
+        let mut data = Vec::with_capacity(16);
+        data.extend_from_slice(&self.generation.to_le_bytes());
+        data.extend_from_slice(&id.to_le_bytes());
+        nfs3::nfs_fh3 { data }
+    }
+
+    fn fh_to_id(&self, id: &nfs3::nfs_fh3) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        if id.data.len() != 16 {
+            return Err(nfs3::nfsstat3::NFS3ERR_BADHANDLE);
+        }
+        let generation = u64::from_le_bytes(id.data[0..8].try_into().unwrap());
+        if generation != self.generation {
+            return Err(nfs3::nfsstat3::NFS3ERR_STALE);
+        }
+        Ok(u64::from_le_bytes(id.data[8..16].try_into().unwrap()))
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        match filename.as_slice() {
+            b"." => {
+                self.resolve_dir(dirid).await?;
+                Ok(dirid)
+            }
+            b".." => {
+                self.resolve_dir(dirid).await?;
+                if dirid == self.root_id {
+                    return Ok(dirid);
+                }
+                let ids = self.ids.lock().unwrap();
+                let record = ids
+                    .records
+                    .get(&dirid)
+                    .ok_or(nfs3::nfsstat3::NFS3ERR_STALE)?;
+                let (parent, _) = record.names.first().ok_or(nfs3::nfsstat3::NFS3ERR_STALE)?;
+                Ok(*parent)
+            }
+            name => Ok(self.lookup_child(dirid, check_name(name)?).await?.0),
+        }
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (_, meta) = self.resolve(id).await?;
+        Ok(metadata_to_fattr3(id, &meta))
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (path, meta) = self.resolve(id).await?;
+        let follow = self.options.follow_symlinks;
+        let meta = blocking(move || {
+            apply_sattr(&path, &meta, &setattr, follow)?;
+            stat(&path, follow).map_err(io_error)
+        })
+        .await?;
+        Ok(metadata_to_fattr3(id, &meta))
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let (path, meta) = self.resolve(id).await?;
+        check_regular(&meta)?;
+        blocking(move || {
+            let file = File::open(&path).map_err(io_error)?;
+            let size = file.metadata().map_err(io_error)?.len();
+            let len = size.saturating_sub(offset).min(count as u64) as usize;
+            let mut buf = vec![0; len];
+            let mut read = 0;
+            while read < len {
+                match file.read_at(&mut buf[read..], offset + read as u64) {
+                    Ok(0) => break,
+                    Ok(n) => read += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(io_error(e)),
+                }
+            }
+            buf.truncate(read);
+            Ok((buf, offset + read as u64 >= size))
+        })
+        .await
+    }
+
+    async fn read_file_range(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<Option<vfs::FileRange>, nfs3::nfsstat3> {
+        let (path, meta) = self.resolve(id).await?;
+        check_regular(&meta)?;
+        blocking(move || {
+            let file = File::open(&path).map_err(io_error)?;
+            let size = file.metadata().map_err(io_error)?.len();
+            let start = offset.min(size);
+            let len = (size - start).min(count as u64) as u32;
+            Ok(Some(vfs::FileRange {
+                file: Arc::new(file),
+                offset: start,
+                len,
+                eof: start + len as u64 >= size,
+            }))
+        })
+        .await
+    }
+
+    /// Writes data without syncing it, like an UNSTABLE write
+    ///
+    /// Callers that need the data on disk use [`NFSFileSystem::write_wcc`] with a
+    /// stable level, or [`NFSFileSystem::commit`] once they are done writing.
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let res = self
+            .write_wcc(id, offset, data, nfs3::file::stable_how::UNSTABLE)
+            .await;
+        res.result.map(|(attr, _)| attr)
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let (path, meta) = match self.resolve(id).await {
+            Ok(found) => found,
+            Err(e) => {
+                return WccResult {
+                    result: Err(e),
+                    wcc: nfs3::wcc_data::default(),
+                }
+            }
+        };
+        let before = nfs3::pre_op_attr::attributes(metadata_to_fattr3(id, &meta).into());
+        if let Err(e) = check_regular(&meta) {
+            return WccResult {
+                result: Err(e),
+                wcc: nfs3::wcc_data {
+                    before,
+                    after: nfs3::post_op_attr::attributes(metadata_to_fattr3(id, &meta)),
+                },
+            };
+        }
+        let data = data.to_vec();
+        let written = blocking(move || {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .map_err(io_error)?;
+            file.write_all_at(&data, offset).map_err(io_error)?;
+            match stable {
+                nfs3::file::stable_how::UNSTABLE => {}
+                nfs3::file::stable_how::DATA_SYNC => file.sync_data().map_err(io_error)?,
+                nfs3::file::stable_how::FILE_SYNC => file.sync_all().map_err(io_error)?,
+            }
+            file.metadata().map_err(io_error)
+        })
+        .await;
+        match written {
+            Ok(meta) => {
+                let attr = metadata_to_fattr3(id, &meta);
+                WccResult {
+                    result: Ok((attr, stable)),
+                    wcc: nfs3::wcc_data {
+                        before,
+                        after: nfs3::post_op_attr::attributes(attr),
+                    },
+                }
+            }
+            Err(e) => WccResult {
+                result: Err(e),
+                wcc: nfs3::wcc_data {
+                    before,
+                    after: match self.getattr(id).await {
+                        Ok(attr) => nfs3::post_op_attr::attributes(attr),
+                        Err(_) => nfs3::post_op_attr::Void,
+                    },
+                },
+            },
+        }
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let mode = match attr.mode {
+            nfs3::set_mode3::mode(mode) => mode & 0o7777,
+            nfs3::set_mode3::Void => 0o644,
+        };
+        self.create_child(dirid, filename, Some(attr), move |path| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(mode)
+                .open(path)
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let create = |path: &Path| {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o644)
+                .open(path)
+                .map(|_| ())
+        };
+        Ok(self.create_child(dirid, filename, None, create).await?.0)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        self.create_child(dirid, dirname, None, |path| std::fs::create_dir(path))
+            .await
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.remove_entry(dirid, filename, vfs::RemoveKind::Any)
+            .await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        let remove = self.remove_entry(dirid, filename, kind);
+        vfs::dir_op_wcc(self, dirid, remove).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        if from_filename.as_slice() == b"." || from_filename.as_slice() == b".." {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        let from_name = check_name(from_filename)?;
+        let to_name = check_new_name(to_filename)?.to_os_string();
+        let (from_dir, _) = self.resolve_dir(from_dirid).await?;
+        let (to_dir, _) = self.resolve_dir(to_dirid).await?;
+        let from_path = from_dir.join(from_name);
+        let to_path = to_dir.join(&to_name);
+        let follow = self.options.follow_symlinks;
+        let renamed = blocking(move || {
+            let key = inode_key(&stat(&from_path, follow).map_err(io_error)?);
+            let replaced = match std::fs::symlink_metadata(&to_path) {
+                Ok(meta) => {
+                    let target = stat(&to_path, follow).map_err(io_error)?;
+                    Some(removed_link(&meta, &target))
+                }
+                Err(_) => None,
+            };
+            std::fs::rename(&from_path, &to_path).map_err(io_error)?;
+            Ok((key, replaced))
+        })
+        .await;
+        self.invalidate_listing(from_dirid);
+        self.invalidate_listing(to_dirid);
+        let (key, replaced) = renamed?;
+        // renaming a name onto another name of the same file changes nothing
+        if replaced.is_some_and(|(replaced, _)| replaced == key) {
+            return Ok(());
+        }
+        let from_name = from_name.to_os_string();
+        self.with_ids(|ids| {
+            if let Some((replaced, links_left)) = replaced {
+                ids.unlink(replaced, to_dirid, &to_name, links_left);
+            }
+            // the new name goes first, so the object is not forgotten in between
+            ids.observe(to_dirid, &to_name, key);
+            if (from_dirid, from_name.as_os_str()) != (to_dirid, to_name.as_os_str()) {
+                ids.unlink(key, from_dirid, &from_name, true);
+            }
+        });
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let listing = self
+            .readdir_simple_after(dirid, start_after, max_entries)
+            .await?;
+        let mut entries = Vec::with_capacity(listing.entries.len());
+        for entry in listing.entries {
+            // entries removed since they were listed are skipped
+            if let Ok(attr) = self.getattr(entry.fileid).await {
+                entries.push(vfs::DirEntry {
+                    fileid: entry.fileid,
+                    name: entry.name,
+                    attr,
+                });
+            }
+        }
+        Ok(vfs::ReadDirResult {
+            entries,
+            end: listing.end,
+        })
+    }
+
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
+    ) -> Result<vfs::ReadDirSimpleResult, nfs3::nfsstat3> {
+        let listing = self.list(dirid).await?;
+        let start = listing.partition_point(|(id, _)| *id <= start_after);
+        let mut entries: Vec<vfs::DirEntrySimple> = Vec::new();
+        let mut iter = listing[start..].iter().peekable();
+        while let Some((fileid, name)) = iter.next() {
+            let fileid = *fileid;
+            entries.push(vfs::DirEntrySimple {
+                fileid,
+                name: name.as_bytes().to_vec().into(),
+                attr: None,
+            });
+            // the cookie is the file ID, so all names of a hard-linked file
+            // in this directory must be returned together
+            let same_file_next = matches!(iter.peek(), Some((next, _)) if *next == fileid);
+            if entries.len() >= count && !same_file_next {
+                break;
+            }
+        }
+        Ok(vfs::ReadDirSimpleResult::new(
+            entries,
+            iter.peek().is_none(),
+        ))
+    }
+
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<vfs::DirEntryStream<'_>, nfs3::nfsstat3> {
+        let listing = self.list(dirid).await?;
+        let start = listing.partition_point(|(id, _)| *id <= start_after);
+        let entries = (start..listing.len()).map(move |i| {
+            let (fileid, name) = &listing[i];
+            Ok(vfs::DirEntrySimple {
+                fileid: *fileid,
+                name: name.as_bytes().to_vec().into(),
+                attr: None,
+            })
+        });
+        Ok(futures::stream::iter(entries).boxed())
+    }
+
+    async fn symlink(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let target = OsString::from_vec(symlink.to_vec());
+        self.create_child(dirid, linkname, Some(*attr), move |path| {
+            std::os::unix::fs::symlink(&target, path)
+        })
+        .await
+    }
+
+    async fn readlink(&self, id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        let (path, meta) = self.resolve(id).await?;
+        if !meta.file_type().is_symlink() {
+            return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+        }
+        let target = blocking(move || std::fs::read_link(&path).map_err(io_error)).await?;
+        Ok(target.into_os_string().into_vec().into())
+    }
+
+    async fn link(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (src, meta) = self.resolve(file_id).await?;
+        if meta.is_dir() {
+            return Err(nfs3::nfsstat3::NFS3ERR_ISDIR);
+        }
+        let (_, attr) = self
+            .create_child(link_dir_id, link_name, None, move |path| {
+                std::fs::hard_link(&src, path)
+            })
+            .await?;
+        Ok(attr)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn mknod(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let (kind, dev) = match ftype {
+            nfs3::ftype3::NF3CHR => (
+                libc::S_IFCHR,
+                libc::makedev(specdata.specdata1, specdata.specdata2),
+            ),
+            nfs3::ftype3::NF3BLK => (
+                libc::S_IFBLK,
+                libc::makedev(specdata.specdata1, specdata.specdata2),
+            ),
+            nfs3::ftype3::NF3FIFO => (libc::S_IFIFO, 0),
+            nfs3::ftype3::NF3SOCK => (libc::S_IFSOCK, 0),
+            _ => return Err(nfs3::nfsstat3::NFS3ERR_BADTYPE),
+        };
+        let mode = match attrs.mode {
+            nfs3::set_mode3::mode(mode) => mode & 0o7777,
+            nfs3::set_mode3::Void => 0o644,
+        };
+        self.create_child(dir_id, name, Some(*attrs), move |path| {
+            let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: `path` is a valid NUL-terminated string
+            if unsafe { libc::mknod(path.as_ptr(), kind | mode, dev) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn mknod(
+        &self,
+        _dir_id: nfs3::fileid3,
+        _name: &nfs3::filename3,
+        _ftype: nfs3::ftype3,
+        _specdata: nfs3::specdata3,
+        _attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        _offset: u64,
+        _count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let (path, meta) = self.resolve(file_id).await?;
+        let meta = if meta.is_file() {
+            blocking(move || {
+                let file = File::open(&path).map_err(io_error)?;
+                file.sync_all().map_err(io_error)?;
+                file.metadata().map_err(io_error)
+            })
+            .await?
+        } else {
+            meta
+        };
+        Ok(metadata_to_fattr3(file_id, &meta))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Temporary directory that is removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!(
+                "nfsserve-passthrough-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(path.join("export")).unwrap();
+            TempDir(path)
+        }
+
+        fn export(&self) -> PathBuf {
+            self.0.join("export")
+        }
+
+        fn options(&self) -> PassthroughOptions {
+            PassthroughOptions {
+                id_map_path: Some(self.0.join("ids")),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn name(s: &str) -> nfs3::filename3 {
+        s.as_bytes().into()
+    }
+
+    #[tokio::test]
+    async fn removals_check_the_entry_kind() {
+        let dir = TempDir::new("remove");
+        std::fs::create_dir(dir.export().join("d")).unwrap();
+        std::fs::write(dir.export().join("f"), b"data").unwrap();
+        let fs = PassthroughFS::new(dir.export()).unwrap();
+        let root = fs.root_dir();
+        let res = fs
+            .remove_wcc(root, &name("d"), vfs::RemoveKind::NonDir)
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_ISDIR)));
+        let res = fs.remove_wcc(root, &name("f"), vfs::RemoveKind::Dir).await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_NOTDIR)));
+        assert!(dir.export().join("d").is_dir() && dir.export().join("f").is_file());
+        fs.remove_wcc(root, &name("d"), vfs::RemoveKind::Dir)
+            .await
+            .result
+            .unwrap();
+        fs.remove_wcc(root, &name("f"), vfs::RemoveKind::NonDir)
+            .await
+            .result
+            .unwrap();
+        assert_eq!(std::fs::read_dir(dir.export()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn hard_links_survive_removing_one_name() {
+        let dir = TempDir::new("links");
+        let fs = PassthroughFS::new(dir.export()).unwrap();
+        let root = fs.root_dir();
+        let (file, _) = fs
+            .create(root, &name("a"), Default::default())
+            .await
+            .unwrap();
+        fs.link(file, root, &name("b")).await.unwrap();
+
+        fs.remove(root, &name("a")).await.unwrap();
+        assert_eq!(fs.getattr(file).await.unwrap().nlink, 1);
+        fs.remove(root, &name("b")).await.unwrap();
+        assert!(matches!(
+            fs.getattr(file).await,
+            Err(nfs3::nfsstat3::NFS3ERR_STALE)
+        ));
+    }
+
+    #[tokio::test]
+    async fn names_are_kept_across_restarts() {
+        let dir = TempDir::new("restart");
+        let fs = PassthroughFS::with_options(dir.export(), dir.options()).unwrap();
+        let root = fs.root_dir();
+        let (sub, _) = fs.mkdir(root, &name("sub")).await.unwrap();
+        let (file, _) = fs
+            .create(sub, &name("a"), Default::default())
+            .await
+            .unwrap();
+        fs.link(file, root, &name("b")).await.unwrap();
+        fs.remove(sub, &name("a")).await.unwrap();
+        fs.rename(root, &name("b"), sub, &name("c")).await.unwrap();
+        let fh = fs.id_to_fh(file);
+        drop(fs);
+
+        let fs = PassthroughFS::with_options(dir.export(), dir.options()).unwrap();
+        let id = fs.fh_to_id(&fh).unwrap();
+        assert_eq!(id, file);
+        assert_eq!(fs.getattr(id).await.unwrap().nlink, 1);
+        assert_eq!(fs.lookup(sub, &name("c")).await.unwrap(), file);
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_files_are_forgotten() {
+        let dir = TempDir::new("evict");
+        let fs = PassthroughFS::new(dir.export()).unwrap();
+        fs.ids.lock().unwrap().max_records = 10;
+        let root = fs.root_dir();
+        let (sub, _) = fs.mkdir(root, &name("sub")).await.unwrap();
+        let mut files = Vec::new();
+        for i in 0..20 {
+            let (file, _) = fs
+                .create(sub, &name(&i.to_string()), Default::default())
+                .await
+                .unwrap();
+            files.push(file);
+        }
+        assert!(fs.ids.lock().unwrap().records.len() <= 10);
+        // the directory holding the remembered files is never forgotten
+        fs.getattr(sub).await.unwrap();
+        fs.getattr(files[19]).await.unwrap();
+        assert!(matches!(
+            fs.getattr(files[0]).await,
+            Err(nfs3::nfsstat3::NFS3ERR_STALE)
+        ));
+        // looking a forgotten file up again makes it reachable under the same ID
+        assert_eq!(fs.lookup(sub, &name("0")).await.unwrap(), files[0]);
+        fs.getattr(files[0]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_id_map_is_compacted_while_running() {
+        let dir = TempDir::new("compact");
+        let fs = PassthroughFS::with_options(dir.export(), dir.options()).unwrap();
+        let root = fs.root_dir();
+        for _ in 0..ID_MAP_COMPACT_SLACK {
+            fs.create(root, &name("a"), Default::default())
+                .await
+                .unwrap();
+            fs.remove(root, &name("a")).await.unwrap();
+        }
+        let lines = std::fs::read_to_string(dir.0.join("ids"))
+            .unwrap()
+            .lines()
+            .count();
+        assert!(lines <= ID_MAP_COMPACT_SLACK + 1, "{} lines", lines);
+    }
+}
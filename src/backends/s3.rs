@@ -0,0 +1,1944 @@
+//! File system backed by an S3-compatible object store.
+//!
+//! [`S3FS`] exports a bucket, or a key prefix within it, mapping directories onto
+//! key prefixes and regular files onto objects:
+//! - Directories are listed with ListObjectsV2, using `/` as the delimiter
+//! - Empty directories are kept as marker objects, following the configured
+//!   [`DirMarker`] convention
+//! - Reads are ranged GET requests
+//! - Writes to a file are collected and sent as a multipart upload, one part as soon
+//!   as `part_size` contiguous bytes are available. COMMIT, stable writes, SETATTR
+//!   and reads of the file complete the upload, which replaces the object
+//!
+//! Objects cannot be modified in place, so changing an existing object uploads it
+//! again in full, copying the unchanged data from the old object, and renames copy
+//! every object involved before deleting the originals. Symbolic links, hard links
+//! and special files are not supported. Permissions, owners and times are not
+//! stored: every object reports the modes and owner given in the options, and
+//! changes to them are ignored.
+//!
+//! File IDs are assigned to paths on first sight and are not persistent, so file
+//! handles do not survive a server restart. Only the IDs of about a million
+//! recently used paths are remembered; handles of paths unused for longer become
+//! stale. Uploads interrupted by a crash remain in the bucket until they are
+//! aborted, for example by a lifecycle rule.
+//!
+//! A directory is listed once when a client starts reading it, and the listing is
+//! kept while the client pages through it. Changes made by other users of the
+//! bucket in the meantime show up the next time the directory is read.
+//!
+//! Requests are signed with AWS Signature Version 4. Requires the `s3` feature.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::file_ids::ListingCache;
+use crate::protocol::xdr::nfs3;
+use crate::vfs::{self, NFSFileSystem, WccResult};
+use crate::write_ranges::WriteRanges;
+
+/// File ID of the root directory
+const ROOT_ID: nfs3::fileid3 = 1;
+
+/// Smallest part size accepted for multipart uploads
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Largest number of parts of a multipart upload
+const MAX_PARTS: usize = 10000;
+
+/// Number of paths whose file IDs are remembered
+const MAX_IDS: usize = 1 << 20;
+
+/// Payload hash sent with every request; the payload is not signed
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Convention for marking directories that have no objects below them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DirMarker {
+    /// An empty object named after the directory with a trailing slash (`dir/`),
+    /// as created by the AWS console and most S3 tools
+    #[default]
+    Slash,
+    /// An empty object named after the directory with a suffix, such as the
+    /// `dir_$folder$` objects written by Hadoop
+    Suffix(String),
+    /// No markers: a directory exists as long as there are objects below it, and
+    /// new directories are only remembered by the server until files are added
+    None,
+}
+
+/// Options of an [`S3FS`]
+#[derive(Clone)]
+pub struct S3Options {
+    /// URL of the object store, such as `https://s3.eu-west-1.amazonaws.com`
+    /// or `http://127.0.0.1:9000`
+    pub endpoint: String,
+    /// Region used for signing requests
+    pub region: String,
+    /// Name of the bucket
+    pub bucket: String,
+    /// Key prefix exported as the root directory, empty to export the whole bucket
+    pub prefix: String,
+    /// Access key ID
+    pub access_key: String,
+    /// Secret access key
+    pub secret_key: String,
+    /// Session token of temporary credentials
+    pub session_token: Option<String>,
+    /// Address the bucket as part of the host name (`bucket.host`) instead of
+    /// the path (`host/bucket`)
+    pub virtual_hosted: bool,
+    /// Convention for marking empty directories
+    pub dir_marker: DirMarker,
+    /// Size of the parts of multipart uploads; at least 5 MiB
+    ///
+    /// At most 10000 parts can be uploaded, which limits the size of files
+    /// written through the server.
+    pub part_size: usize,
+    /// Owner user ID reported for every object
+    pub uid: u32,
+    /// Owner group ID reported for every object
+    pub gid: u32,
+    /// Permission bits reported for files
+    pub file_mode: u32,
+    /// Permission bits reported for directories
+    pub dir_mode: u32,
+}
+
+impl Default for S3Options {
+    fn default() -> Self {
+        S3Options {
+            endpoint: String::new(),
+            region: "us-east-1".to_string(),
+            bucket: String::new(),
+            prefix: String::new(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            session_token: None,
+            virtual_hosted: false,
+            dir_marker: DirMarker::default(),
+            part_size: 8 * 1024 * 1024,
+            uid: 0,
+            gid: 0,
+            file_mode: 0o644,
+            dir_mode: 0o755,
+        }
+    }
+}
+
+impl std::fmt::Debug for S3Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // leave the credentials out of logs
+        f.debug_struct("S3Options")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("access_key", &self.access_key)
+            .field("virtual_hosted", &self.virtual_hosted)
+            .field("dir_marker", &self.dir_marker)
+            .field("part_size", &self.part_size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the current time as an NFS timestamp
+fn now() -> nfs3::nfstime3 {
+    let d = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    nfs3::nfstime3 {
+        seconds: d.as_secs() as u32,
+        nseconds: d.subsec_nanos(),
+    }
+}
+
+/// Returns the number of days since the epoch of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Returns the proleptic Gregorian date of a number of days since the epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Formats a time as the date and the timestamp used in signatures
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let t = secs.rem_euclid(86400);
+    let date = format!("{year:04}{month:02}{day:02}");
+    let timestamp = format!("{date}T{:02}{:02}{:02}Z", t / 3600, t / 60 % 60, t % 60);
+    (date, timestamp)
+}
+
+/// Parses an ISO 8601 time as used in listings, such as `2009-10-12T17:50:30.000Z`
+fn parse_iso_time(s: &str) -> Option<nfs3::nfstime3> {
+    let num = |range: std::ops::Range<usize>| s.get(range)?.parse::<i64>().ok();
+    let days = days_from_civil(num(0..4)?, num(5..7)?, num(8..10)?);
+    let secs = days * 86400 + num(11..13)? * 3600 + num(14..16)? * 60 + num(17..19)?;
+    Some(nfs3::nfstime3 {
+        seconds: secs as u32,
+        nseconds: 0,
+    })
+}
+
+/// Parses an HTTP date, such as `Wed, 21 Oct 2015 07:28:00 GMT`
+fn parse_http_time(s: &str) -> Option<nfs3::nfstime3> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = s.split_whitespace().skip(1);
+    let day = parts.next()?.parse::<i64>().ok()?;
+    let month = MONTHS.iter().position(|m| Some(*m) == parts.next())? as i64 + 1;
+    let year = parts.next()?.parse::<i64>().ok()?;
+    let mut clock = parts.next()?.split(':').map(|v| v.parse::<i64>().ok());
+    let (h, m, sec) = (clock.next()??, clock.next()??, clock.next()??);
+    let secs = days_from_civil(year, month, day) * 86400 + h * 3600 + m * 60 + sec;
+    Some(nfs3::nfstime3 {
+        seconds: secs as u32,
+        nseconds: 0,
+    })
+}
+
+/// Percent-encodes a string as required by signature version 4
+///
+/// Only unreserved characters are kept, and `/` if requested.
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+/// Formats bytes as lowercase hexadecimal
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Computes an HMAC-SHA256 of a string
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Returns the raw contents of every element with the given name
+///
+/// Responses of the object store are simple enough to be picked apart by name.
+fn xml_elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+    let mut result = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        let Some(end) = after.find(&close) else {
+            break;
+        };
+        result.push(&after[..end]);
+        rest = &after[end + close.len()..];
+    }
+    result
+}
+
+/// Returns the text of the first element with the given name
+fn xml_text(xml: &str, name: &str) -> Option<String> {
+    xml_elements(xml, name).first().map(|s| xml_unescape(s))
+}
+
+/// Replaces XML entities and character references
+fn xml_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..semi];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Maps an unexpected response status to an NFS error
+fn status_error(status: StatusCode) -> nfs3::nfsstat3 {
+    match status {
+        StatusCode::NOT_FOUND => nfs3::nfsstat3::NFS3ERR_NOENT,
+        StatusCode::FORBIDDEN => nfs3::nfsstat3::NFS3ERR_ACCES,
+        _ => nfs3::nfsstat3::NFS3ERR_IO,
+    }
+}
+
+/// An object in a listing
+#[derive(Debug)]
+struct Object {
+    /// Key of the object
+    key: String,
+    /// Size of the object
+    size: u64,
+    /// Last modification time of the object
+    mtime: nfs3::nfstime3,
+}
+
+/// One page of a listing
+#[derive(Debug, Default)]
+struct Page {
+    /// Objects in the page
+    objects: Vec<Object>,
+    /// Common prefixes in the page, ending with the delimiter
+    prefixes: Vec<String>,
+    /// Token to continue the listing with, if it is truncated
+    next: Option<String>,
+}
+
+/// Signing client for the S3 REST API
+struct Client {
+    /// HTTP client
+    http: reqwest::Client,
+    /// Scheme and authority requests are sent to
+    base: String,
+    /// Host name and port as signed
+    host: String,
+    /// Connection and credential settings
+    options: S3Options,
+}
+
+impl Client {
+    /// Creates a client for the object store in the options
+    fn new(options: &S3Options) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        let url = reqwest::Url::parse(&options.endpoint)
+            .map_err(|e| invalid(format!("invalid endpoint {}: {e}", options.endpoint)))?;
+        let mut host = url
+            .host_str()
+            .ok_or_else(|| invalid(format!("endpoint {} has no host", options.endpoint)))?
+            .to_string();
+        if options.virtual_hosted {
+            host = format!("{}.{host}", options.bucket);
+        }
+        if let Some(port) = url.port() {
+            host = format!("{host}:{port}");
+        }
+        let http = reqwest::Client::builder()
+            .build()
+            .map_err(|e| invalid(format!("cannot create HTTP client: {e}")))?;
+        Ok(Client {
+            http,
+            base: format!("{}://{host}", url.scheme()),
+            host,
+            options: options.clone(),
+        })
+    }
+
+    /// Sends a signed request for an object, or for the bucket if the key is empty
+    ///
+    /// Headers whose names start with `x-amz-` are signed. Fails only if no
+    /// response was received.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: Bytes,
+    ) -> Result<reqwest::Response, nfs3::nfsstat3> {
+        let path = match (self.options.virtual_hosted, key.is_empty()) {
+            (true, _) => format!("/{}", uri_encode(key, true)),
+            (false, true) => format!("/{}", uri_encode(&self.options.bucket, false)),
+            (false, false) => format!(
+                "/{}/{}",
+                uri_encode(&self.options.bucket, false),
+                uri_encode(key, true)
+            ),
+        };
+        let mut params: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, false), uri_encode(v, false)))
+            .collect();
+        params.sort();
+        let query = params
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let (date, timestamp) = amz_date(SystemTime::now());
+        let mut signed: Vec<(String, String)> = vec![
+            ("host".to_string(), self.host.clone()),
+            (
+                "x-amz-content-sha256".to_string(),
+                UNSIGNED_PAYLOAD.to_string(),
+            ),
+            ("x-amz-date".to_string(), timestamp.clone()),
+        ];
+        if let Some(token) = &self.options.session_token {
+            signed.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        for (name, value) in headers {
+            if name.starts_with("x-amz-") {
+                signed.push((name.to_string(), value.trim().to_string()));
+            }
+        }
+        signed.sort();
+        let signed_names = signed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(name, value)| format!("{name}:{value}\n"))
+            .collect();
+        let canonical = format!(
+            "{method}\n{path}\n{query}\n{canonical_headers}\n{signed_names}\n{UNSIGNED_PAYLOAD}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.options.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let mut signing_key = hmac(format!("AWS4{}", self.options.secret_key).as_bytes(), &date);
+        for part in [self.options.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part);
+        }
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_names}, Signature={}",
+            self.options.access_key,
+            hex(&hmac(&signing_key, &to_sign))
+        );
+
+        let url = if query.is_empty() {
+            format!("{}{path}", self.base)
+        } else {
+            format!("{}{path}?{query}", self.base)
+        };
+        let mut request = self
+            .http
+            .request(method.clone(), url)
+            .header("authorization", authorization)
+            .body(body);
+        for (name, value) in signed.iter().filter(|(name, _)| name != "host") {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        for (name, value) in headers
+            .iter()
+            .filter(|(name, _)| !name.starts_with("x-amz-"))
+        {
+            request = request.header(*name, *value);
+        }
+        request.send().await.map_err(|e| {
+            warn!("{method} {key} failed: {e}");
+            nfs3::nfsstat3::NFS3ERR_IO
+        })
+    }
+
+    /// Sends a signed request and fails unless it succeeds
+    async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: Bytes,
+    ) -> Result<reqwest::Response, nfs3::nfsstat3> {
+        let response = self.send(method.clone(), key, query, headers, body).await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            if status != StatusCode::NOT_FOUND {
+                warn!(
+                    "{method} {key} failed with {status}: {}",
+                    xml_text(&body, "Message").unwrap_or(body)
+                );
+            }
+            return Err(status_error(status));
+        }
+        Ok(response)
+    }
+
+    /// Reads the body of a response that can report an error with a success status
+    async fn checked_text(&self, response: reqwest::Response) -> Result<String, nfs3::nfsstat3> {
+        let body = response.text().await.map_err(|e| {
+            warn!("cannot read response: {e}");
+            nfs3::nfsstat3::NFS3ERR_IO
+        })?;
+        if let Some(error) = xml_elements(&body, "Error").first() {
+            warn!(
+                "request failed: {}",
+                xml_text(error, "Message").unwrap_or_default()
+            );
+            return Err(nfs3::nfsstat3::NFS3ERR_IO);
+        }
+        Ok(body)
+    }
+
+    /// Returns the size and modification time of an object, or `None` if it does not exist
+    async fn head(&self, key: &str) -> Result<Option<(u64, nfs3::nfstime3)>, nfs3::nfsstat3> {
+        let response = match self
+            .request(Method::HEAD, key, &[], &[], Bytes::new())
+            .await
+        {
+            Ok(response) => response,
+            Err(nfs3::nfsstat3::NFS3ERR_NOENT) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let size = header("content-length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let mtime = header("last-modified")
+            .and_then(|v| parse_http_time(&v))
+            .unwrap_or_default();
+        Ok(Some((size, mtime)))
+    }
+
+    /// Reads a range of an object
+    ///
+    /// Returns the data, which is shorter than requested at the end of the object,
+    /// and whether the end of the object was reached.
+    async fn get(&self, key: &str, offset: u64, len: u64) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        if len == 0 {
+            let (size, _) = self.head(key).await?.ok_or(nfs3::nfsstat3::NFS3ERR_NOENT)?;
+            return Ok((Bytes::new(), offset >= size));
+        }
+        let range = format!("bytes={offset}-{}", offset + len - 1);
+        let response = self
+            .send(Method::GET, key, &[], &[("range", &range)], Bytes::new())
+            .await?;
+        let status = response.status();
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok((Bytes::new(), true));
+        }
+        if !status.is_success() {
+            return Err(status_error(status));
+        }
+        // "bytes first-last/total"
+        let total = response
+            .headers()
+            .get("content-range")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse::<u64>().ok());
+        let body = response.bytes().await.map_err(|e| {
+            warn!("cannot read {key}: {e}");
+            nfs3::nfsstat3::NFS3ERR_IO
+        })?;
+        if status == StatusCode::PARTIAL_CONTENT {
+            let total = total.unwrap_or(offset + body.len() as u64);
+            return Ok((body.clone(), offset + body.len() as u64 >= total));
+        }
+        // the whole object was returned
+        let start = (offset as usize).min(body.len());
+        let end = (offset + len).min(body.len() as u64) as usize;
+        Ok((body.slice(start..end), end == body.len()))
+    }
+
+    /// Stores an object
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), nfs3::nfsstat3> {
+        self.request(Method::PUT, key, &[], &[], data).await?;
+        Ok(())
+    }
+
+    /// Deletes an object; deleting a missing object succeeds
+    async fn delete(&self, key: &str) -> Result<(), nfs3::nfsstat3> {
+        match self
+            .request(Method::DELETE, key, &[], &[], Bytes::new())
+            .await
+        {
+            Ok(_) | Err(nfs3::nfsstat3::NFS3ERR_NOENT) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Copies an object within the bucket
+    async fn copy(&self, from: &str, to: &str) -> Result<(), nfs3::nfsstat3> {
+        let source = format!(
+            "/{}/{}",
+            uri_encode(&self.options.bucket, false),
+            uri_encode(from, true)
+        );
+        let response = self
+            .request(
+                Method::PUT,
+                to,
+                &[],
+                &[("x-amz-copy-source", &source)],
+                Bytes::new(),
+            )
+            .await?;
+        self.checked_text(response).await?;
+        Ok(())
+    }
+
+    /// Lists one page of the objects with a key prefix
+    ///
+    /// With `delimiter`, keys continuing below the next `/` are rolled up into
+    /// common prefixes.
+    async fn list(
+        &self,
+        prefix: &str,
+        delimiter: bool,
+        max_keys: Option<usize>,
+        token: Option<&str>,
+    ) -> Result<Page, nfs3::nfsstat3> {
+        let max_keys = max_keys.map(|n| n.to_string());
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if delimiter {
+            query.push(("delimiter", "/"));
+        }
+        if let Some(max_keys) = &max_keys {
+            query.push(("max-keys", max_keys));
+        }
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+        let response = self
+            .request(Method::GET, "", &query, &[], Bytes::new())
+            .await?;
+        let body = self.checked_text(response).await?;
+        let mut page = Page::default();
+        for contents in xml_elements(&body, "Contents") {
+            let Some(key) = xml_text(contents, "Key") else {
+                continue;
+            };
+            page.objects.push(Object {
+                key,
+                size: xml_text(contents, "Size")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0),
+                mtime: xml_text(contents, "LastModified")
+                    .and_then(|v| parse_iso_time(&v))
+                    .unwrap_or_default(),
+            });
+        }
+        for common in xml_elements(&body, "CommonPrefixes") {
+            page.prefixes.extend(xml_text(common, "Prefix"));
+        }
+        if xml_text(&body, "IsTruncated").as_deref() == Some("true") {
+            page.next = xml_text(&body, "NextContinuationToken");
+        }
+        Ok(page)
+    }
+
+    /// Starts a multipart upload, returning its ID
+    async fn create_upload(&self, key: &str) -> Result<String, nfs3::nfsstat3> {
+        let response = self
+            .request(Method::POST, key, &[("uploads", "")], &[], Bytes::new())
+            .await?;
+        let body = self.checked_text(response).await?;
+        xml_text(&body, "UploadId").ok_or(nfs3::nfsstat3::NFS3ERR_IO)
+    }
+
+    /// Uploads a part of a multipart upload, returning its entity tag
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: usize,
+        data: Bytes,
+    ) -> Result<String, nfs3::nfsstat3> {
+        let number = number.to_string();
+        let query = [("partNumber", number.as_str()), ("uploadId", upload_id)];
+        let response = self.request(Method::PUT, key, &query, &[], data).await?;
+        response
+            .headers()
+            .get("etag")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .ok_or(nfs3::nfsstat3::NFS3ERR_IO)
+    }
+
+    /// Completes a multipart upload from its parts in order
+    async fn complete_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        etags: &[String],
+    ) -> Result<(), nfs3::nfsstat3> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in etags.iter().enumerate() {
+            let etag = etag.replace('&', "&amp;").replace('"', "&quot;");
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>",
+                i + 1
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let query = [("uploadId", upload_id)];
+        let response = self
+            .request(Method::POST, key, &query, &[], body.into())
+            .await?;
+        self.checked_text(response).await?;
+        Ok(())
+    }
+
+    /// Aborts a multipart upload, discarding its parts
+    async fn abort_upload(&self, key: &str, upload_id: &str) -> Result<(), nfs3::nfsstat3> {
+        let query = [("uploadId", upload_id)];
+        self.request(Method::DELETE, key, &query, &[], Bytes::new())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Kind of object a path refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// An object
+    File,
+    /// A key prefix
+    Dir,
+}
+
+/// File IDs assigned to paths
+///
+/// IDs are kept in two generations of recent use; when the current one is full,
+/// the IDs only found in the previous one are forgotten, unless they are pinned.
+#[derive(Debug)]
+struct Ids {
+    /// File IDs by path relative to the exported prefix
+    ids: BTreeMap<String, nfs3::fileid3>,
+    /// Paths and kinds by file ID
+    paths: HashMap<nfs3::fileid3, (String, Kind)>,
+    /// IDs assigned or used since the last rotation
+    current: HashSet<nfs3::fileid3>,
+    /// IDs assigned or used before the last rotation
+    previous: HashSet<nfs3::fileid3>,
+    /// IDs that are never forgotten, such as those of files with pending uploads
+    pinned: HashSet<nfs3::fileid3>,
+    /// Next file ID to assign
+    next_id: nfs3::fileid3,
+}
+
+impl Ids {
+    /// Creates a map holding only the root directory
+    fn new() -> Self {
+        let mut ids = Ids {
+            ids: BTreeMap::new(),
+            paths: HashMap::new(),
+            current: HashSet::new(),
+            previous: HashSet::new(),
+            pinned: HashSet::from([ROOT_ID]),
+            next_id: ROOT_ID + 1,
+        };
+        ids.ids.insert(String::new(), ROOT_ID);
+        ids.paths.insert(ROOT_ID, (String::new(), Kind::Dir));
+        ids
+    }
+
+    /// Marks an ID as recently used, forgetting the IDs not used for two generations
+    fn touch(&mut self, id: nfs3::fileid3) {
+        if !self.current.insert(id) {
+            return;
+        }
+        self.previous.remove(&id);
+        if self.current.len() < MAX_IDS / 2 {
+            return;
+        }
+        let expired = std::mem::replace(&mut self.previous, std::mem::take(&mut self.current));
+        for id in expired {
+            if self.pinned.contains(&id) {
+                self.current.insert(id);
+            } else if let Some((path, _)) = self.paths.remove(&id) {
+                self.ids.remove(&path);
+            }
+        }
+    }
+
+    /// Returns the file ID of a path, assigning one on first sight
+    fn id(&mut self, path: &str, kind: Kind) -> nfs3::fileid3 {
+        let id = match self.ids.get(path) {
+            Some(&id) => id,
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.ids.insert(path.to_string(), id);
+                id
+            }
+        };
+        self.paths.insert(id, (path.to_string(), kind));
+        self.touch(id);
+        id
+    }
+
+    /// Returns the path and kind of a file ID
+    fn path(&mut self, id: nfs3::fileid3) -> Result<(String, Kind), nfs3::nfsstat3> {
+        let path = self
+            .paths
+            .get(&id)
+            .cloned()
+            .ok_or(nfs3::nfsstat3::NFS3ERR_STALE)?;
+        self.touch(id);
+        Ok(path)
+    }
+
+    /// Keeps an ID from being forgotten until it is unpinned
+    fn pin(&mut self, id: nfs3::fileid3) {
+        self.pinned.insert(id);
+    }
+
+    /// Lets an ID be forgotten again once it is no longer used
+    fn unpin(&mut self, id: nfs3::fileid3) {
+        if id != ROOT_ID {
+            self.pinned.remove(&id);
+        }
+    }
+
+    /// Returns the paths at or below a path that have file IDs
+    fn below(&self, path: &str) -> Vec<String> {
+        let prefix = format!("{path}/");
+        let exact = self.ids.get_key_value(path).map(|(p, _)| p.clone());
+        exact
+            .into_iter()
+            .chain(
+                self.ids
+                    .range(prefix.clone()..)
+                    .map(|(p, _)| p)
+                    .take_while(|p| p.starts_with(&prefix))
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    /// Moves the file ID of a path, and of everything below it, to a new path
+    fn rename(&mut self, from: &str, to: &str) {
+        let moved = self.below(from);
+        self.forget(to);
+        for path in moved {
+            let id = self.ids.remove(&path).unwrap();
+            let new_path = format!("{to}{}", &path[from.len()..]);
+            if let Some(entry) = self.paths.get_mut(&id) {
+                entry.0 = new_path.clone();
+            }
+            self.ids.insert(new_path, id);
+        }
+    }
+
+    /// Drops the file ID of a path and of everything below it
+    fn forget(&mut self, path: &str) {
+        for path in self.below(path) {
+            if let Some(id) = self.ids.remove(&path) {
+                self.paths.remove(&id);
+                self.current.remove(&id);
+                self.previous.remove(&id);
+            }
+        }
+    }
+}
+
+/// Joins a directory path and a name
+fn child_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// Returns the path of the directory containing a path
+fn parent_path(path: &str) -> &str {
+    path.rfind('/').map_or("", |i| &path[..i])
+}
+
+/// Converts a file name to UTF-8, as object keys must be
+fn utf8_name(name: &[u8]) -> Result<&str, nfs3::nfsstat3> {
+    let name = std::str::from_utf8(name).map_err(|_| nfs3::nfsstat3::NFS3ERR_INVAL)?;
+    if name.is_empty() || name.contains('/') {
+        return Err(nfs3::nfsstat3::NFS3ERR_INVAL);
+    }
+    Ok(name)
+}
+
+/// Data written to a file that has not been made part of the object yet
+#[derive(Debug)]
+struct Upload {
+    /// Key of the object being replaced
+    key: String,
+    /// Size of the object when the upload started; data not written is copied from it
+    base_size: u64,
+    /// Size of the file including the written data
+    size: u64,
+    /// Time of the last write
+    mtime: nfs3::nfstime3,
+    /// ID of the multipart upload, once the first part is uploaded
+    upload_id: Option<String>,
+    /// Entity tags of the uploaded parts
+    etags: Vec<String>,
+    /// Number of bytes uploaded as parts
+    flushed: u64,
+    /// Written data from `flushed` on
+    data: WriteRanges,
+}
+
+impl Upload {
+    /// Starts collecting writes to an object of the given size
+    fn new(key: String, size: u64, mtime: nfs3::nfstime3) -> Self {
+        Upload {
+            key,
+            base_size: size,
+            size,
+            mtime,
+            upload_id: None,
+            etags: Vec::new(),
+            flushed: 0,
+            data: WriteRanges::default(),
+        }
+    }
+
+    /// Adds a write
+    ///
+    /// The write must not start before the uploaded data, and its end must not
+    /// exceed `u64::MAX`.
+    fn insert(&mut self, offset: u64, data: &[u8]) {
+        self.data.insert(offset, data);
+        self.size = self.size.max(offset + data.len() as u64);
+        self.mtime = now();
+    }
+
+    /// Changes the size of the file
+    ///
+    /// The size must not be below the uploaded data.
+    fn truncate(&mut self, size: u64) {
+        self.data.truncate(size);
+        // data cut off must not come back if the file grows again
+        self.base_size = self.base_size.min(size);
+        self.size = size;
+        self.mtime = now();
+    }
+
+    /// Returns whether written data fills the next part
+    fn part_ready(&self, part_size: u64) -> bool {
+        matches!(
+            self.data.first(),
+            Some((pos, data)) if pos == self.flushed && data.len() as u64 >= part_size
+        )
+    }
+
+    /// Drops the written data of the next `len` bytes after they were uploaded
+    fn advance(&mut self, len: u64) {
+        self.flushed += len;
+        self.data.discard_before(self.flushed);
+    }
+}
+
+/// Pending upload of a file, locked while it is changed or sent
+type Slot = Arc<tokio::sync::Mutex<Option<Upload>>>;
+
+/// An entry of a directory listing
+#[derive(Debug)]
+struct Listed {
+    /// File ID of the entry
+    fileid: nfs3::fileid3,
+    /// Name of the entry
+    name: String,
+    /// Kind of object the entry refers to
+    kind: Kind,
+    /// Size of the object
+    size: u64,
+    /// Last modification time of the object
+    mtime: nfs3::nfstime3,
+}
+
+/// Directory listings kept while clients page through them
+///
+/// Prefixes have no timestamps to tell whether a listing is still current, so a
+/// listing is only reused for continued READDIR requests, and is dropped when the
+/// directory is changed through the file system.
+type Listings = ListingCache<(), Listed>;
+
+/// File system backed by an S3-compatible object store
+///
+/// See the [module documentation](self) for details.
+pub struct S3FS {
+    /// Client for the object store
+    client: Client,
+    /// Key prefix of the root directory, empty or ending with `/`
+    prefix: String,
+    /// File IDs assigned to paths
+    ids: Mutex<Ids>,
+    /// Pending uploads by file ID
+    uploads: Mutex<HashMap<nfs3::fileid3, Slot>>,
+    /// Directories without objects, remembered when no markers are written
+    empty_dirs: Mutex<HashSet<String>>,
+    /// Listings of directories being paged through, sorted by file ID
+    listings: Mutex<Listings>,
+    /// Time reported for directories
+    started: nfs3::nfstime3,
+}
+
+impl std::fmt::Debug for S3FS {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3FS")
+            .field("options", &self.client.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl S3FS {
+    /// Creates a file system exporting a bucket
+    ///
+    /// No request is made until the file system is used.
+    ///
+    /// # Arguments
+    /// * `options` - Object store, bucket, credentials and conventions to use
+    pub fn new(options: S3Options) -> std::io::Result<Self> {
+        if options.bucket.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no bucket given",
+            ));
+        }
+        if options.part_size < MIN_PART_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "part size must be at least 5 MiB",
+            ));
+        }
+        let mut prefix = options.prefix.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        Ok(S3FS {
+            client: Client::new(&options)?,
+            prefix,
+            ids: Mutex::new(Ids::new()),
+            uploads: Mutex::new(HashMap::new()),
+            empty_dirs: Mutex::new(HashSet::new()),
+            listings: Mutex::new(Listings::default()),
+            started: now(),
+        })
+    }
+
+    /// Returns the options of the file system
+    pub fn options(&self) -> &S3Options {
+        &self.client.options
+    }
+
+    /// Returns the object key of a file
+    fn key(&self, path: &str) -> String {
+        format!("{}{path}", self.prefix)
+    }
+
+    /// Returns the key prefix of the objects in a directory
+    fn dir_prefix(&self, path: &str) -> String {
+        if path.is_empty() {
+            self.prefix.clone()
+        } else {
+            format!("{}{path}/", self.prefix)
+        }
+    }
+
+    /// Returns the key of the marker of a directory, if markers are written
+    fn marker_key(&self, path: &str) -> Option<String> {
+        match &self.client.options.dir_marker {
+            _ if path.is_empty() => None,
+            DirMarker::Slash => Some(self.dir_prefix(path)),
+            DirMarker::Suffix(suffix) => Some(format!("{}{path}{suffix}", self.prefix)),
+            DirMarker::None => None,
+        }
+    }
+
+    /// Returns the path and kind of a file ID
+    fn path(&self, id: nfs3::fileid3) -> Result<(String, Kind), nfs3::nfsstat3> {
+        self.ids.lock().unwrap().path(id)
+    }
+
+    /// Returns the path of a directory
+    fn dir_path(&self, id: nfs3::fileid3) -> Result<String, nfs3::nfsstat3> {
+        match self.path(id)? {
+            (path, Kind::Dir) => Ok(path),
+            (_, Kind::File) => Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+        }
+    }
+
+    /// Returns the path of a regular file
+    fn file_path(&self, id: nfs3::fileid3) -> Result<String, nfs3::nfsstat3> {
+        match self.path(id)? {
+            (path, Kind::File) => Ok(path),
+            (_, Kind::Dir) => Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+        }
+    }
+
+    /// Builds the attributes of a directory
+    fn dir_attr(&self, id: nfs3::fileid3) -> nfs3::fattr3 {
+        let options = &self.client.options;
+        nfs3::fattr3 {
+            ftype: nfs3::ftype3::NF3DIR,
+            mode: options.dir_mode,
+            nlink: 2,
+            uid: options.uid,
+            gid: options.gid,
+            size: 0,
+            used: 0,
+            rdev: nfs3::specdata3::default(),
+            fsid: 0,
+            fileid: id,
+            atime: self.started,
+            mtime: self.started,
+            ctime: self.started,
+        }
+    }
+
+    /// Builds the attributes of a regular file
+    fn file_attr(&self, id: nfs3::fileid3, size: u64, mtime: nfs3::nfstime3) -> nfs3::fattr3 {
+        let options = &self.client.options;
+        nfs3::fattr3 {
+            ftype: nfs3::ftype3::NF3REG,
+            mode: options.file_mode,
+            nlink: 1,
+            uid: options.uid,
+            gid: options.gid,
+            size,
+            used: size,
+            rdev: nfs3::specdata3::default(),
+            fsid: 0,
+            fileid: id,
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+        }
+    }
+
+    /// Finds out what a path refers to
+    ///
+    /// A directory hides an object of the same name. Returns the kind and, for
+    /// files, the size and modification time.
+    async fn probe(
+        &self,
+        path: &str,
+    ) -> Result<Option<(Kind, u64, nfs3::nfstime3)>, nfs3::nfsstat3> {
+        let key = self.key(path);
+        let dir_prefix = self.dir_prefix(path);
+        let suffix_marker = match &self.client.options.dir_marker {
+            DirMarker::Suffix(_) => self.marker_key(path),
+            _ => None,
+        };
+        let (object, below, marker) = tokio::join!(
+            self.client.head(&key),
+            self.client.list(&dir_prefix, false, Some(1), None),
+            async {
+                match &suffix_marker {
+                    Some(key) => self.client.head(key).await.map(|v| v.is_some()),
+                    None => Ok(false),
+                }
+            }
+        );
+        let is_dir =
+            !below?.objects.is_empty() || marker? || self.empty_dirs.lock().unwrap().contains(path);
+        if is_dir {
+            return Ok(Some((Kind::Dir, 0, self.started)));
+        }
+        Ok(object?.map(|(size, mtime)| (Kind::File, size, mtime)))
+    }
+
+    /// Lists a directory, returning the names, kinds, sizes and times of its entries
+    async fn list(
+        &self,
+        path: &str,
+    ) -> Result<BTreeMap<String, (Kind, u64, nfs3::nfstime3)>, nfs3::nfsstat3> {
+        let dir_prefix = self.dir_prefix(path);
+        let suffix = match &self.client.options.dir_marker {
+            DirMarker::Suffix(suffix) => Some(suffix.as_str()),
+            _ => None,
+        };
+        let mut files = BTreeMap::new();
+        let mut dirs = Vec::new();
+        let mut token = None;
+        loop {
+            let page = self
+                .client
+                .list(&dir_prefix, true, None, token.as_deref())
+                .await?;
+            for object in page.objects {
+                let name = &object.key[dir_prefix.len()..];
+                match suffix.and_then(|s| name.strip_suffix(s)) {
+                    Some(dir) => dirs.push(dir.to_string()),
+                    None => {
+                        files.insert(name.to_string(), (Kind::File, object.size, object.mtime));
+                    }
+                }
+            }
+            for prefix in page.prefixes {
+                let name = prefix[dir_prefix.len()..].trim_end_matches('/');
+                dirs.push(name.to_string());
+            }
+            token = page.next;
+            if token.is_none() {
+                break;
+            }
+        }
+        for dir in self.empty_dirs.lock().unwrap().iter() {
+            if !path.is_empty() && !dir.starts_with(&format!("{path}/")) {
+                continue;
+            }
+            let rest = dir.get(if path.is_empty() { 0 } else { path.len() + 1 }..);
+            if let Some(name) = rest.filter(|name| !name.contains('/')) {
+                dirs.push(name.to_string());
+            }
+        }
+        for dir in dirs {
+            files.insert(dir, (Kind::Dir, 0, self.started));
+        }
+        // names that cannot be presented, such as the marker of the directory
+        // itself or the empty names of keys with doubled slashes
+        files.retain(|name, _| !name.is_empty() && name != "." && name != "..");
+        Ok(files)
+    }
+
+    /// Drops the cached listing of the directory containing a path
+    fn changed(&self, path: &str) {
+        let dirid = self.ids.lock().unwrap().ids.get(parent_path(path)).copied();
+        if let Some(dirid) = dirid {
+            self.listings.lock().unwrap().invalidate(dirid);
+        }
+    }
+
+    /// Returns the pending upload of a file, creating an empty entry if needed
+    fn slot(&self, id: nfs3::fileid3) -> Slot {
+        let mut ids = self.ids.lock().unwrap();
+        // the path of a file must be known until its upload is complete
+        ids.pin(id);
+        self.uploads.lock().unwrap().entry(id).or_default().clone()
+    }
+
+    /// Returns the pending upload of a file if there is an entry for it
+    fn existing(&self, id: nfs3::fileid3) -> Option<Slot> {
+        self.uploads.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Drops the entry of a file if it is empty and not in use
+    ///
+    /// Must be called without holding a reference to the entry.
+    fn release(&self, id: nfs3::fileid3) {
+        let mut ids = self.ids.lock().unwrap();
+        let mut uploads = self.uploads.lock().unwrap();
+        if let Some(slot) = uploads.get(&id) {
+            let unused = Arc::strong_count(slot) == 1
+                && slot.try_lock().map(|u| u.is_none()).unwrap_or(false);
+            if unused {
+                uploads.remove(&id);
+                ids.unpin(id);
+            }
+        }
+    }
+
+    /// Starts collecting writes to a file
+    async fn start_upload(&self, path: &str) -> Result<Upload, nfs3::nfsstat3> {
+        let key = self.key(path);
+        let (size, mtime) = self
+            .client
+            .head(&key)
+            .await?
+            .ok_or(nfs3::nfsstat3::NFS3ERR_STALE)?;
+        Ok(Upload::new(key, size, mtime))
+    }
+
+    /// Assembles the next `len` bytes of an upload from the written data and the
+    /// old object
+    async fn part_data(&self, upload: &Upload, len: u64) -> Result<Vec<u8>, nfs3::nfsstat3> {
+        let start = upload.flushed;
+        let end = start + len;
+        let mut data = vec![0; len as usize];
+        for (from, to) in upload.data.gaps(start, end) {
+            let to = to.min(upload.base_size);
+            if from < to {
+                let (old, _) = self.client.get(&upload.key, from, to - from).await?;
+                let at = (from - start) as usize;
+                data[at..at + old.len()].copy_from_slice(&old);
+            }
+        }
+        upload.data.overlay(start, &mut data);
+        Ok(data)
+    }
+
+    /// Uploads the next part of an upload, starting the multipart upload if needed
+    async fn upload_part(&self, upload: &mut Upload, len: u64) -> Result<(), nfs3::nfsstat3> {
+        if upload.etags.len() >= MAX_PARTS {
+            return Err(nfs3::nfsstat3::NFS3ERR_FBIG);
+        }
+        let data = self.part_data(upload, len).await?;
+        let upload_id = match &upload.upload_id {
+            Some(id) => id.clone(),
+            None => {
+                let id = self.client.create_upload(&upload.key).await?;
+                debug!("started upload {id} of {}", upload.key);
+                upload.upload_id = Some(id.clone());
+                id
+            }
+        };
+        let etag = self
+            .client
+            .upload_part(&upload.key, &upload_id, upload.etags.len() + 1, data.into())
+            .await?;
+        upload.etags.push(etag);
+        upload.advance(len);
+        Ok(())
+    }
+
+    /// Uploads every part filled with written data
+    async fn upload_ready(&self, upload: &mut Upload) -> Result<(), nfs3::nfsstat3> {
+        let part_size = self.client.options.part_size as u64;
+        while upload.part_ready(part_size) {
+            self.upload_part(upload, part_size).await?;
+        }
+        Ok(())
+    }
+
+    /// Completes a pending upload, replacing the object
+    ///
+    /// On failure the upload stays pending and can be retried.
+    async fn finish(&self, pending: &mut Option<Upload>) -> Result<(), nfs3::nfsstat3> {
+        let Some(upload) = pending.as_mut() else {
+            return Ok(());
+        };
+        let part_size = self.client.options.part_size as u64;
+        if upload.upload_id.is_none() && upload.size <= part_size {
+            let data = self.part_data(upload, upload.size).await?;
+            self.client.put(&upload.key, data.into()).await?;
+        } else {
+            while upload.flushed < upload.size {
+                let len = part_size.min(upload.size - upload.flushed);
+                self.upload_part(upload, len).await?;
+            }
+            let upload_id = upload.upload_id.as_deref().unwrap_or_default();
+            self.client
+                .complete_upload(&upload.key, upload_id, &upload.etags)
+                .await?;
+        }
+        debug!("stored {} ({} bytes)", upload.key, upload.size);
+        self.changed(&upload.key[self.prefix.len()..]);
+        *pending = None;
+        Ok(())
+    }
+
+    /// Completes the pending upload of a file, if any
+    async fn flush(&self, id: nfs3::fileid3) -> Result<(), nfs3::nfsstat3> {
+        let Some(slot) = self.existing(id) else {
+            return Ok(());
+        };
+        let result = self.finish(&mut *slot.lock().await).await;
+        drop(slot);
+        self.release(id);
+        result
+    }
+
+    /// Completes the pending uploads of all files at or below a path
+    async fn flush_below(&self, path: &str) -> Result<(), nfs3::nfsstat3> {
+        let below = format!("{path}/");
+        let ids: Vec<nfs3::fileid3> = {
+            let ids = self.ids.lock().unwrap();
+            self.uploads
+                .lock()
+                .unwrap()
+                .keys()
+                .copied()
+                .filter(|id| match ids.paths.get(id) {
+                    Some((p, _)) => *p == path || p.starts_with(&below),
+                    None => false,
+                })
+                .collect()
+        };
+        for id in ids {
+            self.flush(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Discards the pending upload of a file, if any
+    async fn discard(&self, id: nfs3::fileid3) {
+        let Some(slot) = self.existing(id) else {
+            return;
+        };
+        if let Some(upload) = slot.lock().await.take() {
+            if let Some(upload_id) = &upload.upload_id {
+                if let Err(e) = self.client.abort_upload(&upload.key, upload_id).await {
+                    warn!("cannot abort upload {upload_id} of {}: {e:?}", upload.key);
+                }
+            }
+        }
+        drop(slot);
+        self.release(id);
+    }
+
+    /// Changes the size of a file, storing the result
+    async fn resize(
+        &self,
+        id: nfs3::fileid3,
+        path: &str,
+        size: u64,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let slot = self.slot(id);
+        let result = async {
+            let mut pending = slot.lock().await;
+            if pending.as_ref().is_some_and(|u| size < u.flushed) {
+                self.finish(&mut pending).await?;
+            }
+            if pending.is_none() {
+                *pending = Some(self.start_upload(path).await?);
+            }
+            let upload = pending.as_mut().unwrap();
+            upload.truncate(size);
+            let mtime = upload.mtime;
+            self.finish(&mut pending).await?;
+            Ok(self.file_attr(id, size, mtime))
+        }
+        .await;
+        drop(slot);
+        self.release(id);
+        result
+    }
+
+    /// Deletes everything at or below a directory path, copying it to another
+    /// path first if given
+    async fn move_dir(&self, from: &str, to: Option<&str>) -> Result<(), nfs3::nfsstat3> {
+        let from_prefix = self.dir_prefix(from);
+        let mut keys = Vec::new();
+        let mut token = None;
+        loop {
+            let page = self
+                .client
+                .list(&from_prefix, false, None, token.as_deref())
+                .await?;
+            keys.extend(page.objects.into_iter().map(|object| object.key));
+            token = page.next;
+            if token.is_none() {
+                break;
+            }
+        }
+        let mut moves: Vec<(String, Option<String>)> = keys
+            .into_iter()
+            .map(|key| {
+                let target =
+                    to.map(|to| format!("{}{}", self.dir_prefix(to), &key[from_prefix.len()..]));
+                (key, target)
+            })
+            .collect();
+        if let (Some(from_marker), DirMarker::Suffix(_)) =
+            (self.marker_key(from), &self.client.options.dir_marker)
+        {
+            if self.client.head(&from_marker).await?.is_some() {
+                moves.push((from_marker, to.and_then(|to| self.marker_key(to))));
+            }
+        }
+        for (key, target) in &moves {
+            if let Some(target) = target {
+                self.client.copy(key, target).await?;
+            }
+        }
+        for (key, _) in &moves {
+            self.client.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes a directory entry of the given kind
+    async fn remove_entry(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let path = child_path(&self.dir_path(dirid)?, utf8_name(filename)?);
+        self.listings.lock().unwrap().invalidate(dirid);
+        match self.probe(&path).await? {
+            None => return Err(nfs3::nfsstat3::NFS3ERR_NOENT),
+            Some((Kind::File, _, _)) => {
+                kind.check(false)?;
+                let id = self.ids.lock().unwrap().ids.get(&path).copied();
+                if let Some(id) = id {
+                    self.discard(id).await;
+                }
+                self.client.delete(&self.key(&path)).await?;
+            }
+            Some((Kind::Dir, _, _)) => {
+                kind.check(true)?;
+                let marker = self.marker_key(&path);
+                let page = self
+                    .client
+                    .list(&self.dir_prefix(&path), false, Some(2), None)
+                    .await?;
+                if page.objects.iter().any(|o| Some(&o.key) != marker.as_ref()) {
+                    return Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY);
+                }
+                if let Some(marker) = marker {
+                    self.client.delete(&marker).await?;
+                }
+                self.empty_dirs.lock().unwrap().remove(&path);
+            }
+        }
+        self.ids.lock().unwrap().forget(&path);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for S3FS {
+    fn capabilities(&self) -> vfs::Capabilities {
+        vfs::Capabilities::ReadWrite
+    }
+
+    fn root_dir(&self) -> nfs3::fileid3 {
+        ROOT_ID
+    }
+
+    async fn lookup(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let dir = self.dir_path(dirid)?;
+        let path = match filename.as_ref() {
+            b"." => return Ok(dirid),
+            b".." => {
+                let parent = parent_path(&dir).to_string();
+                return Ok(self.ids.lock().unwrap().id(&parent, Kind::Dir));
+            }
+            name => child_path(&dir, utf8_name(name)?),
+        };
+        match self.probe(&path).await? {
+            Some((kind, _, _)) => Ok(self.ids.lock().unwrap().id(&path, kind)),
+            None => Err(nfs3::nfsstat3::NFS3ERR_NOENT),
+        }
+    }
+
+    async fn getattr(&self, id: nfs3::fileid3) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let path = match self.path(id)? {
+            (_, Kind::Dir) => return Ok(self.dir_attr(id)),
+            (path, Kind::File) => path,
+        };
+        if let Some(slot) = self.existing(id) {
+            if let Some(upload) = slot.lock().await.as_ref() {
+                return Ok(self.file_attr(id, upload.size, upload.mtime));
+            }
+        }
+        match self.client.head(&self.key(&path)).await? {
+            Some((size, mtime)) => Ok(self.file_attr(id, size, mtime)),
+            None => Err(nfs3::nfsstat3::NFS3ERR_STALE),
+        }
+    }
+
+    async fn setattr(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        // permissions, owners and times cannot be stored
+        match (setattr.size, self.path(id)?) {
+            (nfs3::set_size3::size(size), (path, Kind::File)) => self.resize(id, &path, size).await,
+            _ => self.getattr(id).await,
+        }
+    }
+
+    async fn read(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3> {
+        let (data, eof) = self.read_bytes(id, offset, count).await?;
+        Ok((data.to_vec(), eof))
+    }
+
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let path = self.file_path(id)?;
+        self.flush(id).await?;
+        match self
+            .client
+            .get(&self.key(&path), offset, count as u64)
+            .await
+        {
+            Err(nfs3::nfsstat3::NFS3ERR_NOENT) => Err(nfs3::nfsstat3::NFS3ERR_STALE),
+            result => result,
+        }
+    }
+
+    async fn write(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        let res = self
+            .write_wcc(id, offset, data, nfs3::file::stable_how::FILE_SYNC)
+            .await;
+        res.result.map(|(attr, _)| attr)
+    }
+
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let mut wcc = nfs3::wcc_data::default();
+        let path = match self.file_path(id) {
+            Ok(path) => path,
+            Err(e) => {
+                return WccResult {
+                    result: Err(e),
+                    wcc,
+                }
+            }
+        };
+        let max_size = (self.client.options.part_size * MAX_PARTS) as u64;
+        let end = offset.checked_add(data.len() as u64);
+        if end.is_none_or(|end| end > max_size) {
+            return WccResult {
+                result: Err(nfs3::nfsstat3::NFS3ERR_FBIG),
+                wcc,
+            };
+        }
+        let slot = self.slot(id);
+        let result = async {
+            let mut pending = slot.lock().await;
+            // uploaded parts cannot change, so writing to them starts over
+            if pending.as_ref().is_some_and(|u| offset < u.flushed) {
+                self.finish(&mut pending).await?;
+            }
+            if pending.is_none() {
+                *pending = Some(self.start_upload(&path).await?);
+            }
+            let upload = pending.as_mut().unwrap();
+            wcc.before =
+                nfs3::pre_op_attr::attributes(self.file_attr(id, upload.size, upload.mtime).into());
+            upload.insert(offset, data);
+            let attr = self.file_attr(id, upload.size, upload.mtime);
+            self.upload_ready(upload).await?;
+            if matches!(stable, nfs3::file::stable_how::UNSTABLE) {
+                return Ok((attr, nfs3::file::stable_how::UNSTABLE));
+            }
+            self.finish(&mut pending).await?;
+            Ok((attr, nfs3::file::stable_how::FILE_SYNC))
+        }
+        .await;
+        drop(slot);
+        self.release(id);
+        if let Ok((attr, _)) = &result {
+            wcc.after = nfs3::post_op_attr::attributes(*attr);
+        }
+        WccResult { result, wcc }
+    }
+
+    async fn create(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let path = child_path(&self.dir_path(dirid)?, utf8_name(filename)?);
+        self.listings.lock().unwrap().invalidate(dirid);
+        // an unchecked create of an existing file only applies the attributes
+        let id = match self.probe(&path).await? {
+            Some((Kind::Dir, _, _)) => return Err(nfs3::nfsstat3::NFS3ERR_EXIST),
+            Some((Kind::File, _, _)) => self.ids.lock().unwrap().id(&path, Kind::File),
+            None => {
+                self.client.put(&self.key(&path), Bytes::new()).await?;
+                let id = self.ids.lock().unwrap().id(&path, Kind::File);
+                self.discard(id).await;
+                id
+            }
+        };
+        Ok((id, self.setattr(id, attr).await?))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<nfs3::fileid3, nfs3::nfsstat3> {
+        let path = child_path(&self.dir_path(dirid)?, utf8_name(filename)?);
+        if self.probe(&path).await?.is_some() {
+            return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+        }
+        self.listings.lock().unwrap().invalidate(dirid);
+        self.client.put(&self.key(&path), Bytes::new()).await?;
+        let id = self.ids.lock().unwrap().id(&path, Kind::File);
+        self.discard(id).await;
+        Ok(id)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        let path = child_path(&self.dir_path(dirid)?, utf8_name(dirname)?);
+        if self.probe(&path).await?.is_some() {
+            return Err(nfs3::nfsstat3::NFS3ERR_EXIST);
+        }
+        self.listings.lock().unwrap().invalidate(dirid);
+        match self.marker_key(&path) {
+            Some(marker) => self.client.put(&marker, Bytes::new()).await?,
+            None => {
+                self.empty_dirs.lock().unwrap().insert(path.clone());
+            }
+        }
+        let id = self.ids.lock().unwrap().id(&path, Kind::Dir);
+        Ok((id, self.dir_attr(id)))
+    }
+
+    async fn remove(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        self.remove_entry(dirid, filename, vfs::RemoveKind::Any)
+            .await
+    }
+
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: vfs::RemoveKind,
+    ) -> WccResult<()> {
+        let remove = self.remove_entry(dirid, filename, kind);
+        vfs::dir_op_wcc(self, dirid, remove).await
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> Result<(), nfs3::nfsstat3> {
+        let from = child_path(&self.dir_path(from_dirid)?, utf8_name(from_filename)?);
+        let to = child_path(&self.dir_path(to_dirid)?, utf8_name(to_filename)?);
+        if from == to {
+            return Ok(());
+        }
+        let kind = match self.probe(&from).await? {
+            Some((kind, _, _)) => kind,
+            None => return Err(nfs3::nfsstat3::NFS3ERR_NOENT),
+        };
+        let target = self.probe(&to).await?.map(|(kind, _, _)| kind);
+        match (kind, target) {
+            (Kind::File, Some(Kind::Dir)) => return Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+            (Kind::Dir, Some(Kind::File)) => return Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+            (Kind::Dir, _) if to.starts_with(&format!("{from}/")) => {
+                return Err(nfs3::nfsstat3::NFS3ERR_INVAL)
+            }
+            _ => {}
+        }
+        self.flush_below(&from).await?;
+        {
+            let mut listings = self.listings.lock().unwrap();
+            listings.invalidate(from_dirid);
+            listings.invalidate(to_dirid);
+        }
+        match kind {
+            Kind::File => {
+                self.client.copy(&self.key(&from), &self.key(&to)).await?;
+                self.client.delete(&self.key(&from)).await?;
+            }
+            Kind::Dir => {
+                if target.is_some() {
+                    // only an empty directory can be replaced
+                    let marker = self.marker_key(&to);
+                    let page = self
+                        .client
+                        .list(&self.dir_prefix(&to), false, Some(2), None)
+                        .await?;
+                    if page.objects.iter().any(|o| Some(&o.key) != marker.as_ref()) {
+                        return Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY);
+                    }
+                    self.move_dir(&to, None).await?;
+                }
+                self.move_dir(&from, Some(&to)).await?;
+                let mut empty_dirs = self.empty_dirs.lock().unwrap();
+                let below = format!("{from}/");
+                let moved: Vec<String> = empty_dirs
+                    .iter()
+                    .filter(|dir| **dir == from || dir.starts_with(&below))
+                    .cloned()
+                    .collect();
+                for dir in moved {
+                    empty_dirs.remove(&dir);
+                    empty_dirs.insert(format!("{to}{}", &dir[from.len()..]));
+                }
+            }
+        }
+        self.ids.lock().unwrap().rename(&from, &to);
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        max_entries: usize,
+    ) -> Result<vfs::ReadDirResult, nfs3::nfsstat3> {
+        let path = self.dir_path(dirid)?;
+        // a listing is read once per pass and kept while the client pages through it
+        let cached = match start_after {
+            0 => None,
+            _ => self.listings.lock().unwrap().get(dirid, &()),
+        };
+        let listed = match cached {
+            Some(listed) => listed,
+            None => {
+                let listing = self.list(&path).await?;
+                let mut listed: Vec<Listed> = {
+                    let mut ids = self.ids.lock().unwrap();
+                    listing
+                        .into_iter()
+                        .map(|(name, (kind, size, mtime))| Listed {
+                            fileid: ids.id(&child_path(&path, &name), kind),
+                            name,
+                            kind,
+                            size,
+                            mtime,
+                        })
+                        .collect()
+                };
+                // file IDs are assigned in order of discovery and double as cookies
+                listed.sort_by_key(|entry| entry.fileid);
+                let listed = Arc::new(listed);
+                self.listings
+                    .lock()
+                    .unwrap()
+                    .insert(dirid, (), listed.clone());
+                listed
+            }
+        };
+        let first = listed.partition_point(|entry| entry.fileid <= start_after);
+        let page = &listed[first..listed.len().min(first.saturating_add(max_entries))];
+        let end = first + page.len() == listed.len();
+        let mut entries = Vec::with_capacity(page.len());
+        for entry in page {
+            let mut attr = match entry.kind {
+                Kind::Dir => self.dir_attr(entry.fileid),
+                Kind::File => self.file_attr(entry.fileid, entry.size, entry.mtime),
+            };
+            if let Some(slot) = self.existing(entry.fileid) {
+                if let Some(upload) = slot.lock().await.as_ref() {
+                    attr = self.file_attr(entry.fileid, upload.size, upload.mtime);
+                }
+            }
+            entries.push(vfs::DirEntry {
+                fileid: entry.fileid,
+                name: entry.name.as_bytes().into(),
+                attr,
+            });
+        }
+        Ok(vfs::ReadDirResult { entries, end })
+    }
+
+    async fn symlink(
+        &self,
+        _dirid: nfs3::fileid3,
+        _linkname: &nfs3::filename3,
+        _symlink: &nfs3::nfspath3,
+        _attr: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    async fn readlink(&self, _id: nfs3::fileid3) -> Result<nfs3::nfspath3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_INVAL)
+    }
+
+    async fn link(
+        &self,
+        _file_id: nfs3::fileid3,
+        _link_dir_id: nfs3::fileid3,
+        _link_name: &nfs3::filename3,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    async fn mknod(
+        &self,
+        _dir_id: nfs3::fileid3,
+        _name: &nfs3::filename3,
+        _ftype: nfs3::ftype3,
+        _specdata: nfs3::specdata3,
+        _attrs: &nfs3::sattr3,
+    ) -> Result<(nfs3::fileid3, nfs3::fattr3), nfs3::nfsstat3> {
+        Err(nfs3::nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    async fn commit(
+        &self,
+        file_id: nfs3::fileid3,
+        _offset: u64,
+        _count: u32,
+    ) -> Result<nfs3::fattr3, nfs3::nfsstat3> {
+        if let (_, Kind::File) = self.path(file_id)? {
+            self.flush(file_id).await?;
+        }
+        self.getattr(file_id).await
+    }
+}
@@ -0,0 +1,140 @@
+//! Change notifications for mutating NFS procedures.
+//!
+//! A server can report every successful change made through NFS to a listener
+//! registered with [`NFSTcp::set_event_listener`](crate::tcp::NFSTcp::set_event_listener).
+//! This lets indexers, caches and replicators follow the exported tree without
+//! polling the backend.
+//!
+//! One [`NFSEvent`] is sent after each successful CREATE, WRITE, SETATTR, REMOVE,
+//! RMDIR, RENAME, LINK, SYMLINK, MKDIR and MKNOD call, once the reply has been
+//! encoded. Failed calls and read-only procedures produce no events. Every event
+//! carries the credentials of the caller and the address of the client connection
+//! that issued it.
+//!
+//! Events are delivered on a bounded channel and never delay the reply to the
+//! client. When the listener falls behind and the channel is full, new events are
+//! dropped and counted;
+//! [`NFSTcpListener::dropped_events`](crate::tcp::NFSTcpListener::dropped_events)
+//! tells a listener that it has missed changes.
+
+use crate::protocol::xdr::{self, nfs3};
+
+/// A change made through NFS, together with the client that made it
+#[derive(Clone, Debug)]
+pub struct NFSEvent {
+    /// What changed in the file system
+    pub change: Change,
+
+    /// UNIX-style credentials of the caller
+    pub auth: xdr::rpc::auth_unix,
+
+    /// Client's network address (IP:port) of the connection that issued the call
+    pub client_addr: String,
+}
+
+/// A successful mutating NFS procedure
+///
+/// Names are the raw bytes sent by the client; `dirid` is always the directory
+/// containing the named entry.
+#[derive(Clone, Debug)]
+pub enum Change {
+    /// A regular file was created (CREATE)
+    Create {
+        /// Directory the file was created in
+        dirid: nfs3::fileid3,
+        /// Name of the new file
+        name: Vec<u8>,
+        /// Fileid of the new file
+        fileid: nfs3::fileid3,
+    },
+
+    /// Data was written to a file (WRITE)
+    Write {
+        /// File that was written
+        fileid: nfs3::fileid3,
+        /// Offset of the write in bytes
+        offset: nfs3::offset3,
+        /// Number of bytes written
+        count: nfs3::count3,
+    },
+
+    /// File attributes were changed (SETATTR)
+    Setattr {
+        /// File whose attributes changed
+        fileid: nfs3::fileid3,
+        /// Attributes requested by the client
+        attr: nfs3::sattr3,
+    },
+
+    /// A non-directory entry was removed (REMOVE)
+    Remove {
+        /// Directory the entry was removed from
+        dirid: nfs3::fileid3,
+        /// Name of the removed entry
+        name: Vec<u8>,
+    },
+
+    /// A directory was removed (RMDIR)
+    Rmdir {
+        /// Directory the entry was removed from
+        dirid: nfs3::fileid3,
+        /// Name of the removed directory
+        name: Vec<u8>,
+    },
+
+    /// An entry was renamed or moved (RENAME)
+    Rename {
+        /// Source directory
+        from_dirid: nfs3::fileid3,
+        /// Name in the source directory
+        from_name: Vec<u8>,
+        /// Destination directory
+        to_dirid: nfs3::fileid3,
+        /// Name in the destination directory
+        to_name: Vec<u8>,
+    },
+
+    /// A hard link was created (LINK)
+    Link {
+        /// Existing file that was linked
+        fileid: nfs3::fileid3,
+        /// Directory the link was created in
+        dirid: nfs3::fileid3,
+        /// Name of the new link
+        name: Vec<u8>,
+    },
+
+    /// A symbolic link was created (SYMLINK)
+    Symlink {
+        /// Directory the link was created in
+        dirid: nfs3::fileid3,
+        /// Name of the new link
+        name: Vec<u8>,
+        /// Fileid of the new link
+        fileid: nfs3::fileid3,
+        /// Target path stored in the link
+        target: Vec<u8>,
+    },
+
+    /// A directory was created (MKDIR)
+    Mkdir {
+        /// Parent directory
+        dirid: nfs3::fileid3,
+        /// Name of the new directory
+        name: Vec<u8>,
+        /// Fileid of the new directory
+        fileid: nfs3::fileid3,
+    },
+
+    /// A special file was created (MKNOD)
+    Mknod {
+        /// Directory the node was created in
+        dirid: nfs3::fileid3,
+        /// Name of the new node
+        name: Vec<u8>,
+        /// Fileid of the new node
+        fileid: nfs3::fileid3,
+        /// Type of the new node
+        ftype: nfs3::ftype3,
+    },
+}
@@ -0,0 +1,329 @@
+//! Bookkeeping shared by file systems that assign file IDs of their own.
+//!
+//! [`NameTree`] maps file IDs to the names they were looked up by, for adapters
+//! that have nothing but names to identify objects with. [`ListingCache`] keeps
+//! the sorted listings of a few directories while clients page through them.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use crate::protocol::xdr::nfs3;
+
+/// File ID of the root directory
+pub(crate) const ROOT_ID: nfs3::fileid3 = 1;
+
+/// Number of directory listings kept for clients paging through them
+const LISTING_CACHE_SIZE: usize = 64;
+
+/// An object that has been assigned a file ID
+#[derive(Debug)]
+pub(crate) struct Node<Q: ?Sized + ToOwned, D> {
+    /// File ID of the parent directory (the root is its own parent)
+    pub parent: nfs3::fileid3,
+    /// Name of the object in its parent directory
+    pub name: Q::Owned,
+    /// Other names of the object created with hard links,
+    /// as parent directory file ID and name
+    pub links: Vec<(nfs3::fileid3, Q::Owned)>,
+    /// Type of the object when it was assigned its file ID
+    pub ftype: nfs3::ftype3,
+    /// Children that have been assigned file IDs, by name
+    pub children: HashMap<Q::Owned, nfs3::fileid3>,
+    /// What the file system needs to find the object again
+    pub data: D,
+}
+
+/// Mapping between file IDs and the names of objects
+///
+/// Names are of type `Q` (`str` or `[u8]`); every object carries data of type `D`.
+pub(crate) struct NameTree<Q: ?Sized + ToOwned, D> {
+    /// Known objects by file ID
+    nodes: HashMap<nfs3::fileid3, Node<Q, D>>,
+    /// Next file ID to assign
+    next_id: nfs3::fileid3,
+}
+
+impl<Q: ?Sized + ToOwned, D> fmt::Debug for NameTree<Q, D>
+where
+    Node<Q, D>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NameTree")
+            .field("nodes", &self.nodes)
+            .field("next_id", &self.next_id)
+            .finish()
+    }
+}
+
+impl<Q, D> NameTree<Q, D>
+where
+    Q: ?Sized + ToOwned + Hash + Eq,
+    Q::Owned: Hash + Eq + Default,
+{
+    /// Creates a tree containing only the root directory
+    ///
+    /// # Arguments
+    /// * `root` - The data of the root directory
+    pub fn new(root: D) -> Self {
+        let node = Node {
+            parent: ROOT_ID,
+            name: Q::Owned::default(),
+            links: Vec::new(),
+            ftype: nfs3::ftype3::NF3DIR,
+            children: HashMap::new(),
+            data: root,
+        };
+        NameTree {
+            nodes: HashMap::from([(ROOT_ID, node)]),
+            next_id: ROOT_ID + 1,
+        }
+    }
+
+    /// Returns a known object
+    pub fn get(&self, id: nfs3::fileid3) -> Result<&Node<Q, D>, nfs3::nfsstat3> {
+        self.nodes.get(&id).ok_or(nfs3::nfsstat3::NFS3ERR_STALE)
+    }
+
+    /// Returns a known object for changing its data
+    pub fn get_mut(&mut self, id: nfs3::fileid3) -> Result<&mut Node<Q, D>, nfs3::nfsstat3> {
+        self.nodes.get_mut(&id).ok_or(nfs3::nfsstat3::NFS3ERR_STALE)
+    }
+
+    /// Returns the directories containing a name of an object
+    pub fn parents(&self, id: nfs3::fileid3) -> Vec<nfs3::fileid3> {
+        match self.nodes.get(&id) {
+            Some(node) => std::iter::once(node.parent)
+                .chain(node.links.iter().map(|(parent, _)| *parent))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the file ID of a child, assigning a new one with the given data if
+    /// the child is unknown or has changed its type
+    ///
+    /// A child that is already known keeps its data.
+    pub fn child(
+        &mut self,
+        parent: nfs3::fileid3,
+        name: &Q,
+        ftype: nfs3::ftype3,
+        data: D,
+    ) -> nfs3::fileid3 {
+        let existing = self
+            .nodes
+            .get(&parent)
+            .and_then(|dir| dir.children.get(name))
+            .copied();
+        if let Some(id) = existing {
+            if self
+                .nodes
+                .get(&id)
+                .is_some_and(|node| node.ftype as u32 == ftype as u32)
+            {
+                return id;
+            }
+            self.forget_child(parent, name);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.nodes.insert(
+            id,
+            Node {
+                parent,
+                name: name.to_owned(),
+                links: Vec::new(),
+                ftype,
+                children: HashMap::new(),
+                data,
+            },
+        );
+        if let Some(dir) = self.nodes.get_mut(&parent) {
+            dir.children.insert(name.to_owned(), id);
+        }
+        id
+    }
+
+    /// Records another name of an existing object
+    pub fn link(&mut self, id: nfs3::fileid3, parent: nfs3::fileid3, name: &Q) {
+        self.forget_child(parent, name);
+        if !self.nodes.contains_key(&id) {
+            return;
+        }
+        let Some(dir) = self.nodes.get_mut(&parent) else {
+            return;
+        };
+        dir.children.insert(name.to_owned(), id);
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.links.push((parent, name.to_owned()));
+        }
+    }
+
+    /// Removes a name from the children of a directory if it refers to the given object
+    fn remove_name(&mut self, parent: nfs3::fileid3, name: &Q, id: nfs3::fileid3) {
+        if let Some(dir) = self.nodes.get_mut(&parent) {
+            if dir.children.get(name) == Some(&id) {
+                dir.children.remove(name);
+            }
+        }
+    }
+
+    /// Invalidates the file ID of an object and everything below it
+    pub fn forget(&mut self, id: nfs3::fileid3) {
+        if id == ROOT_ID {
+            return;
+        }
+        let mut pending = vec![id];
+        while let Some(id) = pending.pop() {
+            let Some(node) = self.nodes.remove(&id) else {
+                continue;
+            };
+            self.remove_name(node.parent, node.name.borrow(), id);
+            for (parent, name) in &node.links {
+                self.remove_name(*parent, name.borrow(), id);
+            }
+            pending.extend(node.children.into_values());
+        }
+    }
+
+    /// Removes a name of a child, invalidating its file ID if it was its last name
+    pub fn forget_child(&mut self, parent: nfs3::fileid3, name: &Q) {
+        let id = self
+            .nodes
+            .get(&parent)
+            .and_then(|dir| dir.children.get(name))
+            .copied();
+        let Some(id) = id else {
+            return;
+        };
+        let Some(node) = self.nodes.get_mut(&id) else {
+            return;
+        };
+        if node.parent == parent && node.name.borrow() == name {
+            if node.links.is_empty() {
+                self.forget(id);
+                return;
+            }
+            // another name takes over as the path of the object
+            let (new_parent, new_name) = node.links.remove(0);
+            node.parent = new_parent;
+            node.name = new_name;
+        } else {
+            node.links
+                .retain(|(p, n)| !(*p == parent && n.borrow() == name));
+        }
+        self.remove_name(parent, name, id);
+    }
+
+    /// Moves an object to a new parent and name, keeping its file ID
+    pub fn rename(
+        &mut self,
+        from_parent: nfs3::fileid3,
+        from_name: &Q,
+        to_parent: nfs3::fileid3,
+        to_name: &Q,
+    ) {
+        let id = self
+            .nodes
+            .get_mut(&from_parent)
+            .and_then(|dir| dir.children.remove(from_name));
+        self.forget_child(to_parent, to_name);
+        let Some(id) = id else {
+            return;
+        };
+        if let Some(node) = self.nodes.get_mut(&id) {
+            if node.parent == from_parent && node.name.borrow() == from_name {
+                node.parent = to_parent;
+                node.name = to_name.to_owned();
+            } else if let Some(link) = node
+                .links
+                .iter_mut()
+                .find(|(p, n)| *p == from_parent && n.borrow() == from_name)
+            {
+                *link = (to_parent, to_name.to_owned());
+            }
+        }
+        match self.nodes.get_mut(&to_parent) {
+            Some(dir) => {
+                dir.children.insert(to_name.to_owned(), id);
+            }
+            None => self.forget(id),
+        }
+    }
+}
+
+/// Listing of a directory kept while a client pages through it
+#[derive(Debug)]
+struct CachedListing<V, E> {
+    /// What tells whether the directory changed since it was listed
+    verifier: V,
+    /// Entries of the directory
+    entries: Arc<Vec<E>>,
+    /// Value of the use counter when the listing was last used
+    last_used: u64,
+}
+
+/// Recently read directory listings, keyed by directory file ID
+///
+/// A listing is returned only while the directory's verifier (usually its
+/// timestamps) is unchanged.
+#[derive(Debug)]
+pub(crate) struct ListingCache<V, E> {
+    /// Listings by directory file ID
+    listings: HashMap<nfs3::fileid3, CachedListing<V, E>>,
+    /// Counter incremented on every use, used to find the least recently used listing
+    uses: u64,
+}
+
+impl<V, E> Default for ListingCache<V, E> {
+    fn default() -> Self {
+        ListingCache {
+            listings: HashMap::new(),
+            uses: 0,
+        }
+    }
+}
+
+impl<V: PartialEq, E> ListingCache<V, E> {
+    /// Returns the listing of a directory if it is still current
+    pub fn get(&mut self, dirid: nfs3::fileid3, verifier: &V) -> Option<Arc<Vec<E>>> {
+        self.uses += 1;
+        let uses = self.uses;
+        let cached = self.listings.get_mut(&dirid)?;
+        if cached.verifier != *verifier {
+            self.listings.remove(&dirid);
+            return None;
+        }
+        cached.last_used = uses;
+        Some(cached.entries.clone())
+    }
+
+    /// Stores the listing of a directory, evicting the least recently used one if full
+    pub fn insert(&mut self, dirid: nfs3::fileid3, verifier: V, entries: Arc<Vec<E>>) {
+        if self.listings.len() >= LISTING_CACHE_SIZE && !self.listings.contains_key(&dirid) {
+            let oldest = self
+                .listings
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.listings.remove(&oldest);
+            }
+        }
+        self.uses += 1;
+        let cached = CachedListing {
+            verifier,
+            entries,
+            last_used: self.uses,
+        };
+        self.listings.insert(dirid, cached);
+    }
+
+    /// Discards the listing of a directory
+    pub fn invalidate(&mut self, dirid: nfs3::fileid3) {
+        self.listings.remove(&dirid);
+    }
+}
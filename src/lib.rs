@@ -24,6 +24,34 @@
 //! - `protocol`: Internal module that implements the NFS, MOUNT, and PORTMAP protocols,
 //!   including XDR (External Data Representation) encoding/decoding.
 //!
+//! - `adapters`: Adapters that implement or extend file systems:
+//!   - `BlockingAdapter` runs synchronous `BlockingNFSFileSystem` implementations on
+//!     Tokio's blocking thread pool.
+//!   - `PathAdapter` implements the VFS API on top of the simpler path-based
+//!     `PathFileSystem` trait.
+//!   - `ReadOnly` exports a file system read-only.
+//!   - `Subtree` exports a directory of another file system as its root.
+//!   - `Overlay` stacks a writable upper layer on read-only lower layers.
+//!   - `Mux` combines several file systems into one tree.
+//!   - `Cached` caches attributes and lookups of slow file systems.
+//!   - `ReadAhead` caches blocks and reads ahead for file systems with expensive reads.
+//!   - `WriteBack` buffers and coalesces small writes.
+//!   - `Quota` limits the space and inodes used per user, per group and in total.
+//!   - `Encrypted` encrypts file contents, and optionally names, at rest (feature `encryption`).
+//!   - `Compressed` compresses file contents transparently (feature `compression`).
+//!   - `Faulty` injects errors and delays for resilience testing.
+//!   - `Traced` logs file system calls.
+//!
+//! - `backends`: Ready-to-use file system implementations:
+//!   - `MemFS` keeps everything in memory.
+//!   - `PassthroughFS` exports a local directory (Unix only).
+//!   - `ArchiveFS` serves the contents of a tar or zip archive read-only (feature `archive`).
+//!   - `S3FS` stores files in an S3-compatible object store (feature `s3`).
+//!   - `DedupFS` stores file contents as deduplicated chunks in a local directory
+//!     (feature `dedup`).
+//!
+//! - `events`: Typed change notifications emitted after each successful mutating NFS procedure.
+//!
 //! - `fs_util`: Utility functions for working with file systems.
 //!
 //! ## Standards Compliance
@@ -39,12 +67,17 @@
 //! To create an NFS server, implement the `NFSFileSystem` trait and use the `NFSTcpListener`
 //! to expose it over the network.
 
+mod file_ids;
 mod protocol;
 mod write_counter;
+mod write_ranges;
 
 #[cfg(not(target_os = "windows"))]
 pub mod fs_util;
 
+pub mod adapters;
+pub mod backends;
+pub mod events;
 pub mod tcp;
 pub mod vfs;
 
@@ -61,29 +61,12 @@ pub async fn nfsproc3_commit(
     }
     let id = id.unwrap();
 
-    // get the object attributes before the commit
-    let pre_obj_attr = match context.vfs.getattr(id).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(_) => nfs3::pre_op_attr::Void,
-    };
-
     // Call VFS commit method
-    match context.vfs.commit(id, args.offset, args.count).await {
-        Ok(fattr) => {
-            let post_obj_attr = nfs3::post_op_attr::attributes(fattr);
-
+    let res = context.vfs.commit_wcc(id, args.offset, args.count).await;
+    match res.result {
+        Ok(_) => {
             let res = nfs3::file::COMMIT3resok {
-                file_wcc: nfs3::wcc_data {
-                    before: pre_obj_attr,
-                    after: post_obj_attr,
-                },
+                file_wcc: res.wcc,
                 verf: context.vfs.serverid(),
             };
 
@@ -93,20 +76,10 @@ pub async fn nfsproc3_commit(
             res.serialize(output)?;
         }
         Err(stat) => {
-            let post_obj_attr = match context.vfs.getattr(id).await {
-                Ok(v) => nfs3::post_op_attr::attributes(v),
-                Err(_) => nfs3::post_op_attr::Void,
-            };
-
-            let wcc_data = nfs3::wcc_data {
-                before: pre_obj_attr,
-                after: post_obj_attr,
-            };
-
             debug!("nfsproc3_commit error: {:?}", stat);
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
-            wcc_data.serialize(output)?;
+            res.wcc.serialize(output)?;
         }
     }
 
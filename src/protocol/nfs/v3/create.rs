@@ -23,6 +23,7 @@ use std::io::{Read, Write};
 
 use tracing::{debug, error, warn};
 
+use crate::events;
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
 use crate::vfs;
@@ -79,24 +80,6 @@ pub async fn nfsproc3_create(
     // found the directory, get the attributes
     let dirid = dirid.unwrap();
 
-    // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(stat) => {
-            error!("Cannot stat directory");
-            xdr::rpc::make_success_reply(xid).serialize(output)?;
-            stat.serialize(output)?;
-            nfs3::wcc_data::default().serialize(output)?;
-            return Ok(());
-        }
-    };
     let mut target_attributes = nfs3::sattr3::default();
 
     match createhow {
@@ -109,20 +92,19 @@ pub async fn nfsproc3_create(
             debug!("create guarded {:?}", target_attributes);
             if context.vfs.lookup(dirid, &dirops.name).await.is_ok() {
                 // file exists. Fail with NFS3ERR_EXIST.
-                // Re-read dir attributes
-                // for post op attr
-                let post_dir_attr = match context.vfs.getattr(dirid).await {
-                    Ok(v) => nfs3::post_op_attr::attributes(v),
-                    Err(_) => nfs3::post_op_attr::Void,
+                // Nothing changed, so the directory attributes
+                // serve as both pre and post op attr
+                let dir_wcc = match context.vfs.getattr(dirid).await {
+                    Ok(v) => nfs3::wcc_data {
+                        before: nfs3::pre_op_attr::attributes(v.into()),
+                        after: nfs3::post_op_attr::attributes(v),
+                    },
+                    Err(_) => nfs3::wcc_data::default(),
                 };
 
                 xdr::rpc::make_success_reply(xid).serialize(output)?;
                 nfs3::nfsstat3::NFS3ERR_EXIST.serialize(output)?;
-                nfs3::wcc_data {
-                    before: pre_dir_attr,
-                    after: post_dir_attr,
-                }
-                .serialize(output)?;
+                dir_wcc.serialize(output)?;
                 return Ok(());
             }
         }
@@ -133,36 +115,30 @@ pub async fn nfsproc3_create(
 
     let fid: Result<nfs3::fileid3, nfs3::nfsstat3>;
     let postopattr: nfs3::post_op_attr;
-    // fill in the fid and post op attr here
+    let wcc_res: nfs3::wcc_data;
+    // fill in the fid, post op attr and dir wcc here
     if matches!(createhow, nfs3::createmode3::EXCLUSIVE) {
         // the API for exclusive is very slightly different
         // We are not returning a post op attribute
-        fid = context.vfs.create_exclusive(dirid, &dirops.name).await;
+        let res = context.vfs.create_exclusive_wcc(dirid, &dirops.name).await;
+        fid = res.result;
         postopattr = nfs3::post_op_attr::Void;
+        wcc_res = res.wcc;
     } else {
         // create!
         let res = context
             .vfs
-            .create(dirid, &dirops.name, target_attributes)
+            .create_wcc(dirid, &dirops.name, target_attributes)
             .await;
-        fid = res.map(|x| x.0);
-        postopattr = if let Ok((_, fattr)) = res {
+        fid = res.result.map(|x| x.0);
+        postopattr = if let Ok((_, fattr)) = res.result {
             nfs3::post_op_attr::attributes(fattr)
         } else {
             nfs3::post_op_attr::Void
         };
+        wcc_res = res.wcc;
     }
 
-    // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => nfs3::post_op_attr::attributes(v),
-        Err(_) => nfs3::post_op_attr::Void,
-    };
-    let wcc_res = nfs3::wcc_data {
-        before: pre_dir_attr,
-        after: post_dir_attr,
-    };
-
     match fid {
         Ok(fid) => {
             debug!("create success --> {:?}, {:?}", fid, postopattr);
@@ -173,6 +149,11 @@ pub async fn nfsproc3_create(
             nfs3::post_op_fh3::handle(fh).serialize(output)?;
             postopattr.serialize(output)?;
             wcc_res.serialize(output)?;
+            context.notify(events::Change::Create {
+                dirid,
+                name: dirops.name.to_vec(),
+                fileid: fid,
+            });
         }
         Err(e) => {
             error!("create error --> {:?}", e);
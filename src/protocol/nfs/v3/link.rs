@@ -20,6 +20,7 @@ use std::io::{Read, Write};
 
 use tracing::{debug, warn};
 
+use crate::events;
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
 use crate::vfs;
@@ -82,41 +83,20 @@ pub async fn nfsproc3_link(
     }
     let dirid = dirid.unwrap();
 
-    // Get the directory attributes before the operation
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(_) => nfs3::pre_op_attr::Void,
-    };
-
     // Call VFS link method
-    match context.vfs.link(fileid, dirid, &args.link.name).await {
+    let res = context.vfs.link_wcc(fileid, dirid, &args.link.name).await;
+    match res.result {
         Ok(fattr) => {
-            // Get file attributes
-            let file_attr = nfs3::post_op_attr::attributes(fattr);
-
-            // Get the directory attributes after the operation
-            let post_dir_attr = match context.vfs.getattr(dirid).await {
-                Ok(v) => nfs3::post_op_attr::attributes(v),
-                Err(_) => nfs3::post_op_attr::Void,
-            };
-
-            let wcc_res = nfs3::wcc_data {
-                before: pre_dir_attr,
-                after: post_dir_attr,
-            };
-
             debug!("nfsproc3_link success");
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             nfs3::nfsstat3::NFS3_OK.serialize(output)?;
-            file_attr.serialize(output)?;
-            wcc_res.serialize(output)?;
+            nfs3::post_op_attr::attributes(fattr).serialize(output)?;
+            res.wcc.serialize(output)?;
+            context.notify(events::Change::Link {
+                fileid,
+                dirid,
+                name: args.link.name.to_vec(),
+            });
         }
         Err(stat) => {
             // Get file attributes
@@ -125,22 +105,11 @@ pub async fn nfsproc3_link(
                 Err(_) => nfs3::post_op_attr::Void,
             };
 
-            // Get the directory attributes after the operation (unchanged)
-            let post_dir_attr = match context.vfs.getattr(dirid).await {
-                Ok(v) => nfs3::post_op_attr::attributes(v),
-                Err(_) => nfs3::post_op_attr::Void,
-            };
-
-            let wcc_res = nfs3::wcc_data {
-                before: pre_dir_attr,
-                after: post_dir_attr,
-            };
-
             debug!("nfsproc3_link failed: {:?}", stat);
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             file_attr.serialize(output)?;
-            wcc_res.serialize(output)?;
+            res.wcc.serialize(output)?;
         }
     }
 
@@ -19,6 +19,7 @@ use std::io::{Read, Write};
 
 use tracing::{debug, error, warn};
 
+use crate::events;
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
 use crate::vfs;
@@ -72,38 +73,10 @@ pub async fn nfsproc3_mkdir(
     // found the directory, get the attributes
     let dirid = dirid.unwrap();
 
-    // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(stat) => {
-            error!("Cannot stat directory");
-            xdr::rpc::make_success_reply(xid).serialize(output)?;
-            stat.serialize(output)?;
-            nfs3::wcc_data::default().serialize(output)?;
-            return Ok(());
-        }
-    };
-
-    let res = context.vfs.mkdir(dirid, &args.dirops.name).await;
-
-    // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => nfs3::post_op_attr::attributes(v),
-        Err(_) => nfs3::post_op_attr::Void,
-    };
-    let wcc_res = nfs3::wcc_data {
-        before: pre_dir_attr,
-        after: post_dir_attr,
-    };
+    let res = context.vfs.mkdir_wcc(dirid, &args.dirops.name).await;
+    let wcc_res = res.wcc;
 
-    match res {
+    match res.result {
         Ok((fid, fattr)) => {
             debug!("mkdir success --> {:?}, {:?}", fid, fattr);
             xdr::rpc::make_success_reply(xid).serialize(output)?;
@@ -113,6 +86,11 @@ pub async fn nfsproc3_mkdir(
             nfs3::post_op_fh3::handle(fh).serialize(output)?;
             nfs3::post_op_attr::attributes(fattr).serialize(output)?;
             wcc_res.serialize(output)?;
+            context.notify(events::Change::Mkdir {
+                dirid,
+                name: args.dirops.name.to_vec(),
+                fileid: fid,
+            });
         }
         Err(e) => {
             debug!("mkdir error {:?} --> {:?}", xid, e);
@@ -23,6 +23,7 @@ use std::io::{Read, Write};
 
 use tracing::{debug, error, warn};
 
+use crate::events;
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
 use crate::vfs;
@@ -75,73 +76,42 @@ pub async fn nfsproc3_mknod(
     // found the directory, get the attributes
     let dirid = dirid.unwrap();
 
-    // get the object attributes before the operation
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(_) => nfs3::pre_op_attr::Void,
-    };
-
     // Create default attributes if necessary
     let attr = nfs3::sattr3::default();
 
     // Call VFS mknod method
-    match context
+    let res = context
         .vfs
-        .mknod(
+        .mknod_wcc(
             dirid,
             &args.where_dir.name,
             args.what.mknod_type,
             args.what.device.device,
             &attr,
         )
-        .await
-    {
+        .await;
+    match res.result {
         Ok((fid, fattr)) => {
             debug!("nfsproc3_mknod success --> {:?}, {:?}", fid, fattr);
-
-            // Get the directory attributes after the operation
-            let post_dir_attr = match context.vfs.getattr(dirid).await {
-                Ok(v) => nfs3::post_op_attr::attributes(v),
-                Err(_) => nfs3::post_op_attr::Void,
-            };
-
-            let wcc_res = nfs3::wcc_data {
-                before: pre_dir_attr,
-                after: post_dir_attr,
-            };
-
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             nfs3::nfsstat3::NFS3_OK.serialize(output)?;
             // serialize MKNOD3resok
             let fh = context.vfs.id_to_fh(fid);
             nfs3::post_op_fh3::handle(fh).serialize(output)?;
             nfs3::post_op_attr::attributes(fattr).serialize(output)?;
-            wcc_res.serialize(output)?;
+            res.wcc.serialize(output)?;
+            context.notify(events::Change::Mknod {
+                dirid,
+                name: args.where_dir.name.to_vec(),
+                fileid: fid,
+                ftype: args.what.mknod_type,
+            });
         }
         Err(stat) => {
             debug!("nfsproc3_mknod error --> {:?}", stat);
-
-            // Get the directory attributes after the operation (unchanged)
-            let post_dir_attr = match context.vfs.getattr(dirid).await {
-                Ok(v) => nfs3::post_op_attr::attributes(v),
-                Err(_) => nfs3::post_op_attr::Void,
-            };
-
-            let wcc_res = nfs3::wcc_data {
-                before: pre_dir_attr,
-                after: post_dir_attr,
-            };
-
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
-            wcc_res.serialize(output)?;
+            res.wcc.serialize(output)?;
         }
     }
 
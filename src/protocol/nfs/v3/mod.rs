@@ -37,7 +37,7 @@
 //! - Better attribute caching with the ACCESS procedure
 //! - Enhanced directory reading with READDIRPLUS
 
-use std::io::{Read, Write};
+use std::io::Read;
 
 use num_traits::cast::FromPrimitive;
 use tracing::warn;
@@ -63,6 +63,7 @@ mod readdirplus;
 mod readlink;
 mod remove;
 mod rename;
+mod rmdir;
 mod setattr;
 mod symlink;
 mod write;
@@ -85,6 +86,7 @@ use readdirplus::nfsproc3_readdirplus;
 use readlink::nfsproc3_readlink;
 use remove::nfsproc3_remove;
 use rename::nfsproc3_rename;
+use rmdir::nfsproc3_rmdir;
 use setattr::nfsproc3_setattr;
 use symlink::nfsproc3_symlink;
 use write::nfsproc3_write;
@@ -110,7 +112,7 @@ pub async fn handle_nfs(
     xid: u32,
     call: xdr::rpc::call_body,
     input: &mut impl Read,
-    output: &mut impl Write,
+    output: &mut rpc::ResponseBuffer,
     context: &rpc::Context,
 ) -> Result<(), anyhow::Error> {
     if call.vers != nfs3::VERSION {
@@ -143,7 +145,7 @@ pub async fn handle_nfs(
         nfs3::NFSProgram::NFSPROC3_CREATE => nfsproc3_create(xid, input, output, context).await?,
         nfs3::NFSProgram::NFSPROC3_SETATTR => nfsproc3_setattr(xid, input, output, context).await?,
         nfs3::NFSProgram::NFSPROC3_REMOVE => nfsproc3_remove(xid, input, output, context).await?,
-        nfs3::NFSProgram::NFSPROC3_RMDIR => nfsproc3_remove(xid, input, output, context).await?,
+        nfs3::NFSProgram::NFSPROC3_RMDIR => nfsproc3_rmdir(xid, input, output, context).await?,
         nfs3::NFSProgram::NFSPROC3_RENAME => nfsproc3_rename(xid, input, output, context).await?,
         nfs3::NFSProgram::NFSPROC3_MKDIR => nfsproc3_mkdir(xid, input, output, context).await?,
         nfs3::NFSProgram::NFSPROC3_SYMLINK => nfsproc3_symlink(xid, input, output, context).await?,
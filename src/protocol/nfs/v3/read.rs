@@ -19,6 +19,15 @@ use tracing::{debug, error};
 
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
+use crate::vfs;
+
+/// Data returned by the VFS for a READ
+enum ReadData {
+    /// Data read into memory, with the eof flag
+    Bytes(bytes::Bytes, bool),
+    /// A range of a local file to send directly
+    File(vfs::FileRange),
+}
 
 /// Handles NFSv3 READ procedure (procedure 6)
 ///
@@ -39,7 +48,7 @@ use crate::protocol::xdr::{self, nfs3, XDR};
 pub async fn nfsproc3_read(
     xid: u32,
     input: &mut impl Read,
-    output: &mut impl Write,
+    output: &mut rpc::ResponseBuffer,
     context: &rpc::Context,
 ) -> Result<(), anyhow::Error> {
     let mut args = nfs3::file::READ3args::default();
@@ -59,17 +68,42 @@ pub async fn nfsproc3_read(
         Ok(v) => nfs3::post_op_attr::attributes(v),
         Err(_) => nfs3::post_op_attr::Void,
     };
-    match context.vfs.read(id, args.offset, args.count).await {
-        Ok((bytes, eof)) => {
-            let res = nfs3::file::READ3resok {
-                file_attributes: obj_attr,
-                count: bytes.len() as u32,
-                eof,
-                data: bytes,
+    let res = match context
+        .vfs
+        .read_file_range(id, args.offset, args.count)
+        .await
+    {
+        Ok(Some(range)) => clamp_to_file_size(range).await.map(ReadData::File),
+        Ok(None) => context
+            .vfs
+            .read_bytes(id, args.offset, args.count)
+            .await
+            .map(|(bytes, eof)| ReadData::Bytes(bytes, eof)),
+        Err(stat) => Err(stat),
+    };
+    match res {
+        Ok(data) => {
+            let (count, eof) = match &data {
+                ReadData::Bytes(bytes, eof) => (bytes.len() as u32, *eof),
+                ReadData::File(range) => (range.len, range.eof),
             };
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             nfs3::nfsstat3::NFS3_OK.serialize(output)?;
-            res.serialize(output)?;
+            // serialize READ3resok field by field so that the data
+            // is appended to the reply without being copied
+            obj_attr.serialize(output)?;
+            count.serialize(output)?;
+            eof.serialize(output)?;
+            // opaque data<>: length, contents and padding to 4 bytes
+            count.serialize(output)?;
+            match data {
+                ReadData::Bytes(bytes, _) => output.append_bytes(bytes),
+                ReadData::File(range) => {
+                    output.append_file(range.file, range.offset, range.len as usize)
+                }
+            }
+            let pad = ((4 - count % 4) % 4) as usize;
+            output.write_all(&[0_u8; 4][..pad])?;
         }
         Err(stat) => {
             error!("nfsproc3_read error {:?} --> {:?}", xid, stat);
@@ -80,3 +114,25 @@ pub async fn nfsproc3_read(
     }
     Ok(())
 }
+
+/// Shortens a file range to the current size of the file
+///
+/// The length is encoded in the reply before the data is sent, so it must not
+/// promise more than the file holds. The file is checked here, just before
+/// encoding, rather than trusting the size the backend saw.
+async fn clamp_to_file_size(range: vfs::FileRange) -> Result<vfs::FileRange, nfs3::nfsstat3> {
+    let file = range.file.clone();
+    let size = tokio::task::spawn_blocking(move || file.metadata().map(|m| m.len()))
+        .await
+        .map_err(|_| nfs3::nfsstat3::NFS3ERR_IO)?
+        .map_err(|_| nfs3::nfsstat3::NFS3ERR_IO)?;
+    let available = size.saturating_sub(range.offset);
+    if available >= range.len as u64 {
+        return Ok(range);
+    }
+    Ok(vfs::FileRange {
+        len: available as u32,
+        eof: true,
+        ..range
+    })
+}
@@ -21,6 +21,7 @@
 
 use std::io::{Read, Write};
 
+use futures::StreamExt;
 use tracing::{debug, error, trace};
 
 use crate::protocol::rpc;
@@ -80,85 +81,96 @@ pub async fn nfsproc3_readdir(
     let has_version = args.cookieverf != nfs3::cookieverf3::default();
     // subtract off the final entryplus* field (which must be false) and the eof
     let max_bytes_allowed = args.dircount as usize - 128;
-    // args.dircount is bytes of just fileid, name, cookie.
-    // This is hard to ballpark, so we just divide it by 16
-    let estimated_max_results = args.dircount / 16;
-    let mut ctr = 0;
-    match context
-        .vfs
-        .readdir_simple(dirid, estimated_max_results as usize)
-        .await
-    {
-        Ok(result) => {
-            // we count dir_count seperately as it is just a subset of fields
-            let mut accumulated_dircount: usize = 0;
-            let mut all_entries_written = true;
-
-            // this is a wrapper around a writer that also just counts the number of bytes
-            // written
-            let mut counting_output = crate::write_counter::WriteCounter::new(output);
-
-            xdr::rpc::make_success_reply(xid).serialize(&mut counting_output)?;
-            nfs3::nfsstat3::NFS3_OK.serialize(&mut counting_output)?;
-            dir_attr.serialize(&mut counting_output)?;
-            dirversion.serialize(&mut counting_output)?;
-            for entry in result.entries {
-                let entry = nfs3::dir::entry3 {
-                    fileid: entry.fileid,
-                    name: entry.name,
-                    cookie: entry.fileid,
-                };
-                // write the entry into a buffer first
-                let mut write_buf: Vec<u8> = Vec::new();
-                let mut write_cursor = std::io::Cursor::new(&mut write_buf);
-                // true flag for the entryplus3* to mark that this contains an entry
-                true.serialize(&mut write_cursor)?;
-                entry.serialize(&mut write_cursor)?;
-                write_cursor.flush()?;
-                let added_dircount = std::mem::size_of::<nfs3::fileid3>()                   // fileid
-                                    + std::mem::size_of::<u32>() + entry.name.len()  // name
-                                    + std::mem::size_of::<nfs3::cookie3>(); // cookie
-                let added_output_bytes = write_buf.len();
-                // check if we can write without hitting the limits
-                if added_output_bytes + counting_output.bytes_written() < max_bytes_allowed {
-                    trace!("  -- dirent {:?}", entry);
-                    // commit the entry
-                    ctr += 1;
-                    counting_output.write_all(&write_buf)?;
-                    accumulated_dircount += added_dircount;
-                    trace!(
-                        "  -- lengths: {:?} / {:?} / {:?}",
-                        accumulated_dircount,
-                        counting_output.bytes_written(),
-                        max_bytes_allowed
-                    );
-                } else {
-                    trace!(" -- insufficient space. truncating");
-                    all_entries_written = false;
-                    break;
-                }
-            }
-            // false flag for the final entryplus* linked list
-            false.serialize(&mut counting_output)?;
-            // eof flag is only valid here if we wrote everything
-            if all_entries_written {
-                debug!("  -- readdir eof {:?}", result.end);
-                result.end.serialize(&mut counting_output)?;
-            } else {
-                debug!("  -- readdir eof {:?}", false);
-                false.serialize(&mut counting_output)?;
-            }
-            debug!(
-                "readir {}, has_version {},  start at {}, flushing {} entries, complete {}",
-                dirid, has_version, args.cookie, ctr, all_entries_written
-            );
-        }
+    let mut entries = match context.vfs.readdir_stream(dirid, args.cookie).await {
+        Ok(entries) => entries,
         Err(stat) => {
             error!("readdir error {:?} --> {:?} ", xid, stat);
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             dir_attr.serialize(output)?;
+            return Ok(());
         }
     };
+    // pull the first entry before writing anything so that a listing which
+    // fails straight away can still be reported as an error
+    let mut next = entries.next().await;
+    if let Some(Err(stat)) = next {
+        error!("readdir error {:?} --> {:?} ", xid, stat);
+        xdr::rpc::make_success_reply(xid).serialize(output)?;
+        stat.serialize(output)?;
+        dir_attr.serialize(output)?;
+        return Ok(());
+    }
+
+    let mut ctr = 0;
+    // we count dir_count seperately as it is just a subset of fields
+    let mut accumulated_dircount: usize = 0;
+    let mut eof = false;
+
+    // this is a wrapper around a writer that also just counts the number of bytes
+    // written
+    let mut counting_output = crate::write_counter::WriteCounter::new(output);
+
+    xdr::rpc::make_success_reply(xid).serialize(&mut counting_output)?;
+    nfs3::nfsstat3::NFS3_OK.serialize(&mut counting_output)?;
+    dir_attr.serialize(&mut counting_output)?;
+    dirversion.serialize(&mut counting_output)?;
+    loop {
+        let entry = match next {
+            Some(Ok(entry)) => entry,
+            Some(Err(stat)) => {
+                // the client will resume from the last cookie sent
+                debug!(" -- readdir stream error {:?}. truncating", stat);
+                break;
+            }
+            None => {
+                eof = true;
+                break;
+            }
+        };
+        let entry = nfs3::dir::entry3 {
+            fileid: entry.fileid,
+            name: entry.name,
+            cookie: entry.fileid,
+        };
+        // write the entry into a buffer first
+        let mut write_buf: Vec<u8> = Vec::new();
+        let mut write_cursor = std::io::Cursor::new(&mut write_buf);
+        // true flag for the entryplus3* to mark that this contains an entry
+        true.serialize(&mut write_cursor)?;
+        entry.serialize(&mut write_cursor)?;
+        write_cursor.flush()?;
+        let added_dircount = std::mem::size_of::<nfs3::fileid3>()                   // fileid
+                            + std::mem::size_of::<u32>() + entry.name.len()  // name
+                            + std::mem::size_of::<nfs3::cookie3>(); // cookie
+        let added_output_bytes = write_buf.len();
+        // check if we can write without hitting the limits
+        if added_output_bytes + counting_output.bytes_written() < max_bytes_allowed {
+            trace!("  -- dirent {:?}", entry);
+            // commit the entry
+            ctr += 1;
+            counting_output.write_all(&write_buf)?;
+            accumulated_dircount += added_dircount;
+            trace!(
+                "  -- lengths: {:?} / {:?} / {:?}",
+                accumulated_dircount,
+                counting_output.bytes_written(),
+                max_bytes_allowed
+            );
+        } else {
+            trace!(" -- insufficient space. truncating");
+            break;
+        }
+        next = entries.next().await;
+    }
+    // false flag for the final entryplus* linked list
+    false.serialize(&mut counting_output)?;
+    // eof is only reached if the stream ran out before the reply filled up
+    debug!("  -- readdir eof {:?}", eof);
+    eof.serialize(&mut counting_output)?;
+    debug!(
+        "readir {}, has_version {},  start at {}, flushing {} entries, complete {}",
+        dirid, has_version, args.cookie, ctr, eof
+    );
     Ok(())
 }
@@ -27,6 +27,7 @@ use std::io::{Read, Write};
 
 use tracing::{debug, error, warn};
 
+use crate::events;
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
 use crate::vfs;
@@ -62,6 +63,31 @@ pub async fn nfsproc3_remove(
     input: &mut impl Read,
     output: &mut impl Write,
     context: &rpc::Context,
+) -> Result<(), anyhow::Error> {
+    remove_entry(xid, input, output, context, false).await
+}
+
+/// Removes a directory entry on behalf of REMOVE or RMDIR
+///
+/// Both procedures share the same arguments and reply format and are handled by
+/// the same VFS call, which is told the kind of entry to remove so that REMOVE of
+/// a directory fails with NFS3ERR_ISDIR and RMDIR of anything else with
+/// NFS3ERR_NOTDIR, as RFC 1813 requires; the change notification follows the
+/// procedure.
+///
+/// # Arguments
+///
+/// * `xid` - RPC transaction ID
+/// * `input` - Input stream containing the REMOVE or RMDIR arguments
+/// * `output` - Output stream for writing the response
+/// * `context` - Server context containing VFS
+/// * `rmdir` - Whether the call is RMDIR rather than REMOVE
+pub(super) async fn remove_entry(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &rpc::Context,
+    rmdir: bool,
 ) -> Result<(), anyhow::Error> {
     // if we do not have write capabilities
     if !matches!(context.vfs.capabilities(), vfs::Capabilities::ReadWrite) {
@@ -75,7 +101,7 @@ pub async fn nfsproc3_remove(
     let mut dirops = nfs3::diropargs3::default();
     dirops.deserialize(input)?;
 
-    debug!("nfsproc3_remove({:?}, {:?}) ", xid, dirops);
+    debug!("nfsproc3_remove({:?}, {:?}, rmdir={}) ", xid, dirops, rmdir);
 
     // find the directory with the file
     let dirid = context.vfs.fh_to_id(&dirops.dir);
@@ -89,44 +115,30 @@ pub async fn nfsproc3_remove(
     }
     let dirid = dirid.unwrap();
 
-    // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(stat) => {
-            error!("Cannot stat directory");
-            xdr::rpc::make_success_reply(xid).serialize(output)?;
-            stat.serialize(output)?;
-            nfs3::wcc_data::default().serialize(output)?;
-            return Ok(());
-        }
+    // RMDIR only removes directories and REMOVE only removes everything else
+    let kind = if rmdir {
+        vfs::RemoveKind::Dir
+    } else {
+        vfs::RemoveKind::NonDir
     };
 
     // delete!
-    let res = context.vfs.remove(dirid, &dirops.name).await;
+    let res = context.vfs.remove_wcc(dirid, &dirops.name, kind).await;
+    let wcc_res = res.wcc;
 
-    // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => nfs3::post_op_attr::attributes(v),
-        Err(_) => nfs3::post_op_attr::Void,
-    };
-    let wcc_res = nfs3::wcc_data {
-        before: pre_dir_attr,
-        after: post_dir_attr,
-    };
-
-    match res {
+    match res.result {
         Ok(()) => {
             debug!("remove success");
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             nfs3::nfsstat3::NFS3_OK.serialize(output)?;
             wcc_res.serialize(output)?;
+            let name = dirops.name.to_vec();
+            let change = if rmdir {
+                events::Change::Rmdir { dirid, name }
+            } else {
+                events::Change::Remove { dirid, name }
+            };
+            context.notify(change);
         }
         Err(e) => {
             error!("remove error {:?} --> {:?}", xid, e);
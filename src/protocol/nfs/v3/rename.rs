@@ -28,6 +28,7 @@ use std::io::{Read, Write};
 
 use tracing::{debug, error, warn};
 
+use crate::events;
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
 use crate::vfs;
@@ -103,76 +104,27 @@ pub async fn nfsproc3_rename(
     let from_dirid = from_dirid.unwrap();
     let to_dirid = to_dirid.unwrap();
 
-    // get the object attributes before the write
-    let pre_from_dir_attr = match context.vfs.getattr(from_dirid).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(stat) => {
-            error!("Cannot stat directory");
-            xdr::rpc::make_success_reply(xid).serialize(output)?;
-            stat.serialize(output)?;
-            nfs3::wcc_data::default().serialize(output)?;
-            return Ok(());
-        }
-    };
-
-    // get the object attributes before the write
-    let pre_to_dir_attr = match context.vfs.getattr(to_dirid).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(stat) => {
-            error!("Cannot stat directory");
-            xdr::rpc::make_success_reply(xid).serialize(output)?;
-            stat.serialize(output)?;
-            nfs3::wcc_data::default().serialize(output)?;
-            return Ok(());
-        }
-    };
-
     // rename!
     let res = context
         .vfs
-        .rename(from_dirid, &fromdirops.name, to_dirid, &todirops.name)
+        .rename_wcc(from_dirid, &fromdirops.name, to_dirid, &todirops.name)
         .await;
+    let from_wcc_res = res.from_dir_wcc;
+    let to_wcc_res = res.to_dir_wcc;
 
-    // Re-read dir attributes for post op attr
-    let post_from_dir_attr = match context.vfs.getattr(from_dirid).await {
-        Ok(v) => nfs3::post_op_attr::attributes(v),
-        Err(_) => nfs3::post_op_attr::Void,
-    };
-    let post_to_dir_attr = match context.vfs.getattr(to_dirid).await {
-        Ok(v) => nfs3::post_op_attr::attributes(v),
-        Err(_) => nfs3::post_op_attr::Void,
-    };
-    let from_wcc_res = nfs3::wcc_data {
-        before: pre_from_dir_attr,
-        after: post_from_dir_attr,
-    };
-
-    let to_wcc_res = nfs3::wcc_data {
-        before: pre_to_dir_attr,
-        after: post_to_dir_attr,
-    };
-
-    match res {
+    match res.result {
         Ok(()) => {
             debug!("rename success");
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             nfs3::nfsstat3::NFS3_OK.serialize(output)?;
             from_wcc_res.serialize(output)?;
             to_wcc_res.serialize(output)?;
+            context.notify(events::Change::Rename {
+                from_dirid,
+                from_name: fromdirops.name.to_vec(),
+                to_dirid,
+                to_name: todirops.name.to_vec(),
+            });
         }
         Err(e) => {
             error!("rename error {:?} --> {:?}", xid, e);
@@ -0,0 +1,52 @@
+//! Implementation of the RMDIR procedure (procedure 13) for NFS version 3 protocol
+//! as defined in RFC 1813 section 3.3.13.
+//!
+//! The RMDIR procedure removes (deletes) a subdirectory from a directory. If the
+//! directory entry of the subdirectory is the last reference to the subdirectory,
+//! the subdirectory may be destroyed.
+//!
+//! The client specifies:
+//! - The file handle for the directory containing the subdirectory to be removed
+//! - The name of the subdirectory to be removed
+//!
+//! On successful return, the server provides:
+//! - The attributes of the directory before and after the operation (weak cache consistency)
+//!
+//! RMDIR shares its arguments and reply with REMOVE, and both are served by the same
+//! VFS call; see the `remove` module for the common implementation.
+//!
+//! Common errors include:
+//! - NFS3ERR_ROFS - If the file system is read-only
+//! - NFS3ERR_NOENT - If the target directory doesn't exist
+//! - NFS3ERR_NOTEMPTY - If the target directory is not empty
+//! - NFS3ERR_NOTDIR - If the target is not a directory
+//! - NFS3ERR_ACCES - If the client doesn't have permission to remove the directory
+
+use std::io::{Read, Write};
+
+use crate::protocol::rpc;
+
+/// Handles NFSv3 RMDIR procedure (procedure 13)
+///
+/// RMDIR deletes an empty subdirectory.
+/// Takes directory handle and name of the subdirectory to be removed.
+/// Returns directory attributes before and after the operation.
+///
+/// # Arguments
+///
+/// * `xid` - RPC transaction ID
+/// * `input` - Input stream containing the RMDIR arguments
+/// * `output` - Output stream for writing the response
+/// * `context` - Server context containing VFS
+///
+/// # Returns
+///
+/// * `Result<(), anyhow::Error>` - Ok(()) on success or an error
+pub async fn nfsproc3_rmdir(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &rpc::Context,
+) -> Result<(), anyhow::Error> {
+    super::remove::remove_entry(xid, input, output, context, true).await
+}
@@ -30,6 +30,7 @@ use std::io::{Read, Write};
 
 use tracing::{debug, error, warn};
 
+use crate::events;
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
 use crate::vfs;
@@ -93,46 +94,18 @@ pub async fn nfsproc3_symlink(
     // found the directory, get the attributes
     let dirid = dirid.unwrap();
 
-    // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(stat) => {
-            error!("Cannot stat directory");
-            xdr::rpc::make_success_reply(xid).serialize(output)?;
-            stat.serialize(output)?;
-            nfs3::wcc_data::default().serialize(output)?;
-            return Ok(());
-        }
-    };
-
     let res = context
         .vfs
-        .symlink(
+        .symlink_wcc(
             dirid,
             &args.dirops.name,
             &args.symlink.symlink_data,
             &args.symlink.symlink_attributes,
         )
         .await;
+    let wcc_res = res.wcc;
 
-    // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => nfs3::post_op_attr::attributes(v),
-        Err(_) => nfs3::post_op_attr::Void,
-    };
-    let wcc_res = nfs3::wcc_data {
-        before: pre_dir_attr,
-        after: post_dir_attr,
-    };
-
-    match res {
+    match res.result {
         Ok((fid, fattr)) => {
             debug!("symlink success --> {:?}, {:?}", fid, fattr);
             xdr::rpc::make_success_reply(xid).serialize(output)?;
@@ -142,6 +115,12 @@ pub async fn nfsproc3_symlink(
             nfs3::post_op_fh3::handle(fh).serialize(output)?;
             nfs3::post_op_attr::attributes(fattr).serialize(output)?;
             wcc_res.serialize(output)?;
+            context.notify(events::Change::Symlink {
+                dirid,
+                name: args.dirops.name.to_vec(),
+                fileid: fid,
+                target: args.symlink.symlink_data.to_vec(),
+            });
         }
         Err(e) => {
             debug!("symlink error --> {:?}", e);
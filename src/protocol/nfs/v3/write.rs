@@ -18,8 +18,10 @@
 
 use std::io::{Read, Write};
 
+use num_traits::cast::FromPrimitive;
 use tracing::{debug, error, warn};
 
+use crate::events;
 use crate::protocol::rpc;
 use crate::protocol::xdr::{self, nfs3, XDR};
 use crate::vfs;
@@ -73,40 +75,35 @@ pub async fn nfsproc3_write(
     }
     let id = id.unwrap();
 
-    // get the object attributes before the write
-    let pre_obj_attr = match context.vfs.getattr(id).await {
-        Ok(v) => {
-            let wccattr = nfs3::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs3::pre_op_attr::attributes(wccattr)
-        }
-        Err(_) => nfs3::pre_op_attr::Void,
-    };
-
-    match context.vfs.write(id, args.offset, &args.data).await {
-        Ok(fattr) => {
-            debug!("write success {:?} --> {:?}", xid, fattr);
+    // an unknown stability level is treated as the strictest one
+    let stable = FromPrimitive::from_u32(args.stable).unwrap_or(nfs3::file::stable_how::FILE_SYNC);
+    let res = context
+        .vfs
+        .write_wcc(id, args.offset, &args.data, stable)
+        .await;
+    match res.result {
+        Ok((fattr, committed)) => {
+            debug!("write success {:?} --> {:?} {:?}", xid, fattr, committed);
             let res = nfs3::file::WRITE3resok {
-                file_wcc: nfs3::wcc_data {
-                    before: pre_obj_attr,
-                    after: nfs3::post_op_attr::attributes(fattr),
-                },
+                file_wcc: res.wcc,
                 count: args.count,
-                committed: nfs3::file::stable_how::FILE_SYNC,
+                committed,
                 verf: context.vfs.serverid(),
             };
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             nfs3::nfsstat3::NFS3_OK.serialize(output)?;
             res.serialize(output)?;
+            context.notify(events::Change::Write {
+                fileid: id,
+                offset: args.offset,
+                count: args.count,
+            });
         }
         Err(stat) => {
             error!("write error {:?} --> {:?}", xid, stat);
             xdr::rpc::make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
-            nfs3::wcc_data::default().serialize(output)?;
+            res.wcc.serialize(output)?;
         }
     }
     Ok(())
@@ -4,15 +4,61 @@
 //! are processed in the exact order they were received, preserving FIFO semantics
 //! necessary for proper NFS protocol operation.
 
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+
 use anyhow::anyhow;
+use bytes::Bytes;
 use tokio::sync::mpsc;
 use tracing::{debug, error, trace};
 
 use crate::protocol::rpc;
 
+/// Payloads smaller than this are copied into the serialized data instead of
+/// being kept as a separate segment
+const MIN_SEGMENT_SIZE: usize = 1024;
+
+/// A piece of an RPC response
+#[derive(Debug, Clone)]
+pub enum ResponseSegment {
+    /// Data held in memory
+    Bytes(Bytes),
+    /// A range of a local file, sent to the socket directly from the file
+    ///
+    /// If the file is shorter than expected by the time it is sent, writing
+    /// the response fails and the connection is closed.
+    File {
+        /// The file to send data from
+        file: Arc<File>,
+        /// Byte offset within the file
+        offset: u64,
+        /// Number of bytes to send
+        len: usize,
+    },
+}
+
+impl ResponseSegment {
+    /// Returns the length of the segment in bytes
+    pub fn len(&self) -> usize {
+        match self {
+            ResponseSegment::Bytes(b) => b.len(),
+            ResponseSegment::File { len, .. } => *len,
+        }
+    }
+}
+
 /// Represents a response buffer that minimizes data copying
+///
+/// The response is kept as a list of segments. Serialized protocol data is
+/// written through the `Write` implementation, while large payloads such as
+/// READ data can be appended as reference-counted buffers or file ranges without
+/// copying. Memory segments are sent to the socket with vectored writes and file
+/// ranges with `sendfile` where available.
 pub struct ResponseBuffer {
-    /// Internal buffer for writing data
+    /// Completed segments of the response
+    segments: Vec<ResponseSegment>,
+    /// Internal buffer for data written after the last completed segment
     buffer: Vec<u8>,
     /// Indicates that the buffer contains data to send
     has_content: bool,
@@ -22,14 +68,40 @@ impl ResponseBuffer {
     /// Creates a new response buffer with pre-allocated capacity
     pub fn with_capacity(capacity: usize) -> Self {
         Self {
+            segments: Vec::new(),
             buffer: Vec::with_capacity(capacity),
             has_content: false,
         }
     }
 
-    /// Gets the internal buffer for writing
-    pub fn get_mut_buffer(&mut self) -> &mut Vec<u8> {
-        &mut self.buffer
+    /// Appends a shared buffer to the response without copying it
+    pub fn append_bytes(&mut self, data: Bytes) {
+        if data.len() < MIN_SEGMENT_SIZE {
+            self.buffer.extend_from_slice(&data);
+            return;
+        }
+        self.seal();
+        self.segments.push(ResponseSegment::Bytes(data));
+    }
+
+    /// Appends a range of a local file to the response
+    ///
+    /// The data is not read here; it is sent from the file when the
+    /// response is written to the socket.
+    pub fn append_file(&mut self, file: Arc<File>, offset: u64, len: usize) {
+        self.seal();
+        self.segments
+            .push(ResponseSegment::File { file, offset, len });
+    }
+
+    /// Moves the data written so far into a completed segment
+    fn seal(&mut self) {
+        if !self.buffer.is_empty() {
+            self.segments
+                .push(ResponseSegment::Bytes(Bytes::from(std::mem::take(
+                    &mut self.buffer,
+                ))));
+        }
     }
 
     /// Marks the buffer as containing data to send
@@ -42,18 +114,36 @@ impl ResponseBuffer {
         self.has_content
     }
 
-    /// Takes the internal buffer, consuming the structure
-    pub fn into_inner(self) -> Vec<u8> {
-        self.buffer
+    /// Takes the response segments, consuming the structure
+    pub fn into_segments(mut self) -> Vec<ResponseSegment> {
+        self.seal();
+        self.segments
     }
 
     /// Clears the buffer for reuse
     pub fn clear(&mut self) {
+        self.segments.clear();
         self.buffer.clear();
         self.has_content = false;
     }
 }
 
+impl Write for ResponseBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.buffer.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 /// RPC command type with context
 #[derive(Debug)]
 pub struct RpcCommand {
@@ -7,6 +7,7 @@
 //! - Access to file system resources
 //! - Tracking of client sessions and requests
 //! - Mount status monitoring
+//! - Change notifications for mutating procedures
 //!
 //! This module serves as a bridge between the RPC layer and the underlying
 //! file system, providing each protocol handler with the information it needs
@@ -14,10 +15,12 @@
 //! server configuration.
 
 use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use tokio::sync::mpsc;
 
+use crate::events;
 use crate::protocol::xdr;
 use crate::vfs;
 
@@ -54,6 +57,14 @@ pub struct Context {
     /// Used to track file system mount status changes
     pub mount_signal: Option<mpsc::Sender<bool>>,
 
+    /// Channel for sending change notifications
+    /// Receives an event after each successful mutating NFS procedure
+    pub event_signal: Option<mpsc::Sender<events::NFSEvent>>,
+
+    /// Number of change notifications dropped because the event channel was full
+    /// Shared by all connections of a listener
+    pub dropped_events: Arc<AtomicU64>,
+
     /// Name of the exported file system available to clients
     pub export_name: Arc<String>,
 
@@ -62,6 +73,31 @@ pub struct Context {
     pub transaction_tracker: Arc<super::TransactionTracker>,
 }
 
+impl Context {
+    /// Reports a successful change to the registered event listener, if any
+    ///
+    /// The event is stamped with the credentials of the current call and the
+    /// client address of this connection. This never waits: when the listener
+    /// falls behind the event is dropped and counted in `dropped_events`, and a
+    /// closed channel is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `change` - The change made by the procedure
+    pub fn notify(&self, change: events::Change) {
+        if let Some(ref chan) = self.event_signal {
+            let event = events::NFSEvent {
+                change,
+                auth: self.auth.clone(),
+                client_addr: self.client_addr.clone(),
+            };
+            if let Err(mpsc::error::TrySendError::Full(_)) = chan.try_send(event) {
+                self.dropped_events.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
 impl fmt::Debug for Context {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("rpc::Context")
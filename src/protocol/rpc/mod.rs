@@ -26,9 +26,11 @@
 
 mod command_queue;
 mod context;
+mod sendfile;
 mod transaction_tracker;
 mod wire;
 
+pub use command_queue::ResponseBuffer;
 pub use context::Context;
 pub use transaction_tracker::TransactionTracker;
 pub use wire::{write_fragment, SocketMessageHandler};
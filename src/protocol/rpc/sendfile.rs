@@ -0,0 +1,177 @@
+//! Sending file ranges to a TCP socket without copying them through user space.
+//!
+//! On Linux the data is transferred with the `sendfile` system call, which moves
+//! it from the page cache to the socket inside the kernel. On other platforms the
+//! range is read into a bounded buffer and written to the socket.
+//!
+//! Reading the file may block on disk I/O when the data is not cached, so file
+//! access always happens on tokio's blocking thread pool, never on a runtime worker.
+//!
+//! The READ handler clamps the range to the size of the file before it encodes the
+//! reply. If the file still turns out to be shorter (e.g. it was truncated in the
+//! meantime), an error is returned and the connection must be closed: the record
+//! header already promised the full length, and padding it would hand the client
+//! data that was never in the file.
+
+use std::fs::File;
+use std::sync::Arc;
+
+#[cfg(not(target_os = "linux"))]
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// Size of the buffer used when data has to be copied through user space
+#[cfg(not(target_os = "linux"))]
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+/// How long a `sendfile` transfer waits for the client to make room in the socket buffer
+#[cfg(target_os = "linux")]
+const SEND_TIMEOUT_MS: libc::c_int = 120_000;
+
+/// Sends `len` bytes of `file` starting at `offset` to the socket
+///
+/// Fails with `UnexpectedEof` if the file ends before `len` bytes were sent;
+/// the message on the socket is then incomplete.
+///
+/// # Arguments
+///
+/// * `socket` - The TCP stream to write to
+/// * `file` - The file to send data from
+/// * `offset` - Byte offset within the file
+/// * `len` - Number of bytes to send
+pub async fn send_file_range(
+    socket: &mut TcpStream,
+    file: &Arc<File>,
+    offset: u64,
+    len: usize,
+) -> std::io::Result<()> {
+    let sent = copy_file_range_to_socket(socket, file, offset, len).await?;
+    if sent < len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("file shorter than expected: sent {sent} of {len} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+/// Sends up to `len` bytes of the file with `sendfile`, returning the number of bytes sent
+///
+/// The transfer runs on a blocking thread, since `sendfile` reads the file from disk
+/// on a page cache miss. It uses a duplicate of the socket descriptor, so the socket
+/// stays open until the transfer returns even if this future is dropped, and waits
+/// for the socket to become writable with `poll`.
+#[cfg(target_os = "linux")]
+async fn copy_file_range_to_socket(
+    socket: &mut TcpStream,
+    file: &Arc<File>,
+    offset: u64,
+    len: usize,
+) -> std::io::Result<usize> {
+    use std::os::unix::io::{AsFd, AsRawFd};
+
+    let socket_fd = socket.as_fd().try_clone_to_owned()?;
+    let file = file.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut file_offset = offset as libc::off_t;
+        let mut sent = 0;
+        while sent < len {
+            // SAFETY: both descriptors stay open for the duration of the call
+            // and `file_offset` is a valid pointer to an off_t.
+            let n = unsafe {
+                libc::sendfile(
+                    socket_fd.as_raw_fd(),
+                    file.as_raw_fd(),
+                    &mut file_offset,
+                    len - sent,
+                )
+            };
+            if n > 0 {
+                sent += n as usize;
+                continue;
+            }
+            if n == 0 {
+                // end of file
+                break;
+            }
+            let err = std::io::Error::last_os_error();
+            match err.kind() {
+                std::io::ErrorKind::WouldBlock => wait_writable(socket_fd.as_raw_fd())?,
+                std::io::ErrorKind::Interrupted => {}
+                _ => return Err(err),
+            }
+        }
+        Ok(sent)
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+/// Blocks until the socket can take more data, or fails after [`SEND_TIMEOUT_MS`]
+#[cfg(target_os = "linux")]
+fn wait_writable(fd: std::os::unix::io::RawFd) -> std::io::Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    loop {
+        // SAFETY: `pollfd` is a valid pollfd structure and the count is 1.
+        let n = unsafe { libc::poll(&mut pollfd, 1, SEND_TIMEOUT_MS) };
+        match n {
+            // errors and hangups are reported by the next sendfile call
+            n if n > 0 => return Ok(()),
+            0 => return Err(std::io::ErrorKind::TimedOut.into()),
+            _ => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Sends up to `len` bytes of the file through a bounded buffer, returning the number of bytes sent
+///
+/// Each chunk is read on a blocking thread and then written to the socket.
+#[cfg(not(target_os = "linux"))]
+async fn copy_file_range_to_socket(
+    socket: &mut TcpStream,
+    file: &Arc<File>,
+    offset: u64,
+    len: usize,
+) -> std::io::Result<usize> {
+    let mut sent = 0;
+    while sent < len {
+        let want = std::cmp::min(len - sent, COPY_BUFFER_SIZE);
+        let position = offset + sent as u64;
+        let file = file.clone();
+        let buf = tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; want];
+            let n = read_at(&file, &mut buf, position)?;
+            buf.truncate(n);
+            Ok::<_, std::io::Error>(buf)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        if buf.is_empty() {
+            break;
+        }
+        socket.write_all(&buf).await?;
+        sent += buf.len();
+    }
+    Ok(sent)
+}
+
+/// Reads from the file at the given offset without moving the file cursor
+#[cfg(all(not(target_os = "linux"), unix))]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// Reads from the file at the given offset
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
@@ -18,7 +18,7 @@
 //! while providing efficient transmission of RPC messages of any size.
 
 use std::io::Cursor;
-use std::io::{Read, Write};
+use std::io::{IoSlice, Read};
 
 use anyhow::anyhow;
 use tokio::io::AsyncReadExt;
@@ -27,9 +27,13 @@ use tokio::io::DuplexStream;
 use tokio::sync::mpsc;
 use tracing::{debug, error, trace, warn};
 
-use crate::protocol::rpc::command_queue::{CommandQueue, CommandResult, ResponseBuffer};
+use crate::protocol::rpc::command_queue::{
+    CommandQueue, CommandResult, ResponseBuffer, ResponseSegment,
+};
+use crate::protocol::rpc::sendfile;
 use crate::protocol::xdr::{self, mount, nfs3, portmap, XDR};
 use crate::protocol::{nfs, rpc};
+use crate::vfs;
 
 // Information from RFC 5531 (ONC RPC v2)
 // https://datatracker.ietf.org/doc/html/rfc5531
@@ -60,17 +64,19 @@ const DEFAULT_RESPONSE_BUFFER_CAPACITY: usize = 8192;
 /// Returns true if a response was sent, false otherwise (for retransmissions).
 pub async fn handle_rpc(
     input: &mut impl Read,
-    output: &mut impl Write,
+    output: &mut ResponseBuffer,
     mut context: rpc::Context,
 ) -> Result<bool, anyhow::Error> {
     let mut recv = xdr::rpc::rpc_msg::default();
     recv.deserialize(input)?;
     let xid = recv.xid;
     if let xdr::rpc::rpc_body::CALL(call) = recv.body {
+        let mut caller = None;
         if let xdr::rpc::auth_flavor::AUTH_UNIX = call.cred.flavor {
             let mut auth = xdr::rpc::auth_unix::default();
             auth.deserialize(&mut Cursor::new(&call.cred.body))?;
-            context.auth = auth;
+            context.auth = auth.clone();
+            caller = Some(auth);
         }
         if call.rpcvers != 2 {
             warn!("Invalid RPC version {} != 2", call.rpcvers);
@@ -93,7 +99,8 @@ pub async fn handle_rpc(
 
         let res = {
             if call.prog == nfs3::PROGRAM {
-                nfs::v3::handle_nfs(xid, call, input, output, &context).await
+                let handler = nfs::v3::handle_nfs(xid, call, input, output, &context);
+                vfs::with_caller(caller, handler).await
             } else if call.prog == portmap::PROGRAM {
                 nfs::portmap::handle_portmap(xid, call, input, output, &context)
             } else if call.prog == mount::PROGRAM {
@@ -161,36 +168,40 @@ async fn read_fragment(
     Ok(is_last)
 }
 
-/// Writes data as record-marked fragments to a TCP stream
+/// Writes a message made of several segments as record-marked fragments to a TCP stream
 ///
 /// Implements the RFC 5531 (previously RFC 1057 section 10) Record Marking Standard for TCP transport.
 /// This standard enables RPC to utilize TCP as a transport while maintaining proper
 /// message boundaries essential for RPC semantics.
 ///
 /// The function:
-/// 1. Divides large buffers into manageable fragments (maximum 2GB each)
+/// 1. Divides the message into manageable fragments (maximum 2GB each)
 /// 2. Prefixes each fragment with a 4-byte header
 ///    - The lower 31 bits contain the fragment length
 ///    - The highest bit indicates if this is the last fragment (1=last, 0=more)
-/// 3. Writes both header and data to the socket
+/// 3. Writes the header and in-memory segments of each fragment with vectored writes,
+///    so they are never copied into a contiguous buffer, and sends file segments
+///    directly from the file
 ///
 /// This ensures reliable transmission of RPC messages over TCP with proper
 /// message framing and enables receivers to allocate appropriate buffer space.
 pub async fn write_fragment(
     socket: &mut tokio::net::TcpStream,
-    buf: &[u8],
+    segments: &[ResponseSegment],
 ) -> Result<(), anyhow::Error> {
     // Maximum fragment size is 2^31 - 1 bytes
     const MAX_FRAGMENT_SIZE: usize = (1 << 31) - 1;
 
-    let mut offset = 0;
-    while offset < buf.len() {
+    let mut remaining: usize = segments.iter().map(|s| s.len()).sum();
+    // position of the next byte to send
+    let mut segment_idx = 0;
+    let mut segment_offset = 0;
+    while remaining > 0 {
         // Calculate the size of this fragment
-        let remaining = buf.len() - offset;
         let fragment_size = std::cmp::min(remaining, MAX_FRAGMENT_SIZE);
 
         // Determine if this is the last fragment
-        let is_last = offset + fragment_size >= buf.len();
+        let is_last = fragment_size == remaining;
 
         // Create the fragment header
         // The highest bit indicates if this is the last fragment
@@ -199,26 +210,64 @@ pub async fn write_fragment(
         } else {
             fragment_size as u32
         };
-
         let header_buf = u32::to_be_bytes(fragment_header);
-        socket.write_all(&header_buf).await?;
-
         trace!(
             "Writing fragment length:{}, last:{}",
             fragment_size,
             is_last
         );
-        socket
-            .write_all(&buf[offset..offset + fragment_size])
-            .await?;
 
-        offset += fragment_size;
+        // Memory is gathered into a single vectored write until
+        // a file segment is reached
+        let mut slices = vec![IoSlice::new(&header_buf)];
+        let mut needed = fragment_size;
+        while needed > 0 {
+            let segment = &segments[segment_idx];
+            let len = std::cmp::min(segment.len() - segment_offset, needed);
+            match segment {
+                ResponseSegment::Bytes(data) => {
+                    if len > 0 {
+                        slices.push(IoSlice::new(&data[segment_offset..segment_offset + len]));
+                    }
+                }
+                ResponseSegment::File { file, offset, .. } => {
+                    write_all_vectored(socket, &mut slices).await?;
+                    slices.clear();
+                    sendfile::send_file_range(socket, file, offset + segment_offset as u64, len)
+                        .await?;
+                }
+            }
+            needed -= len;
+            segment_offset += len;
+            if segment_offset == segment.len() {
+                segment_idx += 1;
+                segment_offset = 0;
+            }
+        }
+        write_all_vectored(socket, &mut slices).await?;
+
+        remaining -= fragment_size;
     }
 
     Ok(())
 }
 
-pub type SocketMessageType = Result<Vec<u8>, anyhow::Error>;
+/// Writes all of the given slices to the socket, retrying on partial writes
+async fn write_all_vectored(
+    socket: &mut tokio::net::TcpStream,
+    mut slices: &mut [IoSlice<'_>],
+) -> std::io::Result<()> {
+    while !slices.is_empty() {
+        let written = socket.write_vectored(slices).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, written);
+    }
+    Ok(())
+}
+
+pub type SocketMessageType = Result<Vec<ResponseSegment>, anyhow::Error>;
 
 /// Handles RPC message processing over a TCP connection
 ///
@@ -272,7 +321,7 @@ impl SocketMessageHandler {
             while let Some(result) = result_receiver.recv().await {
                 match result {
                     Ok(Some(response_buffer)) if response_buffer.has_content() => {
-                        let _ = msgsend.send(Ok(response_buffer.into_inner()));
+                        let _ = msgsend.send(Ok(response_buffer.into_segments()));
                     }
                     Ok(None) => {
                         // No response needed, so nothing to send
@@ -355,12 +404,8 @@ pub fn process_rpc_command<'a>(
         // Create cursor for reading data
         let mut input_cursor = Cursor::new(data_clone);
 
-        // Get internal buffer for writing
-        let output_buffer = output.get_mut_buffer();
-        let mut output_cursor = Cursor::new(output_buffer);
-
         // Call RPC handler
-        let result = handle_rpc(&mut input_cursor, &mut output_cursor, context).await?;
+        let result = handle_rpc(&mut input_cursor, output, context).await?;
 
         // If response was generated, return true
         Ok(result)
@@ -10,6 +10,7 @@
 //! on mount/unmount operations.
 
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{io, net::IpAddr};
@@ -21,6 +22,7 @@ use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 use tracing::{debug, error, info};
 
+use crate::events::NFSEvent;
 use crate::protocol::{rpc, xdr};
 use crate::vfs::NFSFileSystem;
 
@@ -35,6 +37,10 @@ pub struct NFSTcpListener<T: NFSFileSystem + Send + Sync + 'static> {
     arcfs: Arc<T>,
     /// Optional channel for sending mount/unmount notifications
     mount_signal: Option<mpsc::Sender<bool>>,
+    /// Optional channel for sending change notifications
+    event_signal: Option<mpsc::Sender<NFSEvent>>,
+    /// Number of change notifications dropped because the channel was full
+    dropped_events: Arc<AtomicU64>,
     /// Name of the exported file system path
     export_name: Arc<String>,
     /// Tracker for RPC transactions to handle retransmissions
@@ -109,7 +115,10 @@ async fn process_socket(
                     }
                     Some(Ok(msg)) => {
                         if let Err(e) = rpc::write_fragment(&mut socket, &msg).await {
+                            // a reply may have been cut short, so the stream
+                            // can no longer be parsed by the client
                             error!("Write error {:?}", e);
+                            return Err(e);
                         }
                     }
                     None => {
@@ -127,6 +136,7 @@ async fn process_socket(
 /// This trait provides methods for:
 /// - Getting information about the listening socket
 /// - Setting up mount event notifications
+/// - Setting up change notifications for mutating procedures
 /// - Starting the server to process client connections
 #[async_trait]
 pub trait NFSTcp: Send + Sync {
@@ -153,6 +163,22 @@ pub trait NFSTcp: Send + Sync {
     ///   * `false` when a client unmounts the file system
     fn set_mount_listener(&mut self, signal: mpsc::Sender<bool>);
 
+    /// Registers a channel to receive notifications about changes made through NFS
+    ///
+    /// An event is sent after each successful CREATE, WRITE, SETATTR, REMOVE, RMDIR,
+    /// RENAME, LINK, SYMLINK, MKDIR and MKNOD call. Procedures never wait for the
+    /// listener; events that do not fit in the channel are dropped.
+    ///
+    /// The default implementation ignores the channel, for servers that do not
+    /// report changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `signal` - MPSC sender that will receive an `NFSEvent` per change
+    fn set_event_listener(&mut self, signal: mpsc::Sender<NFSEvent>) {
+        let _ = signal;
+    }
+
     /// Starts the NFS server and processes client connections
     ///
     /// This method:
@@ -172,7 +198,7 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
     /// # Arguments
     ///
     /// * `ipstr` - IP address and port in the format "IP:PORT" (e.g. "127.0.0.1:2049")
-    ///             Special value "auto:PORT" attempts to find an available local address
+    ///   Special value "auto:PORT" attempts to find an available local address
     /// * `fs` - Implementation of the NFSFileSystem trait that will handle NFS operations
     ///
     /// # Returns
@@ -244,6 +270,8 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
             port,
             arcfs,
             mount_signal: None,
+            event_signal: None,
+            dropped_events: Arc::new(AtomicU64::new(0)),
             export_name: Arc::from("/".to_string()),
             transaction_tracker: Arc::new(rpc::TransactionTracker::new(Duration::from_secs(60))),
         })
@@ -267,6 +295,15 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
                 .trim_start_matches('/')
         ))
     }
+
+    /// Returns the number of change notifications dropped so far
+    ///
+    /// Events are dropped instead of delaying replies when the channel registered
+    /// with [`NFSTcp::set_event_listener`] is full. A listener that sees this number
+    /// grow has missed changes and should rescan the parts of the tree it follows.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
 }
 
 #[async_trait]
@@ -302,6 +339,19 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcp for NFSTcpListener<T> {
         self.mount_signal = Some(signal);
     }
 
+    /// Registers a channel to receive notifications about changes made through NFS
+    ///
+    /// An event is sent after each successful CREATE, WRITE, SETATTR, REMOVE, RMDIR,
+    /// RENAME, LINK, SYMLINK, MKDIR and MKNOD call. When the channel is full, the
+    /// event is dropped and counted; see [`NFSTcpListener::dropped_events`].
+    ///
+    /// # Arguments
+    ///
+    /// * `signal` - MPSC sender that will receive an `NFSEvent` per change
+    fn set_event_listener(&mut self, signal: mpsc::Sender<NFSEvent>) {
+        self.event_signal = Some(signal);
+    }
+
     /// Starts the NFS server and processes client connections
     ///
     /// This method:
@@ -321,6 +371,8 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcp for NFSTcpListener<T> {
                 auth: xdr::rpc::auth_unix::default(),
                 vfs: self.arcfs.clone(),
                 mount_signal: self.mount_signal.clone(),
+                event_signal: self.event_signal.clone(),
+                dropped_events: self.dropped_events.clone(),
                 export_name: self.export_name.clone(),
                 transaction_tracker: self.transaction_tracker.clone(),
             };
@@ -19,33 +19,75 @@
 //! - File handle management that detects stale handles after server restarts
 
 use std::cmp::Ordering;
-use std::sync::Once;
+use std::sync::{Arc, Once};
 use std::time::SystemTime;
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use tracing::warn;
 
-use crate::protocol::xdr::nfs3;
+use crate::protocol::xdr::{self, nfs3};
 
-/// Simplified directory entry containing only file ID and name
+/// Simplified directory entry containing the file ID and name
 ///
-/// Used for simple directory listing operations where full attributes are not needed
+/// Used for directory listing operations where full attributes are not needed,
+/// or are fetched separately for only the entries that are sent to the client.
+/// Construct it with [`DirEntrySimple::new`] so that new fields can be added later.
 #[derive(Default, Debug)]
+#[non_exhaustive]
 pub struct DirEntrySimple {
     /// Unique file identifier within the file system (similar to inode number)
     pub fileid: nfs3::fileid3,
     /// File name (without path components)
     pub name: nfs3::filename3,
+    /// File attributes, if the backend had them at hand while listing the directory
+    ///
+    /// READDIRPLUS fetches missing attributes with [`NFSFileSystem::getattr_batch`].
+    pub attr: Option<nfs3::fattr3>,
+}
+
+impl DirEntrySimple {
+    /// Creates an entry without attributes
+    ///
+    /// # Arguments
+    /// * `fileid` - Unique file identifier within the file system
+    /// * `name` - File name (without path components)
+    pub fn new(fileid: nfs3::fileid3, name: nfs3::filename3) -> DirEntrySimple {
+        DirEntrySimple {
+            fileid,
+            name,
+            attr: None,
+        }
+    }
+
+    /// Attaches attributes the backend had at hand while listing the directory
+    ///
+    /// # Arguments
+    /// * `attr` - File attributes of the entry
+    pub fn with_attr(mut self, attr: nfs3::fattr3) -> DirEntrySimple {
+        self.attr = Some(attr);
+        self
+    }
 }
 
 /// Result returned by readdir_simple operations
 ///
-/// Contains a vector of simplified directory entries and an EOF flag
+/// Contains a vector of simplified directory entries and an EOF flag.
+/// Construct it with [`ReadDirSimpleResult::new`] so that new fields can be added later.
 #[derive(Default, Debug)]
+#[non_exhaustive]
 pub struct ReadDirSimpleResult {
     /// List of directory entries with minimal information
     pub entries: Vec<DirEntrySimple>,
     /// Indicates if the end of directory has been reached
     pub end: bool,
+    /// Cookie to continue listing after, if it is not the file ID of the last entry
+    ///
+    /// Backends that filter out some of the entries they list (for example names
+    /// that cannot be decoded) set this to the last entry they skipped, so that a
+    /// page on which every entry was filtered out does not end the listing early.
+    pub resume_after: Option<nfs3::fileid3>,
 }
 
 /// Full directory entry containing file ID, name and attributes
@@ -73,6 +115,28 @@ pub struct ReadDirResult {
 }
 
 impl ReadDirSimpleResult {
+    /// Creates a page of entries that resumes after the last entry
+    ///
+    /// # Arguments
+    /// * `entries` - Directory entries of this page, in listing order
+    /// * `end` - Whether the end of the directory has been reached
+    pub fn new(entries: Vec<DirEntrySimple>, end: bool) -> ReadDirSimpleResult {
+        ReadDirSimpleResult {
+            entries,
+            end,
+            resume_after: None,
+        }
+    }
+
+    /// Sets the cookie to continue listing after, see [`ReadDirSimpleResult::resume_after`]
+    ///
+    /// # Arguments
+    /// * `cookie` - File ID of the last entry the backend listed for this page
+    pub fn with_resume_after(mut self, cookie: Option<nfs3::fileid3>) -> ReadDirSimpleResult {
+        self.resume_after = cookie;
+        self
+    }
+
     /// Converts a full ReadDirResult to a simplified ReadDirSimpleResult
     ///
     /// This allows implementations to provide just the full readdir operation,
@@ -84,15 +148,209 @@ impl ReadDirSimpleResult {
             .map(|e| DirEntrySimple {
                 fileid: e.fileid,
                 name: e.name.clone(),
+                attr: Some(e.attr),
             })
             .collect();
-        ReadDirSimpleResult {
-            entries,
-            end: result.end,
+        ReadDirSimpleResult::new(entries, result.end)
+    }
+}
+
+/// A range of a local file to be sent as READ data
+///
+/// Returned by [`NFSFileSystem::read_file_range`] so that the server can send
+/// the data directly from the file to the socket (using `sendfile` on Linux)
+/// instead of reading it into memory first.
+#[derive(Debug, Clone)]
+pub struct FileRange {
+    /// The open file to read from
+    pub file: Arc<std::fs::File>,
+    /// Byte offset within the file
+    pub offset: u64,
+    /// Number of bytes to send
+    pub len: u32,
+    /// Indicates if the range reaches the end of the file
+    pub eof: bool,
+}
+
+/// Stream of directory entries returned by [`NFSFileSystem::readdir_stream`]
+pub type DirEntryStream<'a> = BoxStream<'a, Result<DirEntrySimple, nfs3::nfsstat3>>;
+
+/// Number of entries requested per readdir_simple_after call when paging a directory stream
+const READDIR_STREAM_BATCH: usize = 128;
+
+/// Result of a mutating operation together with weak cache consistency data
+///
+/// The `wcc` field describes the object the NFS reply reports on: the file itself
+/// for SETATTR, WRITE and COMMIT, or the parent directory for operations that
+/// change the namespace. It is carried for failed operations as well, since the
+/// NFS replies include it in both cases.
+#[derive(Debug)]
+pub struct WccResult<T> {
+    /// Outcome of the operation
+    pub result: Result<T, nfs3::nfsstat3>,
+    /// Attributes before and after the operation
+    pub wcc: nfs3::wcc_data,
+}
+
+/// Result of a rename together with weak cache consistency data for both directories
+#[derive(Debug)]
+pub struct RenameWccResult {
+    /// Outcome of the operation
+    pub result: Result<(), nfs3::nfsstat3>,
+    /// Attributes of the source directory before and after the operation
+    pub from_dir_wcc: nfs3::wcc_data,
+    /// Attributes of the target directory before and after the operation
+    pub to_dir_wcc: nfs3::wcc_data,
+}
+
+/// Kind of directory entry a removal applies to
+///
+/// REMOVE only removes entries that are not directories and RMDIR only removes
+/// directories, while [`NFSFileSystem::remove`] removes either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoveKind {
+    /// Any entry
+    Any,
+    /// Anything but a directory, as for REMOVE
+    NonDir,
+    /// A directory, as for RMDIR
+    Dir,
+}
+
+impl RemoveKind {
+    /// Checks that an entry may be removed
+    ///
+    /// Fails with NFS3ERR_ISDIR for a directory that is not to be removed, and with
+    /// NFS3ERR_NOTDIR for any other entry that is not to be removed.
+    ///
+    /// # Arguments
+    /// * `is_dir` - Whether the entry is a directory
+    pub fn check(self, is_dir: bool) -> Result<(), nfs3::nfsstat3> {
+        match self {
+            RemoveKind::NonDir if is_dir => Err(nfs3::nfsstat3::NFS3ERR_ISDIR),
+            RemoveKind::Dir if !is_dir => Err(nfs3::nfsstat3::NFS3ERR_NOTDIR),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Fetches the pre-operation attributes of an object, or `Void` if they are unavailable
+async fn pre_op_attr<F: NFSFileSystem + ?Sized>(fs: &F, id: nfs3::fileid3) -> nfs3::pre_op_attr {
+    match fs.getattr(id).await {
+        Ok(v) => nfs3::pre_op_attr::attributes(v.into()),
+        Err(_) => nfs3::pre_op_attr::Void,
+    }
+}
+
+/// Fetches the post-operation attributes of an object, or `Void` if they are unavailable
+async fn post_op_attr<F: NFSFileSystem + ?Sized>(fs: &F, id: nfs3::fileid3) -> nfs3::post_op_attr {
+    match fs.getattr(id).await {
+        Ok(v) => nfs3::post_op_attr::attributes(v),
+        Err(_) => nfs3::post_op_attr::Void,
+    }
+}
+
+/// Runs a directory-modifying operation bracketed by getattr calls on the directory
+///
+/// This is the fallback used by the `*_wcc` methods of [`NFSFileSystem`] for backends
+/// that cannot capture the directory attributes atomically with the change, and by
+/// such backends when they override a `*_wcc` method for another reason.
+/// The operation is not started if the directory attributes cannot be read.
+pub(crate) async fn dir_op_wcc<F, T>(
+    fs: &F,
+    dirid: nfs3::fileid3,
+    op: impl std::future::Future<Output = Result<T, nfs3::nfsstat3>>,
+) -> WccResult<T>
+where
+    F: NFSFileSystem + ?Sized,
+{
+    let before = match fs.getattr(dirid).await {
+        Ok(v) => nfs3::pre_op_attr::attributes(v.into()),
+        Err(stat) => {
+            return WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data::default(),
+            }
         }
+    };
+    let result = op.await;
+    let after = post_op_attr(fs, dirid).await;
+    WccResult {
+        result,
+        wcc: nfs3::wcc_data { before, after },
     }
 }
 
+/// Pages through a directory listing, starting with a page that was already read
+///
+/// This is the paging loop of the default [`NFSFileSystem::readdir_stream`], shared with
+/// adapters that read the pages some other way. It keeps paging until a page reports
+/// the end of the directory, and ends with NFS3ERR_SERVERFAULT on an empty page that
+/// is not the last one and has no [`ReadDirSimpleResult::resume_after`] cookie.
+///
+/// # Arguments
+/// * `dirid` - The directory being listed
+/// * `start_after` - Cookie the first page was read after
+/// * `first` - The first page
+/// * `next_page` - Reads the page after the given cookie
+pub(crate) fn paged_entries<'a, F, Fut>(
+    dirid: nfs3::fileid3,
+    start_after: nfs3::fileid3,
+    first: ReadDirSimpleResult,
+    next_page: F,
+) -> DirEntryStream<'a>
+where
+    F: FnMut(nfs3::fileid3) -> Fut + Send + 'a,
+    Fut: std::future::Future<Output = Result<ReadDirSimpleResult, nfs3::nfsstat3>> + Send + 'a,
+{
+    let state = (
+        first.entries.into_iter(),
+        first.end,
+        start_after,
+        first.resume_after,
+        next_page,
+    );
+    futures::stream::unfold(
+        state,
+        move |(mut page, mut end, mut last, mut resume, mut next_page)| async move {
+            loop {
+                if let Some(entry) = page.next() {
+                    last = entry.fileid;
+                    return Some((Ok(entry), (page, end, last, resume, next_page)));
+                }
+                if end {
+                    return None;
+                }
+                if let Some(cookie) = resume.take() {
+                    last = cookie;
+                }
+                match next_page(last).await {
+                    Ok(next) => {
+                        if next.entries.is_empty()
+                            && !next.end
+                            && next.resume_after.is_none_or(|cookie| cookie == last)
+                        {
+                            warn!(
+                                "readdir of {} returned an empty page without a cookie after {}",
+                                dirid, last
+                            );
+                            return Some((
+                                Err(nfs3::nfsstat3::NFS3ERR_SERVERFAULT),
+                                (page, true, last, None, next_page),
+                            ));
+                        }
+                        page = next.entries.into_iter();
+                        end = next.end;
+                        resume = next.resume_after;
+                    }
+                    Err(stat) => return Some((Err(stat), (page, true, last, None, next_page))),
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
 /// Server generation number used to detect stale file handles
 ///
 /// This value is initialized once at server startup and included in all file handles
@@ -116,6 +374,32 @@ fn get_generation_number() -> u64 {
     }
 }
 
+tokio::task_local! {
+    /// Credentials of the client whose NFS request is being handled
+    static CALLER: xdr::rpc::auth_unix;
+}
+
+/// Returns the credentials of the client whose request is being handled
+///
+/// The NFS handlers make the AUTH_UNIX credentials of each request available to
+/// the file system calls they make, so that implementations can apply per-user
+/// policies. Returns `None` outside of request handling, e.g. when a file system
+/// is used directly, and for requests without AUTH_UNIX credentials.
+pub fn caller() -> Option<xdr::rpc::auth_unix> {
+    CALLER.try_with(|auth| auth.clone()).ok()
+}
+
+/// Runs a future with the given client credentials available through [`caller`]
+pub(crate) async fn with_caller<T>(
+    auth: Option<xdr::rpc::auth_unix>,
+    f: impl std::future::Future<Output = T>,
+) -> T {
+    match auth {
+        Some(auth) => CALLER.scope(auth, f).await,
+        None => f.await,
+    }
+}
+
 /// Defines the access capabilities supported by a file system implementation
 pub enum Capabilities {
     /// File system supports read operations only
@@ -232,6 +516,59 @@ pub trait NFSFileSystem: Sync {
         count: u32,
     ) -> Result<(Vec<u8>, bool), nfs3::nfsstat3>;
 
+    /// Reads data from a file into a reference-counted buffer
+    ///
+    /// This is the method used by the READ procedure. The returned buffer is handed
+    /// to the socket without being copied, so backends that already hold file data
+    /// in shared buffers (e.g. a cache) should override this to return them directly.
+    /// The default implementation wraps the result of [`NFSFileSystem::read`].
+    ///
+    /// # Arguments
+    /// * `id` - The file ID to read from
+    /// * `offset` - Byte offset within the file to start reading
+    /// * `count` - Maximum number of bytes to read
+    ///
+    /// # Returns
+    /// * `Result<(Bytes, bool), nfsstat3>` - Tuple of (data, eof_flag) on success, or an NFS error code
+    async fn read_bytes(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Bytes, bool), nfs3::nfsstat3> {
+        let (data, eof) = self.read(id, offset, count).await?;
+        Ok((Bytes::from(data), eof))
+    }
+
+    /// Locates the data for a read in a local file
+    ///
+    /// Backends that store file contents in local files can implement this so
+    /// that READ replies are sent directly from the file to the socket without
+    /// passing through user space. Returning `Ok(None)` makes the server fall
+    /// back to [`NFSFileSystem::read_bytes`], which is what the default
+    /// implementation does.
+    ///
+    /// The length of the range should already be clamped to the file size. The
+    /// server clamps it again before encoding the reply; if the file still shrinks
+    /// before the data is sent, the connection is closed instead of sending data
+    /// that is not in the file.
+    ///
+    /// # Arguments
+    /// * `id` - The file ID to read from
+    /// * `offset` - Byte offset within the file to start reading
+    /// * `count` - Maximum number of bytes to read
+    ///
+    /// # Returns
+    /// * `Result<Option<FileRange>, nfsstat3>` - The file range to send, `None` to read normally, or an NFS error code
+    async fn read_file_range(
+        &self,
+        _id: nfs3::fileid3,
+        _offset: u64,
+        _count: u32,
+    ) -> Result<Option<FileRange>, nfs3::nfsstat3> {
+        Ok(None)
+    }
+
     /// Writes data to a file
     ///
     /// This method writes data to a file starting at the specified offset.
@@ -365,8 +702,9 @@ pub trait NFSFileSystem: Sync {
     /// Simplified version of readdir that returns only file names and IDs
     ///
     /// This is a convenience method that provides a simpler interface when full
-    /// file attributes are not needed. The default implementation calls the full
-    /// readdir method and converts the result.
+    /// file attributes are not needed. It always lists from the beginning of the
+    /// directory; the default implementation calls
+    /// [`NFSFileSystem::readdir_simple_after`] with a zero cookie.
     ///
     /// # Arguments
     /// * `dirid` - The directory ID to read
@@ -378,12 +716,93 @@ pub trait NFSFileSystem: Sync {
         &self,
         dirid: nfs3::fileid3,
         count: usize,
+    ) -> Result<ReadDirSimpleResult, nfs3::nfsstat3> {
+        self.readdir_simple_after(dirid, 0, count).await
+    }
+
+    /// Simplified version of readdir that returns file names and IDs starting after a cookie
+    ///
+    /// This is the listing used by READDIR and READDIRPLUS. Backends for which
+    /// attributes are expensive should override it so that directory listing does
+    /// not compute attributes for entries that are never sent; READDIRPLUS then
+    /// calls [`NFSFileSystem::getattr_batch`] for just the entries that fit in the reply.
+    /// The default implementation calls the full readdir method and converts the result.
+    ///
+    /// # Arguments
+    /// * `dirid` - The directory ID to read
+    /// * `start_after` - Cookie (file ID) to start reading after (0 for beginning)
+    /// * `count` - Maximum number of entries to return
+    ///
+    /// # Returns
+    /// * `Result<ReadDirSimpleResult, nfsstat3>` - Simplified directory entries on success, or an NFS error code
+    async fn readdir_simple_after(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+        count: usize,
     ) -> Result<ReadDirSimpleResult, nfs3::nfsstat3> {
         Ok(ReadDirSimpleResult::from_readdir_result(
-            &self.readdir(dirid, 0, count).await?,
+            &self.readdir(dirid, start_after, count).await?,
         ))
     }
 
+    /// Lists a directory as a stream of entries
+    ///
+    /// READDIR and READDIRPLUS pull entries from this stream lazily and stop as soon
+    /// as the reply is full, so backends with very large directories should override
+    /// this to produce entries incrementally instead of materializing the whole listing.
+    /// The default implementation pages through [`NFSFileSystem::readdir_simple_after`]
+    /// in batches of a fixed size.
+    ///
+    /// The default implementation keeps paging until a page reports the end of the
+    /// directory. A page without entries that is not the last one must carry a
+    /// [`ReadDirSimpleResult::resume_after`] cookie; otherwise the stream ends with
+    /// NFS3ERR_SERVERFAULT rather than silently truncating the listing.
+    ///
+    /// Errors that prevent listing the directory at all (e.g. NFS3ERR_NOTDIR) should be
+    /// returned directly rather than as the first stream item.
+    ///
+    /// # Arguments
+    /// * `dirid` - The directory ID to read
+    /// * `start_after` - Cookie (file ID) to start reading after (0 for beginning)
+    ///
+    /// # Returns
+    /// * `Result<DirEntryStream, nfsstat3>` - Stream of directory entries on success, or an NFS error code
+    async fn readdir_stream(
+        &self,
+        dirid: nfs3::fileid3,
+        start_after: nfs3::fileid3,
+    ) -> Result<DirEntryStream<'_>, nfs3::nfsstat3> {
+        let first = self
+            .readdir_simple_after(dirid, start_after, READDIR_STREAM_BATCH)
+            .await?;
+        Ok(paged_entries(dirid, start_after, first, move |last| {
+            self.readdir_simple_after(dirid, last, READDIR_STREAM_BATCH)
+        }))
+    }
+
+    /// Gets the attributes of several files at once
+    ///
+    /// Used by READDIRPLUS to fetch attributes for the directory entries that are
+    /// returned to the client. Backends that can serve several lookups in a single
+    /// query should override this. The default implementation calls getattr for each ID.
+    ///
+    /// # Arguments
+    /// * `ids` - The file IDs to get attributes for
+    ///
+    /// # Returns
+    /// * `Vec<Result<fattr3, nfsstat3>>` - The attributes of each file, in the same order as `ids`
+    async fn getattr_batch(
+        &self,
+        ids: &[nfs3::fileid3],
+    ) -> Vec<Result<nfs3::fattr3, nfs3::nfsstat3>> {
+        let mut res = Vec::with_capacity(ids.len());
+        for id in ids {
+            res.push(self.getattr(*id).await);
+        }
+        res
+    }
+
     /// Creates a symbolic link
     ///
     /// This method creates a symbolic link in the specified directory pointing to the target path.
@@ -478,6 +897,357 @@ pub trait NFSFileSystem: Sync {
         count: u32,
     ) -> Result<nfs3::fattr3, nfs3::nfsstat3>;
 
+    /// Sets attributes and returns weak cache consistency data for the object
+    ///
+    /// If `guard` is set, the change is only applied when the current ctime of the
+    /// object matches it; otherwise NFS3ERR_NOT_SYNC is returned.
+    /// The default implementation brackets [`NFSFileSystem::setattr`] with getattr calls.
+    /// Backends that can snapshot attributes atomically with the change should
+    /// override this to give clients exact cache consistency.
+    ///
+    /// # Arguments
+    /// * `id` - The file ID to set attributes for
+    /// * `setattr` - The attributes to set
+    /// * `guard` - Optional ctime the object must currently have
+    ///
+    /// # Returns
+    /// * `WccResult<fattr3>` - The updated attributes and the object's wcc data
+    async fn setattr_wcc(
+        &self,
+        id: nfs3::fileid3,
+        setattr: nfs3::sattr3,
+        guard: Option<nfs3::nfstime3>,
+    ) -> WccResult<nfs3::fattr3> {
+        let pre = match self.getattr(id).await {
+            Ok(v) => v,
+            Err(stat) => {
+                return WccResult {
+                    result: Err(stat),
+                    wcc: nfs3::wcc_data::default(),
+                }
+            }
+        };
+        let before = nfs3::pre_op_attr::attributes(pre.into());
+        if let Some(c) = guard {
+            if c.seconds != pre.ctime.seconds || c.nseconds != pre.ctime.nseconds {
+                return WccResult {
+                    result: Err(nfs3::nfsstat3::NFS3ERR_NOT_SYNC),
+                    wcc: nfs3::wcc_data {
+                        before,
+                        after: nfs3::post_op_attr::attributes(pre),
+                    },
+                };
+            }
+        }
+        match self.setattr(id, setattr).await {
+            Ok(post) => WccResult {
+                result: Ok(post),
+                wcc: nfs3::wcc_data {
+                    before,
+                    after: nfs3::post_op_attr::attributes(post),
+                },
+            },
+            Err(stat) => WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data {
+                    before,
+                    after: post_op_attr(self, id).await,
+                },
+            },
+        }
+    }
+
+    /// Writes data and returns weak cache consistency data for the file
+    ///
+    /// The client asks for the data to be committed to stable storage at least as
+    /// far as `stable` says, and the implementation reports how far it actually was.
+    /// Reporting `UNSTABLE` lets an implementation buffer the data until
+    /// [`NFSFileSystem::commit`] is called; it must not report less than was asked for.
+    ///
+    /// The default implementation takes the pre-operation attributes with getattr,
+    /// uses the attributes returned by [`NFSFileSystem::write`] as post-operation attributes
+    /// and reports `FILE_SYNC`.
+    ///
+    /// # Arguments
+    /// * `id` - The file ID to write to
+    /// * `offset` - Byte offset within the file to start writing
+    /// * `data` - The data to write
+    /// * `stable` - How far the data must be committed before returning
+    ///
+    /// # Returns
+    /// * `WccResult<(fattr3, stable_how)>` - The updated attributes, how far the data
+    ///   was committed and the file's wcc data
+    async fn write_wcc(
+        &self,
+        id: nfs3::fileid3,
+        offset: u64,
+        data: &[u8],
+        _stable: nfs3::file::stable_how,
+    ) -> WccResult<(nfs3::fattr3, nfs3::file::stable_how)> {
+        let before = pre_op_attr(self, id).await;
+        match self.write(id, offset, data).await {
+            Ok(fattr) => WccResult {
+                result: Ok((fattr, nfs3::file::stable_how::FILE_SYNC)),
+                wcc: nfs3::wcc_data {
+                    before,
+                    after: nfs3::post_op_attr::attributes(fattr),
+                },
+            },
+            Err(stat) => WccResult {
+                result: Err(stat),
+                wcc: nfs3::wcc_data {
+                    before,
+                    after: post_op_attr(self, id).await,
+                },
+            },
+        }
+    }
+
+    /// Creates a file and returns weak cache consistency data for the parent directory
+    ///
+    /// The default implementation brackets [`NFSFileSystem::create`] with getattr calls on the directory.
+    ///
+    /// # Arguments
+    /// * `dirid` - The parent directory ID
+    /// * `filename` - The name for the new file
+    /// * `attr` - Initial attributes for the new file
+    ///
+    /// # Returns
+    /// * `WccResult<(fileid3, fattr3)>` - The new file's ID and attributes and the directory's wcc data
+    async fn create_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        attr: nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        dir_op_wcc(self, dirid, self.create(dirid, filename, attr)).await
+    }
+
+    /// Exclusively creates a file and returns weak cache consistency data for the parent directory
+    ///
+    /// The default implementation brackets [`NFSFileSystem::create_exclusive`] with getattr calls on the directory.
+    ///
+    /// # Arguments
+    /// * `dirid` - The parent directory ID
+    /// * `filename` - The name for the new file
+    ///
+    /// # Returns
+    /// * `WccResult<fileid3>` - The new file's ID and the directory's wcc data
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+    ) -> WccResult<nfs3::fileid3> {
+        dir_op_wcc(self, dirid, self.create_exclusive(dirid, filename)).await
+    }
+
+    /// Creates a directory and returns weak cache consistency data for the parent directory
+    ///
+    /// The default implementation brackets [`NFSFileSystem::mkdir`] with getattr calls on the directory.
+    ///
+    /// # Arguments
+    /// * `dirid` - The parent directory ID
+    /// * `dirname` - The name for the new directory
+    ///
+    /// # Returns
+    /// * `WccResult<(fileid3, fattr3)>` - The new directory's ID and attributes and the parent's wcc data
+    async fn mkdir_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        dirname: &nfs3::filename3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        dir_op_wcc(self, dirid, self.mkdir(dirid, dirname)).await
+    }
+
+    /// Removes a file or directory of the given kind and returns weak cache consistency
+    /// data for the parent directory
+    ///
+    /// An entry of another kind is not removed, and the call fails as described in
+    /// [`RemoveKind::check`]. The default implementation brackets
+    /// [`NFSFileSystem::remove`] with getattr calls on the directory, and looks up
+    /// the entry to check its type first unless `kind` is [`RemoveKind::Any`].
+    /// Backends that learn the type of the entry while removing it should override
+    /// this to check it there, saving REMOVE and RMDIR the extra calls.
+    ///
+    /// # Arguments
+    /// * `dirid` - The parent directory ID
+    /// * `filename` - The name of the file or directory to remove
+    /// * `kind` - The kind of entry to remove
+    ///
+    /// # Returns
+    /// * `WccResult<()>` - The outcome and the directory's wcc data
+    async fn remove_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        filename: &nfs3::filename3,
+        kind: RemoveKind,
+    ) -> WccResult<()> {
+        let remove = async {
+            if kind != RemoveKind::Any {
+                let id = self.lookup(dirid, filename).await?;
+                let attr = self.getattr(id).await?;
+                kind.check(matches!(attr.ftype, nfs3::ftype3::NF3DIR))?;
+            }
+            self.remove(dirid, filename).await
+        };
+        dir_op_wcc(self, dirid, remove).await
+    }
+
+    /// Renames a file or directory and returns weak cache consistency data for both directories
+    ///
+    /// The default implementation brackets [`NFSFileSystem::rename`] with getattr calls on
+    /// the source and target directories.
+    ///
+    /// # Arguments
+    /// * `from_dirid` - The source parent directory ID
+    /// * `from_filename` - The source file or directory name
+    /// * `to_dirid` - The destination parent directory ID
+    /// * `to_filename` - The destination file or directory name
+    ///
+    /// # Returns
+    /// * `RenameWccResult` - The outcome and the wcc data of both directories
+    async fn rename_wcc(
+        &self,
+        from_dirid: nfs3::fileid3,
+        from_filename: &nfs3::filename3,
+        to_dirid: nfs3::fileid3,
+        to_filename: &nfs3::filename3,
+    ) -> RenameWccResult {
+        let mut before = [nfs3::pre_op_attr::Void; 2];
+        for (slot, dirid) in before.iter_mut().zip([from_dirid, to_dirid]) {
+            match self.getattr(dirid).await {
+                Ok(v) => *slot = nfs3::pre_op_attr::attributes(v.into()),
+                Err(stat) => {
+                    return RenameWccResult {
+                        result: Err(stat),
+                        from_dir_wcc: nfs3::wcc_data::default(),
+                        to_dir_wcc: nfs3::wcc_data::default(),
+                    }
+                }
+            }
+        }
+        let result = self
+            .rename(from_dirid, from_filename, to_dirid, to_filename)
+            .await;
+        RenameWccResult {
+            result,
+            from_dir_wcc: nfs3::wcc_data {
+                before: before[0],
+                after: post_op_attr(self, from_dirid).await,
+            },
+            to_dir_wcc: nfs3::wcc_data {
+                before: before[1],
+                after: post_op_attr(self, to_dirid).await,
+            },
+        }
+    }
+
+    /// Creates a symbolic link and returns weak cache consistency data for the parent directory
+    ///
+    /// The default implementation brackets [`NFSFileSystem::symlink`] with getattr calls on the directory.
+    ///
+    /// # Arguments
+    /// * `dirid` - The parent directory ID
+    /// * `linkname` - The name of the symbolic link
+    /// * `symlink` - The target path that the link points to
+    /// * `attr` - Initial attributes for the symbolic link
+    ///
+    /// # Returns
+    /// * `WccResult<(fileid3, fattr3)>` - The new symlink's ID and attributes and the directory's wcc data
+    async fn symlink_wcc(
+        &self,
+        dirid: nfs3::fileid3,
+        linkname: &nfs3::filename3,
+        symlink: &nfs3::nfspath3,
+        attr: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        dir_op_wcc(self, dirid, self.symlink(dirid, linkname, symlink, attr)).await
+    }
+
+    /// Creates a hard link and returns weak cache consistency data for the link directory
+    ///
+    /// The default implementation brackets [`NFSFileSystem::link`] with getattr calls on the directory.
+    ///
+    /// # Arguments
+    /// * `file_id` - The ID of the existing file to link to
+    /// * `link_dir_id` - The parent directory ID for the new link
+    /// * `link_name` - The name for the new link
+    ///
+    /// # Returns
+    /// * `WccResult<fattr3>` - The file's attributes and the directory's wcc data
+    async fn link_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        link_dir_id: nfs3::fileid3,
+        link_name: &nfs3::filename3,
+    ) -> WccResult<nfs3::fattr3> {
+        dir_op_wcc(
+            self,
+            link_dir_id,
+            self.link(file_id, link_dir_id, link_name),
+        )
+        .await
+    }
+
+    /// Creates a special node and returns weak cache consistency data for the parent directory
+    ///
+    /// The default implementation brackets [`NFSFileSystem::mknod`] with getattr calls on the directory.
+    ///
+    /// # Arguments
+    /// * `dir_id` - The parent directory ID
+    /// * `name` - The name for the new special file
+    /// * `ftype` - The type of special file to create
+    /// * `specdata` - Device-specific information (major/minor numbers)
+    /// * `attrs` - Initial attributes for the new file
+    ///
+    /// # Returns
+    /// * `WccResult<(fileid3, fattr3)>` - The new file's ID and attributes and the directory's wcc data
+    async fn mknod_wcc(
+        &self,
+        dir_id: nfs3::fileid3,
+        name: &nfs3::filename3,
+        ftype: nfs3::ftype3,
+        specdata: nfs3::specdata3,
+        attrs: &nfs3::sattr3,
+    ) -> WccResult<(nfs3::fileid3, nfs3::fattr3)> {
+        dir_op_wcc(
+            self,
+            dir_id,
+            self.mknod(dir_id, name, ftype, specdata, attrs),
+        )
+        .await
+    }
+
+    /// Commits data to stable storage and returns weak cache consistency data for the file
+    ///
+    /// The default implementation takes the pre-operation attributes with getattr
+    /// and uses the attributes returned by [`NFSFileSystem::commit`] as post-operation attributes.
+    ///
+    /// # Arguments
+    /// * `file_id` - The file ID to commit
+    /// * `offset` - Starting offset for the commit operation
+    /// * `count` - Number of bytes to commit
+    ///
+    /// # Returns
+    /// * `WccResult<fattr3>` - The file attributes after commit and the file's wcc data
+    async fn commit_wcc(
+        &self,
+        file_id: nfs3::fileid3,
+        offset: u64,
+        count: u32,
+    ) -> WccResult<nfs3::fattr3> {
+        let before = pre_op_attr(self, file_id).await;
+        let result = self.commit(file_id, offset, count).await;
+        let after = match result {
+            Ok(fattr) => nfs3::post_op_attr::attributes(fattr),
+            Err(_) => post_op_attr(self, file_id).await,
+        };
+        WccResult {
+            result,
+            wcc: nfs3::wcc_data { before, after },
+        }
+    }
+
     /// Retrieves static file system information
     ///
     /// This method provides information about the file system's capabilities and parameters.
@@ -518,6 +1288,33 @@ pub trait NFSFileSystem: Sync {
         Ok(res)
     }
 
+    /// Retrieves volatile file system state, such as the free space
+    ///
+    /// The reported values may depend on the client, see [`caller`].
+    /// The default implementation reports a practically unlimited file system.
+    ///
+    /// # Arguments
+    /// * `id` - The file ID of an object in the file system
+    ///
+    /// # Returns
+    /// * `Result<FSSTAT3resok, nfsstat3>` - File system state on success, or an NFS error code
+    async fn fsstat(&self, id: nfs3::fileid3) -> Result<nfs3::fs::FSSTAT3resok, nfs3::nfsstat3> {
+        let obj_attributes = match self.getattr(id).await {
+            Ok(v) => nfs3::post_op_attr::attributes(v),
+            Err(_) => nfs3::post_op_attr::Void,
+        };
+        Ok(nfs3::fs::FSSTAT3resok {
+            obj_attributes,
+            tbytes: 1024 * 1024 * 1024 * 1024,
+            fbytes: 1024 * 1024 * 1024 * 1024,
+            abytes: 1024 * 1024 * 1024 * 1024,
+            tfiles: 1024 * 1024 * 1024,
+            ffiles: 1024 * 1024 * 1024,
+            afiles: 1024 * 1024 * 1024,
+            invarsec: u32::MAX,
+        })
+    }
+
     /// Converts a file ID to an opaque NFS file handle
     ///
     /// This method creates an opaque file handle from a file ID by combining
@@ -0,0 +1,238 @@
+//! Buffered write data of a file, kept as ranges that neither overlap nor touch.
+//!
+//! Used by file systems that collect writes in memory before storing them.
+//! Writes that overlap or touch a buffered range extend it in place, so that
+//! appending to a file copies every byte once rather than the whole range on
+//! every write.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Written data by offset
+#[derive(Debug, Default)]
+pub(crate) struct WriteRanges {
+    /// Data by offset; the ranges neither overlap nor touch
+    ranges: BTreeMap<u64, Vec<u8>>,
+    /// Total size of the ranges
+    bytes: usize,
+}
+
+impl WriteRanges {
+    /// Returns the total size of the buffered data
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Adds a write, merging it with the ranges it overlaps or touches
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Position of the data in the file
+    /// * `data` - The written data; its end must not exceed `u64::MAX`
+    pub fn insert(&mut self, offset: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let (start, mut buf) = match self.ranges.range(..=offset).next_back() {
+            Some((&pos, range)) if pos + range.len() as u64 >= offset => {
+                (pos, self.ranges.remove(&pos).unwrap())
+            }
+            _ => (offset, Vec::new()),
+        };
+        self.bytes -= buf.len();
+        let at = (offset - start) as usize;
+        let overlap = (buf.len() - at).min(data.len());
+        buf[at..at + overlap].copy_from_slice(&data[..overlap]);
+        buf.extend_from_slice(&data[overlap..]);
+        // ranges starting within the grown range, of which only a tail may remain
+        let stop = start + buf.len() as u64;
+        let covered: Vec<u64> = self
+            .ranges
+            .range((Bound::Excluded(start), Bound::Included(stop)))
+            .map(|(&pos, _)| pos)
+            .collect();
+        for pos in covered {
+            let range = self.ranges.remove(&pos).unwrap();
+            self.bytes -= range.len();
+            let skip = (start + buf.len() as u64 - pos) as usize;
+            if range.len() > skip {
+                buf.extend_from_slice(&range[skip..]);
+            }
+        }
+        self.bytes += buf.len();
+        self.ranges.insert(start, buf);
+    }
+
+    /// Removes and returns the first range
+    pub fn pop_first(&mut self) -> Option<(u64, Vec<u8>)> {
+        let (offset, data) = self.ranges.pop_first()?;
+        self.bytes -= data.len();
+        Some((offset, data))
+    }
+
+    /// Returns a range taken with [`Self::pop_first`] that could not be stored
+    pub fn put_back(&mut self, offset: u64, data: Vec<u8>) {
+        self.bytes += data.len();
+        self.ranges.insert(offset, data);
+    }
+
+    /// Returns the ranges that may overlap the range from `start` to `end`
+    fn covering(&self, start: u64, end: u64) -> impl Iterator<Item = (u64, &Vec<u8>)> {
+        let first = match self.ranges.range(..=start).next_back() {
+            Some((&pos, _)) => pos,
+            None => start,
+        };
+        self.ranges
+            .range(first..end.max(first))
+            .map(|(&pos, range)| (pos, range))
+    }
+
+    /// Copies the buffered data over the part of `out` it covers
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Position in the file of the first byte of `out`
+    /// * `out` - Data read from storage, to be updated with the buffered writes
+    pub fn overlay(&self, offset: u64, out: &mut [u8]) {
+        let end = offset.saturating_add(out.len() as u64);
+        for (start, range) in self.covering(offset, end) {
+            let stop = start + range.len() as u64;
+            if stop <= offset {
+                continue;
+            }
+            let from = start.max(offset);
+            let to = stop.min(end);
+            out[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&range[(from - start) as usize..(to - start) as usize]);
+        }
+    }
+}
+
+/// Operations of uploads that send the data in order, in parts
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+impl WriteRanges {
+    /// Returns the first range
+    pub fn first(&self) -> Option<(u64, &[u8])> {
+        self.ranges
+            .first_key_value()
+            .map(|(&offset, data)| (offset, data.as_slice()))
+    }
+
+    /// Drops the data at and beyond an offset
+    pub fn truncate(&mut self, size: u64) {
+        let cut = self.ranges.split_off(&size);
+        self.bytes -= cut.values().map(Vec::len).sum::<usize>();
+        if let Some((&pos, range)) = self.ranges.iter_mut().next_back() {
+            let len = (size - pos).min(range.len() as u64) as usize;
+            self.bytes -= range.len() - len;
+            range.truncate(len);
+        }
+    }
+
+    /// Drops the data before an offset
+    pub fn discard_before(&mut self, offset: u64) {
+        let mut kept = self.ranges.split_off(&offset);
+        for (pos, mut range) in std::mem::take(&mut self.ranges) {
+            self.bytes -= range.len();
+            if pos + range.len() as u64 > offset {
+                let tail = range.split_off((offset - pos) as usize);
+                self.bytes += tail.len();
+                kept.insert(offset, tail);
+            }
+        }
+        self.ranges = kept;
+    }
+
+    /// Returns the parts of the range from `start` to `end` that hold no buffered data
+    pub fn gaps(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut pos = start;
+        for (at, range) in self.covering(start, end) {
+            if at > pos {
+                gaps.push((pos, at));
+            }
+            pos = pos.max(at + range.len() as u64);
+        }
+        if pos < end {
+            gaps.push((pos, end));
+        }
+        gaps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the ranges as offsets and data
+    fn ranges(w: &WriteRanges) -> Vec<(u64, Vec<u8>)> {
+        w.ranges.iter().map(|(&pos, r)| (pos, r.clone())).collect()
+    }
+
+    #[test]
+    fn appends_extend_the_range_in_place() {
+        let mut w = WriteRanges::default();
+        w.insert(0, b"ab");
+        w.ranges.get_mut(&0).unwrap().reserve(16);
+        let reserved = w.ranges[&0].as_ptr();
+        w.insert(2, b"cd");
+        w.insert(4, b"ef");
+        assert_eq!(w.ranges[&0].as_ptr(), reserved);
+        assert_eq!(ranges(&w), vec![(0, b"abcdef".to_vec())]);
+        assert_eq!(w.bytes(), 6);
+    }
+
+    #[test]
+    fn writes_merge_with_the_ranges_they_overlap_or_touch() {
+        let mut w = WriteRanges::default();
+        w.insert(10, b"klm");
+        w.insert(0, b"abc");
+        w.insert(20, b"uvw");
+        assert_eq!(w.bytes(), 9);
+        // overwrites the middle of a range
+        w.insert(1, b"B");
+        // bridges the gap between two ranges and keeps the tail of the second
+        w.insert(3, b"defghij");
+        assert_eq!(
+            ranges(&w),
+            vec![(0, b"aBcdefghijklm".to_vec()), (20, b"uvw".to_vec())]
+        );
+        // covers a range completely
+        w.insert(19, b"TUVWX");
+        assert_eq!(ranges(&w)[1], (19, b"TUVWX".to_vec()));
+        assert_eq!(w.bytes(), 18);
+        w.insert(u64::MAX - 2, b"xy");
+        assert_eq!(w.bytes(), 20);
+    }
+
+    #[test]
+    fn overlay_copies_the_covered_part() {
+        let mut w = WriteRanges::default();
+        w.insert(2, b"cd");
+        w.insert(6, b"gh");
+        let mut out = *b"......";
+        w.overlay(1, &mut out);
+        assert_eq!(&out, b".cd..g");
+        let (offset, data) = w.pop_first().unwrap();
+        assert_eq!((offset, w.bytes()), (2, 2));
+        w.put_back(offset, data);
+        assert_eq!(w.bytes(), 4);
+    }
+
+    #[test]
+    fn truncating_and_discarding_keep_the_rest() {
+        let mut w = WriteRanges::default();
+        w.insert(0, b"abcd");
+        w.insert(6, b"ghij");
+        assert_eq!(w.gaps(2, 12), vec![(4, 6), (10, 12)]);
+        w.discard_before(2);
+        assert_eq!(w.first(), Some((2, &b"cd"[..])));
+        w.truncate(8);
+        assert_eq!(ranges(&w), vec![(2, b"cd".to_vec()), (6, b"gh".to_vec())]);
+        assert_eq!(w.bytes(), 4);
+        w.truncate(3);
+        w.discard_before(7);
+        assert_eq!(w.bytes(), 0);
+        assert_eq!(w.gaps(0, 4), vec![(0, 4)]);
+    }
+}
@@ -0,0 +1,726 @@
+//! Tests of the S3 backend against a minimal in-process object store.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use nfsserve::backends::{DirMarker, S3Options, S3FS};
+use nfsserve::vfs::NFSFileSystem;
+use nfsserve::xdr::nfs3;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Name of the bucket served by the mock
+const BUCKET: &str = "bucket";
+
+/// Size of a mebibyte
+const MIB: usize = 1024 * 1024;
+
+/// Smallest size of a part other than the last one accepted by the object store
+const MIN_PART_SIZE: usize = 5 * MIB;
+
+/// Modification time reported for every object
+const LAST_MODIFIED: &str = "Mon, 01 Jan 2024 00:00:00 GMT";
+
+/// A request received by the mock
+#[derive(Debug, Clone)]
+struct Request {
+    /// HTTP method
+    method: String,
+    /// Object key, empty for requests on the bucket
+    key: String,
+    /// Decoded query parameters
+    query: HashMap<String, String>,
+    /// Headers by lowercase name
+    headers: HashMap<String, String>,
+    /// Request body
+    body: Vec<u8>,
+}
+
+/// A response of the mock as status, headers and body
+type Response = (u16, Vec<(String, String)>, Vec<u8>);
+
+/// A multipart upload as key and parts by number, with their entity tags
+type Upload = (String, BTreeMap<u32, (String, Vec<u8>)>);
+
+/// Contents of the mock object store
+#[derive(Default)]
+struct State {
+    /// Objects by key
+    objects: BTreeMap<String, Vec<u8>>,
+    /// Multipart uploads by ID
+    uploads: HashMap<String, Upload>,
+    /// Number of multipart uploads started
+    started: u32,
+    /// Requests received, oldest first
+    requests: Vec<Request>,
+}
+
+/// Object store serving one bucket with path-style requests
+///
+/// Implements just enough of the S3 REST API for [`S3FS`]. Signatures are not
+/// checked.
+#[derive(Clone)]
+struct MockS3 {
+    /// URL of the object store
+    endpoint: String,
+    /// Contents of the object store
+    state: Arc<Mutex<State>>,
+}
+
+impl MockS3 {
+    /// Starts an empty object store on a free local port
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mock = MockS3 {
+            endpoint: format!("http://{}", listener.local_addr().unwrap()),
+            state: Arc::default(),
+        };
+        let server = mock.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let server = server.clone();
+                tokio::spawn(async move { server.serve(stream).await });
+            }
+        });
+        mock
+    }
+
+    /// Creates a file system exporting the bucket with the smallest part size
+    fn fs(&self, dir_marker: DirMarker) -> S3FS {
+        S3FS::new(S3Options {
+            endpoint: self.endpoint.clone(),
+            bucket: BUCKET.to_string(),
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+            dir_marker,
+            part_size: MIN_PART_SIZE,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// Stores an object directly
+    fn put(&self, key: &str, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.objects.insert(key.to_string(), data.to_vec());
+    }
+
+    /// Returns the contents of an object
+    fn object(&self, key: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().objects.get(key).cloned()
+    }
+
+    /// Returns the keys of all objects in order
+    fn keys(&self) -> Vec<String> {
+        self.state.lock().unwrap().objects.keys().cloned().collect()
+    }
+
+    /// Returns the requests received since the last call
+    fn take_requests(&self) -> Vec<Request> {
+        std::mem::take(&mut self.state.lock().unwrap().requests)
+    }
+
+    /// Answers the requests of a connection until the client closes it
+    async fn serve(&self, stream: TcpStream) {
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let target = parts.next().unwrap_or_default().to_string();
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+                }
+            }
+            let len = headers
+                .get("content-length")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; len];
+            if stream.read_exact(&mut body).await.is_err() {
+                return;
+            }
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+            let path = decode(path);
+            let key = match path.strip_prefix(&format!("/{BUCKET}")) {
+                Some(rest) => rest.strip_prefix('/').unwrap_or(rest).to_string(),
+                None => return,
+            };
+            let query = query
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .map(|param| {
+                    let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                    (decode(name), decode(value))
+                })
+                .collect();
+            let request = Request {
+                method,
+                key,
+                query,
+                headers,
+                body,
+            };
+            let (status, headers, body) = self.handle(&request);
+            let is_head = request.method == "HEAD";
+            // logged before answering, so the client cannot see the response first
+            self.state.lock().unwrap().requests.push(request);
+            let mut head = format!("HTTP/1.1 {status} Mock\r\n");
+            for (name, value) in &headers {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+            if status != 204 && !headers.iter().any(|(name, _)| name == "content-length") {
+                head.push_str(&format!("content-length: {}\r\n", body.len()));
+            }
+            head.push_str("\r\n");
+            let stream = stream.get_mut();
+            if stream.write_all(head.as_bytes()).await.is_err() {
+                return;
+            }
+            if !is_head && stream.write_all(&body).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Executes a request
+    fn handle(&self, request: &Request) -> Response {
+        let mut state = self.state.lock().unwrap();
+        let key = request.key.as_str();
+        let query = &request.query;
+        match (request.method.as_str(), key.is_empty()) {
+            ("GET", true) => list(&state, query),
+            ("HEAD", false) => match state.objects.get(key) {
+                Some(data) => (
+                    200,
+                    vec![
+                        ("content-length".to_string(), data.len().to_string()),
+                        ("last-modified".to_string(), LAST_MODIFIED.to_string()),
+                    ],
+                    Vec::new(),
+                ),
+                None => (404, Vec::new(), Vec::new()),
+            },
+            ("GET", false) => match state.objects.get(key) {
+                Some(data) => get(data, request.headers.get("range")),
+                None => error(404, "NoSuchKey"),
+            },
+            ("PUT", false) if query.contains_key("partNumber") => {
+                let Some((_, parts)) = state.uploads.get_mut(&query["uploadId"]) else {
+                    return error(404, "NoSuchUpload");
+                };
+                let number: u32 = query["partNumber"].parse().unwrap();
+                let etag = format!("\"part-{number}-{}\"", request.body.len());
+                parts.insert(number, (etag.clone(), request.body.clone()));
+                (200, vec![("etag".to_string(), etag)], Vec::new())
+            }
+            ("PUT", false) if request.headers.contains_key("x-amz-copy-source") => {
+                let source = decode(&request.headers["x-amz-copy-source"]);
+                let source = source.strip_prefix(&format!("/{BUCKET}/")).unwrap_or("");
+                let Some(data) = state.objects.get(source).cloned() else {
+                    return error(404, "NoSuchKey");
+                };
+                state.objects.insert(key.to_string(), data);
+                let body = "<CopyObjectResult><LastModified>2024-01-01T00:00:00.000Z\
+                            </LastModified></CopyObjectResult>";
+                (200, Vec::new(), body.as_bytes().to_vec())
+            }
+            ("PUT", false) => {
+                state.objects.insert(key.to_string(), request.body.clone());
+                (200, Vec::new(), Vec::new())
+            }
+            ("POST", false) if query.contains_key("uploads") => {
+                state.started += 1;
+                let upload_id = format!("upload-{}", state.started);
+                state
+                    .uploads
+                    .insert(upload_id.clone(), (key.to_string(), BTreeMap::new()));
+                let body = format!(
+                    "<InitiateMultipartUploadResult><Bucket>{BUCKET}</Bucket><Key>{}</Key>\
+                     <UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>",
+                    escape(key)
+                );
+                (200, Vec::new(), body.into_bytes())
+            }
+            ("POST", false) if query.contains_key("uploadId") => {
+                let Some((_, parts)) = state.uploads.remove(&query["uploadId"]) else {
+                    return error(404, "NoSuchUpload");
+                };
+                let body = String::from_utf8_lossy(&request.body).to_string();
+                let listed = elements(&body, "Part");
+                let mut data = Vec::new();
+                for (i, part) in listed.iter().enumerate() {
+                    let number: u32 = elements(part, "PartNumber")[0].parse().unwrap();
+                    let etag = elements(part, "ETag")[0].replace("&quot;", "\"");
+                    let Some((expected, part_data)) = parts.get(&number) else {
+                        return error(400, "InvalidPart");
+                    };
+                    if *expected != etag {
+                        return error(400, "InvalidPart");
+                    }
+                    if i + 1 < listed.len() && part_data.len() < MIN_PART_SIZE {
+                        return error(400, "EntityTooSmall");
+                    }
+                    data.extend_from_slice(part_data);
+                }
+                state.objects.insert(key.to_string(), data);
+                let body = format!(
+                    "<CompleteMultipartUploadResult><Key>{}</Key></CompleteMultipartUploadResult>",
+                    escape(key)
+                );
+                (200, Vec::new(), body.into_bytes())
+            }
+            ("DELETE", false) if query.contains_key("uploadId") => {
+                state.uploads.remove(&query["uploadId"]);
+                (204, Vec::new(), Vec::new())
+            }
+            ("DELETE", false) => {
+                state.objects.remove(key);
+                (204, Vec::new(), Vec::new())
+            }
+            _ => error(400, "InvalidRequest"),
+        }
+    }
+}
+
+/// Answers a ListObjectsV2 request
+fn list(state: &State, query: &HashMap<String, String>) -> Response {
+    let prefix = query.get("prefix").map(String::as_str).unwrap_or("");
+    let delimiter = query.contains_key("delimiter");
+    let max_keys = query
+        .get("max-keys")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+    // the token is the last key or common prefix returned
+    let token = query.get("continuation-token");
+    let mut entries: Vec<(String, Option<usize>)> = Vec::new();
+    for (key, data) in state.objects.range(prefix.to_string()..) {
+        let Some(rest) = key.strip_prefix(prefix) else {
+            break;
+        };
+        let entry = match rest.find('/').filter(|_| delimiter) {
+            Some(slash) => (format!("{prefix}{}", &rest[..=slash]), None),
+            None => (key.clone(), Some(data.len())),
+        };
+        if token.is_some_and(|token| entry.0 <= *token) || entries.last() == Some(&entry) {
+            continue;
+        }
+        entries.push(entry);
+    }
+    let truncated = entries.len() > max_keys;
+    entries.truncate(max_keys);
+    let mut body = format!(
+        "<ListBucketResult><Name>{BUCKET}</Name><Prefix>{}</Prefix>\
+         <IsTruncated>{truncated}</IsTruncated>",
+        escape(prefix)
+    );
+    if truncated {
+        let last = &entries.last().unwrap().0;
+        body.push_str(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            escape(last)
+        ));
+    }
+    for (key, size) in &entries {
+        match size {
+            Some(size) => body.push_str(&format!(
+                "<Contents><Key>{}</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified>\
+                 <Size>{size}</Size></Contents>",
+                escape(key)
+            )),
+            None => body.push_str(&format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                escape(key)
+            )),
+        }
+    }
+    body.push_str("</ListBucketResult>");
+    (200, Vec::new(), body.into_bytes())
+}
+
+/// Answers a GET request for an object, honoring a `bytes=first-last` range
+fn get(data: &[u8], range: Option<&String>) -> Response {
+    let Some(range) = range.and_then(|r| r.strip_prefix("bytes=")) else {
+        return (200, Vec::new(), data.to_vec());
+    };
+    let (first, last) = range.split_once('-').unwrap();
+    let first: usize = first.parse().unwrap();
+    let last: usize = last.parse().unwrap();
+    if first >= data.len() {
+        return error(416, "InvalidRange");
+    }
+    let last = last.min(data.len() - 1);
+    let content_range = format!("bytes {first}-{last}/{}", data.len());
+    (
+        206,
+        vec![("content-range".to_string(), content_range)],
+        data[first..=last].to_vec(),
+    )
+}
+
+/// Builds an error response
+fn error(status: u16, code: &str) -> Response {
+    let body = format!("<Error><Code>{code}</Code><Message>{code}</Message></Error>");
+    (status, Vec::new(), body.into_bytes())
+}
+
+/// Returns the contents of the elements with the given name
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+    xml.split(&open)
+        .skip(1)
+        .filter_map(|rest| rest.split_once(&close).map(|(inner, _)| inner))
+        .collect()
+}
+
+/// Escapes text for XML
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Decodes a percent-encoded string
+fn decode(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = s.get(i + 1..i + 3).filter(|_| bytes[i] == b'%');
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).unwrap()
+}
+
+fn name(s: &str) -> nfs3::filename3 {
+    s.as_bytes().into()
+}
+
+/// Returns test data that differs between seeds
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// Returns the names of a directory in listing order
+async fn names(fs: &S3FS, dirid: nfs3::fileid3) -> Vec<String> {
+    let result = fs.readdir(dirid, 0, 100).await.unwrap();
+    assert!(result.end);
+    result
+        .entries
+        .iter()
+        .map(|e| String::from_utf8(e.name.to_vec()).unwrap())
+        .collect()
+}
+
+/// Counts the requests with a method and a query parameter
+fn count(requests: &[Request], method: &str, param: &str) -> usize {
+    requests
+        .iter()
+        .filter(|r| r.method == method && r.query.contains_key(param))
+        .count()
+}
+
+#[tokio::test]
+async fn reads_fetch_ranges() {
+    let s3 = MockS3::start().await;
+    let content = data(1000, 1);
+    s3.put("file", &content);
+    let fs = s3.fs(DirMarker::Slash);
+    let id = fs.lookup(fs.root_dir(), &name("file")).await.unwrap();
+    s3.take_requests();
+
+    let (read, eof) = fs.read(id, 100, 200).await.unwrap();
+    assert_eq!(read, &content[100..300]);
+    assert!(!eof);
+    let requests = s3.take_requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "GET");
+    assert_eq!(requests[0].headers["range"], "bytes=100-299");
+
+    let (read, eof) = fs.read(id, 900, 200).await.unwrap();
+    assert_eq!(read, &content[900..]);
+    assert!(eof);
+    let (read, eof) = fs.read(id, 2000, 10).await.unwrap();
+    assert!(read.is_empty());
+    assert!(eof);
+}
+
+#[tokio::test]
+async fn small_files_are_stored_with_one_put() {
+    let s3 = MockS3::start().await;
+    let fs = s3.fs(DirMarker::Slash);
+    let (id, _) = fs
+        .create(fs.root_dir(), &name("small"), Default::default())
+        .await
+        .unwrap();
+    s3.take_requests();
+
+    assert_eq!(fs.write(id, 0, b"hello world").await.unwrap().size, 11);
+    let requests = s3.take_requests();
+    assert_eq!(count(&requests, "POST", "uploads"), 0);
+    let puts: Vec<_> = requests.iter().filter(|r| r.method == "PUT").collect();
+    assert_eq!(puts.len(), 1);
+    assert_eq!(puts[0].key, "small");
+    assert_eq!(s3.object("small").unwrap(), b"hello world");
+}
+
+#[tokio::test]
+async fn large_files_are_stored_with_multipart_uploads() {
+    let s3 = MockS3::start().await;
+    let fs = s3.fs(DirMarker::Slash);
+    let (id, _) = fs
+        .create(fs.root_dir(), &name("big"), Default::default())
+        .await
+        .unwrap();
+    s3.take_requests();
+
+    let content = data(12 * MIB + 5, 2);
+    for (i, chunk) in content.chunks(MIB).enumerate() {
+        let res = fs
+            .write_wcc(
+                id,
+                (i * MIB) as u64,
+                chunk,
+                nfs3::file::stable_how::UNSTABLE,
+            )
+            .await;
+        res.result.unwrap();
+    }
+    // full parts are uploaded as soon as they are written
+    let requests = s3.take_requests();
+    assert_eq!(count(&requests, "POST", "uploads"), 1);
+    assert_eq!(count(&requests, "PUT", "partNumber"), 2);
+    assert_eq!(s3.object("big").unwrap(), b"");
+    assert_eq!(fs.getattr(id).await.unwrap().size, content.len() as u64);
+
+    fs.commit(id, 0, 0).await.unwrap();
+    let requests = s3.take_requests();
+    assert_eq!(count(&requests, "PUT", "partNumber"), 1);
+    assert_eq!(count(&requests, "POST", "uploadId"), 1);
+    assert_eq!(s3.object("big").unwrap(), content);
+    let (read, _) = fs.read(id, 5 * MIB as u64 - 10, 20).await.unwrap();
+    assert_eq!(read, &content[5 * MIB - 10..5 * MIB + 10]);
+}
+
+#[tokio::test]
+async fn rewriting_an_uploaded_part_starts_over() {
+    let s3 = MockS3::start().await;
+    let fs = s3.fs(DirMarker::Slash);
+    let (id, _) = fs
+        .create(fs.root_dir(), &name("file"), Default::default())
+        .await
+        .unwrap();
+    let mut content = data(6 * MIB, 3);
+    let unstable = nfs3::file::stable_how::UNSTABLE;
+    fs.write_wcc(id, 0, &content, unstable)
+        .await
+        .result
+        .unwrap();
+    s3.take_requests();
+
+    // the first part is already uploaded, so the upload is completed first
+    fs.write_wcc(id, 100, b"patch", unstable)
+        .await
+        .result
+        .unwrap();
+    let requests = s3.take_requests();
+    assert_eq!(count(&requests, "POST", "uploadId"), 1);
+    assert_eq!(s3.object("file").unwrap(), content);
+
+    // the new upload copies the unchanged data from the stored object
+    fs.commit(id, 0, 0).await.unwrap();
+    content[100..105].copy_from_slice(b"patch");
+    let requests = s3.take_requests();
+    assert_eq!(count(&requests, "POST", "uploads"), 1);
+    assert_eq!(count(&requests, "POST", "uploadId"), 1);
+    assert!(requests
+        .iter()
+        .any(|r| r.method == "GET" && r.headers.contains_key("range")));
+    assert_eq!(s3.object("file").unwrap(), content);
+}
+
+#[tokio::test]
+async fn renames_copy_and_delete_objects() {
+    let s3 = MockS3::start().await;
+    let fs = s3.fs(DirMarker::Slash);
+    let root = fs.root_dir();
+    let (id, _) = fs
+        .create(root, &name("a"), Default::default())
+        .await
+        .unwrap();
+    fs.write(id, 0, b"data").await.unwrap();
+    s3.take_requests();
+
+    fs.rename(root, &name("a"), root, &name("b")).await.unwrap();
+    let requests = s3.take_requests();
+    let copy = requests
+        .iter()
+        .position(|r| r.method == "PUT" && r.key == "b")
+        .unwrap();
+    assert_eq!(
+        decode(&requests[copy].headers["x-amz-copy-source"]),
+        format!("/{BUCKET}/a")
+    );
+    let delete = requests
+        .iter()
+        .position(|r| r.method == "DELETE" && r.key == "a")
+        .unwrap();
+    assert!(copy < delete);
+    assert_eq!(s3.keys(), ["b"]);
+    assert_eq!(fs.read(id, 0, 10).await.unwrap().0, b"data");
+
+    let (dir, _) = fs.mkdir(root, &name("d")).await.unwrap();
+    let (file, _) = fs
+        .create(dir, &name("f"), Default::default())
+        .await
+        .unwrap();
+    fs.write(file, 0, b"inner").await.unwrap();
+    fs.rename(root, &name("d"), root, &name("e")).await.unwrap();
+    assert_eq!(s3.keys(), ["b", "e/", "e/f"]);
+    let dir = fs.lookup(root, &name("e")).await.unwrap();
+    let file = fs.lookup(dir, &name("f")).await.unwrap();
+    assert_eq!(fs.read(file, 0, 10).await.unwrap().0, b"inner");
+}
+
+/// Checks how directories are stored with a marker convention
+///
+/// # Arguments
+///
+/// * `dir_marker` - The convention to use
+/// * `marker` - The key of the marker of a directory named `d`, if markers are written
+async fn check_dir_marker(dir_marker: DirMarker, marker: Option<&str>) {
+    let s3 = MockS3::start().await;
+    let fs = s3.fs(dir_marker.clone());
+    let root = fs.root_dir();
+    let (dir, attr) = fs.mkdir(root, &name("d")).await.unwrap();
+    assert!(matches!(attr.ftype, nfs3::ftype3::NF3DIR));
+    assert_eq!(s3.keys(), marker.into_iter().collect::<Vec<_>>());
+    assert_eq!(names(&fs, root).await, ["d"]);
+    assert!(names(&fs, dir).await.is_empty());
+
+    // objects below a directory make it visible regardless of markers
+    let (file, _) = fs
+        .create(dir, &name("f"), Default::default())
+        .await
+        .unwrap();
+    fs.write(file, 0, b"x").await.unwrap();
+    let other = s3.fs(dir_marker.clone());
+    let dir = other.lookup(root, &name("d")).await.unwrap();
+    assert!(matches!(
+        other.getattr(dir).await.unwrap().ftype,
+        nfs3::ftype3::NF3DIR
+    ));
+    assert_eq!(names(&other, dir).await, ["f"]);
+    assert!(matches!(
+        other.remove(root, &name("d")).await,
+        Err(nfs3::nfsstat3::NFS3ERR_NOTEMPTY)
+    ));
+
+    // an empty directory survives a restart only with a marker
+    other.remove(dir, &name("f")).await.unwrap();
+    let restarted = s3.fs(dir_marker);
+    let found = restarted.lookup(root, &name("d")).await;
+    assert_eq!(found.is_ok(), marker.is_some());
+
+    fs.remove(root, &name("d")).await.unwrap();
+    assert!(s3.keys().is_empty());
+    assert!(names(&fs, root).await.is_empty());
+}
+
+#[tokio::test]
+async fn slash_markers() {
+    check_dir_marker(DirMarker::Slash, Some("d/")).await;
+}
+
+#[tokio::test]
+async fn suffix_markers() {
+    check_dir_marker(
+        DirMarker::Suffix("_$folder$".to_string()),
+        Some("d_$folder$"),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn no_markers() {
+    check_dir_marker(DirMarker::None, None).await;
+}
+
+#[tokio::test]
+async fn paging_a_directory_lists_it_once() {
+    let s3 = MockS3::start().await;
+    for i in 0..250 {
+        s3.put(&format!("dir/{i:03}"), b"x");
+    }
+    let fs = s3.fs(DirMarker::Slash);
+    let dir = fs.lookup(fs.root_dir(), &name("dir")).await.unwrap();
+    s3.take_requests();
+
+    let mut listed = Vec::new();
+    let mut cookie = 0;
+    loop {
+        let page = fs.readdir(dir, cookie, 10).await.unwrap();
+        listed.extend(
+            page.entries
+                .iter()
+                .map(|e| String::from_utf8(e.name.to_vec()).unwrap()),
+        );
+        cookie = page.entries.last().map_or(cookie, |e| e.fileid);
+        if page.end {
+            break;
+        }
+    }
+    assert_eq!(listed.len(), 250);
+    assert_eq!(count(&s3.take_requests(), "GET", "list-type"), 1);
+    let page = fs.readdir(dir, u64::MAX, 10).await.unwrap();
+    assert!(page.entries.is_empty() && page.end);
+
+    // a change through the file system drops the kept listing
+    let (file, _) = fs
+        .create(dir, &name("new"), Default::default())
+        .await
+        .unwrap();
+    let page = fs.readdir(dir, cookie, 10).await.unwrap();
+    assert_eq!(page.entries.len(), 1);
+    assert_eq!(page.entries[0].fileid, file);
+}
+
+#[tokio::test]
+async fn writes_past_the_largest_size_fail() {
+    let s3 = MockS3::start().await;
+    let fs = s3.fs(DirMarker::Slash);
+    let (id, _) = fs
+        .create(fs.root_dir(), &name("file"), Default::default())
+        .await
+        .unwrap();
+    for offset in [u64::MAX - 1, (MIN_PART_SIZE * 10000) as u64] {
+        let res = fs
+            .write_wcc(id, offset, b"data", nfs3::file::stable_how::UNSTABLE)
+            .await;
+        assert!(matches!(res.result, Err(nfs3::nfsstat3::NFS3ERR_FBIG)));
+    }
+    assert_eq!(fs.getattr(id).await.unwrap().size, 0);
+}